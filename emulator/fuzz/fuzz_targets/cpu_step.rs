@@ -0,0 +1,45 @@
+#![no_main]
+
+use emulator::gb::cpu::CPU;
+use emulator::gb::ram::RAM;
+use libfuzzer_sys::fuzz_target;
+
+/*
+Runs whatever the fuzzer comes up with as a program. The first 13 bytes set the registers
+and how the CPU runs, the rest is copied into memory from 0x0000, over the peripherals too.
+Nothing may panic: illegal opcodes lock the CPU up and every address wraps around.
+
+  cargo fuzz run cpu_step
+ */
+
+const HEADER_SIZE: usize = 13;
+const MAX_STEPS: usize = 10_000;
+
+fuzz_target!(|data: &[u8]| {
+    if data.len() < HEADER_SIZE {
+        return;
+    }
+    let (header, program) = data.split_at(HEADER_SIZE);
+    let word = |i: usize| u16::from_le_bytes([header[i], header[i + 1]]);
+
+    let mut ram = RAM::new();
+    for (address, &byte) in program.iter().take(0x10000).enumerate() {
+        ram.write(address as u16, byte);
+    }
+    let mut cpu = CPU::new(&mut ram);
+    cpu.registers.set_af(word(0));
+    cpu.registers.set_bc(word(2));
+    cpu.registers.set_de(word(4));
+    cpu.registers.set_hl(word(6));
+    cpu.registers.set_sp(word(8));
+    cpu.registers.set_pc(word(10));
+    cpu.interrupt_master_enable = header[12] & 0x01 != 0;
+    cpu.m_cycle_mode = header[12] & 0x02 != 0;
+
+    for _ in 0..MAX_STEPS {
+        cpu.step();
+        if cpu.lockup.is_some() {
+            break;
+        }
+    }
+});
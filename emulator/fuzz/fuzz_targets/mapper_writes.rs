@@ -0,0 +1,62 @@
+#![no_main]
+
+use emulator::gb::cartridge::Cartridge;
+use emulator::gb::state::{StateReader, StateWriter};
+use libfuzzer_sys::fuzz_target;
+
+/*
+Drives a mapper with a sequence of register writes, RAM accesses and clock ticks. The first
+4 bytes pick the cartridge type, how many ROM banks there are, and the ROM and RAM size codes
+the header claims, which don't have to agree with the real size. The rest is read 4 bytes
+at a time: an operation, an address and a value.
+
+  cargo fuzz run mapper_writes
+ */
+
+const HEADER_SIZE: usize = 4;
+const ROM_BANK_SIZE: usize = 0x4000;
+const MAX_ROM_BANKS: usize = 8;
+const CARTRIDGE_TYPES: [u8; 19] = [
+    0x00, 0x01, 0x02, 0x03, 0x05, 0x06, 0x08, 0x09, 0x0F, 0x10, 0x11, 0x12, 0x13, 0x19, 0x1A, 0x1B, 0x1C, 0x1D, 0x1E,
+];
+
+fuzz_target!(|data: &[u8]| {
+    if data.len() < HEADER_SIZE {
+        return;
+    }
+    let (header, operations) = data.split_at(HEADER_SIZE);
+
+    // Each bank starts with its own number, so a read shows which one is mapped in
+    let banks = header[1] as usize % MAX_ROM_BANKS + 1;
+    let mut rom = vec![0; banks * ROM_BANK_SIZE];
+    for bank in 0..banks {
+        rom[bank * ROM_BANK_SIZE] = bank as u8;
+    }
+    rom[0x147] = CARTRIDGE_TYPES[header[0] as usize % CARTRIDGE_TYPES.len()];
+    rom[0x148] = header[2] % 9;
+    rom[0x149] = header[3] % 6;
+    let mut cartridge = Cartridge::new(rom).expect("Only supported headers are built");
+
+    for operation in operations.chunks_exact(4) {
+        let address = u16::from_le_bytes([operation[1], operation[2]]);
+        let value = operation[3];
+        match operation[0] % 5 {
+            0 => cartridge.write(address & 0x7FFF, value),
+            1 => cartridge.write(0xA000 | (address & 0x1FFF), value),
+            2 => {
+                cartridge.read(address & 0x7FFF);
+            }
+            3 => {
+                cartridge.read(0xA000 | (address & 0x1FFF));
+            }
+            _ => cartridge.tick(value as u32 * 0x10000),
+        }
+    }
+
+    let mut writer = StateWriter::new();
+    cartridge.save_state(&mut writer);
+    let state = writer.into_bytes();
+    let ram = cartridge.ram().to_vec();
+    cartridge.load_state(&mut StateReader::new(&state)).expect("A state just saved has to load");
+    assert_eq!(cartridge.ram(), ram);
+});
@@ -0,0 +1,34 @@
+#![no_main]
+
+use emulator::gb::GameBoy;
+use emulator::gb::cartridge::Cartridge;
+use emulator::gb::joypad::Buttons;
+use emulator::gb::ram::RAM;
+use libfuzzer_sys::fuzz_target;
+
+/*
+Loads whatever the fuzzer comes up with as a ROM image. A header the emulator doesn't
+support has to come back as an error, anything it accepts has to boot, run a few frames
+and survive a save state round trip.
+
+  cargo fuzz run rom_image
+ */
+
+const FRAMES: usize = 4;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(cartridge) = Cartridge::new(data.to_vec()) else {
+        return;
+    };
+    let mut ram = RAM::with_cartridge(cartridge);
+    let mut gameboy = GameBoy::new(&mut ram);
+    for _ in 0..FRAMES {
+        gameboy.run_frame(&mut |_| Buttons::NONE);
+        if gameboy.cpu.lockup.is_some() {
+            break;
+        }
+    }
+
+    let state = gameboy.save_state();
+    gameboy.load_state(&state).expect("A state just saved has to load");
+});
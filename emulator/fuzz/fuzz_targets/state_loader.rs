@@ -0,0 +1,41 @@
+#![no_main]
+
+use emulator::gb::GameBoy;
+use emulator::gb::cartridge::Cartridge;
+use emulator::gb::joypad::Buttons;
+use emulator::gb::movie::Movie;
+use emulator::gb::ram::RAM;
+use libfuzzer_sys::fuzz_target;
+
+/*
+Feeds whatever the fuzzer comes up with to the save state and movie loaders. A state that
+loads has to leave a machine that runs, one that doesn't may have been partly applied but
+nothing may panic. The cartridge is an MBC3 with RAM and a clock, so the cartridge section
+is needed too.
+
+States carry the CRC32 of their ROM right after the magic and versions. The fuzzer would
+rarely guess it, so it's written in before loading. Starting from a real state saved by
+the emulator gets it to the component sections much sooner.
+
+  cargo fuzz run state_loader corpus/state_loader
+ */
+
+const CHECKSUM_OFFSET: usize = 8;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = Movie::from_bytes(data);
+
+    let mut rom = vec![0; 0x8000];
+    rom[0x147] = 0x10; // MBC3 + TIMER + RAM + BATTERY
+    rom[0x149] = 0x03;
+    let mut ram = RAM::with_cartridge(Cartridge::new(rom).unwrap());
+    let mut gameboy = GameBoy::new(&mut ram);
+
+    let mut state = data.to_vec();
+    if let Some(checksum) = state.get_mut(CHECKSUM_OFFSET..CHECKSUM_OFFSET + 4) {
+        checksum.copy_from_slice(&gameboy.rom_checksum().to_le_bytes());
+    }
+    if gameboy.load_state(&state).is_ok() {
+        gameboy.run_frame(&mut |_| Buttons::NONE);
+    }
+});
@@ -0,0 +1,652 @@
+use std::collections::VecDeque;
+use std::io::{self, BufRead, Write};
+use std::panic::{self, AssertUnwindSafe};
+
+use crate::gb::cpu::{Lockup, CPU};
+use crate::gb::disassembler::{disassemble, instruction_size};
+use crate::gb::ram::{Access, WatchHit, Watchpoint};
+use crate::gb::register::Flags;
+use crate::gb::GameBoy;
+use crate::headless::parse_number;
+
+/*
+Interactive debugger, started with `emulator debug rom.gb`. See HELP for the commands.
+
+Conditions compare registers (a f b c d e h l af bc de hl sp pc), flags (zf nf hf cf, ime),
+numbers and memory ([hl], [$c000]) with == != < <= > >=, joined by && and ||.
+A lone operand is true when it is non zero.
+*/
+
+const PROMPT: &str = "(gbdb) ";
+const HISTORY_SIZE: usize = 16;
+const DEFAULT_DISASSEMBLY_LENGTH: usize = 5;
+const DEFAULT_HEXDUMP_LENGTH: u16 = 0x40;
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+enum Operand {
+    Register(&'static str),
+    Number(u16),
+    Memory(&'static str), // Byte at the address held in a 16-bit register
+    Address(u16),         // Byte at a fixed address
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+struct Clause {
+    left: Operand,
+    comparison: Comparison,
+    right: Operand,
+}
+
+// Parsed register expression: any of the groups holds when all of its clauses do
+#[derive(Debug, PartialEq, Clone)]
+pub struct Expression {
+    groups: Vec<Vec<Clause>>,
+}
+
+const REGISTERS: [&str; 19] = [
+    "a", "f", "b", "c", "d", "e", "h", "l", "af", "bc", "de", "hl", "sp", "pc", "zf", "nf", "hf", "cf", "ime",
+];
+
+fn register_value(cpu: &CPU, name: &str) -> u16 {
+    let registers = &cpu.registers;
+    let flags = Flags::from_u8(registers.get_f());
+    match name {
+        "a" => registers.get_a() as u16,
+        "f" => registers.get_f() as u16,
+        "b" => registers.get_b() as u16,
+        "c" => registers.get_c() as u16,
+        "d" => registers.get_d() as u16,
+        "e" => registers.get_e() as u16,
+        "h" => registers.get_h() as u16,
+        "l" => registers.get_l() as u16,
+        "af" => registers.get_af(),
+        "bc" => registers.get_bc(),
+        "de" => registers.get_de(),
+        "hl" => registers.get_hl(),
+        "sp" => registers.get_sp(),
+        "pc" => registers.get_pc(),
+        "zf" => flags.zero as u16,
+        "nf" => flags.subtract as u16,
+        "hf" => flags.half_carry as u16,
+        "cf" => flags.carry as u16,
+        "ime" => cpu.interrupt_master_enable as u16,
+        _ => unreachable!(),
+    }
+}
+
+impl Operand {
+    fn parse(token: &str) -> Result<Operand, String> {
+        let token = token.to_ascii_lowercase();
+        if let Some(inner) = token.strip_prefix('[').and_then(|t| t.strip_suffix(']')) {
+            return match Operand::parse(inner)? {
+                Operand::Register(name) if name.len() == 2 && !name.ends_with('f') => Ok(Operand::Memory(name)),
+                Operand::Number(address) => Ok(Operand::Address(address)),
+                _ => Err(format!("Cannot dereference {}", inner)),
+            };
+        }
+        if let Some(&name) = REGISTERS.iter().find(|&&name| name == token) {
+            return Ok(Operand::Register(name));
+        }
+        match parse_number(&token) {
+            Some(value) if value <= 0xFFFF => Ok(Operand::Number(value as u16)),
+            _ => Err(format!("Unknown operand: {}", token)),
+        }
+    }
+
+    fn value(&self, cpu: &CPU) -> u16 {
+        match self {
+            Operand::Register(name) => register_value(cpu, name),
+            Operand::Number(value) => *value,
+            Operand::Memory(name) => cpu.ram.peek(register_value(cpu, name)) as u16,
+            Operand::Address(address) => cpu.ram.peek(*address) as u16,
+        }
+    }
+}
+
+fn tokenize(text: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '[' {
+            let mut token = String::new();
+            for c in chars.by_ref() {
+                if !c.is_whitespace() {
+                    token.push(c);
+                }
+                if c == ']' {
+                    break;
+                }
+            }
+            tokens.push(token);
+        } else if "=!<>&|".contains(c) {
+            let mut token = String::new();
+            while let Some(&c) = chars.peek() {
+                if !"=!<>&|".contains(c) {
+                    break;
+                }
+                token.push(c);
+                chars.next();
+            }
+            tokens.push(token);
+        } else {
+            let mut token = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() || "=!<>&|[".contains(c) {
+                    break;
+                }
+                token.push(c);
+                chars.next();
+            }
+            tokens.push(token);
+        }
+    }
+    tokens
+}
+
+impl Expression {
+    pub fn parse(text: &str) -> Result<Expression, String> {
+        let tokens = tokenize(text);
+        let mut groups = vec![Vec::new()];
+        let mut index = 0;
+        while index < tokens.len() {
+            let left = Operand::parse(&tokens[index])?;
+            index += 1;
+
+            let comparison = match tokens.get(index).map(String::as_str) {
+                Some("==") => Some(Comparison::Equal),
+                Some("!=") => Some(Comparison::NotEqual),
+                Some("<") => Some(Comparison::Less),
+                Some("<=") => Some(Comparison::LessEqual),
+                Some(">") => Some(Comparison::Greater),
+                Some(">=") => Some(Comparison::GreaterEqual),
+                _ => None,
+            };
+            let clause = match comparison {
+                Some(comparison) => {
+                    let right = tokens.get(index + 1).ok_or("Missing right hand side")?;
+                    index += 2;
+                    Clause { left, comparison, right: Operand::parse(right)? }
+                }
+                None => Clause { left, comparison: Comparison::NotEqual, right: Operand::Number(0) },
+            };
+            groups.last_mut().unwrap().push(clause);
+
+            match tokens.get(index).map(String::as_str) {
+                None => break,
+                Some("&&") => {}
+                Some("||") => groups.push(Vec::new()),
+                Some(token) => return Err(format!("Expected && or ||, found {}", token)),
+            }
+            index += 1;
+            if index >= tokens.len() {
+                return Err("Expression ends with an operator".to_string());
+            }
+        }
+        if groups[0].is_empty() {
+            return Err("Empty expression".to_string());
+        }
+        Ok(Expression { groups })
+    }
+
+    pub fn evaluate(&self, cpu: &CPU) -> bool {
+        self.groups.iter().any(|group| {
+            group.iter().all(|clause| {
+                let left = clause.left.value(cpu);
+                let right = clause.right.value(cpu);
+                match clause.comparison {
+                    Comparison::Equal => left == right,
+                    Comparison::NotEqual => left != right,
+                    Comparison::Less => left < right,
+                    Comparison::LessEqual => left <= right,
+                    Comparison::Greater => left > right,
+                    Comparison::GreaterEqual => left >= right,
+                }
+            })
+        })
+    }
+}
+
+pub struct Breakpoint {
+    pub id: usize,
+    pub address: Option<u16>, // None breaks anywhere the condition holds
+    pub condition: Option<Expression>,
+    pub text: String,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum StopReason {
+    Stepped,
+    Breakpoint(usize), // The breakpoint's id
+    Watchpoint(WatchHit),
+    Returned,
+    Crashed(String),
+    LockedUp(Lockup),
+}
+
+pub struct Debugger<'a> {
+    pub gameboy: GameBoy<'a>,
+    pub breakpoints: Vec<Breakpoint>,
+    watchpoint_ids: Vec<usize>, // Ids of the RAM's watchpoints, in the same order
+    next_id: usize, // Breakpoints and watchpoints share ids, which are never reused
+    history: VecDeque<u16>, // Recently executed PCs, oldest first
+    last_command: String,
+    pub running: bool, // Cleared by quit
+}
+
+impl<'a> Debugger<'a> {
+    pub fn new(gameboy: GameBoy<'a>) -> Self {
+        Debugger {
+            gameboy,
+            breakpoints: Vec::new(),
+            watchpoint_ids: Vec::new(),
+            next_id: 0,
+            history: VecDeque::new(),
+            last_command: String::new(),
+            running: true,
+        }
+    }
+
+    fn cpu(&self) -> &CPU<'a> {
+        &self.gameboy.cpu
+    }
+
+    fn pc(&self) -> u16 {
+        self.gameboy.cpu.registers.get_pc()
+    }
+
+    // Runs until done returns true, a breakpoint or watchpoint triggers, or the CPU panics or locks up.
+    // done is given the CPU after each instruction along with the opcode it just ran
+    fn run_until(&mut self, mut done: impl FnMut(&CPU, u8) -> bool) -> StopReason {
+        self.gameboy.cpu.ram.take_watch_hit();
+        loop {
+            let pc = self.pc();
+            let opcode = self.gameboy.cpu.ram.peek(pc);
+            if self.history.len() == HISTORY_SIZE {
+                self.history.pop_front();
+            }
+            self.history.push_back(pc);
+
+            let gameboy = &mut self.gameboy;
+            if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| gameboy.step())) {
+                let message = payload
+                    .downcast_ref::<String>()
+                    .cloned()
+                    .or_else(|| payload.downcast_ref::<&str>().map(|s| s.to_string()))
+                    .unwrap_or_else(|| "unknown panic".to_string());
+                return StopReason::Crashed(message);
+            }
+            if let Some(lockup) = self.gameboy.cpu.lockup {
+                return StopReason::LockedUp(lockup);
+            }
+
+            if let Some(hit) = self.gameboy.cpu.ram.take_watch_hit() {
+                return StopReason::Watchpoint(hit);
+            }
+            if done(&self.gameboy.cpu, opcode) {
+                return StopReason::Returned;
+            }
+            if let Some(id) = self.triggered_breakpoint() {
+                return StopReason::Breakpoint(id);
+            }
+        }
+    }
+
+    fn triggered_breakpoint(&self) -> Option<usize> {
+        let pc = self.pc();
+        self.breakpoints
+            .iter()
+            .find(|breakpoint| {
+                breakpoint.address.is_none_or(|address| address == pc)
+                    && breakpoint.condition.as_ref().is_none_or(|condition| condition.evaluate(self.cpu()))
+            })
+            .map(|breakpoint| breakpoint.id)
+    }
+
+    pub fn step(&mut self, count: u64) -> StopReason {
+        let mut remaining = count;
+        match self.run_until(|_, _| {
+            remaining -= 1;
+            remaining == 0
+        }) {
+            StopReason::Returned => StopReason::Stepped,
+            reason => reason,
+        }
+    }
+
+    pub fn step_over(&mut self) -> StopReason {
+        let pc = self.pc();
+        let opcode = self.gameboy.cpu.ram.peek(pc);
+        let is_call = matches!(opcode, 0xC4 | 0xCC | 0xCD | 0xD4 | 0xDC) || (opcode & 0xC7) == 0xC7;
+        if !is_call {
+            return self.step(1);
+        }
+
+        let return_address = pc.wrapping_add(instruction_size(opcode) as u16);
+        let sp = self.gameboy.cpu.registers.get_sp();
+        match self.run_until(|cpu, _| cpu.registers.get_pc() == return_address && cpu.registers.get_sp() >= sp) {
+            StopReason::Returned => StopReason::Stepped,
+            reason => reason,
+        }
+    }
+
+    pub fn continue_execution(&mut self) -> StopReason {
+        self.run_until(|_, _| false)
+    }
+
+    pub fn finish(&mut self) -> StopReason {
+        let sp = self.gameboy.cpu.registers.get_sp();
+        self.run_until(|cpu, opcode| {
+            let is_return = matches!(opcode, 0xC0 | 0xC8 | 0xC9 | 0xD0 | 0xD8 | 0xD9);
+            is_return && cpu.registers.get_sp() > sp
+        })
+    }
+
+    // Returns the new breakpoint's id
+    pub fn add_breakpoint(&mut self, address: Option<u16>, condition: Option<Expression>, text: String) -> usize {
+        let id = self.take_id();
+        self.breakpoints.push(Breakpoint { id, address, condition, text });
+        id
+    }
+
+    // Returns the new watchpoint's id
+    pub fn add_watchpoint(&mut self, address: u16, on_read: bool, on_write: bool) -> usize {
+        let id = self.take_id();
+        self.gameboy.cpu.ram.watchpoints.push(Watchpoint { address, on_read, on_write });
+        self.watchpoint_ids.push(id);
+        id
+    }
+
+    fn take_id(&mut self) -> usize {
+        self.next_id += 1;
+        self.next_id - 1
+    }
+
+    // Removes the breakpoint or watchpoint with the given id. Returns false if there is none
+    pub fn delete(&mut self, id: usize) -> bool {
+        if let Some(index) = self.breakpoints.iter().position(|breakpoint| breakpoint.id == id) {
+            self.breakpoints.remove(index);
+        } else if let Some(index) = self.watchpoint_ids.iter().position(|&watchpoint_id| watchpoint_id == id) {
+            self.watchpoint_ids.remove(index);
+            self.gameboy.cpu.ram.watchpoints.remove(index);
+        } else {
+            return false;
+        }
+        true
+    }
+
+    // Runs one command line and returns what it printed
+    pub fn execute(&mut self, line: &str) -> String {
+        let line = if line.trim().is_empty() { self.last_command.clone() } else { line.trim().to_string() };
+        self.last_command = line.clone();
+
+        let (command, arguments) = line.split_once(char::is_whitespace).unwrap_or((line.as_str(), ""));
+        let arguments = arguments.trim();
+        let words: Vec<&str> = arguments.split_whitespace().collect();
+
+        let result = match command {
+            "" => Ok(String::new()),
+            "s" | "step" => match words.first() {
+                Some(count) => parse_number(count).filter(|&n| n > 0).ok_or("step takes a positive count".to_string()),
+                None => Ok(1),
+            }
+            .map(|count| self.step(count as u64))
+            .map(|reason| self.describe_stop(&reason)),
+            "n" | "next" => {
+                let reason = self.step_over();
+                Ok(self.describe_stop(&reason))
+            }
+            "c" | "continue" => {
+                let reason = self.continue_execution();
+                Ok(self.describe_stop(&reason))
+            }
+            "finish" => {
+                let reason = self.finish();
+                Ok(self.describe_stop(&reason))
+            }
+            "b" | "break" => self.break_command(arguments),
+            "w" | "watch" => self.watch_command(&words),
+            "d" | "delete" => self.delete_command(&words),
+            "i" | "info" => Ok(self.info()),
+            "r" | "regs" => Ok(self.registers()),
+            "x" => self.hexdump_command(&words),
+            "dis" => self.disassemble_command(&words),
+            "h" | "help" => Ok(HELP.to_string()),
+            "q" | "quit" => {
+                self.running = false;
+                Ok(String::new())
+            }
+            _ => Err(format!("Unknown command: {} (try help)", command)),
+        };
+        result.unwrap_or_else(|error| error)
+    }
+
+    fn break_command(&mut self, arguments: &str) -> Result<String, String> {
+        let (location, condition) = if let Some(condition) = arguments.strip_prefix("if ") {
+            ("", Some(condition))
+        } else {
+            match arguments.split_once(" if ") {
+                Some((location, condition)) => (location.trim(), Some(condition)),
+                None => (arguments, None),
+            }
+        };
+
+        let address = if location.is_empty() {
+            None
+        } else {
+            Some(parse_address(location)?)
+        };
+        let condition = condition.map(Expression::parse).transpose()?;
+        if address.is_none() && condition.is_none() {
+            return Err("break takes an address and/or `if EXPR`".to_string());
+        }
+
+        let id = self.add_breakpoint(address, condition, arguments.to_string());
+        Ok(format!("Breakpoint {}: {}", id, arguments))
+    }
+
+    fn watch_command(&mut self, words: &[&str]) -> Result<String, String> {
+        let address = parse_address(words.first().ok_or("watch takes an address")?)?;
+        let (on_read, on_write) = match words.get(1).copied().unwrap_or("w") {
+            "r" => (true, false),
+            "w" => (false, true),
+            "rw" => (true, true),
+            mode => return Err(format!("Unknown watch mode: {} (use r, w or rw)", mode)),
+        };
+        let id = self.add_watchpoint(address, on_read, on_write);
+        Ok(format!("Watchpoint {}: ${:04X}", id, address))
+    }
+
+    fn delete_command(&mut self, words: &[&str]) -> Result<String, String> {
+        let id = words.first().and_then(|word| word.parse::<usize>().ok()).ok_or("delete takes a number")?;
+        if !self.delete(id) {
+            return Err(format!("No breakpoint or watchpoint {}", id));
+        }
+        Ok(format!("Deleted {}", id))
+    }
+
+    pub fn info(&self) -> String {
+        let mut lines = Vec::new();
+        for breakpoint in &self.breakpoints {
+            lines.push(format!("{}: break {}", breakpoint.id, breakpoint.text));
+        }
+        for (id, watchpoint) in self.watchpoint_ids.iter().zip(&self.cpu().ram.watchpoints) {
+            let mode = match (watchpoint.on_read, watchpoint.on_write) {
+                (true, true) => "rw",
+                (true, false) => "r",
+                _ => "w",
+            };
+            lines.push(format!("{}: watch ${:04X} {}", id, watchpoint.address, mode));
+        }
+        if lines.is_empty() {
+            lines.push("No breakpoints or watchpoints".to_string());
+        }
+        lines.join("\n")
+    }
+
+    pub fn registers(&self) -> String {
+        let cpu = self.cpu();
+        let registers = &cpu.registers;
+        let flags = Flags::from_u8(registers.get_f());
+        let flag = |set: bool, name: char| if set { name } else { '-' };
+        format!(
+            "AF={:04X} BC={:04X} DE={:04X} HL={:04X} SP={:04X} PC={:04X}\nFlags: {}{}{}{}  IME={} HALT={}",
+            registers.get_af(),
+            registers.get_bc(),
+            registers.get_de(),
+            registers.get_hl(),
+            registers.get_sp(),
+            registers.get_pc(),
+            flag(flags.zero, 'Z'),
+            flag(flags.subtract, 'N'),
+            flag(flags.half_carry, 'H'),
+            flag(flags.carry, 'C'),
+            cpu.interrupt_master_enable as u8,
+            cpu.halted as u8,
+        )
+    }
+
+    fn hexdump_command(&self, words: &[&str]) -> Result<String, String> {
+        let address = parse_address(words.first().ok_or("x takes an address")?)?;
+        let length = match words.get(1) {
+            Some(length) => parse_number(length).filter(|&n| n > 0 && n <= 0x10000).ok_or("Invalid length")?,
+            None => DEFAULT_HEXDUMP_LENGTH as u32,
+        };
+        Ok(self.hexdump(address, length))
+    }
+
+    pub fn hexdump(&self, address: u16, length: u32) -> String {
+        let mut lines = Vec::new();
+        let mut offset = 0;
+        while offset < length {
+            let start = address.wrapping_add(offset as u16);
+            let count = (length - offset).min(16);
+            let bytes: Vec<u8> = (0..count).map(|i| self.cpu().ram.peek(start.wrapping_add(i as u16))).collect();
+            let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+            let ascii: String = bytes.iter().map(|&byte| if (0x20..0x7F).contains(&byte) { byte as char } else { '.' }).collect();
+            lines.push(format!("{:04X}: {:<47}  |{}|", start, hex.join(" "), ascii));
+            offset += count;
+        }
+        lines.join("\n")
+    }
+
+    fn disassemble_command(&self, words: &[&str]) -> Result<String, String> {
+        let length = match words.get(1) {
+            Some(length) => parse_number(length).ok_or("Invalid count")? as usize,
+            None => DEFAULT_DISASSEMBLY_LENGTH,
+        };
+        match words.first() {
+            Some(address) => Ok(self.disassemble_from(parse_address(address)?, length)),
+            None => Ok(self.disassemble_around_pc(length)),
+        }
+    }
+
+    fn disassembly_line(&self, address: u16) -> (String, u16) {
+        let ram = &self.cpu().ram;
+        let code = disassemble(&|address| ram.peek(address), address);
+        let bytes: Vec<String> = (0..code.size as u16).map(|i| format!("{:02X}", ram.peek(address.wrapping_add(i)))).collect();
+        let marker = if address == self.pc() { "=>" } else { "  " };
+        let line = format!("{} {:04X}: {:<9} {}", marker, address, bytes.join(" "), code.text());
+        (line, address.wrapping_add(code.size as u16))
+    }
+
+    pub fn disassemble_from(&self, address: u16, length: usize) -> String {
+        let mut lines = Vec::new();
+        let mut address = address;
+        for _ in 0..length {
+            let (line, next) = self.disassembly_line(address);
+            lines.push(line);
+            address = next;
+        }
+        lines.join("\n")
+    }
+
+    // The last few executed instructions, then the ones coming up from PC. Going backwards
+    // from PC is ambiguous, so the history of executed addresses is used instead
+    pub fn disassemble_around_pc(&self, length: usize) -> String {
+        let mut lines: Vec<String> = self
+            .history
+            .iter()
+            .skip(self.history.len().saturating_sub(3))
+            .filter(|&&address| address != self.pc())
+            .map(|&address| self.disassembly_line(address).0)
+            .collect();
+        lines.push(self.disassemble_from(self.pc(), length));
+        lines.join("\n")
+    }
+
+    fn describe_stop(&self, reason: &StopReason) -> String {
+        let header = match reason {
+            StopReason::Stepped | StopReason::Returned => None,
+            StopReason::Breakpoint(id) => {
+                let text = self.breakpoints.iter().find(|breakpoint| breakpoint.id == *id).map_or("", |breakpoint| &breakpoint.text);
+                Some(format!("Breakpoint {}: {}", id, text))
+            }
+            StopReason::Watchpoint(hit) => Some(format!(
+                "Watchpoint: {} ${:04X} = ${:02X}",
+                if hit.access == Access::Read { "read" } else { "write" },
+                hit.address,
+                hit.value
+            )),
+            StopReason::Crashed(message) => Some(format!("CPU crashed: {}", message)),
+            StopReason::LockedUp(lockup) => Some(format!("CPU locked up: {}", lockup)),
+        };
+        let current = self.disassembly_line(self.pc()).0;
+        match header {
+            Some(header) => format!("{}\n{}", header, current),
+            None => current,
+        }
+    }
+
+    // Reads commands from stdin until quit or end of input
+    pub fn repl(&mut self) -> io::Result<()> {
+        let stdin = io::stdin();
+        let mut stdout = io::stdout();
+        println!("{}", self.describe_stop(&StopReason::Stepped));
+        while self.running {
+            write!(stdout, "{}", PROMPT)?;
+            stdout.flush()?;
+
+            let mut line = String::new();
+            if stdin.lock().read_line(&mut line)? == 0 {
+                break;
+            }
+            let output = self.execute(&line);
+            if !output.is_empty() {
+                println!("{}", output);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn parse_address(text: &str) -> Result<u16, String> {
+    parse_number(text)
+        .and_then(|value| u16::try_from(value).ok())
+        .ok_or_else(|| format!("Invalid address: {}", text))
+}
+
+const HELP: &str = "s, step [N]             run N instructions
+n, next                 step over CALL and RST
+c, continue             run until a breakpoint or watchpoint
+finish                  run until the current function returns
+b, break ADDR [if EXPR] break at ADDR, optionally only when EXPR holds
+b, break if EXPR        break wherever EXPR holds
+w, watch ADDR [r|w|rw]  break on reads and/or writes of ADDR
+d, delete N             remove breakpoint or watchpoint N
+i, info                 list breakpoints and watchpoints
+r, regs                 show registers and flags
+x ADDR [LEN]            hexdump memory
+dis [ADDR] [N]          disassemble, around PC by default
+q, quit";
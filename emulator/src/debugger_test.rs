@@ -0,0 +1,174 @@
+#[cfg(test)]
+mod tests {
+    use crate::debugger::{Debugger, Expression, StopReason};
+    use crate::gb::cpu::Lockup;
+    use crate::gb::ram::{Access, RAM};
+    use crate::gb::GameBoy;
+
+    /*
+    0x0100: LD HL, 0xC000
+    0x0103: CALL 0x0110
+    0x0106: INC A
+    0x0107: JP 0x0106
+    0x0110: LD (HL), A
+    0x0111: LD B, (HL)
+    0x0112: RET
+    */
+    fn create_ram() -> RAM {
+        let mut ram = RAM::new();
+        let program: [(u16, &[u8]); 2] = [
+            (0x0100, &[0x21, 0x00, 0xC0, 0xCD, 0x10, 0x01, 0x3C, 0xC3, 0x06, 0x01]),
+            (0x0110, &[0x77, 0x46, 0xC9]),
+        ];
+        for (address, bytes) in program {
+            for (i, &byte) in bytes.iter().enumerate() {
+                ram.write(address + i as u16, byte);
+            }
+        }
+        ram
+    }
+
+    #[test]
+    fn test_step_and_next() {
+        let mut ram = create_ram();
+        let mut debugger = Debugger::new(GameBoy::new(&mut ram));
+
+        assert_eq!(debugger.step(1), StopReason::Stepped);
+        assert_eq!(debugger.gameboy.cpu.registers.get_pc(), 0x0103);
+
+        // next runs the whole call
+        assert_eq!(debugger.step_over(), StopReason::Stepped);
+        assert_eq!(debugger.gameboy.cpu.registers.get_pc(), 0x0106);
+        assert_eq!(debugger.gameboy.cpu.registers.get_b(), 0x01, "The called function should have run");
+    }
+
+    #[test]
+    fn test_finish() {
+        let mut ram = create_ram();
+        let mut debugger = Debugger::new(GameBoy::new(&mut ram));
+        debugger.step(2);
+        assert_eq!(debugger.gameboy.cpu.registers.get_pc(), 0x0110);
+
+        assert_eq!(debugger.finish(), StopReason::Returned);
+        assert_eq!(debugger.gameboy.cpu.registers.get_pc(), 0x0106);
+    }
+
+    #[test]
+    fn test_pc_breakpoint() {
+        let mut ram = create_ram();
+        let mut debugger = Debugger::new(GameBoy::new(&mut ram));
+        debugger.execute("break $0111");
+        assert_eq!(debugger.continue_execution(), StopReason::Breakpoint(0));
+        assert_eq!(debugger.gameboy.cpu.registers.get_pc(), 0x0111);
+    }
+
+    #[test]
+    fn test_conditional_breakpoint() {
+        let mut ram = create_ram();
+        let mut debugger = Debugger::new(GameBoy::new(&mut ram));
+        debugger.execute("b 0x0106 if a == 5");
+        debugger.continue_execution();
+        assert_eq!(debugger.gameboy.cpu.registers.get_a(), 5);
+
+        debugger.execute("delete 0");
+        debugger.execute("b if a>=0x10 && zf == 0 || b == 0xFF");
+        debugger.continue_execution();
+        assert_eq!(debugger.gameboy.cpu.registers.get_a(), 0x10);
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let mut ram = create_ram();
+        let mut debugger = Debugger::new(GameBoy::new(&mut ram));
+        debugger.execute("watch 0xC000");
+        match debugger.continue_execution() {
+            StopReason::Watchpoint(hit) => {
+                assert_eq!((hit.address, hit.access, hit.value), (0xC000, Access::Write, 0x01));
+            }
+            reason => panic!("Expected a watchpoint, got {:?}", reason),
+        }
+
+        debugger.execute("d 0");
+        debugger.execute("w $C000 r");
+        match debugger.continue_execution() {
+            StopReason::Watchpoint(hit) => assert_eq!(hit.access, Access::Read),
+            reason => panic!("Expected a watchpoint, got {:?}", reason),
+        }
+        assert_eq!(debugger.gameboy.cpu.registers.get_pc(), 0x0112);
+    }
+
+    #[test]
+    fn test_ids_survive_deletes() {
+        let mut ram = create_ram();
+        let mut debugger = Debugger::new(GameBoy::new(&mut ram));
+        assert_eq!(debugger.execute("watch 0xC000"), "Watchpoint 0: $C000");
+        assert_eq!(debugger.execute("break $0106"), "Breakpoint 1: $0106");
+        assert_eq!(debugger.execute("watch 0xC001 r"), "Watchpoint 2: $C001");
+        assert_eq!(debugger.execute("break $0111"), "Breakpoint 3: $0111");
+
+        debugger.execute("delete 1");
+        assert_eq!(debugger.execute("info"), "3: break $0111\n0: watch $C000 w\n2: watch $C001 r");
+        debugger.execute("delete 0");
+        assert_eq!(debugger.execute("break $0110"), "Breakpoint 4: $0110");
+        assert_eq!(debugger.execute("info"), "3: break $0111\n4: break $0110\n2: watch $C001 r");
+        assert!(debugger.execute("delete 0").starts_with("No breakpoint"));
+
+        assert_eq!(debugger.continue_execution(), StopReason::Breakpoint(4));
+        assert_eq!(debugger.execute("c").lines().next(), Some("Breakpoint 3: $0111"));
+    }
+
+    #[test]
+    fn test_expressions() {
+        let mut ram = create_ram();
+        let gameboy = GameBoy::new(&mut ram);
+        let cpu = &gameboy.cpu;
+
+        assert!(Expression::parse("pc == $100").unwrap().evaluate(cpu));
+        assert!(Expression::parse("zf && cf").unwrap().evaluate(cpu), "Post boot flags are Z-HC");
+        assert!(!Expression::parse("nf").unwrap().evaluate(cpu));
+        assert!(Expression::parse("[pc] == 0x21").unwrap().evaluate(cpu));
+        assert!(Expression::parse("[$0101] == 0").unwrap().evaluate(cpu));
+        assert!(Expression::parse("a == 2 || sp > 0xFF00").unwrap().evaluate(cpu));
+        assert!(Expression::parse("a == ").is_err());
+        assert!(Expression::parse("q == 1").is_err());
+        assert!(Expression::parse("a == 1 &&").is_err());
+    }
+
+    #[test]
+    fn test_display_commands() {
+        let mut ram = create_ram();
+        let mut debugger = Debugger::new(GameBoy::new(&mut ram));
+
+        let registers = debugger.execute("regs");
+        assert!(registers.contains("AF=01B0"), "{}", registers);
+        assert!(registers.contains("Flags: Z-HC"), "{}", registers);
+
+        let dump = debugger.execute("x 0x0110 4");
+        assert_eq!(dump, "0110: 77 46 C9 00                                      |wF..|");
+
+        let disassembly = debugger.execute("dis");
+        assert!(disassembly.starts_with("=> 0100: 21 00 C0  LD HL, $C000"), "{}", disassembly);
+        assert!(disassembly.contains("CALL $0110"));
+
+        debugger.execute("s");
+        debugger.execute(""); // Repeats the last command
+        assert_eq!(debugger.gameboy.cpu.registers.get_pc(), 0x0110);
+        assert!(debugger.execute("dis").contains("   0103: CD 10 01  CALL $0110"));
+
+        assert!(debugger.execute("info").contains("No breakpoints"));
+        assert!(debugger.execute("bogus").starts_with("Unknown command"));
+        debugger.execute("quit");
+        assert!(!debugger.running);
+    }
+
+    #[test]
+    fn test_lockup_stops_execution() {
+        let mut ram = create_ram();
+        ram.write(0x0106, 0xD3);
+        let mut debugger = Debugger::new(GameBoy::new(&mut ram));
+        let lockup = Lockup { opcode: 0xD3, address: 0x0106 };
+        assert_eq!(debugger.continue_execution(), StopReason::LockedUp(lockup));
+        // Stepping a hung CPU goes nowhere
+        assert_eq!(debugger.continue_execution(), StopReason::LockedUp(lockup));
+    }
+}
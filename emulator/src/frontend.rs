@@ -0,0 +1,305 @@
+pub mod audio;
+
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use minifb::{Key, KeyRepeat, Window, WindowOptions};
+
+use crate::gb::joypad::{Button, Buttons};
+use crate::gb::movie::{self, Movie, MoviePlayer, MovieRecorder, MovieStart};
+use crate::gb::ram::RAM;
+use crate::gb::rewind::{self, Rewind};
+use crate::gb::video::{VideoFormat, VideoRecorder};
+use crate::gb::{GameBoy, FRAMES_PER_SECOND, SCREEN_HEIGHT, SCREEN_WIDTH};
+
+const KEY_MAP: [(Key, Button); 8] = [
+    (Key::Right, Button::Right),
+    (Key::Left, Button::Left),
+    (Key::Up, Button::Up),
+    (Key::Down, Button::Down),
+    (Key::X, Button::A),
+    (Key::Z, Button::B),
+    (Key::Backspace, Button::Select),
+    (Key::Enter, Button::Start),
+];
+
+// Held to play the game backwards
+const REWIND_KEY: Key = Key::R;
+
+// Saves what is on the screen as a PNG
+const SCREENSHOT_KEY: Key = Key::F12;
+
+// Starts and stops recording a clip
+const VIDEO_KEY: Key = Key::F10;
+
+// How far behind the frame clock can fall before it gives up catching up
+const MAX_FRAME_LAG: u32 = 4;
+
+pub struct Frontend {
+    window: Window,
+    scale: usize,
+    pixels: Vec<u32>, // Scaled 0RGB pixels handed to the window
+    audio: Option<audio::AudioOutput>,
+}
+
+impl Frontend {
+    // Without audio, or when no sound device can be opened, the caller keeps time itself
+    pub fn new(title: &str, scale: usize, audio: bool) -> Result<Self, Box<dyn Error>> {
+        let scale = scale.max(1);
+        let width = SCREEN_WIDTH * scale;
+        let height = SCREEN_HEIGHT * scale;
+        let mut window = Window::new(title, width, height, WindowOptions::default())?;
+        // Frames are paced by the emulator, not by the window
+        window.set_target_fps(0);
+
+        Ok(Frontend {
+            window,
+            scale,
+            pixels: vec![0; width * height],
+            audio: if audio { audio::AudioOutput::new() } else { None },
+        })
+    }
+
+    // Output rate the APU should produce samples at
+    pub fn sample_rate(&self) -> Option<u32> {
+        self.audio.as_ref().map(audio::AudioOutput::sample_rate)
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.window.is_open() && !self.window.is_key_down(Key::Escape)
+    }
+
+    pub fn is_key_down(&self, key: Key) -> bool {
+        self.window.is_key_down(key)
+    }
+
+    // Only true on the frame the key went down, holding it doesn't repeat
+    pub fn is_key_pressed(&self, key: Key) -> bool {
+        self.window.is_key_pressed(key, KeyRepeat::No)
+    }
+
+    pub fn buttons(&self) -> Buttons {
+        let mut buttons = Buttons::NONE;
+        for (key, button) in KEY_MAP {
+            buttons.set(button, self.window.is_key_down(key));
+        }
+        buttons
+    }
+
+    // Shows an RGBA frame, scaled up by whole pixels
+    pub fn present(&mut self, rgba: &[u8]) -> Result<(), Box<dyn Error>> {
+        let width = SCREEN_WIDTH * self.scale;
+        for y in 0..SCREEN_HEIGHT * self.scale {
+            let source_row = (y / self.scale) * SCREEN_WIDTH;
+            for x in 0..width {
+                let pixel = &rgba[(source_row + x / self.scale) * 4..][..4];
+                self.pixels[y * width + x] = (pixel[0] as u32) << 16 | (pixel[1] as u32) << 8 | pixel[2] as u32;
+            }
+        }
+        self.window.update_with_buffer(&self.pixels, width, SCREEN_HEIGHT * self.scale)?;
+        Ok(())
+    }
+
+    // Hands the frame's samples to the audio device. Returns false if there is no audio
+    // to pace against and the caller has to keep time itself
+    pub fn queue_audio(&mut self, samples: &[f32]) -> bool {
+        match &self.audio {
+            Some(audio) => {
+                audio.play(samples);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+// Keeps frames at the Game Boy's refresh rate when there is no audio clock to follow
+pub struct FrameClock {
+    frame_duration: Duration,
+    next_frame: Instant,
+}
+
+impl FrameClock {
+    pub fn new() -> Self {
+        FrameClock {
+            frame_duration: Duration::from_secs_f64(1.0 / FRAMES_PER_SECOND),
+            next_frame: Instant::now(),
+        }
+    }
+
+    pub fn wait(&mut self) {
+        self.next_frame += self.frame_duration;
+        let now = Instant::now();
+        if self.next_frame > now {
+            thread::sleep(self.next_frame - now);
+        } else if now - self.next_frame > self.frame_duration * MAX_FRAME_LAG {
+            // Too far behind (e.g. the window was being dragged), start counting from now
+            self.next_frame = now;
+        }
+    }
+}
+
+impl Default for FrameClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct FrontendOptions {
+    pub scale: usize,
+    pub movie: Option<Movie>,    // Played back instead of reading the keyboard, until it ends
+    pub record: Option<PathBuf>, // Records a movie from power on, written when the window closes
+    pub screenshot_dir: PathBuf, // Where screenshots and clips started from the keyboard go
+    pub screenshot_scale: u32,   // Screenshots are saved at native size unless this is above 1
+    pub video: Option<PathBuf>,  // Records a clip from the start, until the video key or the window closes
+    pub video_format: VideoFormat,
+    pub audio: bool, // Plays sound when a device can be opened, otherwise runs silently
+}
+
+impl FrontendOptions {
+    pub fn new() -> Self {
+        FrontendOptions {
+            scale: 3,
+            movie: None,
+            record: None,
+            screenshot_dir: PathBuf::from("."),
+            screenshot_scale: 1,
+            video: None,
+            video_format: VideoFormat::Gif,
+            audio: true,
+        }
+    }
+}
+
+impl Default for FrontendOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Runs a game in a window until it is closed
+pub fn run(ram: &mut RAM, title: &str, options: FrontendOptions) -> Result<(), Box<dyn Error>> {
+    let mut frontend = Frontend::new(title, options.scale, options.audio)?;
+    if let Some(sample_rate) = frontend.sample_rate() {
+        ram.apu.set_sample_rate(sample_rate);
+    }
+
+    let mut gameboy = GameBoy::new(ram);
+    let mut clock = FrameClock::new();
+    let mut rewind = Rewind::new(rewind::DEFAULT_INTERVAL, rewind::DEFAULT_BUDGET);
+    let mut player = match options.movie {
+        Some(movie) => Some(MoviePlayer::new(&mut gameboy, movie)?),
+        None => None,
+    };
+    let mut recorder = options.record.as_ref().map(|_| {
+        let start = match &gameboy.cpu.ram.cartridge {
+            Some(cartridge) if cartridge.has_battery() => MovieStart::Sram(cartridge.ram().to_vec()),
+            _ => MovieStart::PowerOn,
+        };
+        MovieRecorder::new(&mut gameboy, start, movie::DEFAULT_HASH_INTERVAL).expect("Only a state start can fail")
+    });
+    // Going back in time would break the movie being played or recorded
+    let can_rewind = player.is_none() && recorder.is_none();
+    let mut reported_lockup = None;
+    let mut video = match &options.video {
+        Some(path) => Some(start_video(path, options.video_format, &gameboy)?),
+        None => None,
+    };
+
+    while frontend.is_open() {
+        if frontend.is_key_pressed(SCREENSHOT_KEY) {
+            let path = capture_path(&options.screenshot_dir, title, gameboy.frame, "png");
+            match gameboy.screenshot().scaled(options.screenshot_scale).save_png(&path) {
+                Ok(()) => println!("Saved screenshot to {}", path.display()),
+                Err(error) => eprintln!("Failed to save {}: {}", path.display(), error),
+            }
+        }
+        if frontend.is_key_pressed(VIDEO_KEY) {
+            match video.take() {
+                Some(recording) => stop_video(recording),
+                None => {
+                    let path = capture_path(&options.screenshot_dir, title, gameboy.frame, options.video_format.extension());
+                    match start_video(&path, options.video_format, &gameboy) {
+                        Ok(recording) => video = Some(recording),
+                        Err(error) => eprintln!("Failed to create {}: {}", path.display(), error),
+                    }
+                }
+            }
+        }
+
+        if can_rewind && frontend.is_key_down(REWIND_KEY) {
+            rewind.step_back(&mut gameboy);
+            clock.wait();
+            frontend.present(gameboy.screen_buffer())?;
+            continue;
+        }
+
+        let buttons = frontend.buttons();
+        match (&mut player, &mut recorder) {
+            (Some(playing), _) => {
+                let movie_buttons = playing.next_frame(&mut gameboy);
+                gameboy.run_frame(&mut |_| movie_buttons.unwrap_or(buttons));
+                if let Err(error) = playing.check_hash(&gameboy) {
+                    eprintln!("{}, handing control back to the keyboard", error);
+                    player = None;
+                } else if playing.is_finished() {
+                    player = None;
+                }
+            }
+            (None, Some(recorder)) => recorder.run_frame(&mut gameboy, buttons),
+            (None, None) => {
+                gameboy.run_frame(&mut |_| buttons);
+                rewind.record(&gameboy);
+            }
+        }
+
+        // The screen stays as it was, the way it does on the hardware, so say why once
+        if let Some(lockup) = gameboy.cpu.lockup && reported_lockup != Some(lockup) {
+            eprintln!("CPU locked up: {}", lockup);
+        }
+        reported_lockup = gameboy.cpu.lockup;
+
+        let samples = gameboy.cpu.ram.apu.take_samples();
+        if let Some((recording, path)) = &mut video
+            && let Err(error) = recording.add_frame(gameboy.frame_buffer(), &samples)
+        {
+            eprintln!("Failed to write {}, recording stopped: {}", path.display(), error);
+            video = None;
+        }
+        if !frontend.queue_audio(&samples) {
+            clock.wait();
+        }
+        frontend.present(gameboy.screen_buffer())?;
+    }
+
+    if let (Some(recorder), Some(path)) = (recorder, &options.record) {
+        fs::write(path, recorder.finish().to_bytes())?;
+    }
+    if let Some(recording) = video {
+        stop_video(recording);
+    }
+    Ok(())
+}
+
+// Named after the game and the frame it was started on, e.g. TETRIS-001234.png
+fn capture_path(dir: &Path, title: &str, frame: u64, extension: &str) -> PathBuf {
+    let name: String = title.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect();
+    dir.join(format!("{}-{:06}.{}", name, frame, extension))
+}
+
+fn start_video(path: &Path, format: VideoFormat, gameboy: &GameBoy) -> std::io::Result<(VideoRecorder, PathBuf)> {
+    let recording = VideoRecorder::create(path, format, gameboy.cpu.ram.apu.sample_rate())?;
+    println!("Recording to {}", path.display());
+    Ok((recording, path.to_path_buf()))
+}
+
+fn stop_video((recording, path): (VideoRecorder, PathBuf)) {
+    let frames = recording.frames();
+    match recording.finish() {
+        Ok(()) => println!("Saved {} frames to {}", frames, path.display()),
+        Err(error) => eprintln!("Failed to save {}: {}", path.display(), error),
+    }
+}
@@ -0,0 +1,113 @@
+use std::ffi::{c_char, c_int, c_long, c_uint, c_ulong, c_void, CStr};
+use std::ptr;
+
+/*
+Sound goes out through ALSA, which is loaded when the window opens rather than linked in,
+the same way the window system is. The binary then builds without the ALSA headers and
+still runs, silently, on a machine without it or without an output device.
+
+Writes block while the device buffer is full, so playing each frame's samples is what
+paces the emulator when there is sound.
+*/
+
+const LIBRARY: &CStr = c"libasound.so.2";
+const DEVICE: &CStr = c"default";
+
+const SAMPLE_RATE: u32 = 48000;
+const CHANNELS: u32 = 2;
+const LATENCY_MICROSECONDS: c_uint = 50_000; // Buffered ahead of what is playing, about three frames
+
+const SND_PCM_STREAM_PLAYBACK: c_int = 0;
+const SND_PCM_FORMAT_FLOAT_LE: c_int = 14;
+const SND_PCM_ACCESS_RW_INTERLEAVED: c_int = 3;
+
+type Open = unsafe extern "C" fn(*mut *mut c_void, *const c_char, c_int, c_int) -> c_int;
+type SetParams = unsafe extern "C" fn(*mut c_void, c_int, c_int, c_uint, c_uint, c_int, c_uint) -> c_int;
+type WriteInterleaved = unsafe extern "C" fn(*mut c_void, *const c_void, c_ulong) -> c_long;
+type Recover = unsafe extern "C" fn(*mut c_void, c_int, c_int) -> c_int;
+type Close = unsafe extern "C" fn(*mut c_void) -> c_int;
+
+// Plays interleaved stereo samples on the default ALSA device
+pub struct AudioOutput {
+    library: *mut c_void,
+    pcm: *mut c_void,
+    write: WriteInterleaved,
+    recover: Recover,
+    close: Close,
+}
+
+impl AudioOutput {
+    // Returns None when ALSA or an output device isn't there
+    pub fn new() -> Option<Self> {
+        let library = unsafe { libc::dlopen(LIBRARY.as_ptr(), libc::RTLD_NOW) };
+        if library.is_null() {
+            return None;
+        }
+        let output = AudioOutput::open(library);
+        if output.is_none() {
+            unsafe { libc::dlclose(library) };
+        }
+        output
+    }
+
+    fn open(library: *mut c_void) -> Option<Self> {
+        let open: Open = symbol(library, c"snd_pcm_open")?;
+        let set_params: SetParams = symbol(library, c"snd_pcm_set_params")?;
+        let write = symbol(library, c"snd_pcm_writei")?;
+        let recover = symbol(library, c"snd_pcm_recover")?;
+        let close: Close = symbol(library, c"snd_pcm_close")?;
+
+        let mut pcm = ptr::null_mut();
+        if unsafe { open(&mut pcm, DEVICE.as_ptr(), SND_PCM_STREAM_PLAYBACK, 0) } < 0 {
+            return None;
+        }
+        // Resampling is left to ALSA when the device runs at another rate
+        let result = unsafe {
+            set_params(pcm, SND_PCM_FORMAT_FLOAT_LE, SND_PCM_ACCESS_RW_INTERLEAVED, CHANNELS, SAMPLE_RATE, 1, LATENCY_MICROSECONDS)
+        };
+        if result < 0 {
+            unsafe { close(pcm) };
+            return None;
+        }
+        Some(AudioOutput { library, pcm, write, recover, close })
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        SAMPLE_RATE
+    }
+
+    // Blocks until the device has taken all the samples
+    pub fn play(&self, samples: &[f32]) {
+        let frames = samples.len() / CHANNELS as usize;
+        let mut played = 0;
+        while played < frames {
+            let rest = &samples[played * CHANNELS as usize..];
+            let written = unsafe { (self.write)(self.pcm, rest.as_ptr().cast(), (frames - played) as c_ulong) };
+            if written >= 0 {
+                played += written as usize;
+            } else if unsafe { (self.recover)(self.pcm, written as c_int, 1) } < 0 {
+                // Underruns are recovered from, anything else loses this frame's sound
+                return;
+            }
+        }
+    }
+}
+
+impl Drop for AudioOutput {
+    fn drop(&mut self) {
+        unsafe {
+            (self.close)(self.pcm);
+            libc::dlclose(self.library);
+        }
+    }
+}
+
+// Looks a function up in the loaded library, F being its function pointer type
+fn symbol<F: Copy>(library: *mut c_void, name: &CStr) -> Option<F> {
+    assert_eq!(size_of::<F>(), size_of::<*mut c_void>());
+    let address = unsafe { libc::dlsym(library, name.as_ptr()) };
+    if address.is_null() {
+        return None;
+    }
+    Some(unsafe { std::mem::transmute_copy(&address) })
+}
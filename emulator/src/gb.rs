@@ -1,6 +1,224 @@
+pub mod alu;
+pub mod alu_test;
+pub mod apu;
+pub mod apu_test;
+pub mod cartridge;
+pub mod cartridge_test;
 pub mod cpu;
+pub mod cpu_test;
+pub mod disassembler;
+pub mod disassembler_test;
+pub mod frame_buffer;
+pub mod frame_buffer_test;
+pub mod gameboy_test;
+pub mod gpu;
+pub mod gpu_test;
+pub mod image;
+pub mod image_test;
+pub mod joypad;
+pub mod joypad_test;
+pub mod movie;
+pub mod movie_test;
+pub mod opcodes;
+pub mod opcodes_test;
+pub mod printer;
+pub mod printer_test;
 pub mod ram;
 pub mod register;
+pub mod rewind;
+pub mod rewind_test;
+pub mod serial;
+pub mod serial_test;
+pub mod state;
+pub mod state_test;
+pub mod timer;
+pub mod trace;
+pub mod trace_test;
+pub mod video;
+pub mod video_test;
 
+use crate::gb::cpu::CPU;
+use crate::gb::frame_buffer::FrameBuffer;
+use crate::gb::image::Image;
+use crate::gb::joypad::InputSource;
+use crate::gb::ram::RAM;
+use crate::gb::state::{StateError, StateReader, StateWriter};
 
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+pub const CYCLES_PER_FRAME: u32 = 70224;
+pub const FRAMES_PER_SECOND: f64 = 59.7275; // CPU_CLOCK_HZ / CYCLES_PER_FRAME
 
+// Upper bound on how long run_frame waits for the GPU, in case the LCD is switched off
+const MAX_CYCLES_PER_FRAME: u64 = CYCLES_PER_FRAME as u64 * 2;
+
+// Save state section tags
+const FRAME_SECTION: &[u8; 4] = b"FRAM";
+const CPU_SECTION: &[u8; 4] = b"CPU ";
+const MEMORY_SECTION: &[u8; 4] = b"MEM ";
+const GPU_SECTION: &[u8; 4] = b"GPU ";
+const TIMER_SECTION: &[u8; 4] = b"TIMR";
+const SERIAL_SECTION: &[u8; 4] = b"SERL";
+const JOYPAD_SECTION: &[u8; 4] = b"JOYP";
+const APU_SECTION: &[u8; 4] = b"APU ";
+const CARTRIDGE_SECTION: &[u8; 4] = b"CART";
+
+pub struct GameBoy<'a> {
+    pub cpu: CPU<'a>,
+    pub frame: u64, // Number of frames run so far
+    rom_checksum: u32, // CRC32 of the cartridge ROM, 0 without one
+}
+
+impl<'a> GameBoy<'a> {
+    pub fn new(ram: &'a mut RAM) -> Self {
+        let mut cpu = CPU::new(ram);
+        boot(&mut cpu);
+
+        let rom_checksum = cpu.ram.cartridge.as_ref().map_or(0, |cartridge| state::crc32(cartridge.rom()));
+        GameBoy { cpu, frame: 0, rom_checksum }
+    }
+
+    // Restarts the game as if the reset button had been pressed. Cartridge RAM and the clock
+    // keep their contents, the frame counter keeps counting
+    pub fn reset(&mut self) {
+        self.cpu.reset();
+        self.cpu.ram.reset();
+        boot(&mut self.cpu);
+    }
+
+    // Switches the machine off and on again. Only battery backed cartridge RAM survives
+    pub fn power_cycle(&mut self) {
+        self.reset();
+        if let Some(cartridge) = &mut self.cpu.ram.cartridge
+            && !cartridge.has_battery()
+        {
+            let cleared = vec![0; cartridge.ram().len()];
+            cartridge.load_ram(&cleared);
+        }
+    }
+
+    // Runs one instruction and advances the rest of the hardware by the same amount
+    pub fn step(&mut self) -> u8 {
+        let cycles = self.cpu.step();
+        // In M-cycle mode the CPU has already ticked the bus as it went
+        if !self.cpu.m_cycle_mode {
+            self.cpu.ram.tick(cycles as u32);
+        }
+        cycles
+    }
+
+    // Runs until the GPU has finished a frame, reading the joypad once at its start
+    pub fn run_frame(&mut self, input: &mut dyn InputSource) {
+        self.run_frame_until(input, &mut |_| false);
+    }
+
+    // Like run_frame, but checks the condition after every instruction and stops early
+    // once it holds. Returns whether it did
+    pub fn run_frame_until(&mut self, input: &mut dyn InputSource, condition: &mut dyn FnMut(&CPU) -> bool) -> bool {
+        let buttons = input.poll(self.frame);
+        self.cpu.ram.set_buttons(buttons);
+
+        self.cpu.ram.gpu.frame_ready = false;
+        let mut cycles = 0;
+        while !self.cpu.ram.gpu.frame_ready && cycles < MAX_CYCLES_PER_FRAME {
+            cycles += self.step() as u64;
+            if condition(&self.cpu) {
+                return true;
+            }
+        }
+        self.frame += 1;
+        false
+    }
+
+    pub fn rom_checksum(&self) -> u32 {
+        self.rom_checksum
+    }
+
+    // RGBA pixels of the last complete frame. The one being drawn stays out of sight until VBlank
+    pub fn screen_buffer(&self) -> &[u8] {
+        self.cpu.ram.gpu.screen.rgba()
+    }
+
+    // The last complete frame, with its shade numbers as well as its colors
+    pub fn frame_buffer(&self) -> &FrameBuffer {
+        &self.cpu.ram.gpu.screen
+    }
+
+    // A copy of the last complete frame at the screen's native size
+    pub fn screenshot(&self) -> Image {
+        Image::new(SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32, self.screen_buffer().to_vec())
+    }
+
+    // The whole machine in the format described in state.rs
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut writer = StateWriter::new();
+        state::write_header(&mut writer, self.rom_checksum, self.screen_buffer(), SCREEN_WIDTH);
+        let ram = &self.cpu.ram;
+        writer.section(FRAME_SECTION, |writer| writer.write_u64(self.frame));
+        writer.section(CPU_SECTION, |writer| self.cpu.save_state(writer));
+        writer.section(MEMORY_SECTION, |writer| ram.save_state(writer));
+        writer.section(GPU_SECTION, |writer| ram.gpu.save_state(writer));
+        writer.section(TIMER_SECTION, |writer| ram.timer.save_state(writer));
+        writer.section(SERIAL_SECTION, |writer| ram.serial.save_state(writer));
+        writer.section(JOYPAD_SECTION, |writer| ram.joypad.save_state(writer));
+        writer.section(APU_SECTION, |writer| ram.apu.save_state(writer));
+        if let Some(cartridge) = &ram.cartridge {
+            writer.section(CARTRIDGE_SECTION, |writer| cartridge.save_state(writer));
+        }
+        writer.into_bytes()
+    }
+
+    // Restores a state from save_state. Nothing changes if it doesn't load
+    pub fn load_state(&mut self, bytes: &[u8]) -> Result<(), StateError> {
+        // Sections are applied one by one, so one that fails further in has to be undone
+        let backup = self.save_state();
+        let result = self.apply_state(bytes);
+        if result.is_err() {
+            self.apply_state(&backup).expect("A state that was just saved loads");
+        }
+        result
+    }
+
+    fn apply_state(&mut self, bytes: &[u8]) -> Result<(), StateError> {
+        let mut reader = StateReader::new(bytes);
+        let header = state::read_header(&mut reader)?;
+        if header.rom_checksum != self.rom_checksum {
+            return Err(StateError::RomMismatch { expected: self.rom_checksum, found: header.rom_checksum });
+        }
+
+        let mut sections = state::read_sections(&mut reader)?;
+        let mut required = vec![FRAME_SECTION, CPU_SECTION, MEMORY_SECTION, GPU_SECTION, TIMER_SECTION, SERIAL_SECTION, JOYPAD_SECTION, APU_SECTION];
+        if self.cpu.ram.cartridge.is_some() {
+            required.push(CARTRIDGE_SECTION);
+        }
+        if let Some(missing) = required.iter().find(|tag| !sections.contains_key(**tag)) {
+            return Err(StateError::MissingSection(**missing));
+        }
+        let mut section = |tag| sections.remove(tag).unwrap();
+
+        self.frame = section(FRAME_SECTION).read_u64()?;
+        self.cpu.load_state(&mut section(CPU_SECTION))?;
+        let ram = &mut self.cpu.ram;
+        ram.load_state(&mut section(MEMORY_SECTION))?;
+        ram.gpu.load_state(&mut section(GPU_SECTION))?;
+        ram.timer.load_state(&mut section(TIMER_SECTION))?;
+        ram.serial.load_state(&mut section(SERIAL_SECTION))?;
+        ram.joypad.load_state(&mut section(JOYPAD_SECTION))?;
+        ram.apu.load_state(&mut section(APU_SECTION))?;
+        if let Some(cartridge) = &mut ram.cartridge {
+            cartridge.load_state(&mut section(CARTRIDGE_SECTION))?;
+        }
+        Ok(())
+    }
+}
+
+// Register values left behind by the DMG boot ROM
+fn boot(cpu: &mut CPU) {
+    cpu.registers.set_a(0x01);
+    cpu.registers.set_f(0xB0);
+    cpu.registers.set_bc(0x0013);
+    cpu.registers.set_de(0x00D8);
+    cpu.registers.set_hl(0x014D);
+    cpu.registers.set_sp(0xFFFE);
+    cpu.registers.set_pc(0x0100);
+}
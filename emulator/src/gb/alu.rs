@@ -0,0 +1,139 @@
+use crate::gb::register::Flags;
+
+/*
+The arithmetic and logic unit, as pure functions of the operands and the incoming carry.
+Each returns the result along with the full set of flags it leaves behind, flags that an
+instruction doesn't touch are passed in and handed back unchanged. The CPU only moves
+values in and out of registers and memory, which keeps every flag rule in one place where
+it can be tested against all inputs.
+
+Half carry is the carry out of bit 3 (bit 11 for ADD HL), for subtraction it's the borrow
+into bit 4 instead.
+
+https://gbdev.io/pandocs/CPU_Instruction_Set.html
+ */
+
+fn flags(zero: bool, subtract: bool, half_carry: bool, carry: bool) -> Flags {
+    Flags { zero, subtract, half_carry, carry }
+}
+
+// ADD and ADC
+pub fn add(a: u8, value: u8, carry: bool) -> (u8, Flags) {
+    let result = a as u16 + value as u16 + carry as u16;
+    let half_carry = (a & 0xF) + (value & 0xF) + carry as u8 > 0xF;
+    (result as u8, flags(result as u8 == 0, false, half_carry, result > 0xFF))
+}
+
+// SUB and SBC
+pub fn sub(a: u8, value: u8, carry: bool) -> (u8, Flags) {
+    let result = (a as i16) - (value as i16) - carry as i16;
+    let half_carry = ((a & 0xF) as i8) - ((value & 0xF) as i8) - (carry as i8) < 0;
+    (result as u8, flags(result as u8 == 0, true, half_carry, result < 0))
+}
+
+// CP is a SUB that only keeps the flags
+pub fn cp(a: u8, value: u8) -> Flags {
+    sub(a, value, false).1
+}
+
+pub fn and(a: u8, value: u8) -> (u8, Flags) {
+    let result = a & value;
+    (result, flags(result == 0, false, true, false))
+}
+
+pub fn or(a: u8, value: u8) -> (u8, Flags) {
+    let result = a | value;
+    (result, flags(result == 0, false, false, false))
+}
+
+pub fn xor(a: u8, value: u8) -> (u8, Flags) {
+    let result = a ^ value;
+    (result, flags(result == 0, false, false, false))
+}
+
+// 8-bit INC, which leaves the carry flag alone
+pub fn inc(value: u8, carry: bool) -> (u8, Flags) {
+    let result = value.wrapping_add(1);
+    (result, flags(result == 0, false, value & 0xF == 0xF, carry))
+}
+
+// 8-bit DEC, which leaves the carry flag alone
+pub fn dec(value: u8, carry: bool) -> (u8, Flags) {
+    let result = value.wrapping_sub(1);
+    (result, flags(result == 0, true, value & 0xF == 0, carry))
+}
+
+// ADD HL, rr. The zero flag is left alone, the carries come out of bits 11 and 15
+pub fn add_16(hl: u16, value: u16, zero: bool) -> (u16, Flags) {
+    let (result, carry) = hl.overflowing_add(value);
+    let half_carry = (hl & 0xFFF) + (value & 0xFFF) > 0xFFF;
+    (result, flags(zero, false, half_carry, carry))
+}
+
+// ADD SP, e and LD HL, SP+e. The flags come from adding the offset's byte to SP's low byte
+pub fn add_sp(sp: u16, offset: i8) -> (u16, Flags) {
+    let value = offset as u8;
+    let half_carry = (sp & 0xF) + (value & 0xF) as u16 > 0xF;
+    let carry = (sp & 0xFF) + value as u16 > 0xFF;
+    (sp.wrapping_add(offset as u16), flags(false, false, half_carry, carry))
+}
+
+/*
+The CB prefixed rotates and shifts, picked by bits 5-3 of the opcode:
+RLC, RRC, RL, RR, SLA, SRA, SWAP, SRL. Carry is what rotates into RL and RR.
+ */
+pub fn shift(operation: u8, value: u8, carry: bool) -> (u8, Flags) {
+    let carry_in = carry as u8;
+    let (result, carry) = match operation & 0x07 {
+        0 => (value.rotate_left(1), value & 0x80 != 0),
+        1 => (value.rotate_right(1), value & 0x01 != 0),
+        2 => (value << 1 | carry_in, value & 0x80 != 0),
+        3 => (value >> 1 | carry_in << 7, value & 0x01 != 0),
+        4 => (value << 1, value & 0x80 != 0),
+        5 => (value >> 1 | (value & 0x80), value & 0x01 != 0),
+        6 => (value.rotate_left(4), false),
+        _ => (value >> 1, value & 0x01 != 0),
+    };
+    (result, flags(result == 0, false, false, carry))
+}
+
+// RLCA, RRCA, RLA and RRA are their CB versions on A, except the zero flag is always cleared
+pub fn rotate_a(operation: u8, a: u8, carry: bool) -> (u8, Flags) {
+    let (result, flags) = shift(operation, a, carry);
+    (result, Flags { zero: false, ..flags })
+}
+
+// BIT leaves the carry flag alone
+pub fn bit(bit: u8, value: u8, carry: bool) -> Flags {
+    flags(value & (1 << bit) == 0, false, true, carry)
+}
+
+/*
+DAA turns A back into two BCD digits after adding or subtracting BCD numbers, using the
+flags that instruction left. After an addition a digit is corrected if it went past 9 or
+carried out, after a subtraction only if it borrowed. The carry flag says whether the
+tens digit overflowed.
+ */
+pub fn daa(a: u8, flags: Flags) -> (u8, Flags) {
+    let mut adjust = 0;
+    let mut carry = flags.carry;
+    if flags.subtract {
+        if flags.half_carry {
+            adjust |= 0x06;
+        }
+        if flags.carry {
+            adjust |= 0x60;
+        }
+        let result = a.wrapping_sub(adjust);
+        return (result, Flags { zero: result == 0, half_carry: false, carry, ..flags });
+    }
+    if flags.half_carry || a & 0xF > 0x9 {
+        adjust |= 0x06;
+    }
+    if flags.carry || a > 0x99 {
+        adjust |= 0x60;
+        carry = true;
+    }
+    let result = a.wrapping_add(adjust);
+    (result, Flags { zero: result == 0, half_carry: false, carry, ..flags })
+}
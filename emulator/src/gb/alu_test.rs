@@ -0,0 +1,185 @@
+#[cfg(test)]
+mod tests {
+    use crate::gb::alu;
+    use crate::gb::register::Flags;
+
+    /*
+    The reference model works the way the hardware does: a chain of one bit full adders,
+    reading the half carry off bit 3 and the carry off the top bit. Subtraction adds the
+    complement with the carry inverted, and the borrows are the inverted carries.
+     */
+
+    // Helper function to add two numbers bit by bit, returning the sum and the carry out of every bit
+    fn ripple_add(a: u32, b: u32, carry: bool, bits: u32) -> (u32, Vec<bool>) {
+        let mut sum = 0;
+        let mut carries = Vec::new();
+        let mut carry = carry as u32;
+        for i in 0..bits {
+            let (x, y) = ((a >> i) & 1, (b >> i) & 1);
+            sum |= (x ^ y ^ carry) << i;
+            carry = (x & y) | (carry & (x ^ y));
+            carries.push(carry == 1);
+        }
+        (sum, carries)
+    }
+
+    fn reference_add(a: u8, value: u8, carry: bool) -> (u8, Flags) {
+        let (sum, carries) = ripple_add(a as u32, value as u32, carry, 8);
+        (sum as u8, Flags { zero: sum == 0, subtract: false, half_carry: carries[3], carry: carries[7] })
+    }
+
+    fn reference_sub(a: u8, value: u8, borrow: bool) -> (u8, Flags) {
+        let (difference, carries) = ripple_add(a as u32, !value as u32, !borrow, 8);
+        (difference as u8, Flags { zero: difference == 0, subtract: true, half_carry: !carries[3], carry: !carries[7] })
+    }
+
+    // Helper function to run a check over every pair of bytes and both carries
+    fn for_all_inputs(mut check: impl FnMut(u8, u8, bool)) {
+        for a in 0..=0xFF {
+            for value in 0..=0xFF {
+                for carry in [false, true] {
+                    check(a, value, carry);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_add_and_sub() {
+        for_all_inputs(|a, value, carry| {
+            assert_eq!(alu::add(a, value, carry), reference_add(a, value, carry), "ADC {:#04X}, {:#04X}, {}", a, value, carry);
+            assert_eq!(alu::sub(a, value, carry), reference_sub(a, value, carry), "SBC {:#04X}, {:#04X}, {}", a, value, carry);
+            assert_eq!(alu::cp(a, value), reference_sub(a, value, false).1, "CP {:#04X}, {:#04X}", a, value);
+        });
+        assert_eq!(alu::add(0x0F, 0x00, true), (0x10, Flags { zero: false, subtract: false, half_carry: true, carry: false }));
+        assert_eq!(alu::sub(0x10, 0x0F, true), (0x00, Flags { zero: true, subtract: true, half_carry: true, carry: false }));
+    }
+
+    #[test]
+    fn test_logic() {
+        for_all_inputs(|a, value, _| {
+            let (result, flags) = alu::and(a, value);
+            assert_eq!((result, flags), (a & value, Flags { zero: a & value == 0, subtract: false, half_carry: true, carry: false }));
+            let (result, flags) = alu::or(a, value);
+            assert_eq!((result, flags), (a | value, Flags { zero: a | value == 0, subtract: false, half_carry: false, carry: false }));
+            let (result, flags) = alu::xor(a, value);
+            assert_eq!((result, flags), (a ^ value, Flags { zero: a == value, subtract: false, half_carry: false, carry: false }));
+        });
+    }
+
+    #[test]
+    fn test_inc_and_dec() {
+        for value in 0..=0xFF {
+            for carry in [false, true] {
+                let (result, flags) = reference_add(value, 1, false);
+                assert_eq!(alu::inc(value, carry), (result, Flags { carry, ..flags }), "INC {:#04X}", value);
+                let (result, flags) = reference_sub(value, 1, false);
+                assert_eq!(alu::dec(value, carry), (result, Flags { carry, ..flags }), "DEC {:#04X}", value);
+            }
+        }
+    }
+
+    #[test]
+    fn test_add_16() {
+        let mut seed = 0x1234_5678u32;
+        for hl in 0..=0xFFFF {
+            // Every HL against a few pseudo random values, a full square would take too long
+            for _ in 0..4 {
+                seed ^= seed << 13;
+                seed ^= seed >> 17;
+                seed ^= seed << 5;
+                let value = seed as u16;
+                for zero in [false, true] {
+                    let (sum, carries) = ripple_add(hl as u32, value as u32, false, 16);
+                    let expected = (sum as u16, Flags { zero, subtract: false, half_carry: carries[11], carry: carries[15] });
+                    assert_eq!(alu::add_16(hl, value, zero), expected, "ADD HL {:#06X}, {:#06X}", hl, value);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_add_sp() {
+        // The flags only depend on the low byte of SP, the high byte only matters for the sum
+        for high in [0x00, 0x7F, 0xC0, 0xFF] {
+            for low in 0..=0xFF {
+                for offset in i8::MIN..=i8::MAX {
+                    let sp = (high as u16) << 8 | low;
+                    let (_, carries) = ripple_add(low as u32, offset as u8 as u32, false, 8);
+                    let sum = (sp as i32 + offset as i32) as u16;
+                    let expected = (sum, Flags { zero: false, subtract: false, half_carry: carries[3], carry: carries[7] });
+                    assert_eq!(alu::add_sp(sp, offset), expected, "ADD SP {:#06X}, {}", sp, offset);
+                }
+            }
+        }
+    }
+
+    // Rotates are spelled out by hand on purpose, so they don't share code with what is tested
+    #[allow(clippy::manual_rotate)]
+    #[test]
+    fn test_shifts() {
+        for value in 0..=0xFFu8 {
+            for carry in [false, true] {
+                // RL and RR rotate through the carry, as if it were a ninth bit
+                let left = (value as u16) << 1 | carry as u16;
+                let right = (carry as u16) << 8 | value as u16;
+                let expected = [
+                    ((value << 1) | (value >> 7), value >> 7 == 1),
+                    ((value >> 1) | (value << 7), value & 1 == 1),
+                    (left as u8, left >> 8 == 1),
+                    ((right >> 1) as u8, right & 1 == 1),
+                    (value << 1, value >> 7 == 1),
+                    (((value as i8) >> 1) as u8, value & 1 == 1),
+                    ((value << 4) | (value >> 4), false),
+                    (value >> 1, value & 1 == 1),
+                ];
+                for (operation, &(result, carry_out)) in expected.iter().enumerate() {
+                    let flags = Flags { zero: result == 0, subtract: false, half_carry: false, carry: carry_out };
+                    assert_eq!(alu::shift(operation as u8, value, carry), (result, flags), "Operation {} on {:#04X}, {}", operation, value, carry);
+                    if operation < 4 {
+                        assert_eq!(alu::rotate_a(operation as u8, value, carry), (result, Flags { zero: false, ..flags }));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_bit() {
+        for value in 0..=0xFFu8 {
+            for bit in 0..8 {
+                for carry in [false, true] {
+                    let flags = alu::bit(bit, value, carry);
+                    assert_eq!(flags, Flags { zero: (value >> bit) & 1 == 0, subtract: false, half_carry: true, carry });
+                }
+            }
+        }
+    }
+
+    // Helper function to pack a number below 100 as two BCD digits
+    fn bcd(number: u32) -> u8 {
+        (((number / 10) << 4) | (number % 10)) as u8
+    }
+
+    #[test]
+    fn test_daa() {
+        // Adding or subtracting any two BCD numbers and adjusting has to give the decimal answer
+        for x in 0..100 {
+            for y in 0..100 {
+                for carry in [false, true] {
+                    let (sum, flags) = alu::add(bcd(x), bcd(y), carry);
+                    let (result, flags) = alu::daa(sum, flags);
+                    let total = x + y + carry as u32;
+                    assert_eq!((result, flags.carry), (bcd(total % 100), total >= 100), "{} + {} + {}", x, y, carry);
+                    assert_eq!((flags.zero, flags.subtract, flags.half_carry), (result == 0, false, false));
+
+                    let (difference, flags) = alu::sub(bcd(x), bcd(y), carry);
+                    let (result, flags) = alu::daa(difference, flags);
+                    let total = x as i32 - y as i32 - carry as i32;
+                    assert_eq!((result, flags.carry), (bcd(total.rem_euclid(100) as u32), total < 0), "{} - {} - {}", x, y, carry);
+                    assert_eq!((flags.zero, flags.subtract, flags.half_carry), (result == 0, true, false));
+                }
+            }
+        }
+    }
+}
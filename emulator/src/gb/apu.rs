@@ -0,0 +1,767 @@
+use crate::gb::state::{StateError, StateReader, StateWriter};
+
+/*
+Audio Processing Unit: two square channels (the first with a frequency sweep), a wave
+channel and a noise channel, mixed into stereo samples at the host's sample rate.
+
+https://gbdev.io/pandocs/Audio.html
+*/
+
+pub const CPU_CLOCK_HZ: u32 = 4_194_304;
+pub const DEFAULT_SAMPLE_RATE: u32 = 48_000;
+
+const APU_START_ADDRESS: u16 = 0xFF10;
+const NR52_ADDRESS: u16 = 0xFF26;
+const WAVE_RAM_ADDRESS: u16 = 0xFF30;
+
+const FRAME_SEQUENCER_PERIOD: u32 = 8192; // 512 Hz
+
+// Bits that always read back as 1, indexed from 0xFF10
+const READ_MASKS: [u8; 0x20] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, // NR10-NR14
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF, // unused, NR21-NR24
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF, // NR30-NR34
+    0xFF, 0xFF, 0x00, 0x00, 0xBF, // unused, NR41-NR44
+    0x00, 0x00, 0x70,             // NR50-NR52
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, // unused
+];
+
+const DUTY_PATTERNS: [[u8; 8]; 4] = [
+    [0, 0, 0, 0, 0, 0, 0, 1], // 12.5%
+    [1, 0, 0, 0, 0, 0, 0, 1], // 25%
+    [1, 0, 0, 0, 0, 1, 1, 1], // 50%
+    [0, 1, 1, 1, 1, 1, 1, 0], // 75%
+];
+
+const NOISE_DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+struct Envelope {
+    initial_volume: u8,
+    increase: bool,
+    period: u8,
+    timer: u8,
+    volume: u8,
+}
+
+impl Envelope {
+    fn new() -> Self {
+        Envelope { initial_volume: 0, increase: false, period: 0, timer: 0, volume: 0 }
+    }
+
+    fn write(&mut self, value: u8) {
+        self.initial_volume = value >> 4;
+        self.increase = (value & 0x08) != 0;
+        self.period = value & 0x07;
+    }
+
+    fn trigger(&mut self) {
+        self.volume = self.initial_volume;
+        self.timer = self.period;
+    }
+
+    fn step(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.increase && self.volume < 15 {
+                self.volume += 1;
+            } else if !self.increase && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+}
+
+struct SquareChannel {
+    enabled: bool,
+    dac_enabled: bool,
+    length_counter: u16,
+    length_enabled: bool,
+    duty: u8,
+    duty_step: u8,
+    frequency: u16,
+    timer: u32,
+    envelope: Envelope,
+    // Frequency sweep, only used by channel 1
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_timer: u8,
+    sweep_enabled: bool,
+    shadow_frequency: u16,
+}
+
+impl SquareChannel {
+    fn new() -> Self {
+        SquareChannel {
+            enabled: false,
+            dac_enabled: false,
+            length_counter: 0,
+            length_enabled: false,
+            duty: 0,
+            duty_step: 0,
+            frequency: 0,
+            timer: 0,
+            envelope: Envelope::new(),
+            sweep_period: 0,
+            sweep_negate: false,
+            sweep_shift: 0,
+            sweep_timer: 0,
+            sweep_enabled: false,
+            shadow_frequency: 0,
+        }
+    }
+
+    fn period(&self) -> u32 {
+        (2048 - self.frequency as u32) * 4
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        if self.length_counter == 0 {
+            self.length_counter = 64;
+        }
+        self.timer = self.period();
+        self.envelope.trigger();
+
+        self.shadow_frequency = self.frequency;
+        self.sweep_timer = if self.sweep_period == 0 { 8 } else { self.sweep_period };
+        self.sweep_enabled = self.sweep_period != 0 || self.sweep_shift != 0;
+        if self.sweep_shift != 0 {
+            self.calculate_sweep();
+        }
+    }
+
+    fn calculate_sweep(&mut self) -> u16 {
+        let delta = self.shadow_frequency >> self.sweep_shift;
+        let frequency = if self.sweep_negate {
+            self.shadow_frequency.wrapping_sub(delta)
+        } else {
+            self.shadow_frequency + delta
+        };
+        if frequency > 2047 {
+            self.enabled = false;
+        }
+        frequency
+    }
+
+    fn step_sweep(&mut self) {
+        if self.sweep_timer > 0 {
+            self.sweep_timer -= 1;
+        }
+        if self.sweep_timer != 0 {
+            return;
+        }
+        self.sweep_timer = if self.sweep_period == 0 { 8 } else { self.sweep_period };
+
+        if self.sweep_enabled && self.sweep_period != 0 {
+            let frequency = self.calculate_sweep();
+            if frequency <= 2047 && self.sweep_shift != 0 {
+                self.shadow_frequency = frequency;
+                self.frequency = frequency;
+                self.calculate_sweep();
+            }
+        }
+    }
+
+    fn step_length(&mut self) {
+        if self.length_enabled && self.length_counter > 0 {
+            self.length_counter -= 1;
+            if self.length_counter == 0 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        let mut remaining = cycles;
+        while remaining >= self.timer {
+            remaining -= self.timer;
+            self.timer = self.period();
+            self.duty_step = (self.duty_step + 1) % 8;
+        }
+        self.timer -= remaining;
+    }
+
+    fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        DUTY_PATTERNS[self.duty as usize][self.duty_step as usize] * self.envelope.volume
+    }
+}
+
+struct WaveChannel {
+    enabled: bool,
+    dac_enabled: bool,
+    length_counter: u16,
+    length_enabled: bool,
+    volume_code: u8,
+    frequency: u16,
+    timer: u32,
+    position: u8,
+    table: [u8; 16],
+}
+
+impl WaveChannel {
+    fn new() -> Self {
+        WaveChannel {
+            enabled: false,
+            dac_enabled: false,
+            length_counter: 0,
+            length_enabled: false,
+            volume_code: 0,
+            frequency: 0,
+            timer: 0,
+            position: 0,
+            table: [0; 16],
+        }
+    }
+
+    fn period(&self) -> u32 {
+        (2048 - self.frequency as u32) * 2
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        if self.length_counter == 0 {
+            self.length_counter = 256;
+        }
+        self.timer = self.period();
+        self.position = 0;
+    }
+
+    fn step_length(&mut self) {
+        if self.length_enabled && self.length_counter > 0 {
+            self.length_counter -= 1;
+            if self.length_counter == 0 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        let mut remaining = cycles;
+        while remaining >= self.timer {
+            remaining -= self.timer;
+            self.timer = self.period();
+            self.position = (self.position + 1) % 32;
+        }
+        self.timer -= remaining;
+    }
+
+    fn output(&self) -> u8 {
+        if !self.enabled || self.volume_code == 0 {
+            return 0;
+        }
+        let byte = self.table[(self.position / 2) as usize];
+        let sample = if self.position.is_multiple_of(2) { byte >> 4 } else { byte & 0x0F };
+        sample >> (self.volume_code - 1)
+    }
+}
+
+struct NoiseChannel {
+    enabled: bool,
+    dac_enabled: bool,
+    length_counter: u16,
+    length_enabled: bool,
+    envelope: Envelope,
+    clock_shift: u8,
+    width_mode: bool,
+    divisor_code: u8,
+    timer: u32,
+    lfsr: u16,
+}
+
+impl NoiseChannel {
+    fn new() -> Self {
+        NoiseChannel {
+            enabled: false,
+            dac_enabled: false,
+            length_counter: 0,
+            length_enabled: false,
+            envelope: Envelope::new(),
+            clock_shift: 0,
+            width_mode: false,
+            divisor_code: 0,
+            timer: 0,
+            lfsr: 0x7FFF,
+        }
+    }
+
+    fn period(&self) -> u32 {
+        NOISE_DIVISORS[self.divisor_code as usize] << self.clock_shift
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        if self.length_counter == 0 {
+            self.length_counter = 64;
+        }
+        self.timer = self.period();
+        self.envelope.trigger();
+        self.lfsr = 0x7FFF;
+    }
+
+    fn step_length(&mut self) {
+        if self.length_enabled && self.length_counter > 0 {
+            self.length_counter -= 1;
+            if self.length_counter == 0 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        let mut remaining = cycles;
+        while remaining >= self.timer {
+            remaining -= self.timer;
+            self.timer = self.period();
+
+            let xor = (self.lfsr & 0x01) ^ ((self.lfsr >> 1) & 0x01);
+            self.lfsr = (self.lfsr >> 1) | (xor << 14);
+            if self.width_mode {
+                self.lfsr = (self.lfsr & !0x40) | (xor << 6);
+            }
+        }
+        self.timer -= remaining;
+    }
+
+    fn output(&self) -> u8 {
+        if !self.enabled || (self.lfsr & 0x01) != 0 {
+            return 0;
+        }
+        self.envelope.volume
+    }
+}
+
+pub struct APU {
+    registers: [u8; 0x20], // Raw values of 0xFF10-0xFF2F
+    enabled: bool,
+    channel1: SquareChannel,
+    channel2: SquareChannel,
+    channel3: WaveChannel,
+    channel4: NoiseChannel,
+    frame_sequencer_clock: u32,
+    frame_sequencer_step: u8,
+    sample_rate: u32,
+    sample_clock: u64,
+    pub samples: Vec<f32>, // Interleaved left/right samples waiting to be played
+}
+
+impl APU {
+    pub fn new() -> Self {
+        APU {
+            registers: [0; 0x20],
+            enabled: false,
+            channel1: SquareChannel::new(),
+            channel2: SquareChannel::new(),
+            channel3: WaveChannel::new(),
+            channel4: NoiseChannel::new(),
+            frame_sequencer_clock: 0,
+            frame_sequencer_step: 0,
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            sample_clock: 0,
+            samples: Vec::new(),
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        self.sample_clock = 0;
+    }
+
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.samples)
+    }
+
+    pub fn read_register(&self, address: u16) -> u8 {
+        match address {
+            NR52_ADDRESS => {
+                let mut value = if self.enabled { 0x80 } else { 0x00 };
+                if self.channel1.enabled { value |= 0x01; }
+                if self.channel2.enabled { value |= 0x02; }
+                if self.channel3.enabled { value |= 0x04; }
+                if self.channel4.enabled { value |= 0x08; }
+                value | READ_MASKS[(NR52_ADDRESS - APU_START_ADDRESS) as usize]
+            }
+            0xFF10..=0xFF2F => {
+                let index = (address - APU_START_ADDRESS) as usize;
+                self.registers[index] | READ_MASKS[index]
+            }
+            0xFF30..=0xFF3F => self.channel3.table[(address - WAVE_RAM_ADDRESS) as usize],
+            _ => panic!("Invalid APU register address: {}", address),
+        }
+    }
+
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        if let 0xFF30..=0xFF3F = address {
+            self.channel3.table[(address - WAVE_RAM_ADDRESS) as usize] = value;
+            return;
+        }
+        if address == NR52_ADDRESS {
+            let enabled = (value & 0x80) != 0;
+            if self.enabled && !enabled {
+                self.power_off();
+            } else if !self.enabled && enabled {
+                self.frame_sequencer_step = 0;
+            }
+            self.enabled = enabled;
+            return;
+        }
+        if !(0xFF10..=0xFF2F).contains(&address) {
+            panic!("Invalid APU register address: {}", address);
+        }
+        // Registers are read only while the APU is powered off
+        if !self.enabled {
+            return;
+        }
+
+        self.registers[(address - APU_START_ADDRESS) as usize] = value;
+        match address {
+            // Channel 1: square with sweep
+            0xFF10 => {
+                self.channel1.sweep_period = (value >> 4) & 0x07;
+                self.channel1.sweep_negate = (value & 0x08) != 0;
+                self.channel1.sweep_shift = value & 0x07;
+            }
+            0xFF11 => {
+                self.channel1.duty = value >> 6;
+                self.channel1.length_counter = 64 - (value & 0x3F) as u16;
+            }
+            0xFF12 => {
+                self.channel1.envelope.write(value);
+                self.channel1.dac_enabled = (value & 0xF8) != 0;
+                if !self.channel1.dac_enabled {
+                    self.channel1.enabled = false;
+                }
+            }
+            0xFF13 => self.channel1.frequency = (self.channel1.frequency & 0x700) | value as u16,
+            0xFF14 => {
+                self.channel1.frequency = (self.channel1.frequency & 0xFF) | (((value & 0x07) as u16) << 8);
+                self.channel1.length_enabled = (value & 0x40) != 0;
+                if (value & 0x80) != 0 {
+                    self.channel1.trigger();
+                }
+            }
+
+            // Channel 2: square
+            0xFF16 => {
+                self.channel2.duty = value >> 6;
+                self.channel2.length_counter = 64 - (value & 0x3F) as u16;
+            }
+            0xFF17 => {
+                self.channel2.envelope.write(value);
+                self.channel2.dac_enabled = (value & 0xF8) != 0;
+                if !self.channel2.dac_enabled {
+                    self.channel2.enabled = false;
+                }
+            }
+            0xFF18 => self.channel2.frequency = (self.channel2.frequency & 0x700) | value as u16,
+            0xFF19 => {
+                self.channel2.frequency = (self.channel2.frequency & 0xFF) | (((value & 0x07) as u16) << 8);
+                self.channel2.length_enabled = (value & 0x40) != 0;
+                if (value & 0x80) != 0 {
+                    self.channel2.trigger();
+                }
+            }
+
+            // Channel 3: wave
+            0xFF1A => {
+                self.channel3.dac_enabled = (value & 0x80) != 0;
+                if !self.channel3.dac_enabled {
+                    self.channel3.enabled = false;
+                }
+            }
+            0xFF1B => self.channel3.length_counter = 256 - value as u16,
+            0xFF1C => self.channel3.volume_code = (value >> 5) & 0x03,
+            0xFF1D => self.channel3.frequency = (self.channel3.frequency & 0x700) | value as u16,
+            0xFF1E => {
+                self.channel3.frequency = (self.channel3.frequency & 0xFF) | (((value & 0x07) as u16) << 8);
+                self.channel3.length_enabled = (value & 0x40) != 0;
+                if (value & 0x80) != 0 {
+                    self.channel3.trigger();
+                }
+            }
+
+            // Channel 4: noise
+            0xFF20 => self.channel4.length_counter = 64 - (value & 0x3F) as u16,
+            0xFF21 => {
+                self.channel4.envelope.write(value);
+                self.channel4.dac_enabled = (value & 0xF8) != 0;
+                if !self.channel4.dac_enabled {
+                    self.channel4.enabled = false;
+                }
+            }
+            0xFF22 => {
+                self.channel4.clock_shift = value >> 4;
+                self.channel4.width_mode = (value & 0x08) != 0;
+                self.channel4.divisor_code = value & 0x07;
+            }
+            0xFF23 => {
+                self.channel4.length_enabled = (value & 0x40) != 0;
+                if (value & 0x80) != 0 {
+                    self.channel4.trigger();
+                }
+            }
+
+            // NR50, NR51 and the unused registers only need their raw value
+            _ => {}
+        }
+    }
+
+    fn power_off(&mut self) {
+        // Powering off clears every register except wave RAM
+        let table = self.channel3.table;
+        self.registers = [0; 0x20];
+        self.channel1 = SquareChannel::new();
+        self.channel2 = SquareChannel::new();
+        self.channel3 = WaveChannel::new();
+        self.channel3.table = table;
+        self.channel4 = NoiseChannel::new();
+    }
+
+    fn step_frame_sequencer(&mut self) {
+        match self.frame_sequencer_step {
+            0 | 4 => self.step_length(),
+            2 | 6 => {
+                self.step_length();
+                self.channel1.step_sweep();
+            }
+            7 => {
+                self.channel1.envelope.step();
+                self.channel2.envelope.step();
+                self.channel4.envelope.step();
+            }
+            _ => {}
+        }
+        self.frame_sequencer_step = (self.frame_sequencer_step + 1) % 8;
+    }
+
+    fn step_length(&mut self) {
+        self.channel1.step_length();
+        self.channel2.step_length();
+        self.channel3.step_length();
+        self.channel4.step_length();
+    }
+
+    pub fn tick(&mut self, cycles: u32) {
+        if self.enabled {
+            self.frame_sequencer_clock += cycles;
+            while self.frame_sequencer_clock >= FRAME_SEQUENCER_PERIOD {
+                self.frame_sequencer_clock -= FRAME_SEQUENCER_PERIOD;
+                self.step_frame_sequencer();
+            }
+
+            self.channel1.tick(cycles);
+            self.channel2.tick(cycles);
+            self.channel3.tick(cycles);
+            self.channel4.tick(cycles);
+        }
+
+        self.sample_clock += cycles as u64 * self.sample_rate as u64;
+        while self.sample_clock >= CPU_CLOCK_HZ as u64 {
+            self.sample_clock -= CPU_CLOCK_HZ as u64;
+            let (left, right) = self.mix();
+            self.samples.push(left);
+            self.samples.push(right);
+        }
+    }
+
+    fn mix(&self) -> (f32, f32) {
+        if !self.enabled {
+            return (0.0, 0.0);
+        }
+
+        let nr50 = self.registers[0x14];
+        let nr51 = self.registers[0x15];
+        let channels = [
+            (self.channel1.dac_enabled, self.channel1.output()),
+            (self.channel2.dac_enabled, self.channel2.output()),
+            (self.channel3.dac_enabled, self.channel3.output()),
+            (self.channel4.dac_enabled, self.channel4.output()),
+        ];
+
+        let mut left = 0.0;
+        let mut right = 0.0;
+        for (index, &(dac_enabled, output)) in channels.iter().enumerate() {
+            if !dac_enabled {
+                continue;
+            }
+            // Each DAC maps 0..15 onto -1.0..1.0
+            let sample = output as f32 / 7.5 - 1.0;
+            if (nr51 & (0x10 << index)) != 0 {
+                left += sample;
+            }
+            if (nr51 & (0x01 << index)) != 0 {
+                right += sample;
+            }
+        }
+
+        let left_volume = (((nr50 >> 4) & 0x07) + 1) as f32 / 8.0;
+        let right_volume = ((nr50 & 0x07) + 1) as f32 / 8.0;
+        (left / 4.0 * left_volume, right / 4.0 * right_volume)
+    }
+}
+
+impl Default for APU {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/*
+Loading masks every field back to the width its register gives it, so a corrupted state
+can't index past the duty and divisor tables or shift further than the value is wide.
+ */
+impl Envelope {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.initial_volume);
+        writer.write_bool(self.increase);
+        writer.write_u8(self.period);
+        writer.write_u8(self.timer);
+        writer.write_u8(self.volume);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.initial_volume = reader.read_u8()? & 0x0F;
+        self.increase = reader.read_bool()?;
+        self.period = reader.read_u8()? & 0x07;
+        self.timer = reader.read_u8()?;
+        self.volume = reader.read_u8()? & 0x0F;
+        Ok(())
+    }
+}
+
+impl SquareChannel {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.enabled);
+        writer.write_bool(self.dac_enabled);
+        writer.write_u16(self.length_counter);
+        writer.write_bool(self.length_enabled);
+        writer.write_u8(self.duty);
+        writer.write_u8(self.duty_step);
+        writer.write_u16(self.frequency);
+        writer.write_u32(self.timer);
+        self.envelope.save_state(writer);
+        writer.write_u8(self.sweep_period);
+        writer.write_bool(self.sweep_negate);
+        writer.write_u8(self.sweep_shift);
+        writer.write_u8(self.sweep_timer);
+        writer.write_bool(self.sweep_enabled);
+        writer.write_u16(self.shadow_frequency);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = reader.read_bool()?;
+        self.dac_enabled = reader.read_bool()?;
+        self.length_counter = reader.read_u16()?;
+        self.length_enabled = reader.read_bool()?;
+        self.duty = reader.read_u8()? & 0x03;
+        self.duty_step = reader.read_u8()? & 0x07;
+        self.frequency = reader.read_u16()? & 0x07FF;
+        self.timer = reader.read_u32()?;
+        self.envelope.load_state(reader)?;
+        self.sweep_period = reader.read_u8()? & 0x07;
+        self.sweep_negate = reader.read_bool()?;
+        self.sweep_shift = reader.read_u8()? & 0x07;
+        self.sweep_timer = reader.read_u8()?;
+        self.sweep_enabled = reader.read_bool()?;
+        self.shadow_frequency = reader.read_u16()? & 0x07FF;
+        Ok(())
+    }
+}
+
+impl WaveChannel {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.enabled);
+        writer.write_bool(self.dac_enabled);
+        writer.write_u16(self.length_counter);
+        writer.write_bool(self.length_enabled);
+        writer.write_u8(self.volume_code);
+        writer.write_u16(self.frequency);
+        writer.write_u32(self.timer);
+        writer.write_u8(self.position);
+        writer.write_bytes(&self.table);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = reader.read_bool()?;
+        self.dac_enabled = reader.read_bool()?;
+        self.length_counter = reader.read_u16()?;
+        self.length_enabled = reader.read_bool()?;
+        self.volume_code = reader.read_u8()? & 0x03;
+        self.frequency = reader.read_u16()? & 0x07FF;
+        self.timer = reader.read_u32()?;
+        self.position = reader.read_u8()? % 32;
+        reader.read_into(&mut self.table)?;
+        Ok(())
+    }
+}
+
+impl NoiseChannel {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.enabled);
+        writer.write_bool(self.dac_enabled);
+        writer.write_u16(self.length_counter);
+        writer.write_bool(self.length_enabled);
+        self.envelope.save_state(writer);
+        writer.write_u8(self.clock_shift);
+        writer.write_bool(self.width_mode);
+        writer.write_u8(self.divisor_code);
+        writer.write_u32(self.timer);
+        writer.write_u16(self.lfsr);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = reader.read_bool()?;
+        self.dac_enabled = reader.read_bool()?;
+        self.length_counter = reader.read_u16()?;
+        self.length_enabled = reader.read_bool()?;
+        self.envelope.load_state(reader)?;
+        self.clock_shift = reader.read_u8()? & 0x0F;
+        self.width_mode = reader.read_bool()?;
+        self.divisor_code = reader.read_u8()? & 0x07;
+        self.timer = reader.read_u32()?;
+        self.lfsr = reader.read_u16()?;
+        Ok(())
+    }
+}
+
+impl APU {
+    // The sample rate and queued samples belong to the host's audio output
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.registers);
+        writer.write_bool(self.enabled);
+        self.channel1.save_state(writer);
+        self.channel2.save_state(writer);
+        self.channel3.save_state(writer);
+        self.channel4.save_state(writer);
+        writer.write_u32(self.frame_sequencer_clock);
+        writer.write_u8(self.frame_sequencer_step);
+        writer.write_u64(self.sample_clock);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        reader.read_into(&mut self.registers)?;
+        self.enabled = reader.read_bool()?;
+        self.channel1.load_state(reader)?;
+        self.channel2.load_state(reader)?;
+        self.channel3.load_state(reader)?;
+        self.channel4.load_state(reader)?;
+        self.frame_sequencer_clock = reader.read_u32()?.min(FRAME_SEQUENCER_PERIOD);
+        self.frame_sequencer_step = reader.read_u8()? % 8;
+        self.sample_clock = reader.read_u64()?.min(CPU_CLOCK_HZ as u64);
+        self.samples.clear();
+        Ok(())
+    }
+}
@@ -0,0 +1,89 @@
+#[cfg(test)]
+mod tests {
+    use crate::gb::apu::{APU, CPU_CLOCK_HZ};
+    use crate::gb::ram::RAM;
+
+    // Helper function to power on the APU with every channel routed to both sides at full volume
+    fn powered_apu() -> APU {
+        let mut apu = APU::new();
+        apu.write_register(0xFF26, 0x80);
+        apu.write_register(0xFF24, 0x77);
+        apu.write_register(0xFF25, 0xFF);
+        apu
+    }
+
+    #[test]
+    fn test_register_read_masks() {
+        let mut apu = powered_apu();
+        apu.write_register(0xFF11, 0x80);
+        assert_eq!(apu.read_register(0xFF11), 0xBF, "Length bits of NR11 should read as 1");
+        assert_eq!(apu.read_register(0xFF13), 0xFF, "Frequency registers are write only");
+        assert_eq!(apu.read_register(0xFF26), 0xF0, "Powered on with no channel playing");
+    }
+
+    #[test]
+    fn test_power_off_clears_registers() {
+        let mut apu = powered_apu();
+        apu.write_register(0xFF30, 0x12);
+        apu.write_register(0xFF26, 0x00);
+        assert_eq!(apu.read_register(0xFF24), 0x00);
+        assert_eq!(apu.read_register(0xFF26), 0x70);
+        assert_eq!(apu.read_register(0xFF30), 0x12, "Wave RAM should survive power off");
+
+        apu.write_register(0xFF24, 0x77);
+        assert_eq!(apu.read_register(0xFF24), 0x00, "Registers should ignore writes while powered off");
+    }
+
+    #[test]
+    fn test_trigger_and_length_counter() {
+        let mut apu = powered_apu();
+        apu.write_register(0xFF17, 0xF0); // Channel 2 at full volume
+        apu.write_register(0xFF16, 0x3F); // Length of 1
+        apu.write_register(0xFF19, 0xC0); // Trigger with length enabled
+        assert_eq!(apu.read_register(0xFF26) & 0x02, 0x02, "Channel 2 should be playing");
+
+        // The first frame sequencer step clocks the length counter
+        apu.tick(8192);
+        assert_eq!(apu.read_register(0xFF26) & 0x02, 0x00, "Channel 2 should stop when its length expires");
+    }
+
+    #[test]
+    fn test_dac_off_disables_channel() {
+        let mut apu = powered_apu();
+        apu.write_register(0xFF12, 0xF0);
+        apu.write_register(0xFF14, 0x80);
+        assert_eq!(apu.read_register(0xFF26) & 0x01, 0x01);
+
+        apu.write_register(0xFF12, 0x00);
+        assert_eq!(apu.read_register(0xFF26) & 0x01, 0x00);
+    }
+
+    #[test]
+    fn test_sample_generation() {
+        let mut apu = powered_apu();
+        apu.set_sample_rate(44_100);
+        apu.write_register(0xFF12, 0xF0);
+        apu.write_register(0xFF11, 0x80); // 50% duty
+        apu.write_register(0xFF13, 0x00);
+        apu.write_register(0xFF14, 0x87); // Frequency 0x700, trigger
+
+        for _ in 0..CPU_CLOCK_HZ / 1024 {
+            apu.tick(1024);
+        }
+        let samples = apu.take_samples();
+        assert_eq!(samples.len(), 44_100 * 2, "One second of stereo samples");
+        assert!(samples.iter().any(|&s| s > 0.0) && samples.iter().any(|&s| s < 0.0), "Square wave should swing both ways");
+        assert!(samples.iter().all(|&s| (-1.0..=1.0).contains(&s)));
+        assert!(apu.take_samples().is_empty());
+    }
+
+    #[test]
+    fn test_apu_mapped_on_bus() {
+        let mut ram = RAM::new();
+        ram.write(0xFF26, 0x80);
+        ram.write(0xFF25, 0xA5);
+        assert_eq!(ram.read(0xFF25), 0xA5);
+        ram.write(0xFF3F, 0x9C);
+        assert_eq!(ram.read(0xFF3F), 0x9C);
+    }
+}
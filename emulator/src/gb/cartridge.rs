@@ -0,0 +1,467 @@
+use std::fmt;
+use crate::gb::state::{StateError, StateReader, StateWriter};
+
+/*
+Cartridge header and memory bank controllers.
+
+ROM is mapped at 0x0000-0x7FFF (bank 0 fixed, 0x4000-0x7FFF switchable) and external
+RAM at 0xA000-0xBFFF. Writes to the ROM area are commands to the mapper.
+
+https://gbdev.io/pandocs/The_Cartridge_Header.html
+https://gbdev.io/pandocs/MBCs.html
+*/
+
+const TITLE_ADDRESS: usize = 0x134;
+const TITLE_END_ADDRESS: usize = 0x143;
+const CARTRIDGE_TYPE_ADDRESS: usize = 0x147;
+const ROM_SIZE_ADDRESS: usize = 0x148;
+const RAM_SIZE_ADDRESS: usize = 0x149;
+const HEADER_CHECKSUM_ADDRESS: usize = 0x14D;
+const GLOBAL_CHECKSUM_ADDRESS: usize = 0x14E;
+const HEADER_SIZE: usize = 0x150;
+
+const ROM_BANK_SIZE: usize = 0x4000;
+const RAM_BANK_SIZE: usize = 0x2000;
+const MBC2_RAM_SIZE: usize = 0x200;
+
+const RTC_CYCLES_PER_SECOND: u32 = 4_194_304;
+
+#[derive(Debug, PartialEq)]
+pub enum CartridgeError {
+    TooSmall(usize),
+    UnsupportedType(u8),
+    InvalidRomSize(u8),
+    InvalidRamSize(u8),
+}
+
+impl fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CartridgeError::TooSmall(size) => write!(f, "ROM is too small to hold a header ({} bytes)", size),
+            CartridgeError::UnsupportedType(value) => write!(f, "Unsupported cartridge type: {:#04X}", value),
+            CartridgeError::InvalidRomSize(value) => write!(f, "Invalid ROM size code: {:#04X}", value),
+            CartridgeError::InvalidRamSize(value) => write!(f, "Invalid RAM size code: {:#04X}", value),
+        }
+    }
+}
+
+impl std::error::Error for CartridgeError {}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Mbc {
+    None,
+    Mbc1,
+    Mbc2,
+    Mbc3,
+    Mbc5,
+}
+
+// MBC3 real time clock registers, selected with 0x08-0x0C
+struct Rtc {
+    seconds: u8,
+    minutes: u8,
+    hours: u8,
+    days: u16, // 9 bits, bit 9 of this value is the day counter carry
+    halted: bool,
+    latched: [u8; 5],
+    latch_value: u8,
+    cycles: u32,
+}
+
+impl Rtc {
+    fn new() -> Self {
+        Rtc { seconds: 0, minutes: 0, hours: 0, days: 0, halted: false, latched: [0; 5], latch_value: 0xFF, cycles: 0 }
+    }
+
+    fn registers(&self) -> [u8; 5] {
+        let mut day_high = ((self.days >> 8) & 0x01) as u8;
+        if self.halted {
+            day_high |= 0x40;
+        }
+        if (self.days & 0x200) != 0 {
+            day_high |= 0x80;
+        }
+        [self.seconds, self.minutes, self.hours, (self.days & 0xFF) as u8, day_high]
+    }
+
+    fn latch(&mut self, value: u8) {
+        // Writing 0x00 then 0x01 copies the running clock into the readable registers
+        if self.latch_value == 0x00 && value == 0x01 {
+            self.latched = self.registers();
+        }
+        self.latch_value = value;
+    }
+
+    fn write(&mut self, register: u8, value: u8) {
+        match register {
+            0x08 => { self.seconds = value & 0x3F; self.cycles = 0; }
+            0x09 => self.minutes = value & 0x3F,
+            0x0A => self.hours = value & 0x1F,
+            0x0B => self.days = (self.days & 0x300) | value as u16,
+            0x0C => {
+                self.days = (self.days & 0xFF) | (((value & 0x01) as u16) << 8) | (((value >> 7) as u16) << 9);
+                self.halted = (value & 0x40) != 0;
+            }
+            _ => {}
+        }
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        if self.halted {
+            return;
+        }
+        self.cycles += cycles;
+        while self.cycles >= RTC_CYCLES_PER_SECOND {
+            self.cycles -= RTC_CYCLES_PER_SECOND;
+            self.advance_second();
+        }
+    }
+
+    fn advance_second(&mut self) {
+        self.seconds = (self.seconds + 1) & 0x3F;
+        if self.seconds != 60 {
+            return;
+        }
+        self.seconds = 0;
+        self.minutes = (self.minutes + 1) & 0x3F;
+        if self.minutes != 60 {
+            return;
+        }
+        self.minutes = 0;
+        self.hours = (self.hours + 1) & 0x1F;
+        if self.hours != 24 {
+            return;
+        }
+        self.hours = 0;
+        let days = (self.days & 0x1FF) + 1;
+        if days > 0x1FF {
+            // Overflow sets the carry bit until the game clears it
+            self.days = 0x200;
+        } else {
+            self.days = (self.days & 0x200) | days;
+        }
+    }
+}
+
+pub struct Cartridge {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    pub title: String,
+    pub cartridge_type: u8,
+    pub header_checksum: u8,
+    pub global_checksum: u16,
+    mbc: Mbc,
+    has_battery: bool,
+    ram_enabled: bool,
+    rom_bank: u16,
+    ram_bank: u8,
+    banking_mode: bool, // MBC1: false selects simple ROM banking, true the advanced mode
+    rtc: Option<Rtc>,
+}
+
+impl Cartridge {
+    pub fn new(rom: Vec<u8>) -> Result<Self, CartridgeError> {
+        if rom.len() < HEADER_SIZE {
+            return Err(CartridgeError::TooSmall(rom.len()));
+        }
+
+        let cartridge_type = rom[CARTRIDGE_TYPE_ADDRESS];
+        let (mbc, has_ram, has_battery, has_rtc) = match cartridge_type {
+            0x00 => (Mbc::None, false, false, false),
+            0x01 => (Mbc::Mbc1, false, false, false),
+            0x02 => (Mbc::Mbc1, true, false, false),
+            0x03 => (Mbc::Mbc1, true, true, false),
+            0x05 => (Mbc::Mbc2, false, false, false),
+            0x06 => (Mbc::Mbc2, false, true, false),
+            0x08 => (Mbc::None, true, false, false),
+            0x09 => (Mbc::None, true, true, false),
+            0x0F => (Mbc::Mbc3, false, true, true),
+            0x10 => (Mbc::Mbc3, true, true, true),
+            0x11 => (Mbc::Mbc3, false, false, false),
+            0x12 => (Mbc::Mbc3, true, false, false),
+            0x13 => (Mbc::Mbc3, true, true, false),
+            0x19 | 0x1C => (Mbc::Mbc5, false, false, false),
+            0x1A | 0x1D => (Mbc::Mbc5, true, false, false),
+            0x1B | 0x1E => (Mbc::Mbc5, true, true, false),
+            _ => return Err(CartridgeError::UnsupportedType(cartridge_type)),
+        };
+
+        let rom_size_code = rom[ROM_SIZE_ADDRESS];
+        if rom_size_code > 0x08 {
+            return Err(CartridgeError::InvalidRomSize(rom_size_code));
+        }
+
+        let ram_size_code = rom[RAM_SIZE_ADDRESS];
+        let ram_size = match ram_size_code {
+            0x00 => 0,
+            0x01 => 0x800,
+            0x02 => RAM_BANK_SIZE,
+            0x03 => RAM_BANK_SIZE * 4,
+            0x04 => RAM_BANK_SIZE * 16,
+            0x05 => RAM_BANK_SIZE * 8,
+            _ => return Err(CartridgeError::InvalidRamSize(ram_size_code)),
+        };
+        let ram_size = match mbc {
+            Mbc::Mbc2 => MBC2_RAM_SIZE, // Built into the mapper
+            _ if has_ram => ram_size,
+            _ => 0,
+        };
+
+        let title = rom[TITLE_ADDRESS..TITLE_END_ADDRESS]
+            .iter()
+            .take_while(|&&b| b != 0)
+            .map(|&b| b as char)
+            .collect::<String>()
+            .trim_end()
+            .to_string();
+
+        Ok(Cartridge {
+            title,
+            cartridge_type,
+            header_checksum: rom[HEADER_CHECKSUM_ADDRESS],
+            global_checksum: ((rom[GLOBAL_CHECKSUM_ADDRESS] as u16) << 8) | rom[GLOBAL_CHECKSUM_ADDRESS + 1] as u16,
+            rom,
+            ram: vec![0; ram_size],
+            mbc,
+            has_battery,
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            banking_mode: false,
+            rtc: if has_rtc { Some(Rtc::new()) } else { None },
+        })
+    }
+
+    pub fn get_mbc(&self) -> Mbc {
+        self.mbc
+    }
+
+    pub fn has_battery(&self) -> bool {
+        self.has_battery
+    }
+
+    pub fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    // External RAM, for writing out battery backed saves
+    pub fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    pub fn load_ram(&mut self, data: &[u8]) {
+        let length = data.len().min(self.ram.len());
+        self.ram[..length].copy_from_slice(&data[..length]);
+    }
+
+    // Puts the mapper back in its power on state. RAM and the clock keep their contents
+    pub fn reset(&mut self) {
+        self.ram_enabled = false;
+        self.rom_bank = 1;
+        self.ram_bank = 0;
+        self.banking_mode = false;
+    }
+
+    fn rom_bank_count(&self) -> usize {
+        self.rom.len().div_ceil(ROM_BANK_SIZE).max(1)
+    }
+
+    fn ram_bank_count(&self) -> usize {
+        self.ram.len().div_ceil(RAM_BANK_SIZE).max(1)
+    }
+
+    fn read_rom_bank(&self, bank: usize, address: u16) -> u8 {
+        let bank = bank % self.rom_bank_count();
+        let offset = bank * ROM_BANK_SIZE + (address as usize & (ROM_BANK_SIZE - 1));
+        self.rom.get(offset).copied().unwrap_or(0xFF)
+    }
+
+    fn ram_offset(&self, address: u16) -> usize {
+        let bank = self.ram_bank as usize % self.ram_bank_count();
+        bank * RAM_BANK_SIZE + (address as usize & (RAM_BANK_SIZE - 1))
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        match address {
+            0x0000..=0x3FFF => {
+                // MBC1 advanced mode also banks the lower area with the upper bank bits
+                let bank = if self.mbc == Mbc::Mbc1 && self.banking_mode {
+                    ((self.ram_bank & 0x03) as usize) << 5
+                } else {
+                    0
+                };
+                self.read_rom_bank(bank, address)
+            }
+            0x4000..=0x7FFF => {
+                let bank = match self.mbc {
+                    Mbc::None => 1,
+                    Mbc::Mbc1 => (((self.ram_bank & 0x03) as usize) << 5) | self.rom_bank as usize,
+                    _ => self.rom_bank as usize,
+                };
+                self.read_rom_bank(bank, address)
+            }
+            0xA000..=0xBFFF => self.read_ram(address),
+            _ => panic!("Invalid cartridge address: {}", address),
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        if !self.ram_enabled && self.mbc != Mbc::None {
+            return 0xFF;
+        }
+        match self.mbc {
+            Mbc::Mbc2 => 0xF0 | self.ram[address as usize & (MBC2_RAM_SIZE - 1)],
+            Mbc::Mbc3 if self.ram_bank >= 0x08 => match &self.rtc {
+                Some(rtc) if self.ram_bank <= 0x0C => rtc.latched[(self.ram_bank - 0x08) as usize],
+                _ => 0xFF,
+            },
+            Mbc::Mbc1 if !self.banking_mode => self.ram.get(address as usize & (RAM_BANK_SIZE - 1)).copied().unwrap_or(0xFF),
+            _ => self.ram.get(self.ram_offset(address)).copied().unwrap_or(0xFF),
+        }
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x7FFF => self.write_control(address, value),
+            0xA000..=0xBFFF => self.write_ram(address, value),
+            _ => panic!("Invalid cartridge address: {}", address),
+        }
+    }
+
+    fn write_control(&mut self, address: u16, value: u8) {
+        match self.mbc {
+            Mbc::None => {}
+            Mbc::Mbc1 => match address {
+                0x0000..=0x1FFF => self.ram_enabled = (value & 0x0F) == 0x0A,
+                0x2000..=0x3FFF => {
+                    let bank = (value & 0x1F) as u16;
+                    self.rom_bank = if bank == 0 { 1 } else { bank };
+                }
+                0x4000..=0x5FFF => self.ram_bank = value & 0x03,
+                _ => self.banking_mode = (value & 0x01) != 0,
+            },
+            Mbc::Mbc2 => {
+                if address <= 0x3FFF {
+                    // Address bit 8 selects between RAM enable and ROM bank
+                    if (address & 0x0100) == 0 {
+                        self.ram_enabled = (value & 0x0F) == 0x0A;
+                    } else {
+                        let bank = (value & 0x0F) as u16;
+                        self.rom_bank = if bank == 0 { 1 } else { bank };
+                    }
+                }
+            }
+            Mbc::Mbc3 => match address {
+                0x0000..=0x1FFF => self.ram_enabled = (value & 0x0F) == 0x0A,
+                0x2000..=0x3FFF => {
+                    let bank = (value & 0x7F) as u16;
+                    self.rom_bank = if bank == 0 { 1 } else { bank };
+                }
+                0x4000..=0x5FFF => self.ram_bank = value,
+                _ => {
+                    if let Some(rtc) = &mut self.rtc {
+                        rtc.latch(value);
+                    }
+                }
+            },
+            Mbc::Mbc5 => match address {
+                0x0000..=0x1FFF => self.ram_enabled = (value & 0x0F) == 0x0A,
+                0x2000..=0x2FFF => self.rom_bank = (self.rom_bank & 0x100) | value as u16,
+                0x3000..=0x3FFF => self.rom_bank = (self.rom_bank & 0xFF) | (((value & 0x01) as u16) << 8),
+                0x4000..=0x5FFF => self.ram_bank = value & 0x0F,
+                _ => {}
+            },
+        }
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) {
+        if !self.ram_enabled && self.mbc != Mbc::None {
+            return;
+        }
+        match self.mbc {
+            Mbc::Mbc2 => self.ram[address as usize & (MBC2_RAM_SIZE - 1)] = value & 0x0F,
+            Mbc::Mbc3 if self.ram_bank >= 0x08 => {
+                if let Some(rtc) = &mut self.rtc {
+                    rtc.write(self.ram_bank, value);
+                }
+            }
+            _ => {
+                let offset = if self.mbc == Mbc::Mbc1 && !self.banking_mode {
+                    address as usize & (RAM_BANK_SIZE - 1)
+                } else {
+                    self.ram_offset(address)
+                };
+                if let Some(byte) = self.ram.get_mut(offset) {
+                    *byte = value;
+                }
+            }
+        }
+    }
+
+    // Advance the real time clock, if the cartridge has one
+    pub fn tick(&mut self, cycles: u32) {
+        if let Some(rtc) = &mut self.rtc {
+            rtc.tick(cycles);
+        }
+    }
+}
+
+impl Rtc {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.seconds);
+        writer.write_u8(self.minutes);
+        writer.write_u8(self.hours);
+        writer.write_u16(self.days);
+        writer.write_bool(self.halted);
+        writer.write_bytes(&self.latched);
+        writer.write_u8(self.latch_value);
+        writer.write_u32(self.cycles);
+    }
+
+    // Masked the same way as writes from the game, so counting up can't overflow
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.seconds = reader.read_u8()? & 0x3F;
+        self.minutes = reader.read_u8()? & 0x3F;
+        self.hours = reader.read_u8()? & 0x1F;
+        self.days = reader.read_u16()? & 0x3FF;
+        self.halted = reader.read_bool()?;
+        reader.read_into(&mut self.latched)?;
+        self.latch_value = reader.read_u8()?;
+        self.cycles = reader.read_u32()?.min(RTC_CYCLES_PER_SECOND);
+        Ok(())
+    }
+}
+
+impl Cartridge {
+    // Mapper registers, cartridge RAM and the clock. The ROM itself is identified by checksum
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.ram_enabled);
+        writer.write_u16(self.rom_bank);
+        writer.write_u8(self.ram_bank);
+        writer.write_bool(self.banking_mode);
+        writer.write_vec(&self.ram);
+        writer.write_bool(self.rtc.is_some());
+        if let Some(rtc) = &self.rtc {
+            rtc.save_state(writer);
+        }
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.ram_enabled = reader.read_bool()?;
+        self.rom_bank = reader.read_u16()?;
+        self.ram_bank = reader.read_u8()?;
+        self.banking_mode = reader.read_bool()?;
+        let ram = reader.read_vec()?;
+        if ram.len() != self.ram.len() {
+            return Err(StateError::InvalidValue("cartridge RAM size"));
+        }
+        self.ram.copy_from_slice(ram);
+        let has_rtc = reader.read_bool()?;
+        match &mut self.rtc {
+            Some(rtc) if has_rtc => rtc.load_state(reader)?,
+            None if !has_rtc => {}
+            _ => return Err(StateError::InvalidValue("real time clock")),
+        }
+        Ok(())
+    }
+}
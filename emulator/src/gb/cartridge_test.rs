@@ -0,0 +1,140 @@
+#[cfg(test)]
+mod tests {
+    use crate::gb::cartridge::{Cartridge, CartridgeError, Mbc};
+    use crate::gb::ram::RAM;
+
+    // Helper function to build a ROM whose banks each start with their bank number
+    fn create_rom(cartridge_type: u8, rom_size: u8, ram_size: u8) -> Vec<u8> {
+        let banks = 2usize << rom_size;
+        let mut rom = vec![0; banks * 0x4000];
+        for bank in 0..banks {
+            rom[bank * 0x4000] = bank as u8;
+        }
+        rom[0x134..0x139].copy_from_slice(b"TESTS");
+        rom[0x147] = cartridge_type;
+        rom[0x148] = rom_size;
+        rom[0x149] = ram_size;
+        rom
+    }
+
+    #[test]
+    fn test_header() {
+        let cartridge = Cartridge::new(create_rom(0x03, 0x02, 0x03)).unwrap();
+        assert_eq!(cartridge.title, "TESTS");
+        assert_eq!(cartridge.get_mbc(), Mbc::Mbc1);
+        assert!(cartridge.has_battery());
+        assert_eq!(cartridge.ram().len(), 0x8000);
+    }
+
+    #[test]
+    fn test_invalid_header() {
+        assert_eq!(Cartridge::new(vec![0; 0x100]).err(), Some(CartridgeError::TooSmall(0x100)));
+        assert_eq!(Cartridge::new(create_rom(0xFC, 0x00, 0x00)).err(), Some(CartridgeError::UnsupportedType(0xFC)));
+    }
+
+    #[test]
+    fn test_mbc1_rom_banking() {
+        let mut cartridge = Cartridge::new(create_rom(0x01, 0x06, 0x00)).unwrap();
+        assert_eq!(cartridge.read(0x4000), 1, "Bank 1 should be mapped by default");
+
+        cartridge.write(0x2000, 0x05);
+        assert_eq!(cartridge.read(0x4000), 5);
+
+        cartridge.write(0x2000, 0x00);
+        assert_eq!(cartridge.read(0x4000), 1, "Bank 0 should map to bank 1");
+
+        // Upper bits come from the 0x4000-0x5FFF register
+        cartridge.write(0x2000, 0x02);
+        cartridge.write(0x4000, 0x01);
+        assert_eq!(cartridge.read(0x4000), 0x22);
+        assert_eq!(cartridge.read(0x0000), 0, "Simple banking mode keeps bank 0 fixed");
+
+        cartridge.write(0x6000, 0x01);
+        assert_eq!(cartridge.read(0x0000), 0x20, "Advanced banking mode also switches the lower area");
+    }
+
+    #[test]
+    fn test_ram_enable_and_banking() {
+        let mut cartridge = Cartridge::new(create_rom(0x1B, 0x01, 0x03)).unwrap();
+        cartridge.write(0xA000, 0x42);
+        assert_eq!(cartridge.read(0xA000), 0xFF, "RAM should be disabled by default");
+
+        cartridge.write(0x0000, 0x0A);
+        cartridge.write(0xA000, 0x42);
+        cartridge.write(0x4000, 0x02);
+        cartridge.write(0xA000, 0x24);
+        assert_eq!(cartridge.read(0xA000), 0x24);
+
+        cartridge.write(0x4000, 0x00);
+        assert_eq!(cartridge.read(0xA000), 0x42);
+        assert_eq!(cartridge.ram()[0x4000], 0x24);
+    }
+
+    #[test]
+    fn test_mbc5_nine_bit_rom_bank() {
+        let mut rom = create_rom(0x19, 0x08, 0x00);
+        rom[0x100 * 0x4000 + 1] = 0x99;
+        let mut cartridge = Cartridge::new(rom).unwrap();
+        cartridge.write(0x2000, 0x00);
+        cartridge.write(0x3000, 0x01);
+        assert_eq!(cartridge.read(0x4001), 0x99);
+
+        cartridge.write(0x3000, 0x00);
+        assert_eq!(cartridge.read(0x4000), 0, "MBC5 can map bank 0 into the switchable area");
+    }
+
+    #[test]
+    fn test_mbc2_builtin_ram() {
+        let mut cartridge = Cartridge::new(create_rom(0x06, 0x02, 0x00)).unwrap();
+        cartridge.write(0x0000, 0x0A);
+        cartridge.write(0xA000, 0xFF);
+        assert_eq!(cartridge.read(0xA000), 0xFF, "Upper nibble reads as 1");
+        assert_eq!(cartridge.ram()[0], 0x0F);
+        assert_eq!(cartridge.read(0xA200), 0xFF, "RAM is echoed every 512 bytes");
+
+        cartridge.write(0x0100, 0x03);
+        assert_eq!(cartridge.read(0x4000), 3);
+    }
+
+    #[test]
+    fn test_mbc3_rtc_latch() {
+        let mut cartridge = Cartridge::new(create_rom(0x10, 0x01, 0x02)).unwrap();
+        cartridge.write(0x0000, 0x0A);
+        cartridge.tick(4_194_304 * 61);
+
+        cartridge.write(0x4000, 0x08);
+        assert_eq!(cartridge.read(0xA000), 0, "Registers only change when latched");
+
+        cartridge.write(0x6000, 0x00);
+        cartridge.write(0x6000, 0x01);
+        assert_eq!(cartridge.read(0xA000), 1);
+        cartridge.write(0x4000, 0x09);
+        assert_eq!(cartridge.read(0xA000), 1);
+
+        // Halting the clock stops it from counting
+        cartridge.write(0x4000, 0x0C);
+        cartridge.write(0xA000, 0x40);
+        cartridge.tick(4_194_304 * 5);
+        cartridge.write(0x6000, 0x00);
+        cartridge.write(0x6000, 0x01);
+        cartridge.write(0x4000, 0x08);
+        assert_eq!(cartridge.read(0xA000), 1);
+    }
+
+    #[test]
+    fn test_load_ram() {
+        let mut cartridge = Cartridge::new(create_rom(0x03, 0x01, 0x02)).unwrap();
+        cartridge.load_ram(&[1, 2, 3]);
+        cartridge.write(0x0000, 0x0A);
+        assert_eq!(cartridge.read(0xA002), 3);
+    }
+
+    #[test]
+    fn test_cartridge_mapped_on_bus() {
+        let mut ram = RAM::with_cartridge(Cartridge::new(create_rom(0x01, 0x02, 0x00)).unwrap());
+        ram.write(0x2000, 0x03);
+        assert_eq!(ram.read(0x4000), 3);
+        ram.write(0x4000, 0x55); // Mapper command, ROM is not written
+        assert_eq!(ram.read(0x4001), 0);
+    }
+}
@@ -1,13 +1,22 @@
+use crate::gb::alu;
 use crate::gb::register::Registers;
 use crate::gb::register::Flags;
 use crate::gb::register::FlagMasks;
-use crate::gb::ram::RAM;
+use crate::gb::ram::{Access, RAM};
+use crate::gb::opcodes;
+use crate::gb::state::{StateError, StateReader, StateWriter};
+use crate::gb::trace::{self, TraceFormat, Tracer};
 
+use std::fmt;
+use std::sync::OnceLock;
+
+#[derive(Debug, PartialEq, Clone)]
 pub struct Code {
   pub opcode: u8,
   pub mnemonic: String,
   pub operands: Vec<String>,
   pub cycles: u8,
+  pub cycles_taken: u8,
   pub size: u8,
 }
 
@@ -28,6 +37,7 @@ enum InterruptHandlers {
 }
 
 
+#[derive(Copy, Clone)]
 pub enum Instruction {
   ADD(ArithmeticTarget),
   ADC(ArithmeticTarget),
@@ -73,6 +83,7 @@ pub enum Instruction {
   // Enable/Disable Interrupts
   EI, 
   DI, 
+  HALT,
 
   // Stack Operations
   PUSH(ArithmeticTarget, ArithmeticTarget),
@@ -109,34 +120,65 @@ pub enum Instruction {
   SCF,
   CCF,
   CPL,
-  
+
+  // CB prefixed rotates, shifts and bit operations, holding the second opcode byte
+  PREFIX_CB(u8),
+
+  STOP,
+  // Opcodes with no instruction behind them, which hang the CPU
+  ILLEGAL(u8),
 
 }
 
+#[derive(Copy, Clone)]
 pub enum ArithmeticTarget {
   A, B, C, D, E, H, L, F, SP
 }
 
 const INTERRUPT_ENABLE_ADDRESS: u16 = 0xFFFF;
+const DIV_ADDRESS: u16 = 0xFF04;
 const INTERRUPT_FLAG_ADDRESS: u16 = 0xFF0F;
 
+const INTERRUPT_CYCLES: u8 = 20; // Servicing an interrupt
+const HALT_CYCLES: u8 = 4;       // One M-cycle spent halted
+
+// Where the CPU hung, after fetching an opcode that has no instruction
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct Lockup {
+  pub opcode: u8,
+  pub address: u16,
+}
+
+impl fmt::Display for Lockup {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "illegal opcode {:#04X} at {:#06X}", self.opcode, self.address)
+  }
+}
+
 pub struct CPU<'a> {
   pub registers: Registers,
   pub flags: Flags,
   pub ram: &'a mut RAM,
   pub interrupt_master_enable: bool,
-  previous_ime: bool, // TODO: not sure if this is needed
+  ime_pending: bool, // EI only takes effect after the instruction that follows it
   pub halted: bool,
+  halt_bug: bool, // HALT with IME off and an interrupt pending: the next byte is read twice
   pub stopped: bool,
+  pub lockup: Option<Lockup>, // Set for good by an illegal opcode, only a reset clears it
   pub clock_cycles: u64,
+  branch_taken: bool, // Set by conditional jumps, calls and returns whose condition held
+  pub m_cycle_mode: bool, // Tick the bus on every memory access instead of after each instruction
+  bus_cycles: u8, // Cycles already ticked by memory accesses during the current step
+  tracer: Option<Tracer>, // Logs every instruction before it runs
+  pub bus_log: Option<Vec<(u16, u8, Access)>>, // Every memory access in order, when set
 }
 
 macro_rules! pop_16bit {
     ($self:ident, $sp:expr, $setter:ident) => {{
-        let lower_half = $self.ram.read(*$sp);
-        *$sp += 1;
-        let upper_half = $self.ram.read(*$sp);
-        *$sp += 1;
+        let lower_half = $self.read(*$sp);
+        *$sp = $sp.wrapping_add(1);
+        let upper_half = $self.read(*$sp);
+        *$sp = $sp.wrapping_add(1);
         $self.registers.$setter(((upper_half as u16) << 8) | lower_half as u16);
     }};
 }
@@ -144,10 +186,10 @@ macro_rules! pop_16bit {
 macro_rules! push_16bit {
     ($self:ident, $sp:expr, $getter:ident) => {{
         let value = $self.registers.$getter();
-        *$sp -= 1;  
-        $self.ram.write(*$sp, ((value >> 8) & 0xFF) as u8);
-        *$sp -= 1;
-        $self.ram.write(*$sp, (value & 0xFF) as u8);
+        *$sp = $sp.wrapping_sub(1);
+        $self.write(*$sp, ((value >> 8) & 0xFF) as u8);
+        *$sp = $sp.wrapping_sub(1);
+        $self.write(*$sp, (value & 0xFF) as u8);
     }};
 }
 
@@ -169,23 +211,6 @@ macro_rules! arithmetic_op {
     };
 }
 
-macro_rules! carry_op {
-    ($self:ident, $target:ident, $op:ident) => {
-        let carry = $self.registers.get_f() as u8 >> (FlagMasks::CARRY as u8).trailing_zeros() as u8;
-        match $target {
-            ArithmeticTarget::A => $self.$op($self.registers.get_a() + carry),
-            ArithmeticTarget::B => $self.$op($self.registers.get_b() + carry),
-            ArithmeticTarget::C => $self.$op($self.registers.get_c() + carry),
-            ArithmeticTarget::D => $self.$op($self.registers.get_d() + carry),
-            ArithmeticTarget::E => $self.$op($self.registers.get_e() + carry),
-            ArithmeticTarget::H => $self.$op($self.registers.get_h() + carry),
-            ArithmeticTarget::L => $self.$op($self.registers.get_l() + carry),
-            ArithmeticTarget::F => panic!("Invalid carry target"),
-            ArithmeticTarget::SP => panic!("Invalid carry target"),
-        }
-    };
-}
-
 impl<'a> CPU<'a> {
   pub fn new(ram: &'a mut RAM) -> Self {
     CPU {
@@ -193,29 +218,125 @@ impl<'a> CPU<'a> {
       flags: Flags::new(),
       ram: ram,
       interrupt_master_enable: false,
-      previous_ime: false,
+      ime_pending: false,
       halted: false,
+      halt_bug: false,
       stopped: false,
+      lockup: None,
       clock_cycles: 0,
+      branch_taken: false,
+      m_cycle_mode: false,
+      bus_cycles: 0,
+      tracer: None,
+      bus_log: None,
+    }
+  }
+
+  // Starts logging instructions. The Doctor format also pins LY to what its logs expect
+  pub fn set_tracer(&mut self, tracer: Tracer) {
+    if tracer.format() == TraceFormat::Doctor {
+      self.ram.ly_override = Some(trace::DOCTOR_LY);
     }
+    self.tracer = Some(tracer);
+  }
+
+  // Stops logging and hands the tracer back to be finished
+  pub fn take_tracer(&mut self) -> Option<Tracer> {
+    self.ram.ly_override = None;
+    self.tracer.take()
+  }
+
+  // Back to the power on state. The cycle count and M-cycle mode carry on
+  pub fn reset(&mut self) {
+    self.registers = Registers::new();
+    self.flags = Flags::new();
+    self.interrupt_master_enable = false;
+    self.ime_pending = false;
+    self.halted = false;
+    self.halt_bug = false;
+    self.stopped = false;
+    self.lockup = None;
+    self.branch_taken = false;
+    self.bus_cycles = 0;
+  }
+
+  /*
+  Every memory access takes one M-cycle (4 clock cycles). In M-cycle mode the peripherals
+  are ticked as each access happens, so the timer, GPU, DMA and APU see reads and writes
+  at the right point inside an instruction. Internal cycles that don't touch the bus are
+  ticked at the end of the step.
+   */
+  fn read(&mut self, address: u16) -> u8 {
+    if self.m_cycle_mode {
+      self.tick_m_cycle();
+    }
+    let value = self.ram.read(address);
+    if let Some(log) = &mut self.bus_log {
+      log.push((address, value, Access::Read));
+    }
+    value
+  }
+
+  fn write(&mut self, address: u16, value: u8) {
+    if self.m_cycle_mode {
+      self.tick_m_cycle();
+    }
+    if let Some(log) = &mut self.bus_log {
+      log.push((address, value, Access::Write));
+    }
+    self.ram.write(address, value);
+  }
+
+  fn tick_m_cycle(&mut self) {
+    self.ram.tick(4);
+    self.bus_cycles += 4;
+  }
+
+  // STOP sleeps until a button is pressed, and resets DIV like any write to it
+  fn stop(&mut self) {
+    self.stopped = true;
+    self.ram.write(DIV_ADDRESS, 0);
+  }
+
+  // The CPU stops fetching for good. PC is left on the opcode so it shows where it happened
+  fn lock_up(&mut self, opcode: u8) {
+    let address = self.registers.get_pc().wrapping_sub(1);
+    self.registers.set_pc(address);
+    self.lockup = Some(Lockup { opcode, address });
   }
 
   fn ei(&mut self) {
-    self.interrupt_master_enable = true;
+    self.ime_pending = true;
   }
 
   fn di(&mut self) {
     self.interrupt_master_enable = false;
+    self.ime_pending = false;
+  }
+
+  fn halt(&mut self) {
+    if self.interrupt_master_enable || self.pending_interrupts() == 0 {
+      self.halted = true;
+    } else if self.ime_pending {
+      // Right after EI the interrupt is serviced at once, and returns to the HALT
+      let address = self.registers.get_pc().wrapping_sub(1);
+      self.registers.set_pc(address);
+    } else {
+      // The CPU doesn't halt, and fails to advance PC past the next opcode
+      self.halt_bug = true;
+    }
   }
 
   fn set_flags(&mut self, zero: bool, subtract: bool, half_carry: bool, carry: bool) {
-    let flags = Flags {
+    self.set_alu_flags(Flags {
       zero,
       subtract,
       half_carry,
       carry,
-    };
-    eprintln!("flags: {:02x}, zero: {}, subtract: {}, half_carry: {}, carry: {}", flags.to_u8(), zero, subtract, half_carry, carry);
+    });
+  }
+
+  fn set_alu_flags(&mut self, flags: Flags) {
     self.registers.set_f(flags.to_u8());
   }
 
@@ -254,7 +375,7 @@ impl<'a> CPU<'a> {
 
   fn ld_reg_mem(&mut self, target: ArithmeticTarget) {
     let address = self.registers.get_hl();
-    let value = self.ram.read(address);
+    let value = self.read(address);
     match target {
       ArithmeticTarget::A => self.registers.set_a(value),
       ArithmeticTarget::B => self.registers.set_b(value),
@@ -269,7 +390,7 @@ impl<'a> CPU<'a> {
 
   fn ld_mem_imm(&mut self, value: u8) {
     let address = self.registers.get_hl();
-    self.ram.write(address, value);
+    self.write(address, value);
   }
 
   fn ld_reg_imm(&mut self, target: ArithmeticTarget, value: u8) {
@@ -288,34 +409,26 @@ impl<'a> CPU<'a> {
   fn ld_mem_inc(&mut self, increment: bool, load: bool) {
     let address = self.registers.get_hl();
     if increment {
-      self.registers.set_hl(self.registers.get_hl() + 1);
+      self.registers.set_hl(self.registers.get_hl().wrapping_add(1));
     } else {
-      self.registers.set_hl(self.registers.get_hl() - 1);
+      self.registers.set_hl(self.registers.get_hl().wrapping_sub(1));
     }
     if load {
-      let value = self.ram.read(address);
+      let value = self.read(address);
       self.registers.set_a(value);
     } else {
-      self.ram.write(address, self.registers.get_a());
+      self.write(address, self.registers.get_a());
     }
     
   }
 
   fn mod_mem(&mut self, increment: bool) {
     let address = self.registers.get_hl();
-    let value = self.ram.read(address);
-    let half_carry;
-    let zero;
-    if increment {
-      self.ram.write(address, value + 1);
-      half_carry = (value & 0xF) + 1 > 0xF;
-      zero = (value + 1) & 0xFF == 0;
-    } else {
-      self.ram.write(address, value - 1);
-      half_carry = (value & 0xF) == 0;
-      zero = (value - 1) & 0xFF == 0;
-    }
-    self.set_flags(half_carry, false, zero, !increment);
+    let value = self.read(address);
+    let carry = self.get_flags().carry;
+    let (result, flags) = if increment { alu::inc(value, carry) } else { alu::dec(value, carry) };
+    self.write(address, result);
+    self.set_alu_flags(flags);
   }
 
   fn ld_bcde(&mut self, target1: ArithmeticTarget, target2: ArithmeticTarget, load: bool) {
@@ -326,36 +439,39 @@ impl<'a> CPU<'a> {
     };
     
     if load {
-      self.registers.set_a(self.ram.read(address));
+      let value = self.read(address);
+      self.registers.set_a(value);
     } else {
-      self.ram.write(address, self.registers.get_a());
+      self.write(address, self.registers.get_a());
     }
   }
 
   fn ld_imm_16(&mut self, address: u16, load: bool) {
     if load {
-      let value = self.ram.read(address);
+      let value = self.read(address);
       self.registers.set_a(value);
     } else {
-      self.ram.write(address, self.registers.get_a());
+      self.write(address, self.registers.get_a());
     }
   }
 
   fn ld_imm_8(&mut self, value: u8, load: bool) {
     let address = 0xFF00 + value as u16;
     if load {
-      self.registers.set_a(self.ram.read(address));
+      let value = self.read(address);
+      self.registers.set_a(value);
     } else {
-      self.ram.write(address, self.registers.get_a());
+      self.write(address, self.registers.get_a());
     }
   }
 
   fn ld_ac(&mut self, load: bool) {
     let address = 0xFF00 + self.registers.get_c() as u16;
     if load {
-      self.registers.set_a(self.ram.read(address));
+      let value = self.read(address);
+      self.registers.set_a(value);
     } else {
-      self.ram.write(address, self.registers.get_a());
+      self.write(address, self.registers.get_a());
     }
   }
 
@@ -371,15 +487,14 @@ impl<'a> CPU<'a> {
 
   fn store_sp(&mut self, address: u16) {
     let sp = self.registers.get_sp();
-    self.ram.write(address, (sp & 0xFF) as u8);
-    self.ram.write(address + 1, (sp >> 8) as u8);
+    self.write(address, (sp & 0xFF) as u8);
+    self.write(address.wrapping_add(1), (sp >> 8) as u8);
   }
 
   fn inc_sp(&mut self, value: i8) {
-    let (result, carry) = self.registers.get_sp().overflowing_add((value as i16) as u16);
+    let (result, flags) = alu::add_sp(self.registers.get_sp(), value);
     self.registers.set_sp(result);
-    let half_carry = (result & 0xFF) + (((value as i16) as u16) & 0xFF) > 0xFF;
-    self.set_flags(half_carry, carry, false, false);
+    self.set_alu_flags(flags);
   }
 
   fn ld_sp_hl(&mut self) {
@@ -387,10 +502,9 @@ impl<'a> CPU<'a> {
   }
 
   fn ld_hl_sp(&mut self, value: i8) {
-    let (result, carry) = self.registers.get_sp().overflowing_add((value as i16) as u16);
+    let (result, flags) = alu::add_sp(self.registers.get_sp(), value);
     self.registers.set_hl(result);
-    let half_carry = (result & 0xFF) + (((value as i16) as u16) & 0xFF) > 0xFF;
-    self.set_flags(half_carry, carry, false, false);
+    self.set_alu_flags(flags);
   }
 
   fn ret(&mut self, carry: bool, zero: bool, interrupt: bool) {
@@ -399,18 +513,18 @@ impl<'a> CPU<'a> {
     let flags = self.registers.get_f();
     let is_carry_set = flags & (FlagMasks::CARRY as u8) != 0;
     let is_zero_set = flags & (FlagMasks::ZERO as u8) != 0;
-    let is_interrupt_enabled = self.interrupt_master_enable;
 
-    let should_jump = is_carry_set && carry || is_zero_set && zero || interrupt && is_interrupt_enabled  || (!carry && !zero && !interrupt); 
+    let should_jump = is_carry_set && carry || is_zero_set && zero || (!carry && !zero);
+    self.branch_taken = should_jump;
     if should_jump {
       let mut sp = self.registers.get_sp();
       pop_16bit!(self, &mut sp, set_pc);
       self.registers.set_sp(sp);
     }
+    // RETI enables interrupts straight away, without the delay EI has
     if interrupt {
-      let previous_ime = self.previous_ime;
-      self.previous_ime = self.interrupt_master_enable;
-      self.interrupt_master_enable = previous_ime;
+      self.interrupt_master_enable = true;
+      self.ime_pending = false;
     }
   }
 
@@ -420,6 +534,7 @@ impl<'a> CPU<'a> {
     let is_carry_set = flags & (FlagMasks::CARRY as u8) != 0;
     let is_zero_set = flags & (FlagMasks::ZERO as u8) != 0;
     let should_jump = !is_carry_set && carry || !is_zero_set && zero;
+    self.branch_taken = should_jump;
     if should_jump {
       let mut sp = self.registers.get_sp();
       pop_16bit!(self, &mut sp, set_pc);
@@ -427,12 +542,13 @@ impl<'a> CPU<'a> {
     }
   }
      
+  // value is the vector address itself, one of 0x00, 0x08, ..., 0x38
   fn rst(&mut self, value: u8) {
-    assert!(0x00 <= value && value <= 0x7);
+    assert!(value & !0x38 == 0);
     let mut sp = self.registers.get_sp();
     push_16bit!(self, &mut sp, get_pc);
     self.registers.set_sp(sp);
-    self.registers.set_pc(value as u16 * 8);
+    self.registers.set_pc(value as u16);
   }
 
   fn call(&mut self, address: u16, carry: bool, zero: bool, negative: bool) {
@@ -443,10 +559,10 @@ impl<'a> CPU<'a> {
     is_zero_set = if negative { !is_zero_set } else { is_zero_set };
     
     let should_jump = carry && is_carry_set || zero && is_zero_set || !carry && !zero;
+    self.branch_taken = should_jump;
     if should_jump {
+      // PC already points past the call, which is the return address
       let mut sp = self.registers.get_sp();
-      let pc = self.registers.get_pc();
-      self.registers.set_pc(pc + 3); // 3 bytes = 1 byte for instruction, 2 bytes for address
       push_16bit!(self, &mut sp, get_pc);
       self.registers.set_sp(sp);
       self.registers.set_pc(address);
@@ -461,10 +577,11 @@ impl<'a> CPU<'a> {
     is_zero_set = if negative { !is_zero_set } else { is_zero_set };
 
     let should_jump = carry && is_carry_set || zero && is_zero_set || !carry && !zero;
+    self.branch_taken = should_jump;
     if should_jump {
+      // Relative to the address after the JR, which PC already points to
       let pc = self.registers.get_pc();
       let jump_value_i16 = jump_value as i16;
-      /* TODO: Check if this is correct */
       let result = if jump_value_i16 >= 0 {
           pc.wrapping_add(jump_value_i16 as u16)
       } else {
@@ -482,6 +599,7 @@ impl<'a> CPU<'a> {
     is_zero_set = if negative { !is_zero_set } else { is_zero_set };
 
     let should_jump = carry && is_carry_set || zero && is_zero_set || !carry && !zero;
+    self.branch_taken = should_jump;
     if should_jump {
       self.registers.set_pc(jump_value);
     }
@@ -546,121 +664,44 @@ impl<'a> CPU<'a> {
       _ => panic!("Invalid add hl target"),
     };
 
-    let (result, carry) = value.overflowing_add(add_value);
+    let (result, flags) = alu::add_16(value, add_value, self.get_flags().zero);
     self.registers.set_hl(result);
-    let half_carry = (value & 0xFF) + (add_value & 0xFF) > 0xFF;
-    self.set_flags(half_carry, carry, result == 0, false);
+    self.set_alu_flags(flags);
   }
   
   fn inc(&mut self, target: ArithmeticTarget) {
-    let value: u16;
-    match target {
-      ArithmeticTarget::A => {
-        value = self.registers.get_a() as u16;
-        self.registers.set_a(self.registers.get_a() + 1);
-      }
-      ArithmeticTarget::B => {
-        value = self.registers.get_b() as u16;
-        self.registers.set_b(self.registers.get_b() + 1);
-      }
-      ArithmeticTarget::C => {
-        value = self.registers.get_c() as u16;
-        self.registers.set_c(self.registers.get_c() + 1);
-      }
-      ArithmeticTarget::D => {
-        value = self.registers.get_d() as u16;
-        self.registers.set_d(self.registers.get_d() + 1);
-      }
-      ArithmeticTarget::E => {
-        value = self.registers.get_e() as u16;
-        self.registers.set_e(self.registers.get_e() + 1);
-      }
-      ArithmeticTarget::H => {
-        value = self.registers.get_h() as u16;
-        self.registers.set_h(self.registers.get_h() + 1);
-      }
-      ArithmeticTarget::L => {
-        value = self.registers.get_l() as u16;
-        self.registers.set_l(self.registers.get_l() + 1);
-      }
-      ArithmeticTarget::SP => {
-        value = self.registers.get_sp() as u16;
-        self.registers.set_sp(self.registers.get_sp() + 1);
-      }
-      _ => panic!("Invalid increment target"),
-    }
-
     if let ArithmeticTarget::SP = target {
-        return;
+      self.registers.set_sp(self.registers.get_sp().wrapping_add(1));
+      return;
     }
-
-    let half_carry = (value & 0xF) + 1 > 0xF;
-    let carry = (self.registers.get_f() & FlagMasks::CARRY as u8) != 0;
-    let zero = (value + 1) & 0xFF == 0;
-    self.set_flags(zero, false, half_carry, carry);
+    let (result, flags) = alu::inc(self.get_register(&target), self.get_flags().carry);
+    self.set_register(&target, result);
+    self.set_alu_flags(flags);
   }
 
   fn dec(&mut self, target: ArithmeticTarget) {
-    let value: u16;
-    match target {
-      ArithmeticTarget::A => {
-        value = self.registers.get_a() as u16;
-        self.registers.set_a(self.registers.get_a() - 1); 
-      }
-      ArithmeticTarget::B => {
-        value = self.registers.get_b() as u16;
-        self.registers.set_b(self.registers.get_b() - 1);
-      }
-      ArithmeticTarget::C => {  
-        value = self.registers.get_c() as u16;
-        self.registers.set_c(self.registers.get_c() - 1);
-      }
-      ArithmeticTarget::D => {
-        value = self.registers.get_d() as u16;
-        self.registers.set_d(self.registers.get_d() - 1);
-      } 
-      ArithmeticTarget::E => {
-        value = self.registers.get_e() as u16;
-        self.registers.set_e(self.registers.get_e() - 1);
-      }
-      ArithmeticTarget::H => {
-        value = self.registers.get_h() as u16;
-        self.registers.set_h(self.registers.get_h() - 1);
-      }
-      ArithmeticTarget::L => {
-        value = self.registers.get_l() as u16;
-        self.registers.set_l(self.registers.get_l() - 1);
-      } 
-      ArithmeticTarget::SP => {
-        value = self.registers.get_sp() as u16;
-        self.registers.set_sp(self.registers.get_sp() - 1);
-      }
-      _ => panic!("Invalid decrement target"),
-    }
-
     if let ArithmeticTarget::SP = target {
-        return;
+      self.registers.set_sp(self.registers.get_sp().wrapping_sub(1));
+      return;
     }
-
-    let half_carry = (value & 0xF) - 1 < 0;
-    let carry = self.registers.get_f() & FlagMasks::CARRY as u8 != 0;
-    let zero = (value - 1) & 0xFF == 0;
-    self.set_flags(zero, true, half_carry, carry);
+    let (result, flags) = alu::dec(self.get_register(&target), self.get_flags().carry);
+    self.set_register(&target, result);
+    self.set_alu_flags(flags);
   }
 
   fn inc_16(&mut self, target1: ArithmeticTarget, target2: ArithmeticTarget) {
     match (target1, target2) {
       (ArithmeticTarget::B, ArithmeticTarget::C) => {
-        self.registers.set_bc(self.registers.get_bc() + 1);
+        self.registers.set_bc(self.registers.get_bc().wrapping_add(1));
       }
       (ArithmeticTarget::D, ArithmeticTarget::E) => {
-        self.registers.set_de(self.registers.get_de() + 1);
+        self.registers.set_de(self.registers.get_de().wrapping_add(1));
       }
       (ArithmeticTarget::H, ArithmeticTarget::L) => {
-        self.registers.set_hl(self.registers.get_hl() + 1);
+        self.registers.set_hl(self.registers.get_hl().wrapping_add(1));
       }
       (ArithmeticTarget::SP, ArithmeticTarget::SP) => {
-        self.registers.set_sp(self.registers.get_sp() + 1);
+        self.registers.set_sp(self.registers.get_sp().wrapping_add(1));
       }
       _ => {
         panic!("Invalid 16-bit increment target");
@@ -669,22 +710,18 @@ impl<'a> CPU<'a> {
   }
 
   fn dec_16(&mut self, target1: ArithmeticTarget, target2: ArithmeticTarget) {
-    let value: u16; 
     match (target1, target2) {
       (ArithmeticTarget::B, ArithmeticTarget::C) => {
-        value = self.registers.get_bc();
-        self.registers.set_bc(self.registers.get_bc() - 1);
+        self.registers.set_bc(self.registers.get_bc().wrapping_sub(1));
       }
       (ArithmeticTarget::D, ArithmeticTarget::E) => {
-        value = self.registers.get_de();
-        self.registers.set_de(self.registers.get_de() - 1);
+        self.registers.set_de(self.registers.get_de().wrapping_sub(1));
       }
       (ArithmeticTarget::H, ArithmeticTarget::L) => {
-        value = self.registers.get_hl();
-        self.registers.set_hl(self.registers.get_hl() - 1);
+        self.registers.set_hl(self.registers.get_hl().wrapping_sub(1));
       }
       (ArithmeticTarget::SP, ArithmeticTarget::SP) => {
-        self.registers.set_sp(self.registers.get_sp() - 1);
+        self.registers.set_sp(self.registers.get_sp().wrapping_sub(1));
       }
       _ => {
         panic!("Invalid 16-bit decrement target");
@@ -693,112 +730,75 @@ impl<'a> CPU<'a> {
   }
 
   fn nop(&mut self) {
-    self.registers.increment_pc();
   }
 
   fn add(&mut self, value: u8) {
-    let (result, carry) = self.registers.get_a().overflowing_add(value);
+    let (result, flags) = alu::add(self.registers.get_a(), value, false);
     self.registers.set_a(result);
-    let half_carry = (self.registers.get_a() & 0xF) < (value & 0xF);
-    let zero = result == 0;
-    self.set_flags(zero, false, half_carry, carry);
+    self.set_alu_flags(flags);
   }
-  
+
+  fn adc(&mut self, value: u8) {
+    let (result, flags) = alu::add(self.registers.get_a(), value, self.get_flags().carry);
+    self.registers.set_a(result);
+    self.set_alu_flags(flags);
+  }
+
   fn sub(&mut self, value: u8) {
-    let (result, carry) = self.registers.get_a().overflowing_sub(value);
+    let (result, flags) = alu::sub(self.registers.get_a(), value, false);
     self.registers.set_a(result);
-    let half_carry = (self.registers.get_a() & 0xF) < (value & 0xF);
-    let zero = result == 0;
-    self.set_flags(zero, true, half_carry, carry);
+    self.set_alu_flags(flags);
+  }
+
+  fn sbc(&mut self, value: u8) {
+    let (result, flags) = alu::sub(self.registers.get_a(), value, self.get_flags().carry);
+    self.registers.set_a(result);
+    self.set_alu_flags(flags);
   }
 
   fn and(&mut self, value: u8) {
-    self.registers.set_a(self.registers.get_a() & value);
-    let zero = self.registers.get_a() == 0;
-    self.set_flags(zero, false, true, false);
+    let (result, flags) = alu::and(self.registers.get_a(), value);
+    self.registers.set_a(result);
+    self.set_alu_flags(flags);
   }
 
   fn or(&mut self, value: u8) {
-    self.registers.set_a(self.registers.get_a() | value);
-    let zero = self.registers.get_a() == 0;
-    self.set_flags(zero, false, false, false);
+    let (result, flags) = alu::or(self.registers.get_a(), value);
+    self.registers.set_a(result);
+    self.set_alu_flags(flags);
   }
 
   fn xor(&mut self, value: u8) {
-    self.registers.set_a(self.registers.get_a() ^ value);
-    let zero = self.registers.get_a() == 0;
-    self.set_flags(zero, false, false, false);
+    let (result, flags) = alu::xor(self.registers.get_a(), value);
+    self.registers.set_a(result);
+    self.set_alu_flags(flags);
   }
-  
+
   fn cp(&mut self, value: u8) {
-    let result = self.registers.get_a() - value;
-    let carry = self.registers.get_a() < value;
-    let half_carry = ((self.registers.get_a() & 0xF) + (value & 0xF)) > 0xF;
-    let zero = result == 0;
-    self.set_flags(zero, true, half_carry, carry);
+    let flags = alu::cp(self.registers.get_a(), value);
+    self.set_alu_flags(flags);
   }
 
+  // RLCA and RLA, the carry flag rotates into bit 0 unless carry is set
   fn rl(&mut self, carry: bool) {
-    let value = self.registers.get_a();
-    let mut result = value << 1;
-    let overflow = value & (1 << 7) != 0;
-    if carry {
-      result |= overflow as u8;
-    } else {
-      let flags = self.registers.get_f();
-      result |= (flags & (FlagMasks::CARRY as u8) >> 4) as u8;
-    }
+    let operation = if carry { 0 } else { 2 };
+    let (result, flags) = alu::rotate_a(operation, self.registers.get_a(), self.get_flags().carry);
     self.registers.set_a(result);
-    self.set_flags(false, false, false, overflow);
+    self.set_alu_flags(flags);
   }
 
+  // RRCA and RRA, the carry flag rotates into bit 7 unless carry is set
   fn rr(&mut self, carry: bool) {
-    let value = self.registers.get_a();
-    let mut result = value >> 1;
-    let overflow = value & 1 != 0;
-    if carry {
-      result |= (overflow as u8) << 7;
-    } else {
-      let flags = self.registers.get_f();
-      result |= (flags & (FlagMasks::CARRY as u8) >> 4) as u8;
-    }
+    let operation = if carry { 1 } else { 3 };
+    let (result, flags) = alu::rotate_a(operation, self.registers.get_a(), self.get_flags().carry);
     self.registers.set_a(result);
-    self.set_flags(false, false, false, overflow);
+    self.set_alu_flags(flags);
   }
 
   fn daa(&mut self) {
-    /*
-    TODO: I don't fully understand this instruction.
-     */
-    let mut a = self.registers.get_a();
-    let mut adjust = 0;
-    let mut carry = false;
-    let flags = self.registers.get_f();
-    
-    // Check if we need to adjust the lower nibble
-    if (a & 0x0F) > 9 || (flags & (FlagMasks::HALF_CARRY as u8)) != 0 {
-      adjust |= 0x06;
-    }
-    
-    // Check if we need to adjust the upper nibble
-    if (a >> 4) > 9 || (flags & (FlagMasks::CARRY as u8)) != 0 {
-      adjust |= 0x60;
-      carry = true;
-    }
-    
-    // If we're in subtract mode, subtract the adjustment
-    if (flags & (FlagMasks::SUBTRACT as u8)) != 0 {
-      a = a.wrapping_sub(adjust);
-    } else {
-      a = a.wrapping_add(adjust);
-    }
-    
-    // Set the flags
-    let half_carry = (a & 0x0F) < (adjust & 0x0F);
-    let zero = a == 0;
-    self.set_flags(zero, (flags & (FlagMasks::SUBTRACT as u8)) != 0, half_carry, carry);
-    
-    self.registers.set_a(a);
+    let (result, flags) = alu::daa(self.registers.get_a(), self.get_flags());
+    self.registers.set_a(result);
+    self.set_alu_flags(flags);
   }
 
   fn scf(&mut self) {
@@ -818,6 +818,86 @@ impl<'a> CPU<'a> {
     self.set_flags(flags & (FlagMasks::ZERO as u8) != 0, true, true, flags & (FlagMasks::CARRY as u8) != 0);
   }
 
+  // Helper function to read one of the 8-bit registers an instruction names
+  fn get_register(&self, target: &ArithmeticTarget) -> u8 {
+    match target {
+      ArithmeticTarget::A => self.registers.get_a(),
+      ArithmeticTarget::B => self.registers.get_b(),
+      ArithmeticTarget::C => self.registers.get_c(),
+      ArithmeticTarget::D => self.registers.get_d(),
+      ArithmeticTarget::E => self.registers.get_e(),
+      ArithmeticTarget::H => self.registers.get_h(),
+      ArithmeticTarget::L => self.registers.get_l(),
+      _ => panic!("Invalid 8-bit register"),
+    }
+  }
+
+  // Helper function to write one of the 8-bit registers an instruction names
+  fn set_register(&mut self, target: &ArithmeticTarget, value: u8) {
+    match target {
+      ArithmeticTarget::A => self.registers.set_a(value),
+      ArithmeticTarget::B => self.registers.set_b(value),
+      ArithmeticTarget::C => self.registers.set_c(value),
+      ArithmeticTarget::D => self.registers.set_d(value),
+      ArithmeticTarget::E => self.registers.set_e(value),
+      ArithmeticTarget::H => self.registers.set_h(value),
+      ArithmeticTarget::L => self.registers.set_l(value),
+      _ => panic!("Invalid 8-bit register"),
+    }
+  }
+
+  // Helper function to read one of B, C, D, E, H, L, (HL), A by its index in the opcode
+  fn get_r8(&mut self, index: u8) -> u8 {
+    match index {
+      0 => self.registers.get_b(),
+      1 => self.registers.get_c(),
+      2 => self.registers.get_d(),
+      3 => self.registers.get_e(),
+      4 => self.registers.get_h(),
+      5 => self.registers.get_l(),
+      6 => self.read(self.registers.get_hl()),
+      _ => self.registers.get_a(),
+    }
+  }
+
+  // Helper function to write one of B, C, D, E, H, L, (HL), A by its index in the opcode
+  fn set_r8(&mut self, index: u8, value: u8) {
+    match index {
+      0 => self.registers.set_b(value),
+      1 => self.registers.set_c(value),
+      2 => self.registers.set_d(value),
+      3 => self.registers.set_e(value),
+      4 => self.registers.set_h(value),
+      5 => self.registers.set_l(value),
+      6 => self.write(self.registers.get_hl(), value),
+      _ => self.registers.set_a(value),
+    }
+  }
+
+  /*
+  CB prefixed instructions, decoded from the fields of the second byte:
+  bits 7-6 pick the group, bits 5-3 the operation or bit number, bits 2-0 the operand.
+
+  https://gbdev.io/pandocs/CPU_Instruction_Set.html
+   */
+  fn execute_cb(&mut self, opcode: u8) {
+    let operation = (opcode >> 3) & 0x07;
+    let operand = opcode & 0x07;
+    let value = self.get_r8(operand);
+    let carry = self.get_flags().carry;
+
+    match opcode >> 6 {
+      0 => {
+        let (result, flags) = alu::shift(operation, value, carry);
+        self.set_r8(operand, result);
+        self.set_alu_flags(flags);
+      }
+      1 => self.set_alu_flags(alu::bit(operation, value, carry)),
+      2 => self.set_r8(operand, value & !(1 << operation)), // RES
+      _ => self.set_r8(operand, value | (1 << operation)), // SET
+    }
+  }
+
   pub fn execute(&mut self, instruction: Instruction) {
     match instruction {
       Instruction::ADD(target) => {
@@ -825,7 +905,7 @@ impl<'a> CPU<'a> {
       }
 
       Instruction::ADC(target) => {
-        carry_op!(self, target, add);
+        arithmetic_op!(self, target, adc);
       }
 
       Instruction::SUB(target) => {
@@ -833,7 +913,7 @@ impl<'a> CPU<'a> {
       }
 
       Instruction::SBC(target) => {
-        carry_op!(self, target, sub);
+        arithmetic_op!(self, target, sbc);
       }
 
       Instruction::AND(target) => {
@@ -892,6 +972,10 @@ impl<'a> CPU<'a> {
         self.di();
       }
 
+      Instruction::HALT => {
+        self.halt();
+      }
+
       Instruction::PUSH(target, target2) => {
         self.push(target, target2);
       }
@@ -973,15 +1057,15 @@ impl<'a> CPU<'a> {
       }
 
       Instruction::ADC_IMM(value) => {
-        self.add(value + self.registers.get_f() & (FlagMasks::CARRY as u8));
-      } 
+        self.adc(value);
+      }
 
       Instruction::SUB_IMM(value) => {
         self.sub(value);
       }
 
       Instruction::SBC_IMM(value) => {
-        self.sub(value + self.registers.get_f() & (FlagMasks::CARRY as u8));
+        self.sbc(value);
       }
 
       Instruction::AND_IMM(value) => {
@@ -1001,35 +1085,43 @@ impl<'a> CPU<'a> {
       }
 
       Instruction::ADD_MEM => {
-        self.add(self.ram.read(self.registers.get_hl()));
+        let value = self.read(self.registers.get_hl());
+        self.add(value);
       }
 
       Instruction::SUB_MEM => {
-        self.sub(self.ram.read(self.registers.get_hl()));
+        let value = self.read(self.registers.get_hl());
+        self.sub(value);
       }
 
       Instruction::ADC_MEM => {
-        self.add(self.ram.read(self.registers.get_hl()) + self.registers.get_f() & (FlagMasks::CARRY as u8));
+        let value = self.read(self.registers.get_hl());
+        self.adc(value);
       }
 
       Instruction::SBC_MEM => {
-        self.sub(self.ram.read(self.registers.get_hl()) + self.registers.get_f() & (FlagMasks::CARRY as u8));
+        let value = self.read(self.registers.get_hl());
+        self.sbc(value);
       }
 
       Instruction::AND_MEM => {
-        self.and(self.ram.read(self.registers.get_hl()));
+        let value = self.read(self.registers.get_hl());
+        self.and(value);
       }
 
       Instruction::OR_MEM => {
-        self.or(self.ram.read(self.registers.get_hl()));
+        let value = self.read(self.registers.get_hl());
+        self.or(value);
       }
 
       Instruction::XOR_MEM => {
-        self.xor(self.ram.read(self.registers.get_hl()));
+        let value = self.read(self.registers.get_hl());
+        self.xor(value);
       }
 
       Instruction::CP_MEM => {
-        self.cp(self.ram.read(self.registers.get_hl()));
+        let value = self.read(self.registers.get_hl());
+        self.cp(value);
       }
 
       Instruction::LD_REG_IMM_16(target1, target2, value) => {
@@ -1071,396 +1163,107 @@ impl<'a> CPU<'a> {
       Instruction::CPL => {
         self.cpl();
       }
-      
+
+      Instruction::PREFIX_CB(opcode) => {
+        self.execute_cb(opcode);
+      }
+
+      Instruction::STOP => {
+        self.stop();
+      }
+
+      Instruction::ILLEGAL(opcode) => {
+        self.lock_up(opcode);
+      }
     }
   }
   
-  fn decode_instruction(&self, opcode: u8) -> Instruction {
+  fn decode_instruction(&mut self, opcode: u8) -> Instruction {
+    // Only fetch the bytes the instruction actually has, each fetch is a bus access. After
+    // the halt bug the opcode's byte is fetched again as the first operand
     let pc = self.registers.get_pc();
-    let immediate1 = self.ram.read(pc + 1);
-    let immediate2 = self.ram.read(pc + 2);
+    let operands = if self.halt_bug { pc } else { pc.wrapping_add(1) };
+    let entry = opcodes::lookup(opcode);
+    let operand_bytes = if opcode == 0xCB { 1 } else { entry.size - 1 };
+    let immediate1 = if operand_bytes >= 1 { self.read(operands) } else { 0 };
+    let immediate2 = if operand_bytes >= 2 { self.read(operands.wrapping_add(1)) } else { 0 };
     let immediate_16 = (immediate2 as u16) << 8 | immediate1 as u16;
 
-    let instruction = match opcode {
-      0x00 => Instruction::NOP, // NOP
-      0x01 => Instruction::LD_REG_IMM_16(ArithmeticTarget::B, ArithmeticTarget::C, immediate_16), // LD BC, d16
-      0x02 => Instruction::LD_BCDE(ArithmeticTarget::B, ArithmeticTarget::C, false), // LD (BC), A
-      0x03 => Instruction::INC_16(ArithmeticTarget::B, ArithmeticTarget::C), // INC BC
-      0x04 => Instruction::INC(ArithmeticTarget::B), // INC B
-      0x05 => Instruction::DEC(ArithmeticTarget::B), // DEC B
-      0x06 => Instruction::LD_REG_IMM(ArithmeticTarget::B, immediate1), // LD B, d8
-      0x07 => Instruction::RL(true), // RLCA
-      0x08 => Instruction::STORE_SP(immediate_16), // LD (a16), SP
-      0x09 => Instruction::ADD_HL(ArithmeticTarget::B, ArithmeticTarget::C), // ADD HL, BC
-      0x0A => Instruction::LD_BCDE(ArithmeticTarget::B, ArithmeticTarget::C, true), // LD A, (BC)
-      0x0B => Instruction::DEC_16(ArithmeticTarget::B, ArithmeticTarget::C), // DEC BC
-      0x0C => Instruction::INC(ArithmeticTarget::C), // INC C
-      0x0D => Instruction::DEC(ArithmeticTarget::C), // DEC C
-      0x0E => Instruction::LD_REG_IMM(ArithmeticTarget::C, immediate1), // LD C, d8
-      0x0F => Instruction::RR(true), // RRCA
-      
-      // 0x10 => Instruction::STOP, // STOP // Not implemented
-      0x10 => panic!("STOP not implemented!"),
-      0x11 => Instruction::LD_REG_IMM_16(ArithmeticTarget::D, ArithmeticTarget::E, immediate_16), // LD DE, d16
-      0x12 => Instruction::LD_BCDE(ArithmeticTarget::D, ArithmeticTarget::E, false), // LD (DE), A
-      0x13 => Instruction::INC_16(ArithmeticTarget::D, ArithmeticTarget::E), // INC DE
-      0x14 => Instruction::INC(ArithmeticTarget::D), // INC D
-      0x15 => Instruction::DEC(ArithmeticTarget::D), // DEC D
-      0x16 => Instruction::LD_REG_IMM(ArithmeticTarget::D, immediate1), // LD D, d8
-      0x17 => Instruction::RL(false), // RLA
-      0x18 => Instruction::JR(false, false, false, immediate1 as i8), // JR e8
-      0x19 => Instruction::ADD_HL(ArithmeticTarget::D, ArithmeticTarget::E), // ADD HL, DE
-      0x1A => Instruction::LD_BCDE(ArithmeticTarget::D, ArithmeticTarget::E, true), // LD A, (DE)
-      0x1B => Instruction::DEC_16(ArithmeticTarget::D, ArithmeticTarget::E), // DEC DE
-      0x1C => Instruction::INC(ArithmeticTarget::E), // INC E
-      0x1D => Instruction::DEC(ArithmeticTarget::E), // DEC E
-      0x1E => Instruction::LD_REG_IMM(ArithmeticTarget::E, immediate1), // LD E, d8
-      0x1F => Instruction::RR(false), // RRA
-
-      0x20 => Instruction::JR(false, true, true, immediate1 as i8), // JR NZ, e8
-      0x21 => Instruction::LD_REG_IMM_16(ArithmeticTarget::H, ArithmeticTarget::L, immediate_16), // LD HL, d16
-      0x22 => Instruction::LD_MEM_INC(true, false), // LD (HL+), A
-      0x23 => Instruction::INC_16(ArithmeticTarget::H, ArithmeticTarget::L), // INC HL
-      0x24 => Instruction::INC(ArithmeticTarget::H), // INC H
-      0x25 => Instruction::DEC(ArithmeticTarget::H), // DEC H
-      0x26 => Instruction::LD_REG_IMM(ArithmeticTarget::H, immediate1), // LD H, d8
-      0x27 => Instruction::DAA, // DAA
-      0x28 => Instruction::JR(false, true, false, immediate1 as i8), // JR Z, s8
-      0x29 => Instruction::ADD_HL(ArithmeticTarget::H, ArithmeticTarget::L), // ADD HL, HL
-      0x2A => Instruction::LD_MEM_INC(true, true), // LD A, (HL+)
-      0x2B => Instruction::DEC_16(ArithmeticTarget::H, ArithmeticTarget::L), // DEC HL
-      0x2C => Instruction::INC(ArithmeticTarget::L), // INC L
-      0x2D => Instruction::DEC(ArithmeticTarget::L), // DEC L
-      0x2E => Instruction::LD_REG_IMM(ArithmeticTarget::L, immediate1), // LD L, d8
-      0x2F => Instruction::CPL, // CPL
-
-      0x30 => Instruction::JR(true, false, true, immediate1 as i8), // JR NC, e8
-      0x31 => Instruction::LD_REG_IMM_16(ArithmeticTarget::SP, ArithmeticTarget::SP, immediate_16), // LD SP, d16
-      0x32 => Instruction::LD_MEM_INC(false, false), // LD (HL-), A
-      0x33 => Instruction::INC_16(ArithmeticTarget::SP, ArithmeticTarget::SP), // INC SP
-      0x34 => Instruction::MOD_MEM(false), // INC (HL)
-      0x35 => Instruction::MOD_MEM(true), // DEC (HL)
-      0x36 => Instruction::LD_MEM_IMM(immediate1), // LD (HL), d8
-      0x37 => Instruction::SCF, // SCF
-      0x38 => Instruction::JR(true, false, false, immediate1 as i8), // JR C, e8
-      0x39 => Instruction::ADD_HL(ArithmeticTarget::SP, ArithmeticTarget::SP), // ADD HL, SP
-      0x3A => Instruction::LD_MEM_INC(false, true), // LD A, (HL-)
-      0x3B => Instruction::DEC_16(ArithmeticTarget::SP, ArithmeticTarget::SP), // DEC SP
-      0x3C => Instruction::INC(ArithmeticTarget::A), // INC A
-      0x3D => Instruction::DEC(ArithmeticTarget::A), // DEC A
-      0x3E => Instruction::LD_REG_IMM(ArithmeticTarget::A, immediate1), // LD A, d8
-      0x3F => Instruction::CCF, // CCF
-
-
-      0x40 => Instruction::LD_RR(ArithmeticTarget::B, ArithmeticTarget::B), // LD B, B
-      0x41 => Instruction::LD_RR(ArithmeticTarget::B, ArithmeticTarget::C), // LD B, C
-      0x42 => Instruction::LD_RR(ArithmeticTarget::B, ArithmeticTarget::D), // LD B, D
-      0x43 => Instruction::LD_RR(ArithmeticTarget::B, ArithmeticTarget::E), // LD B, E
-      0x44 => Instruction::LD_RR(ArithmeticTarget::B, ArithmeticTarget::H), // LD B, H
-      0x45 => Instruction::LD_RR(ArithmeticTarget::B, ArithmeticTarget::L), // LD B, L
-      0x46 => Instruction::LD_REG_MEM(ArithmeticTarget::B), // LD B, (HL)
-      0x47 => Instruction::LD_RR(ArithmeticTarget::B, ArithmeticTarget::A), // LD B, A
-      0x48 => Instruction::LD_RR(ArithmeticTarget::C, ArithmeticTarget::B), // LD C, B
-      0x49 => Instruction::LD_RR(ArithmeticTarget::C, ArithmeticTarget::C), // LD C, C
-      0x4A => Instruction::LD_RR(ArithmeticTarget::C, ArithmeticTarget::D), // LD C, D
-      0x4B => Instruction::LD_RR(ArithmeticTarget::C, ArithmeticTarget::E), // LD C, E
-      0x4C => Instruction::LD_RR(ArithmeticTarget::C, ArithmeticTarget::H), // LD C, H  
-      0x4D => Instruction::LD_RR(ArithmeticTarget::C, ArithmeticTarget::L), // LD C, L
-      0x4E => Instruction::LD_REG_MEM(ArithmeticTarget::C), // LD C, (HL)
-      0x4F => Instruction::LD_RR(ArithmeticTarget::C, ArithmeticTarget::A), // LD C, A
-     
-      0x50 => Instruction::LD_RR(ArithmeticTarget::D, ArithmeticTarget::B), // LD D, B
-      0x51 => Instruction::LD_RR(ArithmeticTarget::D, ArithmeticTarget::C), // LD D, C
-      0x52 => Instruction::LD_RR(ArithmeticTarget::D, ArithmeticTarget::D), // LD D, D
-      0x53 => Instruction::LD_RR(ArithmeticTarget::D, ArithmeticTarget::E), // LD D, E
-      0x54 => Instruction::LD_RR(ArithmeticTarget::D, ArithmeticTarget::H), // LD D, H
-      0x55 => Instruction::LD_RR(ArithmeticTarget::D, ArithmeticTarget::L), // LD D, L
-      0x56 => Instruction::LD_REG_MEM(ArithmeticTarget::D), // LD D, (HL)
-      0x57 => Instruction::LD_RR(ArithmeticTarget::D, ArithmeticTarget::A), // LD D, A
-      0x58 => Instruction::LD_RR(ArithmeticTarget::E, ArithmeticTarget::B), // LD E, B
-      0x59 => Instruction::LD_RR(ArithmeticTarget::E, ArithmeticTarget::C), // LD E, C
-      0x5A => Instruction::LD_RR(ArithmeticTarget::E, ArithmeticTarget::D), // LD E, D
-      0x5B => Instruction::LD_RR(ArithmeticTarget::E, ArithmeticTarget::E), // LD E, E
-      0x5C => Instruction::LD_RR(ArithmeticTarget::E, ArithmeticTarget::H), // LD E, H
-      0x5D => Instruction::LD_RR(ArithmeticTarget::E, ArithmeticTarget::L), // LD E, L
-      0x5E => Instruction::LD_REG_MEM(ArithmeticTarget::E), // LD E, (HL)
-      0x5F => Instruction::LD_RR(ArithmeticTarget::E, ArithmeticTarget::A), // LD E, A  
-     
-      0x60 => Instruction::LD_RR(ArithmeticTarget::H, ArithmeticTarget::B), // LD H, B  
-      0x61 => Instruction::LD_RR(ArithmeticTarget::H, ArithmeticTarget::C), // LD H, C
-      0x62 => Instruction::LD_RR(ArithmeticTarget::H, ArithmeticTarget::D), // LD H, D
-      0x63 => Instruction::LD_RR(ArithmeticTarget::H, ArithmeticTarget::E), // LD H, E
-      0x64 => Instruction::LD_RR(ArithmeticTarget::H, ArithmeticTarget::H), // LD H, H
-      0x65 => Instruction::LD_RR(ArithmeticTarget::H, ArithmeticTarget::L), // LD H, L
-      0x66 => Instruction::LD_REG_MEM(ArithmeticTarget::H), // LD H, (HL)
-      0x67 => Instruction::LD_RR(ArithmeticTarget::H, ArithmeticTarget::A), // LD H, A
-      0x68 => Instruction::LD_RR(ArithmeticTarget::L, ArithmeticTarget::B), // LD L, B
-      0x69 => Instruction::LD_RR(ArithmeticTarget::L, ArithmeticTarget::C), // LD L, C
-      0x6A => Instruction::LD_RR(ArithmeticTarget::L, ArithmeticTarget::D), // LD L, D
-      0x6B => Instruction::LD_RR(ArithmeticTarget::L, ArithmeticTarget::E), // LD L, E
-      0x6C => Instruction::LD_RR(ArithmeticTarget::L, ArithmeticTarget::H), // LD L, H
-      0x6D => Instruction::LD_RR(ArithmeticTarget::L, ArithmeticTarget::L), // LD L, L
-      0x6E => Instruction::LD_REG_MEM(ArithmeticTarget::L), // LD L, (HL)
-      0x6F => Instruction::LD_RR(ArithmeticTarget::L, ArithmeticTarget::A), // LD L, A
-     
-      0x70 => Instruction::LD_MEM_REG(ArithmeticTarget::B), // LD (HL), B
-      0x71 => Instruction::LD_MEM_REG(ArithmeticTarget::C), // LD (HL), C
-      0x72 => Instruction::LD_MEM_REG(ArithmeticTarget::D), // LD (HL), D
-      0x73 => Instruction::LD_MEM_REG(ArithmeticTarget::E), // LD (HL), E
-      0x74 => Instruction::LD_MEM_REG(ArithmeticTarget::H), // LD (HL), H
-      0x75 => Instruction::LD_MEM_REG(ArithmeticTarget::L), // LD (HL), L
-      // 0x76 => Instruction::HALT, // HALT // Not implemented
-      0x76 => panic!("HALT not implemented!"),
-      0x77 => Instruction::LD_MEM_REG(ArithmeticTarget::A), // LD (HL), A
-      0x78 => Instruction::LD_RR(ArithmeticTarget::A, ArithmeticTarget::B), // LD A, B
-      0x79 => Instruction::LD_RR(ArithmeticTarget::A, ArithmeticTarget::C), // LD A, C
-      0x7A => Instruction::LD_RR(ArithmeticTarget::A, ArithmeticTarget::D), // LD A, D
-      0x7B => Instruction::LD_RR(ArithmeticTarget::A, ArithmeticTarget::E), // LD A, E
-      0x7C => Instruction::LD_RR(ArithmeticTarget::A, ArithmeticTarget::H), // LD A, H
-      0x7D => Instruction::LD_RR(ArithmeticTarget::A, ArithmeticTarget::L), // LD A, L
-      0x7E => Instruction::LD_REG_MEM(ArithmeticTarget::A), // LD A, (HL)
-      0x7F => Instruction::LD_RR(ArithmeticTarget::A, ArithmeticTarget::A), // LD A, A
-     
-      0x80 => Instruction::ADD(ArithmeticTarget::B), // ADD A, B
-      0x81 => Instruction::ADD(ArithmeticTarget::C), // ADD A, C
-      0x82 => Instruction::ADD(ArithmeticTarget::D), // ADD A, D
-      0x83 => Instruction::ADD(ArithmeticTarget::E), // ADD A, E
-      0x84 => Instruction::ADD(ArithmeticTarget::H), // ADD A, H
-      0x85 => Instruction::ADD(ArithmeticTarget::L), // ADD A, L
-      0x86 => Instruction::ADD_MEM, // ADD A, (HL) 
-      0x87 => Instruction::ADD(ArithmeticTarget::A), // ADD A, A
-      0x88 => Instruction::ADC(ArithmeticTarget::B), // ADC A, B
-      0x89 => Instruction::ADC(ArithmeticTarget::C), // ADC A, C
-      0x8A => Instruction::ADC(ArithmeticTarget::D), // ADC A, D
-      0x8B => Instruction::ADC(ArithmeticTarget::E), // ADC A, E
-      0x8C => Instruction::ADC(ArithmeticTarget::H), // ADC A, H
-      0x8D => Instruction::ADC(ArithmeticTarget::L), // ADC A, L
-      0x8E => Instruction::ADC_MEM, // ADC A, (HL)
-      0x8F => Instruction::ADC(ArithmeticTarget::A), // ADC A, A 
-    
-      0x90 => Instruction::SUB(ArithmeticTarget::B), // SUB A, B
-      0x91 => Instruction::SUB(ArithmeticTarget::C), // SUB A, C
-      0x92 => Instruction::SUB(ArithmeticTarget::D), // SUB A, D
-      0x93 => Instruction::SUB(ArithmeticTarget::E), // SUB A, E
-      0x94 => Instruction::SUB(ArithmeticTarget::H), // SUB A, H
-      0x95 => Instruction::SUB(ArithmeticTarget::L), // SUB A, L
-      0x96 => Instruction::SUB_MEM, // SUB A, (HL)
-      0x97 => Instruction::SUB(ArithmeticTarget::A), // SUB A, A
-      0x98 => Instruction::SBC(ArithmeticTarget::B), // SBC A, B
-      0x99 => Instruction::SBC(ArithmeticTarget::C), // SBC A, C
-      0x9A => Instruction::SBC(ArithmeticTarget::D), // SBC A, D
-      0x9B => Instruction::SBC(ArithmeticTarget::E), // SBC A, E
-      0x9C => Instruction::SBC(ArithmeticTarget::H), // SBC A, H
-      0x9D => Instruction::SBC(ArithmeticTarget::L), // SBC A, L
-      0x9E => Instruction::SBC_MEM, // SBC A, (HL)
-      0x9F => Instruction::SBC(ArithmeticTarget::A), // SBC A, A
-    
-      0xA0 => Instruction::AND(ArithmeticTarget::B), // AND A, B
-      0xA1 => Instruction::AND(ArithmeticTarget::C), // AND A, C
-      0xA2 => Instruction::AND(ArithmeticTarget::D), // AND A, D
-      0xA3 => Instruction::AND(ArithmeticTarget::E), // AND A, E
-      0xA4 => Instruction::AND(ArithmeticTarget::H), // AND A, H
-      0xA5 => Instruction::AND(ArithmeticTarget::L), // AND A, L
-      0xA6 => Instruction::AND_MEM, // AND A, (HL)
-      0xA7 => Instruction::AND(ArithmeticTarget::A), // AND A, A
-      0xA8 => Instruction::XOR(ArithmeticTarget::B), // XOR A, B
-      0xA9 => Instruction::XOR(ArithmeticTarget::C), // XOR A, C
-      0xAA => Instruction::XOR(ArithmeticTarget::D), // XOR A, D
-      0xAB => Instruction::XOR(ArithmeticTarget::E), // XOR A, E
-      0xAC => Instruction::XOR(ArithmeticTarget::H), // XOR A, H
-      0xAD => Instruction::XOR(ArithmeticTarget::L), // XOR A, L
-      0xAE => Instruction::XOR_MEM, // XOR A, (HL)
-      0xAF => Instruction::XOR(ArithmeticTarget::A), // XOR A, A
-
-      0xB0 => Instruction::OR(ArithmeticTarget::B), // OR A, B
-      0xB1 => Instruction::OR(ArithmeticTarget::C), // OR A, C
-      0xB2 => Instruction::OR(ArithmeticTarget::D), // OR A, D
-      0xB3 => Instruction::OR(ArithmeticTarget::E), // OR A, E
-      0xB4 => Instruction::OR(ArithmeticTarget::H), // OR A, H
-      0xB5 => Instruction::OR(ArithmeticTarget::L), // OR A, L
-      0xB6 => Instruction::OR_MEM, // OR A, (HL)
-      0xB7 => Instruction::OR(ArithmeticTarget::A), // OR A, A
-      0xB8 => Instruction::CP(ArithmeticTarget::B), // CP A, B
-      0xB9 => Instruction::CP(ArithmeticTarget::C), // CP A, C
-      0xBA => Instruction::CP(ArithmeticTarget::D), // CP A, D
-      0xBB => Instruction::CP(ArithmeticTarget::E), // CP A, E
-      0xBC => Instruction::CP(ArithmeticTarget::H), // CP A, H
-      0xBD => Instruction::CP(ArithmeticTarget::L), // CP A, L
-      0xBE => Instruction::CP_MEM, // CP A, (HL)
-      0xBF => Instruction::CP(ArithmeticTarget::A), // CP A, A
-    
-      0xC0 => Instruction::RET_N(false, true), // RET NZ
-      0xC1 => Instruction::POP(ArithmeticTarget::B, ArithmeticTarget::C), // POP BC
-      0xC2 => Instruction::JP(false, true, true, immediate_16), // JP NZ, a16
-      0xC3 => Instruction::JP(false, false, false, immediate_16), // JP a16
-      0xC4 => Instruction::CALL(immediate_16, false, true, true), // CALL NZ, a16
-      0xC5 => Instruction::PUSH(ArithmeticTarget::B, ArithmeticTarget::C), // PUSH BC
-      0xC6 => Instruction::ADD_IMM(immediate1), // ADD A, d8
-      0xC7 => Instruction::RST(0x00), // RST 00H
-      0xC8 => Instruction::RET(false, true, false), // RET Z
-      0xC9 => Instruction::RET(false, false, false), // RET
-      0xCA => Instruction::JP(false, true, false, immediate_16), // JP Z, a16
-      // 0xCB => Instruction::PREFIX_CB, // PREFIX CB // Not implemented
-      0xCB => panic!("PREFIX CB not implemented!"),
-      0xCC => Instruction::CALL(immediate_16, false, true, false ), // CALL Z, a16
-      0xCD => Instruction::CALL(immediate_16, false, false, false), // CALL a16
-      0xCE => Instruction::ADC_IMM(immediate1), // ADC A, d8
-      0xCF => Instruction::RST(0x08), // RST 08H
-    
-      0xD0 => Instruction::RET_N(true, false), // RET NC, e8
-      0xD1 => Instruction::POP(ArithmeticTarget::D, ArithmeticTarget::E), // POP DE
-      0xD2 => Instruction::JP(true, false, true, immediate_16), // JP NC, a16
-      0xD3 => panic!("OUT (C), A not implemented"),// OUT (C), A
-      0xD4 => Instruction::CALL(immediate_16, true, false, true), // CALL NC, a16
-      0xD5 => Instruction::PUSH(ArithmeticTarget::D, ArithmeticTarget::E), // PUSH DE
-      0xD6 => Instruction::SUB_IMM(immediate1), // SUB A, d8
-      0xD7 => Instruction::RST(0x10), // RST 10H
-      0xD8 => Instruction::RET(true, false, false), // RET C, e8
-      0xD9 => Instruction::RET(false, false, true), // RETI
-      0xDA => Instruction::JP(true, false, false, immediate_16), // JP C, a16
-      0xDB => panic!("IN A, (C) not implemented"),// IN A, (C)
-      0xDC => Instruction::CALL(immediate_16, true, false, false), // CALL C, a16
-      0xDD => panic!("PREFIX DD not implemented"), // PREFIX DD
-      0xDE => Instruction::SBC_IMM(immediate1), // SBC A, d8
-      0xDF => Instruction::RST(0x18), // RST 18H
-    
-      0xE0 => Instruction::LD_IMM_8(immediate1, false), // LD (FF00+d8), A
-      0xE1 => Instruction::POP(ArithmeticTarget::B, ArithmeticTarget::C), // POP HL
-      0xE2 => Instruction::LD_AC(false), // LD (FF00+C), A
-      0xE3 => panic!("EX (SP), HL not implemented"), // EX (SP), HL
-      0xE4 => panic!("CALL HL, a16 not implemented"), // CALL HL, a16
-      0xE5 => Instruction::PUSH(ArithmeticTarget::B, ArithmeticTarget::C), // PUSH HL
-      0xE6 => Instruction::AND_IMM(immediate1), // AND A, d8
-      0xE7 => Instruction::RST(0x20), // RST 20H
-      0xE8 => Instruction::INC_SP(immediate1 as i8), // ADD SP, r8
-      0xE9 => Instruction::JP_HL(), // JP (HL)
-      0xEA => Instruction::LD_IMM_16(immediate_16, false), // LD (a16), A
-      0xEB => panic!("EX DE, HL not implemented"), // EX DE, HL
-      0xEC => panic!("CALL HL, a16 not implemented"), // CALL HL, a16
-      0xED => panic!("PREFIX ED not implemented"), // PREFIX ED
-      0xEE => Instruction::XOR_IMM(immediate1), // XOR A, d8
-      0xEF => Instruction::RST(0x28), // RST 28H
-    
-      0xF0 => Instruction::LD_IMM_8(immediate1, true), // LD A, (FF00+C)
-      0xF1 => Instruction::POP(ArithmeticTarget::A, ArithmeticTarget::B), // POP AF
-      0xF2 => Instruction::LD_AC(true), // LD A, (FF00+C)
-      0xF3 => Instruction::DI, // DI
-      0xF4 => panic!("CALL HL, a16 not implemented"), // CALL HL, a16
-      0xF5 => Instruction::PUSH(ArithmeticTarget::A, ArithmeticTarget::B), // PUSH AF
-      0xF6 => Instruction::OR_IMM(immediate1), // OR A, d8
-      0xF7 => Instruction::RST(0x30), // RST 30H
-      0xF8 => Instruction::LD_HL_SP(immediate1 as i8), // LD HL, SP+r8 // did i not do this???
-      0xF9 => Instruction::LD_SP_HL, // LD SP, HL
-      0xFA => Instruction::LD_IMM_16(immediate_16, false), // LD A, a16
-      0xFB => Instruction::EI, // EI
-      0xFC => panic!("CALL HL, a16 not implemented"), // CALL HL, a16
-      0xFD => panic!("PREFIX FD not implemented"), // PREFIX FD
-      0xFE => Instruction::CP_IMM(immediate1), // CP A, d8
-      0xFF => Instruction::RST(0x38), // RST 38H
-    }; 
-    
-    instruction
+    // Matching the table's text is slow, so every opcode is decoded once up front
+    static DECODED: OnceLock<[Instruction; 256]> = OnceLock::new();
+    let decoded = DECODED.get_or_init(|| std::array::from_fn(|opcode| decode(opcode as u8, opcodes::lookup(opcode as u8), 0, 0)));
+    decoded[opcode as usize].with_immediates(immediate1, immediate_16)
   }
 
   pub fn step(&mut self) -> u8 {
-    // Read opcode at current PC
-    let opcode = self.ram.read(self.registers.get_pc());
-    
-    // Get instruction size and cycles before executing
-    let (size, cycles) = self.get_instruction_info(opcode);
-    // Store original PC to check if it was modified
-    let original_pc = self.registers.get_pc();
-    
-    // Decode and execute the instruction
-    let instruction = self.decode_instruction(opcode);
-    self.execute(instruction);
-    
-    // Only update PC if it wasn't modified by the instruction
-    if self.registers.get_pc() == original_pc {
-      self.registers.set_pc(original_pc + size as u16);
+    self.bus_cycles = 0;
+
+    let cycles = if self.lockup.is_some() {
+      // Nothing gets a locked up CPU going again short of a reset
+      HALT_CYCLES
+    } else if self.stopped {
+      // Pressing a button requests the joypad interrupt, whether or not it's enabled
+      if self.ram.peek(INTERRUPT_FLAG_ADDRESS) & Interrupt::JOYPAD as u8 != 0 {
+        self.stopped = false;
+      }
+      HALT_CYCLES
+    } else if self.handle_interrupts() {
+      INTERRUPT_CYCLES
+    } else if self.halted {
+      // Nothing runs until an interrupt is pending, time still passes
+      HALT_CYCLES
+    } else {
+      self.execute_next()
+    };
+
+    if self.m_cycle_mode {
+      // Whatever the accesses didn't account for is spent inside the CPU
+      self.ram.tick(cycles.saturating_sub(self.bus_cycles) as u32);
     }
     
     // Update total clock cycles
-    self.clock_cycles += cycles as u64;
+    self.clock_cycles = self.clock_cycles.wrapping_add(cycles as u64);
     
     // Return number of cycles for this instruction
     cycles
   }
 
-  fn get_instruction_info(&self, opcode: u8) -> (u8, u8) {
-    // Default size is 1 byte for opcode
-    let mut size = 1;
-    let mut cycles = 4; // Base cycles for most instructions
-
-    match opcode {
-      // 2-byte instructions (opcode + 1 byte immediate)
-      0x06 | 0x0E | 0x16 | 0x1E | 0x26 | 0x2E | 0x36 | 0x3E | // LD r, d8
-      0xC6 | 0xCE | 0xD6 | 0xDE | 0xE6 | 0xEE | 0xF6 | 0xFE | // ALU operations with immediate
-      0x18 | 0x20 | 0x28 | 0x30 | 0x38 => { // JR instructions
-        size = 2;
-        cycles = 8;
-      }
+  // Fetches, decodes and executes the instruction at PC, returning its cycles. Unlike step
+  // this doesn't look at interrupts or HALT, which single instruction tests rely on
+  pub fn execute_next(&mut self) -> u8 {
+    // An EI before this instruction takes effect once it is done
+    let enable_ime = self.ime_pending;
 
-      // 3-byte instructions (opcode + 2 bytes immediate)
-      0x01 | 0x11 | 0x21 | 0x31 | // LD rr, d16
-      0xC2 | 0xC3 | 0xC4 | 0xCA | 0xCC | 0xCD | // JP/CALL instructions
-      0xD2 | 0xD4 | 0xDA | 0xDC | // JP/CALL instructions
-      0xE2 | 0xEA | 0xF2 | 0xFA => { // LD instructions with 16-bit address
-        size = 3;
-        cycles = 12;
-      }
+    if let Some(mut tracer) = self.tracer.take() {
+      tracer.trace(self);
+      self.tracer = Some(tracer);
+    }
 
-      // Special cases for conditional instructions
-      0x20 | 0x28 | 0x30 | 0x38 => { // JR cc, e8
-        cycles = 8; // Not taken
-        // TODO: Add 4 more cycles if condition is met
-      }
-      0xC0 | 0xC8 | 0xD0 | 0xD8 => { // RET cc
-        cycles = 8; // Not taken
-        // TODO: Add 12 more cycles if condition is met
-      }
-      0xC2 | 0xC4 | 0xCA | 0xCC | 0xD2 | 0xD4 | 0xDA | 0xDC => { // JP/CALL cc, a16
-        cycles = 12; // Not taken
-        // TODO: Add 4 more cycles if condition is met
-      }
+    // Read opcode at current PC
+    let pc = self.registers.get_pc();
+    let opcode = self.read(pc);
 
-      // Special cases for other instructions
-      0x08 => { // LD (a16), SP
-        size = 3;
-        cycles = 20;
-      }
-      0xE8 => { // ADD SP, r8
-        size = 2;
-        cycles = 16;
-      }
-      0xF8 => { // LD HL, SP+r8
-        size = 2;
-        cycles = 12;
-      }
-      0xF9 => { // LD SP, HL
-        cycles = 8;
-      }
-      0x00 => { // NOP
-        cycles = 4;
-      }
-      0x10 => { // STOP
-        cycles = 4;
-      }
-      0x76 => { // HALT
-        cycles = 4;
-      }
-      0xF3 | 0xFB => { // DI/EI
-        cycles = 4;
-      }
+    // Decode while PC still points at the opcode, then move past the immediates like the
+    // hardware does, so jumps and calls see the address of the next instruction
+    let instruction = self.decode_instruction(opcode);
+    // Size and taken/not taken cycles come from the opcode table
+    let info = match instruction {
+      Instruction::PREFIX_CB(cb_opcode) => opcodes::lookup_cb(cb_opcode),
+      _ => opcodes::lookup(opcode),
+    };
+    let size = if self.halt_bug { info.size - 1 } else { info.size };
+    self.halt_bug = false;
+    self.registers.set_pc(pc.wrapping_add(size as u16));
+    self.branch_taken = false;
+    self.execute(instruction);
 
-      // Default case - most instructions are 1 byte and take 4 cycles
-      _ => {
-        size = 1;
-        cycles = 4;
-      }
+    // DI or RETI in the meantime clear the pending flag
+    if enable_ime && self.ime_pending {
+      self.interrupt_master_enable = true;
+      self.ime_pending = false;
     }
 
-    (size, cycles)
+    if self.branch_taken { info.cycles_taken } else { info.cycles }
   }
 
   fn get_interrupt_vector(&self, interrupt_flag: u8) -> Interrupt {
@@ -1487,31 +1290,321 @@ impl<'a> CPU<'a> {
     }
   }
 
-  pub fn handle_interrupts(&mut self) {
-    /*
-    TODO: this is probably the next thing to implement
+  // Interrupts that are both requested and enabled
+  fn pending_interrupts(&self) -> u8 {
+    // Peeked, this runs every step and mustn't set off watchpoints
+    self.ram.peek(INTERRUPT_FLAG_ADDRESS) & self.ram.peek(INTERRUPT_ENABLE_ADDRESS) & 0x1F
+  }
+
+  /*
+  Any pending interrupt wakes the CPU from HALT, but only gets serviced while IME is set.
+  Servicing pushes PC and jumps to the handler of the highest priority interrupt, which
+  takes 5 M-cycles: 2 internal, 2 for the push and 1 to set PC.
 
-    https://gbdev.io/pandocs/Interrupts.html
+  The interrupt to service is picked after the high byte of PC is pushed. If that write
+  lands on IE (SP was 0x0000) and disables every pending interrupt, the dispatch is
+  cancelled and PC ends up at 0x0000 instead.
 
-     */
-    if self.interrupt_master_enable {
-      assert!(self.ram.read(INTERRUPT_FLAG_ADDRESS) & 0x1F != 0);
+  https://gbdev.io/pandocs/Interrupts.html
+   */
+  pub fn handle_interrupts(&mut self) -> bool {
+    if self.pending_interrupts() == 0 {
+      return false;
+    }
+    self.halted = false;
+    if !self.interrupt_master_enable {
+      return false;
+    }
+    self.interrupt_master_enable = false;
+    self.ime_pending = false;
+
+    if self.m_cycle_mode {
+      self.tick_m_cycle();
+      self.tick_m_cycle();
+    }
+    let pc = self.registers.get_pc();
+    let mut sp = self.registers.get_sp().wrapping_sub(1);
+    self.write(sp, (pc >> 8) as u8);
+    let pending = self.pending_interrupts();
+    sp = sp.wrapping_sub(1);
+    self.write(sp, (pc & 0xFF) as u8);
+    self.registers.set_sp(sp);
+
+    if pending == 0 {
+      self.registers.set_pc(0x0000);
+    } else {
       let interrupt_flag = self.ram.read(INTERRUPT_FLAG_ADDRESS);
-      let interrupt_enable = self.ram.read(INTERRUPT_ENABLE_ADDRESS);
-      if interrupt_flag & interrupt_enable != 0 {
-        self.previous_ime = self.interrupt_master_enable;
-        self.interrupt_master_enable = false;
+      let interrupt_vector = self.get_interrupt_vector(pending);
+      let interrupt_handler = self.get_interrupt_handler(&interrupt_vector);
+      self.ram.write(INTERRUPT_FLAG_ADDRESS, interrupt_flag & !(interrupt_vector as u8));
+      self.registers.set_pc(interrupt_handler as u16);
+    }
+    true
+  }
+}
 
-        let mut sp = self.registers.get_sp();
-        push_16bit!(self, &mut sp, get_pc);
-        self.registers.set_sp(sp);
+/*
+Instructions are built from the mnemonic and operand templates in opcodes.rs, the same
+entries that give their size, timing and disassembly, so decoding can't drift from those.
+*/
+fn decode(opcode: u8, entry: &opcodes::Opcode, n8: u8, n16: u16) -> Instruction {
+  use opcodes::{A16, A8, E8, N16, N8, S8, SP_E8};
+
+  match (entry.mnemonic, entry.operands) {
+    ("NOP", []) => Instruction::NOP,
+    ("STOP", []) => Instruction::STOP,
+    ("HALT", []) => Instruction::HALT,
+    ("DI", []) => Instruction::DI,
+    ("EI", []) => Instruction::EI,
+    ("DAA", []) => Instruction::DAA,
+    ("CPL", []) => Instruction::CPL,
+    ("SCF", []) => Instruction::SCF,
+    ("CCF", []) => Instruction::CCF,
+    ("RLCA", []) => Instruction::RL(true),
+    ("RLA", []) => Instruction::RL(false),
+    ("RRCA", []) => Instruction::RR(true),
+    ("RRA", []) => Instruction::RR(false),
+    ("PREFIX", []) => Instruction::PREFIX_CB(n8),
+    ("ILLEGAL", []) => Instruction::ILLEGAL(opcode),
+
+    // Loads through HL
+    ("LD", ["[HL]", N8]) => Instruction::LD_MEM_IMM(n8),
+    ("LD", ["[HL+]", "A"]) => Instruction::LD_MEM_INC(true, false),
+    ("LD", ["[HL-]", "A"]) => Instruction::LD_MEM_INC(false, false),
+    ("LD", ["A", "[HL+]"]) => Instruction::LD_MEM_INC(true, true),
+    ("LD", ["A", "[HL-]"]) => Instruction::LD_MEM_INC(false, true),
+    ("LD", ["[HL]", source]) => Instruction::LD_MEM_REG(register(source)),
+    ("LD", [destination, "[HL]"]) => Instruction::LD_REG_MEM(register(destination)),
+
+    // Other loads to and from memory
+    ("LD", [address @ ("[BC]" | "[DE]"), "A"]) => {
+      let (high, low) = register_pair(&address[1..3]);
+      Instruction::LD_BCDE(high, low, false)
+    }
+    ("LD", ["A", address @ ("[BC]" | "[DE]")]) => {
+      let (high, low) = register_pair(&address[1..3]);
+      Instruction::LD_BCDE(high, low, true)
+    }
+    ("LD", ["A", A16]) => Instruction::LD_IMM_16(n16, true),
+    ("LD", [A16, "A"]) => Instruction::LD_IMM_16(n16, false),
+    ("LD", [A16, "SP"]) => Instruction::STORE_SP(n16),
+    ("LDH", ["A", A8]) => Instruction::LD_IMM_8(n8, true),
+    ("LDH", [A8, "A"]) => Instruction::LD_IMM_8(n8, false),
+    ("LDH", ["A", "[C]"]) => Instruction::LD_AC(true),
+    ("LDH", ["[C]", "A"]) => Instruction::LD_AC(false),
+
+    // Loads between registers
+    ("LD", ["HL", SP_E8]) => Instruction::LD_HL_SP(n8 as i8),
+    ("LD", ["SP", "HL"]) => Instruction::LD_SP_HL,
+    ("LD", [pair, N16]) => {
+      let (high, low) = register_pair(pair);
+      Instruction::LD_REG_IMM_16(high, low, n16)
+    }
+    ("LD", [destination, N8]) => Instruction::LD_REG_IMM(register(destination), n8),
+    ("LD", [destination, source]) => Instruction::LD_RR(register(destination), register(source)),
+
+    ("INC", ["[HL]"]) => Instruction::MOD_MEM(true),
+    ("DEC", ["[HL]"]) => Instruction::MOD_MEM(false),
+    ("INC", [pair @ ("BC" | "DE" | "HL" | "SP")]) => {
+      let (high, low) = register_pair(pair);
+      Instruction::INC_16(high, low)
+    }
+    ("DEC", [pair @ ("BC" | "DE" | "HL" | "SP")]) => {
+      let (high, low) = register_pair(pair);
+      Instruction::DEC_16(high, low)
+    }
+    ("INC", [target]) => Instruction::INC(register(target)),
+    ("DEC", [target]) => Instruction::DEC(register(target)),
+    ("ADD", ["HL", pair]) => {
+      let (high, low) = register_pair(pair);
+      Instruction::ADD_HL(high, low)
+    }
+    ("ADD", ["SP", S8]) => Instruction::INC_SP(n8 as i8),
+    ("ADD" | "ADC" | "SBC", ["A", operand]) | ("SUB" | "AND" | "XOR" | "OR" | "CP", [operand]) => {
+      decode_alu(entry.mnemonic, operand, n8)
+    }
+
+    ("JP", ["HL"]) => Instruction::JP_HL(),
+    ("JP", [N16]) => Instruction::JP(false, false, false, n16),
+    ("JP", [condition, N16]) => {
+      let (carry, zero, not) = decode_condition(condition);
+      Instruction::JP(carry, zero, not, n16)
+    }
+    ("JR", [E8]) => Instruction::JR(false, false, false, n8 as i8),
+    ("JR", [condition, E8]) => {
+      let (carry, zero, not) = decode_condition(condition);
+      Instruction::JR(carry, zero, not, n8 as i8)
+    }
+    ("CALL", [N16]) => Instruction::CALL(n16, false, false, false),
+    ("CALL", [condition, N16]) => {
+      let (carry, zero, not) = decode_condition(condition);
+      Instruction::CALL(n16, carry, zero, not)
+    }
+    ("RET", []) => Instruction::RET(false, false, false),
+    ("RETI", []) => Instruction::RET(false, false, true),
+    ("RET", ["NZ"]) => Instruction::RET_N(false, true),
+    ("RET", ["NC"]) => Instruction::RET_N(true, false),
+    ("RET", ["Z"]) => Instruction::RET(false, true, false),
+    ("RET", ["C"]) => Instruction::RET(true, false, false),
+    ("RST", [target]) => Instruction::RST(u8::from_str_radix(&target[1..], 16).expect("RST targets are written as $XX")),
+
+    ("PUSH", [pair]) => {
+      let (high, low) = register_pair(pair);
+      Instruction::PUSH(high, low)
+    }
+    ("POP", [pair]) => {
+      let (high, low) = register_pair(pair);
+      Instruction::POP(high, low)
+    }
+
+    _ => unreachable!("No instruction for opcode {:#04X} {:?}", opcode, entry),
+  }
+}
+
+impl Instruction {
+  // The same instruction with the given immediates in place of its own
+  fn with_immediates(self, n8: u8, n16: u16) -> Instruction {
+    match self {
+      Instruction::ADD_IMM(_) => Instruction::ADD_IMM(n8),
+      Instruction::ADC_IMM(_) => Instruction::ADC_IMM(n8),
+      Instruction::SUB_IMM(_) => Instruction::SUB_IMM(n8),
+      Instruction::SBC_IMM(_) => Instruction::SBC_IMM(n8),
+      Instruction::AND_IMM(_) => Instruction::AND_IMM(n8),
+      Instruction::OR_IMM(_) => Instruction::OR_IMM(n8),
+      Instruction::XOR_IMM(_) => Instruction::XOR_IMM(n8),
+      Instruction::CP_IMM(_) => Instruction::CP_IMM(n8),
+      Instruction::CALL(_, carry, zero, not) => Instruction::CALL(n16, carry, zero, not),
+      Instruction::JR(carry, zero, not, _) => Instruction::JR(carry, zero, not, n8 as i8),
+      Instruction::JP(carry, zero, not, _) => Instruction::JP(carry, zero, not, n16),
+      Instruction::LD_MEM_IMM(_) => Instruction::LD_MEM_IMM(n8),
+      Instruction::LD_REG_IMM(target, _) => Instruction::LD_REG_IMM(target, n8),
+      Instruction::LD_IMM_16(_, load) => Instruction::LD_IMM_16(n16, load),
+      Instruction::LD_IMM_8(_, load) => Instruction::LD_IMM_8(n8, load),
+      Instruction::LD_REG_IMM_16(high, low, _) => Instruction::LD_REG_IMM_16(high, low, n16),
+      Instruction::STORE_SP(_) => Instruction::STORE_SP(n16),
+      Instruction::INC_SP(_) => Instruction::INC_SP(n8 as i8),
+      Instruction::LD_HL_SP(_) => Instruction::LD_HL_SP(n8 as i8),
+      Instruction::PREFIX_CB(_) => Instruction::PREFIX_CB(n8),
+      other => other,
+    }
+  }
+}
 
-        let interrupt_vector = self.get_interrupt_vector(interrupt_flag);
-        let interrupt_handler = self.get_interrupt_handler(&interrupt_vector);
-        self.ram.write(INTERRUPT_FLAG_ADDRESS, interrupt_flag & !(interrupt_vector as u8));
-        self.registers.set_pc(interrupt_handler as u16);
+// The 8-bit arithmetic and logic operations, on a register, (HL) or an immediate
+fn decode_alu(mnemonic: &str, operand: &str, n8: u8) -> Instruction {
+  match operand {
+    opcodes::N8 => match mnemonic {
+      "ADD" => Instruction::ADD_IMM(n8),
+      "ADC" => Instruction::ADC_IMM(n8),
+      "SUB" => Instruction::SUB_IMM(n8),
+      "SBC" => Instruction::SBC_IMM(n8),
+      "AND" => Instruction::AND_IMM(n8),
+      "XOR" => Instruction::XOR_IMM(n8),
+      "OR" => Instruction::OR_IMM(n8),
+      _ => Instruction::CP_IMM(n8),
+    },
+    "[HL]" => match mnemonic {
+      "ADD" => Instruction::ADD_MEM,
+      "ADC" => Instruction::ADC_MEM,
+      "SUB" => Instruction::SUB_MEM,
+      "SBC" => Instruction::SBC_MEM,
+      "AND" => Instruction::AND_MEM,
+      "XOR" => Instruction::XOR_MEM,
+      "OR" => Instruction::OR_MEM,
+      _ => Instruction::CP_MEM,
+    },
+    source => {
+      let source = register(source);
+      match mnemonic {
+        "ADD" => Instruction::ADD(source),
+        "ADC" => Instruction::ADC(source),
+        "SUB" => Instruction::SUB(source),
+        "SBC" => Instruction::SBC(source),
+        "AND" => Instruction::AND(source),
+        "XOR" => Instruction::XOR(source),
+        "OR" => Instruction::OR(source),
+        _ => Instruction::CP(source),
       }
     }
   }
 }
 
+fn register(name: &str) -> ArithmeticTarget {
+  match name {
+    "A" => ArithmeticTarget::A,
+    "B" => ArithmeticTarget::B,
+    "C" => ArithmeticTarget::C,
+    "D" => ArithmeticTarget::D,
+    "E" => ArithmeticTarget::E,
+    "H" => ArithmeticTarget::H,
+    "L" => ArithmeticTarget::L,
+    _ => unreachable!("Not a register: {}", name),
+  }
+}
+
+// High and low halves of a 16-bit register. SP is passed as both
+fn register_pair(name: &str) -> (ArithmeticTarget, ArithmeticTarget) {
+  match name {
+    "AF" => (ArithmeticTarget::A, ArithmeticTarget::F),
+    "BC" => (ArithmeticTarget::B, ArithmeticTarget::C),
+    "DE" => (ArithmeticTarget::D, ArithmeticTarget::E),
+    "HL" => (ArithmeticTarget::H, ArithmeticTarget::L),
+    "SP" => (ArithmeticTarget::SP, ArithmeticTarget::SP),
+    _ => unreachable!("Not a register pair: {}", name),
+  }
+}
+
+// Branch conditions as the carry, zero and negated flags jumps, calls and returns take
+fn decode_condition(name: &str) -> (bool, bool, bool) {
+  match name {
+    "NZ" => (false, true, true),
+    "Z" => (false, true, false),
+    "NC" => (true, false, true),
+    "C" => (true, false, false),
+    _ => unreachable!("Not a condition: {}", name),
+  }
+}
+
+impl CPU<'_> {
+  // M-cycle mode is a host setting and stays as it is
+  pub fn save_state(&self, writer: &mut StateWriter) {
+    for pair in [self.registers.get_af(), self.registers.get_bc(), self.registers.get_de(), self.registers.get_hl()] {
+      writer.write_u16(pair);
+    }
+    writer.write_u16(self.registers.get_sp());
+    writer.write_u16(self.registers.get_pc());
+    writer.write_bool(self.interrupt_master_enable);
+    writer.write_bool(self.ime_pending);
+    writer.write_bool(self.halted);
+    writer.write_bool(self.halt_bug);
+    writer.write_bool(self.stopped);
+    writer.write_u64(self.clock_cycles);
+    writer.write_bool(self.lockup.is_some());
+    let lockup = self.lockup.unwrap_or(Lockup { opcode: 0, address: 0 });
+    writer.write_u8(lockup.opcode);
+    writer.write_u16(lockup.address);
+  }
+
+  pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+    self.registers.set_af(reader.read_u16()?);
+    self.registers.set_bc(reader.read_u16()?);
+    self.registers.set_de(reader.read_u16()?);
+    self.registers.set_hl(reader.read_u16()?);
+    self.registers.set_sp(reader.read_u16()?);
+    self.registers.set_pc(reader.read_u16()?);
+    self.interrupt_master_enable = reader.read_bool()?;
+    self.ime_pending = reader.read_bool()?;
+    self.halted = reader.read_bool()?;
+    self.halt_bug = reader.read_bool()?;
+    self.stopped = reader.read_bool()?;
+    self.clock_cycles = reader.read_u64()?;
+    // Older states end here
+    self.lockup = None;
+    if !reader.is_empty() {
+      let locked = reader.read_bool()?;
+      let lockup = Lockup { opcode: reader.read_u8()?, address: reader.read_u16()? };
+      self.lockup = locked.then_some(lockup);
+    }
+    Ok(())
+  }
+}
@@ -1,9 +1,14 @@
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::gb::cpu::{CPU, Instruction, ArithmeticTarget};
+    use crate::gb::cpu::{CPU, Instruction, ArithmeticTarget, Lockup};
     use crate::gb::ram::RAM;
     use crate::gb::register::Flags;
+    use crate::gb::ram::{Access, Watchpoint};
+    use crate::gb::opcodes;
+    use serde_json::Value;
+    use std::collections::BTreeMap;
+    use std::panic::{self, AssertUnwindSafe};
 
     // Helper function to create a CPU with specific initial state
     fn create_cpu_with_state(
@@ -98,8 +103,8 @@ mod tests {
         let mut ram = RAM::new();
         let mut cpu = create_cpu_with_state(0x33, 0x22, 0x33, 0x44, 0x55, 0x00, 0x77, 0x88, 0x1234, 0x5678, &mut ram);
         cpu.execute(Instruction::SUB(ArithmeticTarget::B));
-        assert_registers(&cpu, 0x11, 0x22, 0x33, 0x44, 0x55, 0x60, 0x77, 0x88, 0x1234, 0x5678);
-        assert_flags(&cpu, false, true, true, false);
+        assert_registers(&cpu, 0x11, 0x22, 0x33, 0x44, 0x55, 0x40, 0x77, 0x88, 0x1234, 0x5678);
+        assert_flags(&cpu, false, true, false, false);
     }
 
     #[test]
@@ -110,8 +115,8 @@ mod tests {
         cpu.ram.write(0, 0x90); // SUB A, B opcode
         let cycles = cpu.step();
         assert_eq!(cycles, 4, "SUB A, B should take 4 cycles");
-        assert_registers(&cpu, 0xFF, 0x01, 0, 0, 0, 0x50, 0, 0, 0, 1);
-        assert_flags(&cpu, false, true, false, true);
+        assert_registers(&cpu, 0xFF, 0x01, 0, 0, 0, 0x70, 0, 0, 0, 1);
+        assert_flags(&cpu, false, true, true, true);
     }
 
     #[test]
@@ -141,7 +146,7 @@ mod tests {
         cpu.ram.write(1, 0x34); // Low byte of address
         cpu.ram.write(2, 0x12); // High byte of address
         let cycles = cpu.step();
-        assert_eq!(cycles, 12, "JP a16 should take 12 cycles");
+        assert_eq!(cycles, 16, "JP a16 should take 16 cycles");
         assert_registers(&cpu, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x1234);
     }
 
@@ -153,8 +158,9 @@ mod tests {
         cpu.ram.write(0, 0x18); // JR e8 opcode
         cpu.ram.write(1, 0x10); // Jump offset
         let cycles = cpu.step();
-        assert_eq!(cycles, 8, "JR e8 should take 8 cycles");
-        assert_registers(&cpu, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x10);
+        assert_eq!(cycles, 12, "JR e8 should take 12 cycles");
+        // The offset is relative to the instruction after the JR
+        assert_registers(&cpu, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x12);
     }
 
     #[test]
@@ -210,4 +216,930 @@ mod tests {
         cpu.execute(Instruction::INC_16(ArithmeticTarget::B, ArithmeticTarget::C));
         assert_eq!(cpu.registers.get_bc(), 0x2234);
     }
-} 
\ No newline at end of file
+
+    #[test]
+    fn test_cb_instructions() {
+        let mut ram = RAM::new();
+        let mut cpu = create_cpu_with_state(0xF1, 0x80, 0, 0, 0, 0x10, 0xC0, 0x00, 0xFFFE, 0, &mut ram);
+        cpu.ram.write(0, 0xCB); // SWAP A
+        cpu.ram.write(1, 0x37);
+        cpu.ram.write(2, 0xCB); // RL B
+        cpu.ram.write(3, 0x10);
+        cpu.ram.write(4, 0xCB); // SET 3, (HL)
+        cpu.ram.write(5, 0xDE);
+        cpu.ram.write(6, 0xCB); // BIT 3, (HL)
+        cpu.ram.write(7, 0x5E);
+
+        assert_eq!(cpu.step(), 8);
+        assert_eq!(cpu.registers.get_a(), 0x1F);
+        assert_flags(&cpu, false, false, false, false);
+
+        assert_eq!(cpu.step(), 8);
+        assert_eq!(cpu.registers.get_b(), 0x00, "RL B shifts the clear carry in");
+        assert_flags(&cpu, true, false, false, true);
+
+        assert_eq!(cpu.step(), 16);
+        assert_eq!(cpu.ram.read(0xC000), 0x08);
+
+        assert_eq!(cpu.step(), 12);
+        assert_flags(&cpu, false, false, true, true);
+        assert_eq!(cpu.registers.get_pc(), 8);
+    }
+
+    #[test]
+    fn test_ldh_sizes() {
+        // LDH (a8), A is two bytes and LD (C), A is one
+        let mut ram = RAM::new();
+        let mut cpu = create_cpu_with_state(0x42, 0, 0x81, 0, 0, 0, 0, 0, 0xFFFE, 0, &mut ram);
+        cpu.ram.write(0, 0xE0);
+        cpu.ram.write(1, 0x80);
+        cpu.ram.write(2, 0xE2);
+        assert_eq!(cpu.step(), 12);
+        assert_eq!(cpu.registers.get_pc(), 2);
+        assert_eq!(cpu.step(), 8);
+        assert_eq!(cpu.registers.get_pc(), 3);
+        assert_eq!(cpu.ram.read(0xFF80), 0x42);
+        assert_eq!(cpu.ram.read(0xFF81), 0x42);
+    }
+
+    #[test]
+    fn test_conditional_branch_cycles() {
+        // (opcode, operands, taken cycles, not taken cycles) for the NZ variants
+        let branches = [
+            (0x20, [0x05, 0x00], 12, 8),  // JR NZ, e8
+            (0xC2, [0x00, 0x40], 16, 12), // JP NZ, a16
+            (0xC4, [0x00, 0x40], 24, 12), // CALL NZ, a16
+            (0xC0, [0x00, 0x00], 20, 8),  // RET NZ
+        ];
+        for (opcode, operands, taken, not_taken) in branches {
+            for (zero, expected) in [(false, taken), (true, not_taken)] {
+                let mut ram = RAM::new();
+                let f = Flags { zero, subtract: false, half_carry: false, carry: false }.to_u8();
+                let mut cpu = create_cpu_with_state(0, 0, 0, 0, 0, f, 0, 0, 0xFFFC, 0x0100, &mut ram);
+                cpu.ram.write(0x0100, opcode);
+                cpu.ram.write(0x0101, operands[0]);
+                cpu.ram.write(0x0102, operands[1]);
+                assert_eq!(cpu.step(), expected, "opcode {:#04X} with Z={}", opcode, zero);
+            }
+        }
+    }
+
+    #[test]
+    fn test_call_ret_rst() {
+        let mut ram = RAM::new();
+        let mut cpu = create_cpu_with_state(0, 0, 0, 0, 0, 0, 0, 0, 0xFFFE, 0x0100, &mut ram);
+        cpu.ram.write(0x0100, 0xCD); // CALL 0x0200
+        cpu.ram.write(0x0101, 0x00);
+        cpu.ram.write(0x0102, 0x02);
+        cpu.ram.write(0x0200, 0xFF); // RST 38H
+        cpu.ram.write(0x0038, 0xC9); // RET
+        cpu.ram.write(0x0201, 0xC9); // RET
+
+        assert_eq!(cpu.step(), 24);
+        assert_eq!(cpu.registers.get_pc(), 0x0200);
+        assert_eq!(cpu.ram.read(0xFFFC), 0x03, "CALL pushes the address after itself");
+
+        assert_eq!(cpu.step(), 16);
+        assert_eq!(cpu.registers.get_pc(), 0x0038);
+        assert_eq!(cpu.ram.read(0xFFFA), 0x01, "RST pushes the address after itself");
+
+        assert_eq!(cpu.step(), 16);
+        assert_eq!(cpu.registers.get_pc(), 0x0201);
+        assert_eq!(cpu.step(), 16);
+        assert_registers(&cpu, 0, 0, 0, 0, 0, 0, 0, 0, 0xFFFE, 0x0103);
+    }
+
+    #[test]
+    fn test_jr_to_self() {
+        let mut ram = RAM::new();
+        let mut cpu = create_cpu_with_state(0, 0, 0, 0, 0, 0, 0, 0, 0xFFFE, 0x0150, &mut ram);
+        cpu.ram.write(0x0150, 0x18); // JR -2
+        cpu.ram.write(0x0151, 0xFE);
+        for _ in 0..3 {
+            assert_eq!(cpu.step(), 12);
+            assert_eq!(cpu.registers.get_pc(), 0x0150);
+        }
+        assert_eq!(cpu.clock_cycles, 36);
+    }
+
+    // Helper function to request and enable interrupts
+    fn request_interrupts(cpu: &mut CPU, flags: u8, enable: u8) {
+        cpu.ram.write(0xFF0F, flags);
+        cpu.ram.write(0xFFFF, enable);
+    }
+
+    #[test]
+    fn test_interrupt_dispatch() {
+        let mut ram = RAM::new();
+        let mut cpu = create_cpu_with_state(0, 0, 0, 0, 0, 0, 0, 0, 0xFFFE, 0x1234, &mut ram);
+        cpu.interrupt_master_enable = true;
+        request_interrupts(&mut cpu, 0x14, 0x1F); // Timer and joypad
+
+        assert_eq!(cpu.step(), 20);
+        assert_eq!(cpu.registers.get_pc(), 0x0050, "Timer has priority over joypad");
+        assert_eq!(cpu.registers.get_sp(), 0xFFFC);
+        assert_eq!((cpu.ram.read(0xFFFD), cpu.ram.read(0xFFFC)), (0x12, 0x34));
+        assert_eq!(cpu.ram.read(0xFF0F) & 0x1F, 0x10, "Only the serviced request is cleared");
+        assert!(!cpu.interrupt_master_enable);
+    }
+
+    #[test]
+    fn test_ei_delay() {
+        let mut ram = RAM::new();
+        let mut cpu = create_cpu_with_state(0, 0, 0, 0, 0, 0, 0, 0, 0xFFFE, 0, &mut ram);
+        cpu.ram.write(0, 0xFB); // EI
+        cpu.ram.write(1, 0x00); // NOP
+        cpu.ram.write(2, 0x00); // NOP
+        request_interrupts(&mut cpu, 0x01, 0x01);
+
+        cpu.step();
+        assert!(!cpu.interrupt_master_enable, "IME is only set after the next instruction");
+        assert_eq!(cpu.step(), 4);
+        assert_eq!(cpu.registers.get_pc(), 2);
+        assert_eq!(cpu.step(), 20);
+        assert_eq!(cpu.registers.get_pc(), 0x0040);
+        assert_eq!(cpu.ram.read(0xFFFC), 0x02, "The instruction after EI ran before the interrupt");
+    }
+
+    #[test]
+    fn test_ei_di_never_enables() {
+        let mut ram = RAM::new();
+        let mut cpu = create_cpu_with_state(0, 0, 0, 0, 0, 0, 0, 0, 0xFFFE, 0, &mut ram);
+        cpu.ram.write(0, 0xFB); // EI
+        cpu.ram.write(1, 0xF3); // DI
+        request_interrupts(&mut cpu, 0x01, 0x01);
+        cpu.step();
+        cpu.step();
+        cpu.step();
+        assert!(!cpu.interrupt_master_enable);
+        assert_eq!(cpu.registers.get_pc(), 3);
+    }
+
+    #[test]
+    fn test_reti_enables_interrupts() {
+        let mut ram = RAM::new();
+        let mut cpu = create_cpu_with_state(0, 0, 0, 0, 0, 0, 0, 0, 0xFFFC, 0x0040, &mut ram);
+        cpu.ram.write(0x0040, 0xD9); // RETI
+        cpu.ram.write(0xFFFC, 0x00);
+        cpu.ram.write(0xFFFD, 0x02);
+        request_interrupts(&mut cpu, 0x04, 0x04);
+
+        assert_eq!(cpu.step(), 16);
+        assert_eq!(cpu.registers.get_pc(), 0x0200);
+        assert!(cpu.interrupt_master_enable);
+        assert_eq!(cpu.step(), 20, "RETI has no delay");
+        assert_eq!(cpu.registers.get_pc(), 0x0050);
+    }
+
+    #[test]
+    fn test_halt() {
+        let mut ram = RAM::new();
+        let mut cpu = create_cpu_with_state(0, 0, 0, 0, 0, 0, 0, 0, 0xFFFE, 0, &mut ram);
+        cpu.ram.write(0, 0x76); // HALT
+        cpu.ram.write(1, 0x04); // INC B
+        cpu.ram.write(0xFFFF, 0x01);
+
+        cpu.step();
+        assert!(cpu.halted);
+        for _ in 0..10 {
+            assert_eq!(cpu.step(), 4);
+        }
+        assert_eq!(cpu.registers.get_pc(), 1);
+
+        // With IME off a pending interrupt wakes the CPU without being serviced
+        cpu.ram.write(0xFF0F, 0x01);
+        cpu.step();
+        assert!(!cpu.halted);
+        assert_eq!(cpu.registers.get_b(), 1);
+        assert_eq!(cpu.registers.get_pc(), 2);
+    }
+
+    #[test]
+    fn test_halt_bug() {
+        // HALT with IME off and an interrupt already pending runs the next byte twice
+        let mut ram = RAM::new();
+        let mut cpu = create_cpu_with_state(0, 0, 0, 0, 0, 0, 0, 0, 0xFFFE, 0, &mut ram);
+        cpu.ram.write(0, 0x76); // HALT
+        cpu.ram.write(1, 0x04); // INC B
+        request_interrupts(&mut cpu, 0x01, 0x01);
+        cpu.step();
+        assert!(!cpu.halted);
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.registers.get_b(), 2);
+        assert_eq!(cpu.registers.get_pc(), 2);
+    }
+
+    #[test]
+    fn test_halt_bug_with_operand() {
+        // The byte after HALT is read as the opcode and again as its operand
+        let mut ram = RAM::new();
+        let mut cpu = create_cpu_with_state(0, 0, 0, 0, 0, 0, 0, 0, 0xFFFE, 0, &mut ram);
+        cpu.ram.write(0, 0x76); // HALT
+        cpu.ram.write(1, 0x3E); // LD A, 0x14
+        cpu.ram.write(2, 0x14); // INC D, once the halt bug has shifted everything
+        request_interrupts(&mut cpu, 0x01, 0x01);
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.registers.get_a(), 0x3E);
+        assert_eq!(cpu.registers.get_pc(), 2);
+        cpu.step();
+        assert_eq!(cpu.registers.get_d(), 1);
+        assert_eq!(cpu.registers.get_pc(), 3);
+    }
+
+    #[test]
+    fn test_interrupt_check_skips_watchpoints() {
+        let mut ram = RAM::new();
+        let mut cpu = create_cpu_with_state(0, 0, 0, 0, 0, 0, 0, 0, 0xFFFE, 0, &mut ram);
+        for address in [0xFF0F, 0xFFFF] {
+            cpu.ram.watchpoints.push(Watchpoint { address, on_read: true, on_write: true });
+        }
+        cpu.step(); // NOP
+        assert_eq!(cpu.ram.take_watch_hit(), None, "Only the program's own accesses count");
+    }
+
+    #[test]
+    fn test_ei_halt_with_interrupt_pending() {
+        // The interrupt is serviced straight away and HALT runs again once it returns
+        let mut ram = RAM::new();
+        let mut cpu = create_cpu_with_state(0, 0, 0, 0, 0, 0, 0, 0, 0xFFFE, 0, &mut ram);
+        cpu.ram.write(0, 0xFB); // EI
+        cpu.ram.write(1, 0x76); // HALT
+        cpu.ram.write(0x40, 0xD9); // RETI
+        request_interrupts(&mut cpu, 0x01, 0x01);
+
+        cpu.step();
+        cpu.step();
+        assert!(!cpu.halted);
+        assert_eq!(cpu.step(), 20, "The interrupt is serviced");
+        assert_eq!(cpu.registers.get_pc(), 0x40);
+        assert_eq!(cpu.ram.read(0xFFFC), 0x01, "Returns to the HALT");
+        cpu.step();
+        assert_eq!(cpu.registers.get_pc(), 1);
+        cpu.step();
+        assert!(cpu.halted);
+        assert_eq!(cpu.registers.get_pc(), 2);
+    }
+
+    #[test]
+    fn test_ie_write_during_push_cancels_interrupt() {
+        // With SP at 0x0000 the high byte of PC is pushed onto IE
+        let mut ram = RAM::new();
+        let mut cpu = create_cpu_with_state(0, 0, 0, 0, 0, 0, 0, 0, 0x0000, 0x0200, &mut ram);
+        cpu.interrupt_master_enable = true;
+        request_interrupts(&mut cpu, 0x01, 0x01);
+        assert_eq!(cpu.step(), 20);
+        assert_eq!(cpu.registers.get_pc(), 0x0000, "IE lost the VBlank bit, nothing is serviced");
+        assert_eq!(cpu.ram.read(0xFF0F) & 0x1F, 0x01, "The request stays pending");
+
+        // A lower priority interrupt that is still enabled gets serviced instead
+        let mut ram = RAM::new();
+        let mut cpu = create_cpu_with_state(0, 0, 0, 0, 0, 0, 0, 0, 0x0000, 0x0200, &mut ram);
+        cpu.interrupt_master_enable = true;
+        request_interrupts(&mut cpu, 0x03, 0x03);
+        cpu.step();
+        assert_eq!(cpu.registers.get_pc(), 0x0048);
+        assert_eq!(cpu.ram.read(0xFF0F) & 0x1F, 0x01);
+    }
+
+    #[test]
+    fn test_illegal_opcode_locks_up() {
+        let mut ram = RAM::new();
+        ram.write(0x0000, 0x00); // NOP
+        ram.write(0x0001, 0xDD);
+        ram.write(0xFFFF, 0x1F); // Every interrupt enabled
+        let mut cpu = create_cpu_with_state(0, 0, 0, 0, 0, 0, 0, 0, 0xFFFE, 0, &mut ram);
+        cpu.interrupt_master_enable = true;
+        cpu.step();
+        assert_eq!(cpu.step(), 4);
+        assert_eq!(cpu.lockup, Some(Lockup { opcode: 0xDD, address: 0x0001 }));
+        assert_eq!(cpu.lockup.unwrap().to_string(), "illegal opcode 0xDD at 0x0001");
+
+        // Not even an interrupt gets it going again, but time still passes
+        cpu.ram.write(0xFF0F, 0x01);
+        for _ in 0..10 {
+            assert_eq!(cpu.step(), 4);
+        }
+        assert_eq!(cpu.registers.get_pc(), 0x0001);
+        assert_eq!(cpu.registers.get_sp(), 0xFFFE);
+        assert_eq!(cpu.clock_cycles, 48);
+
+        cpu.reset();
+        assert_eq!(cpu.lockup, None);
+    }
+
+    #[test]
+    fn test_stop() {
+        let mut ram = RAM::new();
+        for (i, &byte) in [0x10, 0x00, 0x00].iter().enumerate() {
+            ram.write(i as u16, byte); // STOP; NOP
+        }
+        let mut cpu = create_cpu_with_state(0, 0, 0, 0, 0, 0, 0, 0, 0xFFFE, 0, &mut ram);
+        cpu.ram.tick(1024);
+        assert_ne!(cpu.ram.read(0xFF04), 0);
+        cpu.step();
+        assert!(cpu.stopped);
+        assert_eq!(cpu.registers.get_pc(), 0x0002);
+        assert_eq!(cpu.ram.read(0xFF04), 0, "STOP resets DIV");
+
+        for _ in 0..10 {
+            cpu.step();
+        }
+        assert_eq!(cpu.registers.get_pc(), 0x0002);
+
+        // A button press wakes it up even with the joypad interrupt disabled
+        cpu.ram.write(0xFF0F, 0x10);
+        cpu.step();
+        assert!(!cpu.stopped);
+        cpu.step();
+        assert_eq!(cpu.registers.get_pc(), 0x0003);
+    }
+
+    #[test]
+    fn test_address_wraparound() {
+        let mut ram = RAM::flat();
+        let mut cpu = create_cpu_with_state(0, 0x12, 0x34, 0, 0, 0, 0xFF, 0xFF, 0x0001, 0, &mut ram);
+        cpu.execute(Instruction::PUSH(ArithmeticTarget::B, ArithmeticTarget::C));
+        assert_eq!((cpu.ram.read(0x0000), cpu.ram.read(0xFFFF)), (0x12, 0x34));
+        assert_eq!(cpu.registers.get_sp(), 0xFFFF);
+        cpu.execute(Instruction::POP(ArithmeticTarget::D, ArithmeticTarget::E));
+        assert_eq!(cpu.registers.get_de(), 0x1234);
+        assert_eq!(cpu.registers.get_sp(), 0x0001);
+
+        cpu.execute(Instruction::LD_MEM_INC(true, false)); // LD (HL+), A at 0xFFFF
+        assert_eq!(cpu.registers.get_hl(), 0x0000);
+        cpu.execute(Instruction::LD_MEM_INC(false, true)); // LD A, (HL-) at 0x0000
+        assert_eq!(cpu.registers.get_hl(), 0xFFFF);
+        cpu.execute(Instruction::INC_16(ArithmeticTarget::H, ArithmeticTarget::L));
+        assert_eq!(cpu.registers.get_hl(), 0x0000);
+        cpu.execute(Instruction::DEC_16(ArithmeticTarget::B, ArithmeticTarget::C));
+        cpu.execute(Instruction::DEC_16(ArithmeticTarget::H, ArithmeticTarget::L));
+        assert_eq!(cpu.registers.get_hl(), 0xFFFF);
+        cpu.execute(Instruction::STORE_SP(0xFFFF));
+        assert_eq!((cpu.ram.read(0xFFFF), cpu.ram.read(0x0000)), (0x01, 0x00));
+
+        // Fetching runs off the end of memory and carries on from the start
+        cpu.ram.write(0xFFFF, 0x3E); // LD A, d8
+        cpu.ram.write(0x0000, 0x42);
+        cpu.registers.set_pc(0xFFFF);
+        cpu.step();
+        assert_eq!(cpu.registers.get_a(), 0x42);
+        assert_eq!(cpu.registers.get_pc(), 0x0001);
+    }
+
+    // Helper function for a repeatable stream of pseudo random numbers
+    fn next_random(seed: &mut u32) -> u32 {
+        *seed ^= *seed << 13;
+        *seed ^= *seed >> 17;
+        *seed ^= *seed << 5;
+        *seed
+    }
+
+    // Random bytes everywhere, peripherals included, have to run without the CPU panicking
+    #[test]
+    fn test_random_programs_never_panic() {
+        for run in 0..32 {
+            let mut seed = 0x9E37_79B9 ^ run;
+            let mut ram = RAM::new();
+            for address in 0..=0xFFFF {
+                ram.write(address, next_random(&mut seed) as u8);
+            }
+            let mut cpu = CPU::new(&mut ram);
+            cpu.registers.set_af(next_random(&mut seed) as u16);
+            cpu.registers.set_bc(next_random(&mut seed) as u16);
+            cpu.registers.set_de(next_random(&mut seed) as u16);
+            cpu.registers.set_hl(next_random(&mut seed) as u16);
+            cpu.registers.set_sp(next_random(&mut seed) as u16);
+            cpu.registers.set_pc(next_random(&mut seed) as u16);
+            cpu.interrupt_master_enable = run % 3 == 0;
+            cpu.m_cycle_mode = run % 2 == 1;
+            for _ in 0..10_000 {
+                cpu.step();
+                // Carry on somewhere else rather than sit out the rest of the run hung or asleep
+                if cpu.lockup.is_some() || cpu.halted || cpu.stopped {
+                    cpu.reset();
+                    cpu.registers.set_sp(next_random(&mut seed) as u16);
+                    cpu.registers.set_pc(next_random(&mut seed) as u16);
+                }
+            }
+        }
+    }
+
+    /*
+    Single step tests: community suites (SingleStepTests' sm83, formerly GameboyCPUTests) with
+    a JSON file per opcode, each holding cases like
+
+      { "name": "80 0000",
+        "initial": { "pc": 256, "sp": 65534, "a": 1, "b": 2, ..., "ime": 0, "ram": [[256, 128]] },
+        "final": { ... },
+        "cycles": [[256, 128, "r-m"], [null, null, "---"]] }
+
+    Every case runs one instruction from its initial state on flat memory and has to end in
+    the final one. The cycles list has one entry per M-cycle, "r-m" for a read, "-wm" for a
+    write and "---" for an internal one.
+     */
+
+    const REGISTERS: [&str; 10] = ["a", "b", "c", "d", "e", "f", "h", "l", "sp", "pc"];
+
+    // Helper function to read a number out of a single step test, null or missing being None
+    fn json_number(value: &Value) -> Option<u16> {
+        value.as_u64().map(|number| number as u16)
+    }
+
+    // Helper function to read the registers of a CPU in the order of REGISTERS
+    fn register_values(cpu: &CPU) -> [u16; 10] {
+        let r = &cpu.registers;
+        [
+            r.get_a() as u16,
+            r.get_b() as u16,
+            r.get_c() as u16,
+            r.get_d() as u16,
+            r.get_e() as u16,
+            r.get_f() as u16,
+            r.get_h() as u16,
+            r.get_l() as u16,
+            r.get_sp(),
+            r.get_pc(),
+        ]
+    }
+
+    // Runs one case, returning every way the CPU ended up differing from the final state
+    fn run_single_step_case(case: &Value, check_cycles: bool) -> Result<(), String> {
+        let initial = &case["initial"];
+        let expected = &case["final"];
+        let number = |state: &Value, name: &str| json_number(&state[name]).unwrap_or(0);
+
+        let mut ram = RAM::flat();
+        for entry in initial["ram"].as_array().into_iter().flatten() {
+            ram.write(json_number(&entry[0]).unwrap(), json_number(&entry[1]).unwrap() as u8);
+        }
+        if let Some(ie) = json_number(&initial["ie"]) {
+            ram.write(0xFFFF, ie as u8);
+        }
+        let mut cpu = CPU::new(&mut ram);
+        cpu.registers.set_a(number(initial, "a") as u8);
+        cpu.registers.set_b(number(initial, "b") as u8);
+        cpu.registers.set_c(number(initial, "c") as u8);
+        cpu.registers.set_d(number(initial, "d") as u8);
+        cpu.registers.set_e(number(initial, "e") as u8);
+        cpu.registers.set_f(number(initial, "f") as u8);
+        cpu.registers.set_h(number(initial, "h") as u8);
+        cpu.registers.set_l(number(initial, "l") as u8);
+        cpu.registers.set_sp(number(initial, "sp"));
+        cpu.registers.set_pc(number(initial, "pc"));
+        cpu.interrupt_master_enable = number(initial, "ime") != 0;
+        cpu.bus_log = Some(Vec::new());
+
+        let cycles = panic::catch_unwind(AssertUnwindSafe(|| cpu.execute_next()))
+            .map_err(|payload| match payload.downcast_ref::<String>() {
+                Some(message) => format!("panicked: {}", message),
+                None => format!("panicked: {}", payload.downcast_ref::<&str>().unwrap_or(&"?")),
+            })?;
+
+        let mut errors = Vec::new();
+        for (name, found) in REGISTERS.iter().zip(register_values(&cpu)) {
+            if let Some(wanted) = json_number(&expected[*name]) && wanted != found {
+                errors.push(format!("{} {:#X} instead of {:#X}", name, found, wanted));
+            }
+        }
+        if let Some(ime) = json_number(&expected["ime"]) && (ime != 0) != cpu.interrupt_master_enable {
+            errors.push(format!("ime {} instead of {}", cpu.interrupt_master_enable as u8, ime));
+        }
+        for entry in expected["ram"].as_array().into_iter().flatten() {
+            let address = json_number(&entry[0]).unwrap();
+            let (wanted, found) = (json_number(&entry[1]).unwrap() as u8, cpu.ram.peek(address));
+            if wanted != found {
+                errors.push(format!("[{:#06X}] {:#04X} instead of {:#04X}", address, found, wanted));
+            }
+        }
+
+        if check_cycles && let Some(expected_cycles) = case["cycles"].as_array() {
+            if cycles as usize != expected_cycles.len() * 4 {
+                errors.push(format!("{} cycles instead of {}", cycles, expected_cycles.len() * 4));
+            }
+            let wanted: Vec<(u16, u8, Access)> = expected_cycles
+                .iter()
+                .filter_map(|cycle| {
+                    let access = match cycle[2].as_str()? {
+                        kind if kind.starts_with('r') => Access::Read,
+                        kind if kind.contains('w') => Access::Write,
+                        _ => return None,
+                    };
+                    Some((json_number(&cycle[0])?, json_number(&cycle[1])? as u8, access))
+                })
+                .collect();
+            let found = cpu.bus_log.take().unwrap();
+            if found != wanted {
+                errors.push(format!("bus {:?} instead of {:?}", found, wanted));
+            }
+        }
+
+        if errors.is_empty() { Ok(()) } else { Err(errors.join(", ")) }
+    }
+
+    // Helper function to run every case of one file, returning (passed, failures)
+    fn run_single_step_file(json: &str, check_cycles: bool) -> (usize, Vec<String>) {
+        let cases: Vec<Value> = serde_json::from_str(json).expect("Single step tests should be a JSON array");
+        let mut passed = 0;
+        let mut failures = Vec::new();
+        for case in &cases {
+            match run_single_step_case(case, check_cycles) {
+                Ok(()) => passed += 1,
+                Err(error) => failures.push(format!("{}: {}", case["name"].as_str().unwrap_or("?"), error)),
+            }
+        }
+        (passed, failures)
+    }
+
+    #[test]
+    fn test_single_step_runner() {
+        // Flat memory doesn't map serial at 0xFF01, so the second case writes there like anywhere else
+        let json = r#"[
+            { "name": "80 0000",
+              "initial": { "pc": 256, "sp": 65534, "a": 1, "b": 2, "c": 0, "d": 0, "e": 0, "f": 0, "h": 0, "l": 0, "ime": 0, "ram": [[256, 128]] },
+              "final": { "pc": 257, "sp": 65534, "a": 3, "b": 2, "c": 0, "d": 0, "e": 0, "f": 0, "h": 0, "l": 0, "ime": 0, "ram": [[256, 128]] },
+              "cycles": [[256, 128, "r-m"]] },
+            { "name": "77 0000",
+              "initial": { "pc": 40960, "sp": 0, "a": 66, "b": 0, "c": 0, "d": 0, "e": 0, "f": 0, "h": 255, "l": 1, "ime": 1, "ram": [[40960, 119]] },
+              "final": { "pc": 40961, "sp": 0, "a": 66, "b": 0, "c": 0, "d": 0, "e": 0, "f": 0, "h": 255, "l": 1, "ime": 1, "ram": [[40960, 119], [65281, 66]] },
+              "cycles": [[40960, 119, "r-m"], [65281, 66, "-wm"]] },
+            { "name": "00 wrong",
+              "initial": { "pc": 0, "sp": 0, "a": 0, "b": 0, "c": 0, "d": 0, "e": 0, "f": 0, "h": 0, "l": 0, "ime": 0, "ram": [[0, 0]] },
+              "final": { "pc": 2, "sp": 0, "a": 0, "b": 0, "c": 0, "d": 0, "e": 0, "f": 0, "h": 0, "l": 0, "ime": 0, "ram": [[0, 0], [5, 1]] },
+              "cycles": [[0, 0, "r-m"], [null, null, "---"]] }
+        ]"#;
+        let (passed, failures) = run_single_step_file(json, true);
+        assert_eq!(passed, 2, "{:?}", failures);
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0], "00 wrong: pc 0x1 instead of 0x2, [0x0005] 0x00 instead of 0x01, 4 cycles instead of 8");
+    }
+
+    // Runs the suite in SM83_TESTS_DIR if it's set, one JSON file per opcode. Bus activity is
+    // only compared with SM83_CHECK_CYCLES set, as the CPU doesn't model every internal cycle
+    #[test]
+    fn test_single_step_suite() {
+        let Some(dir) = std::env::var_os("SM83_TESTS_DIR") else {
+            eprintln!("Skipping the single step tests, set SM83_TESTS_DIR to the directory of JSON files");
+            return;
+        };
+        let check_cycles = std::env::var_os("SM83_CHECK_CYCLES").is_some();
+
+        let mut results = BTreeMap::new();
+        for entry in std::fs::read_dir(&dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_none_or(|extension| extension != "json") {
+                continue;
+            }
+            let opcode = path.file_stem().unwrap().to_string_lossy().to_uppercase();
+            let json = std::fs::read_to_string(&path).unwrap();
+            results.insert(opcode, run_single_step_file(&json, check_cycles));
+        }
+        assert!(!results.is_empty(), "No JSON files in {:?}", dir);
+
+        let failing: Vec<String> = results
+            .iter()
+            .filter(|(_, (_, failures))| !failures.is_empty())
+            .map(|(opcode, (passed, failures))| {
+                format!("{}: {} of {} failed, first {}", opcode, failures.len(), passed + failures.len(), failures[0])
+            })
+            .collect();
+        assert!(failing.is_empty(), "{} of {} opcodes failed\n{}", failing.len(), results.len(), failing.join("\n"));
+    }
+
+    /*
+    Decoder table: every opcode runs once from the same state on flat memory, and the bus
+    accesses and registers it leaves behind are checked against what its bit fields say it
+    does. The model follows the usual octal layout of the instruction set
+
+      x = bits 7-6, y = bits 5-3, z = bits 2-0, p = y >> 1, q = y & 1
+
+    and only predicts where data goes, not what the ALU makes of it. ALU results and flags
+    can be anything, every other register has to be left alone.
+     */
+
+    const A: usize = 0;
+    const B: usize = 1;
+    const C: usize = 2;
+    const D: usize = 3;
+    const E: usize = 4;
+    const F: usize = 5;
+    const H: usize = 6;
+    const L: usize = 7;
+    const SP: usize = 8;
+    const PC: usize = 9;
+
+    // Registers as in REGISTERS. F is clear, so NZ and NC are taken and Z and C aren't
+    const TABLE_STATE: [u16; 10] = [0x3C, 0x10, 0x32, 0x54, 0x76, 0x00, 0xC8, 0x9A, 0xD000, 0x4000];
+    const TABLE_IMMEDIATE: u16 = 0x1234;
+
+    // Operand registers in the order the opcodes number them, None being (HL)
+    const R8: [Option<usize>; 8] = [Some(B), Some(C), Some(D), Some(E), Some(H), Some(L), None, Some(A)];
+
+    // Opcodes with no instruction behind them, which hang the CPU
+    const ILLEGAL_OPCODES: [u8; 11] = [0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC, 0xFD];
+
+    // What flat memory holds before the instruction runs, outside of the instruction itself
+    fn table_memory(address: u16) -> u8 {
+        ((address >> 8) as u8).wrapping_mul(31) ^ address as u8
+    }
+
+    struct Effects {
+        bus: Vec<(u16, Option<u8>, Access)>, // None for written values that come out of the ALU
+        registers: [Option<u16>; 10],        // None for registers the ALU sets
+    }
+
+    impl Effects {
+        fn pair(&self, high: usize, low: usize) -> u16 {
+            (TABLE_STATE[high] << 8) | TABLE_STATE[low]
+        }
+
+        fn set_pair(&mut self, high: usize, low: usize, value: u16) {
+            self.registers[high] = Some(value >> 8);
+            self.registers[low] = Some(value & 0xFF);
+        }
+
+        fn read(&mut self, address: u16) -> u16 {
+            self.bus.push((address, Some(table_memory(address)), Access::Read));
+            table_memory(address) as u16
+        }
+
+        fn write(&mut self, address: u16, value: Option<u8>) {
+            self.bus.push((address, value, Access::Write));
+        }
+
+        fn pop(&mut self) -> u16 {
+            let sp = TABLE_STATE[SP];
+            self.registers[SP] = Some(sp.wrapping_add(2));
+            self.read(sp) | (self.read(sp.wrapping_add(1)) << 8)
+        }
+
+        fn push(&mut self, value: u16) {
+            let sp = TABLE_STATE[SP];
+            self.registers[SP] = Some(sp.wrapping_sub(2));
+            self.write(sp.wrapping_sub(1), Some((value >> 8) as u8));
+            self.write(sp.wrapping_sub(2), Some(value as u8));
+        }
+
+        // An operand that may be (HL): reads it and says where a result would go
+        fn read_r8(&mut self, index: u8) -> (Option<usize>, u16) {
+            match R8[index as usize] {
+                Some(register) => (Some(register), TABLE_STATE[register]),
+                None => (None, self.read(self.pair(H, L))),
+            }
+        }
+
+        fn write_r8(&mut self, destination: Option<usize>, value: Option<u8>) {
+            match destination {
+                Some(register) => self.registers[register] = value.map(|value| value as u16),
+                None => self.write(self.pair(H, L), value),
+            }
+        }
+    }
+
+    // The bus accesses and registers the model expects from one instruction
+    fn expected_effects(opcode: u8, cb_opcode: u8) -> Effects {
+        let mut effects = Effects { bus: Vec::new(), registers: TABLE_STATE.map(Some) };
+        let pc = TABLE_STATE[PC];
+        let size = if opcode == 0xCB { 2 } else { opcodes::lookup(opcode).size as u16 };
+        effects.bus.push((pc, Some(opcode), Access::Read));
+        for i in 1..size {
+            let byte = if opcode == 0xCB { cb_opcode } else { (TABLE_IMMEDIATE >> (8 * (i - 1))) as u8 };
+            effects.bus.push((pc + i, Some(byte), Access::Read));
+        }
+        let next = pc + size;
+        effects.registers[PC] = Some(next);
+        if ILLEGAL_OPCODES.contains(&opcode) {
+            effects.registers[PC] = Some(pc);
+            return effects;
+        }
+
+        let (x, y, z) = (opcode >> 6, (opcode >> 3) & 7, opcode & 7);
+        let (p, q) = (y >> 1, y & 1);
+        let pairs = [(B, C), (D, E), (H, L), (SP, SP)];
+        let taken = [true, false, true, false]; // NZ, Z, NC, C
+        let immediate8 = TABLE_IMMEDIATE & 0xFF;
+        let relative = (immediate8 as u8 as i8) as u16;
+
+        // Helper to get and set a 16-bit pair, SP standing in for both halves of itself
+        let get_pair = |effects: &Effects, (high, low): (usize, usize)| {
+            if high == SP { TABLE_STATE[SP] } else { effects.pair(high, low) }
+        };
+        let set_pair = |effects: &mut Effects, (high, low): (usize, usize), value: u16| {
+            if high == SP { effects.registers[SP] = Some(value) } else { effects.set_pair(high, low, value) }
+        };
+
+        match (x, z) {
+            (0, 0) => match y {
+                0 => {}
+                1 => {
+                    effects.write(TABLE_IMMEDIATE, Some(TABLE_STATE[SP] as u8));
+                    effects.write(TABLE_IMMEDIATE + 1, Some((TABLE_STATE[SP] >> 8) as u8));
+                }
+                2 => {} // STOP
+                3 => effects.registers[PC] = Some(next.wrapping_add(relative)),
+                _ => {
+                    if taken[y as usize - 4] {
+                        effects.registers[PC] = Some(next.wrapping_add(relative));
+                    }
+                }
+            },
+            (0, 1) if q == 0 => set_pair(&mut effects, pairs[p as usize], TABLE_IMMEDIATE),
+            (0, 1) => {
+                let value = get_pair(&effects, pairs[p as usize]);
+                effects.set_pair(H, L, effects.pair(H, L).wrapping_add(value));
+                effects.registers[F] = None;
+            }
+            (0, 2) => {
+                let hl = effects.pair(H, L);
+                let address = match p {
+                    0 => effects.pair(B, C),
+                    1 => effects.pair(D, E),
+                    2 => { effects.set_pair(H, L, hl.wrapping_add(1)); hl }
+                    _ => { effects.set_pair(H, L, hl.wrapping_sub(1)); hl }
+                };
+                if q == 0 {
+                    effects.write(address, Some(TABLE_STATE[A] as u8));
+                } else {
+                    effects.registers[A] = Some(effects.read(address));
+                }
+            }
+            (0, 3) => {
+                let value = get_pair(&effects, pairs[p as usize]);
+                let result = if q == 0 { value.wrapping_add(1) } else { value.wrapping_sub(1) };
+                set_pair(&mut effects, pairs[p as usize], result);
+            }
+            (0, 4) | (0, 5) => {
+                let (destination, value) = effects.read_r8(y);
+                let result = if z == 4 { value.wrapping_add(1) } else { value.wrapping_sub(1) };
+                effects.write_r8(destination, Some(result as u8));
+                effects.registers[F] = None;
+            }
+            (0, 6) => effects.write_r8(R8[y as usize], Some(immediate8 as u8)),
+            (0, 7) => {
+                // RLCA, RRCA, RLA, RRA, DAA, CPL, SCF, CCF
+                if y < 6 {
+                    effects.registers[A] = None;
+                }
+                effects.registers[F] = None;
+            }
+            (1, 6) if y == 6 => {} // HALT
+            (1, _) => {
+                let (_, value) = effects.read_r8(z);
+                effects.write_r8(R8[y as usize], Some(value as u8));
+            }
+            (2, _) => {
+                effects.read_r8(z);
+                if y != 7 {
+                    effects.registers[A] = None;
+                }
+                effects.registers[F] = None;
+            }
+            (3, 0) => match y {
+                0..=3 => {
+                    if taken[y as usize] {
+                        effects.registers[PC] = Some(effects.pop());
+                    }
+                }
+                4 => effects.write(0xFF00 + immediate8, Some(TABLE_STATE[A] as u8)),
+                6 => effects.registers[A] = Some(effects.read(0xFF00 + immediate8)),
+                _ => {
+                    let result = TABLE_STATE[SP].wrapping_add(relative);
+                    if y == 5 { effects.registers[SP] = Some(result) } else { effects.set_pair(H, L, result) }
+                    effects.registers[F] = None;
+                }
+            },
+            (3, 1) if q == 0 => {
+                let value = effects.pop();
+                match p {
+                    3 => effects.set_pair(A, F, value & 0xFFF0),
+                    _ => effects.set_pair(pairs[p as usize].0, pairs[p as usize].1, value),
+                }
+            }
+            (3, 1) => match p {
+                0 | 1 => effects.registers[PC] = Some(effects.pop()),
+                2 => effects.registers[PC] = Some(effects.pair(H, L)),
+                _ => effects.registers[SP] = Some(effects.pair(H, L)),
+            },
+            (3, 2) => match y {
+                0..=3 => {
+                    if taken[y as usize] {
+                        effects.registers[PC] = Some(TABLE_IMMEDIATE);
+                    }
+                }
+                4 => effects.write(0xFF00 + TABLE_STATE[C], Some(TABLE_STATE[A] as u8)),
+                5 => effects.write(TABLE_IMMEDIATE, Some(TABLE_STATE[A] as u8)),
+                6 => effects.registers[A] = Some(effects.read(0xFF00 + TABLE_STATE[C])),
+                _ => effects.registers[A] = Some(effects.read(TABLE_IMMEDIATE)),
+            },
+            (3, 3) => match y {
+                0 => effects.registers[PC] = Some(TABLE_IMMEDIATE),
+                1 => {
+                    let (destination, value) = effects.read_r8(cb_opcode & 7);
+                    let bit = (cb_opcode >> 3) & 7;
+                    match cb_opcode >> 6 {
+                        0 => effects.write_r8(destination, None),
+                        1 => {}
+                        2 => effects.write_r8(destination, Some(value as u8 & !(1 << bit))),
+                        _ => effects.write_r8(destination, Some(value as u8 | (1 << bit))),
+                    }
+                    if cb_opcode >> 6 < 2 {
+                        effects.registers[F] = None;
+                    }
+                }
+                _ => {} // DI and EI
+            },
+            (3, 4) | (3, 5) => {
+                if z == 5 && q == 0 {
+                    let value = match p {
+                        3 => effects.pair(A, F),
+                        _ => effects.pair(pairs[p as usize].0, pairs[p as usize].1),
+                    };
+                    effects.push(value);
+                } else if z == 5 || taken[y as usize] {
+                    effects.push(next);
+                    effects.registers[PC] = Some(TABLE_IMMEDIATE);
+                }
+            }
+            (3, 6) => {
+                if y != 7 {
+                    effects.registers[A] = None;
+                }
+                effects.registers[F] = None;
+            }
+            _ => {
+                effects.push(next);
+                effects.registers[PC] = Some(y as u16 * 8);
+            }
+        }
+        effects
+    }
+
+    // Runs one instruction from the table state, returning what differs from the model
+    fn check_decoding(opcode: u8, cb_opcode: u8) -> Vec<String> {
+        let mut ram = RAM::flat();
+        for address in 0..=0xFFFF {
+            ram.write(address, table_memory(address));
+        }
+        let pc = TABLE_STATE[PC];
+        ram.write(pc, opcode);
+        ram.write(pc + 1, if opcode == 0xCB { cb_opcode } else { TABLE_IMMEDIATE as u8 });
+        ram.write(pc + 2, (TABLE_IMMEDIATE >> 8) as u8);
+
+        let [a, b, c, d, e, f, h, l, sp, pc] = TABLE_STATE;
+        let mut cpu = create_cpu_with_state(a as u8, b as u8, c as u8, d as u8, e as u8, f as u8, h as u8, l as u8, sp, pc, &mut ram);
+        cpu.bus_log = Some(Vec::new());
+        cpu.execute_next();
+
+        let expected = expected_effects(opcode, cb_opcode);
+        let mut errors = Vec::new();
+        for ((name, found), wanted) in REGISTERS.iter().zip(register_values(&cpu)).zip(expected.registers) {
+            if let Some(wanted) = wanted && wanted != found {
+                errors.push(format!("{} {:#X} instead of {:#X}", name, found, wanted));
+            }
+        }
+        let bus = cpu.bus_log.take().unwrap();
+        let matches = bus.len() == expected.bus.len()
+            && bus.iter().zip(&expected.bus).all(|(&(address, value, access), &(wanted_address, wanted_value, wanted_access))| {
+                address == wanted_address && access == wanted_access && wanted_value.is_none_or(|wanted| wanted == value)
+            });
+        if !matches {
+            errors.push(format!("bus {:X?} instead of {:X?}", bus, expected.bus));
+        }
+        errors
+    }
+
+    #[test]
+    fn test_operand_fetches_match_sizes() {
+        // Registers point far from the code, so the only reads after the opcode are its operands
+        for opcode in (0..=0xFFu8).filter(|&opcode| !opcodes::is_illegal(opcode)) {
+            let mut ram = RAM::flat();
+            let mut cpu = create_cpu_with_state(0, 0xD0, 0, 0xD0, 0, 0, 0xD0, 0, 0xD100, 0xC000, &mut ram);
+            cpu.ram.write(0xC000, opcode);
+            cpu.bus_log = Some(Vec::new());
+            cpu.execute_next();
+
+            let fetches = cpu.bus_log.take().unwrap().iter()
+                .filter(|&&(address, _, access)| access == Access::Read && (0xC001..=0xC002).contains(&address))
+                .count();
+            let expected = if opcode == 0xCB { 1 } else { opcodes::lookup(opcode).size as usize - 1 };
+            assert_eq!(fetches, expected, "operand bytes fetched for {:#04X}", opcode);
+        }
+    }
+
+    #[test]
+    fn test_decoding_table() {
+        let mut failures = Vec::new();
+        for opcode in 0..=0xFF {
+            let cb_opcodes = if opcode == 0xCB { 0..=0xFF } else { 0..=0 };
+            for cb_opcode in cb_opcodes {
+                let errors = check_decoding(opcode, cb_opcode);
+                if !errors.is_empty() {
+                    let name = if opcode == 0xCB { format!("CB {:02X}", cb_opcode) } else { format!("{:02X}", opcode) };
+                    failures.push(format!("{}: {}", name, errors.join(", ")));
+                }
+            }
+        }
+        assert!(failures.is_empty(), "{} opcodes differ from the table\n{}", failures.len(), failures.join("\n"));
+    }
+}
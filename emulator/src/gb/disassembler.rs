@@ -0,0 +1,256 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::gb::cpu::Code;
+use crate::gb::opcodes::{self, A16, A8, E8, N16, N8, S8, SP_E8};
+
+/*
+Turns machine code back into mnemonics, using RGBDS syntax so the output can be
+assembled again. Mnemonics, operands, sizes and cycles all come from the opcode table.
+
+https://gbdev.io/gb-opcodes/optables/
+*/
+
+pub fn is_illegal(opcode: u8) -> bool {
+    opcodes::is_illegal(opcode)
+}
+
+// Number of bytes the instruction at this opcode takes up
+pub fn instruction_size(opcode: u8) -> u8 {
+    // Every CB prefixed instruction is two bytes
+    if opcode == 0xCB {
+        return 2;
+    }
+    opcodes::lookup(opcode).size
+}
+
+fn format_signed(value: i8) -> String {
+    if value < 0 {
+        format!("-${:02X}", value.unsigned_abs())
+    } else {
+        format!("${:02X}", value)
+    }
+}
+
+// Disassembles the instruction at address, reading memory through the given function
+pub fn disassemble(read: &dyn Fn(u16) -> u8, address: u16) -> Code {
+    let opcode = read(address);
+    if opcode == 0xCB {
+        let info = opcodes::lookup_cb(read(address.wrapping_add(1)));
+        let operands = info.operands.iter().map(|operand| operand.to_string()).collect();
+        return Code { opcode, mnemonic: info.mnemonic.to_string(), operands, cycles: info.cycles, cycles_taken: info.cycles_taken, size: info.size };
+    }
+
+    let info = opcodes::lookup(opcode);
+    if opcodes::is_illegal(opcode) {
+        let operands = vec![format!("${:02X}", opcode)];
+        return Code { opcode, mnemonic: "DB".to_string(), operands, cycles: info.cycles, cycles_taken: info.cycles_taken, size: info.size };
+    }
+
+    let immediate8 = read(address.wrapping_add(1));
+    let immediate16 = (read(address.wrapping_add(2)) as u16) << 8 | immediate8 as u16;
+    let operands = info
+        .operands
+        .iter()
+        .map(|&template| match template {
+            N8 => format!("${:02X}", immediate8),
+            N16 => format!("${:04X}", immediate16),
+            A8 => format!("[$FF{:02X}]", immediate8),
+            A16 => format!("[${:04X}]", immediate16),
+            E8 => format!("${:04X}", address.wrapping_add(2).wrapping_add(immediate8 as i8 as u16)),
+            SP_E8 => format!("SP{}{}", if (immediate8 as i8) < 0 { "" } else { "+" }, format_signed(immediate8 as i8)),
+            S8 => format_signed(immediate8 as i8),
+            _ => template.to_string(),
+        })
+        .collect();
+
+    Code { opcode, mnemonic: info.mnemonic.to_string(), operands, cycles: info.cycles, cycles_taken: info.cycles_taken, size: info.size }
+}
+
+impl Code {
+    // Assembly text, e.g. "LD A, [HL+]"
+    pub fn text(&self) -> String {
+        if self.operands.is_empty() {
+            self.mnemonic.clone()
+        } else {
+            format!("{} {}", self.mnemonic, self.operands.join(", "))
+        }
+    }
+}
+
+/*
+Recursive descent over a whole ROM: starting from the entry point and the interrupt
+vectors, follow every jump, call and fall through and only decode what is reachable.
+Everything else is emitted as data, so the listing assembles back to the same bytes.
+
+Only banks 0 and 1 are followed, since which bank is mapped at 0x4000 is only known at
+run time. Jumps into RAM are ignored.
+*/
+
+const ENTRY_POINT: u16 = 0x0100;
+const INTERRUPT_VECTORS: [(u16, &str); 5] = [
+    (0x0040, "VBlankInterrupt"),
+    (0x0048, "LCDStatInterrupt"),
+    (0x0050, "TimerInterrupt"),
+    (0x0058, "SerialInterrupt"),
+    (0x0060, "JoypadInterrupt"),
+];
+const BANK_SIZE: usize = 0x4000;
+const FOLLOWED_SIZE: usize = BANK_SIZE * 2;
+const DATA_BYTES_PER_LINE: usize = 8;
+
+// Where control can go after an instruction
+struct Flow {
+    target: Option<u16>,
+    falls_through: bool,
+    is_call: bool,
+}
+
+fn flow(read: &dyn Fn(u16) -> u8, address: u16, opcode: u8) -> Flow {
+    let immediate8 = read(address.wrapping_add(1));
+    let immediate16 = (read(address.wrapping_add(2)) as u16) << 8 | immediate8 as u16;
+    let relative = address.wrapping_add(2).wrapping_add(immediate8 as i8 as u16);
+    let (target, falls_through, is_call) = match opcode {
+        0x18 => (Some(relative), false, false),
+        0x20 | 0x28 | 0x30 | 0x38 => (Some(relative), true, false),
+        0xC3 => (Some(immediate16), false, false),
+        0xC2 | 0xCA | 0xD2 | 0xDA => (Some(immediate16), true, false),
+        0xCD | 0xC4 | 0xCC | 0xD4 | 0xDC => (Some(immediate16), true, true),
+        0xC7 | 0xCF | 0xD7 | 0xDF | 0xE7 | 0xEF | 0xF7 | 0xFF => (Some((opcode & 0x38) as u16), true, true),
+        0xC9 | 0xD9 | 0xE9 => (None, false, false),
+        _ if is_illegal(opcode) => (None, false, false),
+        _ => (None, true, false),
+    };
+    Flow { target, falls_through, is_call }
+}
+
+pub struct Disassembly {
+    pub instructions: BTreeMap<u16, Code>,
+    pub labels: BTreeMap<u16, String>,
+    targets: BTreeMap<u16, u16>, // Instruction address to the address it jumps or calls to
+}
+
+pub fn disassemble_rom(rom: &[u8]) -> Disassembly {
+    let limit = rom.len().min(FOLLOWED_SIZE);
+    let read = |address: u16| rom.get(address as usize).copied().unwrap_or(0);
+
+    let mut disassembly = Disassembly { instructions: BTreeMap::new(), labels: BTreeMap::new(), targets: BTreeMap::new() };
+    let mut pending = vec![ENTRY_POINT];
+    disassembly.labels.insert(ENTRY_POINT, "Entry".to_string());
+    for (vector, name) in INTERRUPT_VECTORS {
+        pending.push(vector);
+        disassembly.labels.insert(vector, name.to_string());
+    }
+
+    while let Some(address) = pending.pop() {
+        if address as usize >= limit || disassembly.instructions.contains_key(&address) {
+            continue;
+        }
+        let opcode = read(address);
+        let code = disassemble(&read, address);
+        if address as usize + code.size as usize > limit {
+            continue;
+        }
+
+        let flow = flow(&read, address, opcode);
+        if let Some(target) = flow.target {
+            disassembly.targets.insert(address, target);
+            if (target as usize) < limit {
+                let name = if (opcode & 0xC7) == 0xC7 {
+                    format!("RST_{:02X}", target)
+                } else if flow.is_call {
+                    format!("Call_{:04X}", target)
+                } else {
+                    format!("Jump_{:04X}", target)
+                };
+                // Vectors and the entry point keep their names, calls win over jumps
+                let existing = disassembly.labels.get(&target);
+                if existing.is_none_or(|label| label.starts_with("Jump_") && flow.is_call) {
+                    disassembly.labels.insert(target, name);
+                }
+                pending.push(target);
+            }
+        }
+        if flow.falls_through {
+            pending.push(address.wrapping_add(code.size as u16));
+        }
+        disassembly.instructions.insert(address, code);
+    }
+
+    disassembly
+}
+
+impl Disassembly {
+    // Label at an address inside (start, end), which would end up in the middle of an instruction
+    fn has_label_inside(&self, start: u16, end: u32) -> bool {
+        self.labels.range(start + 1..).next().is_some_and(|(&label, _)| (label as u32) < end)
+            || self.instructions.range(start + 1..).next().is_some_and(|(&next, _)| (next as u32) < end)
+    }
+
+    fn instruction_text(&self, address: u16, code: &Code) -> String {
+        let mut operands = code.operands.clone();
+        if let Some(label) = self.targets.get(&address).and_then(|target| self.labels.get(target))
+            && let Some(last) = operands.last_mut()
+        {
+            *last = label.clone();
+        }
+        if operands.is_empty() {
+            code.mnemonic.clone()
+        } else {
+            format!("{} {}", code.mnemonic, operands.join(", "))
+        }
+    }
+}
+
+fn write_data(output: &mut String, bytes: &[u8]) {
+    for chunk in bytes.chunks(DATA_BYTES_PER_LINE) {
+        let values: Vec<String> = chunk.iter().map(|byte| format!("${:02X}", byte)).collect();
+        writeln!(output, "    DB {}", values.join(", ")).unwrap();
+    }
+}
+
+// RGBDS source for the whole ROM
+pub fn format_listing(rom: &[u8], disassembly: &Disassembly) -> String {
+    let mut output = String::new();
+    let banks = rom.len().div_ceil(BANK_SIZE).max(1);
+    for bank in 0..banks {
+        if bank == 0 {
+            writeln!(output, "SECTION \"ROM Bank $000\", ROM0[$0000]").unwrap();
+        } else {
+            writeln!(output, "\nSECTION \"ROM Bank ${:03X}\", ROMX[$4000], BANK[${:X}]", bank, bank).unwrap();
+        }
+
+        let start = bank * BANK_SIZE;
+        let end = rom.len().min(start + BANK_SIZE);
+        let mut data = Vec::new();
+        let mut offset = start;
+        while offset < end {
+            // Addresses as the CPU sees them, only banks 0 and 1 were followed
+            let address = if bank == 0 { offset as u16 } else { (BANK_SIZE + offset - start) as u16 };
+            let followed = bank <= 1;
+
+            if followed && let Some(label) = disassembly.labels.get(&address) {
+                write_data(&mut output, &std::mem::take(&mut data));
+                writeln!(output, "\n{}:", label).unwrap();
+            }
+
+            let code = if followed { disassembly.instructions.get(&address) } else { None };
+            match code {
+                Some(code) if !disassembly.has_label_inside(address, address as u32 + code.size as u32)
+                    && offset + code.size as usize <= end
+                    && !(code.mnemonic == "STOP" && rom[offset + 1] != 0) =>
+                {
+                    write_data(&mut output, &std::mem::take(&mut data));
+                    writeln!(output, "    {}", disassembly.instruction_text(address, code)).unwrap();
+                    offset += code.size as usize;
+                }
+                _ => {
+                    data.push(rom[offset]);
+                    offset += 1;
+                }
+            }
+        }
+        write_data(&mut output, &data);
+    }
+    output
+}
@@ -0,0 +1,128 @@
+#[cfg(test)]
+mod tests {
+    use crate::gb::disassembler::{disassemble, disassemble_rom, format_listing, instruction_size};
+
+    // Helper function to disassemble bytes placed at the given address
+    fn text_at(bytes: &[u8], address: u16) -> String {
+        let read = |at: u16| bytes.get(at.wrapping_sub(address) as usize).copied().unwrap_or(0);
+        disassemble(&read, address).text()
+    }
+
+    fn text(bytes: &[u8]) -> String {
+        text_at(bytes, 0)
+    }
+
+    #[test]
+    fn test_base_instructions() {
+        assert_eq!(text(&[0x00]), "NOP");
+        assert_eq!(text(&[0x01, 0x34, 0x12]), "LD BC, $1234");
+        assert_eq!(text(&[0x08, 0x00, 0xC0]), "LD [$C000], SP");
+        assert_eq!(text(&[0x22]), "LD [HL+], A");
+        assert_eq!(text(&[0x3A]), "LD A, [HL-]");
+        assert_eq!(text(&[0x36, 0x42]), "LD [HL], $42");
+        assert_eq!(text(&[0x78]), "LD A, B");
+        assert_eq!(text(&[0x76]), "HALT");
+        assert_eq!(text(&[0x86]), "ADD A, [HL]");
+        assert_eq!(text(&[0x90]), "SUB B");
+        assert_eq!(text(&[0xEE, 0x0F]), "XOR $0F");
+        assert_eq!(text(&[0xE0, 0x44]), "LDH [$FF44], A");
+        assert_eq!(text(&[0xF2]), "LDH A, [C]");
+        assert_eq!(text(&[0xE8, 0xFE]), "ADD SP, -$02");
+        assert_eq!(text(&[0xF8, 0x05]), "LD HL, SP+$05");
+        assert_eq!(text(&[0xF5]), "PUSH AF");
+        assert_eq!(text(&[0xFF]), "RST $38");
+        assert_eq!(text(&[0xD3]), "DB $D3");
+    }
+
+    #[test]
+    fn test_relative_jump_targets() {
+        assert_eq!(text_at(&[0x18, 0xFE], 0x0150), "JR $0150");
+        assert_eq!(text_at(&[0x20, 0x05], 0x0150), "JR NZ, $0157");
+        assert_eq!(text_at(&[0xC3, 0x50, 0x01], 0x0100), "JP $0150");
+        assert_eq!(text_at(&[0xDC, 0x00, 0x40], 0x0100), "CALL C, $4000");
+    }
+
+    #[test]
+    fn test_cb_instructions() {
+        assert_eq!(text(&[0xCB, 0x11]), "RL C");
+        assert_eq!(text(&[0xCB, 0x37]), "SWAP A");
+        assert_eq!(text(&[0xCB, 0x7C]), "BIT 7, H");
+        assert_eq!(text(&[0xCB, 0x86]), "RES 0, [HL]");
+        assert_eq!(text(&[0xCB, 0xFF]), "SET 7, A");
+
+        let read = |at: u16| [0xCB, 0x46][at as usize];
+        let code = disassemble(&read, 0);
+        assert_eq!((code.size, code.cycles), (2, 12), "BIT b, [HL] takes 12 cycles");
+    }
+
+    #[test]
+    fn test_instruction_sizes() {
+        let sizes: Vec<u8> = [0x00, 0x06, 0x01, 0xCB, 0xE0, 0xEA, 0xF8, 0x18].iter().map(|&op| instruction_size(op)).collect();
+        assert_eq!(sizes, vec![1, 2, 3, 2, 2, 3, 2, 2]);
+    }
+
+    // Helper function to build a 32KB ROM with code placed at the given addresses
+    fn create_rom(code: &[(u16, &[u8])]) -> Vec<u8> {
+        let mut rom = vec![0xFF; 0x8000];
+        for (address, bytes) in code {
+            rom[*address as usize..*address as usize + bytes.len()].copy_from_slice(bytes);
+        }
+        rom
+    }
+
+    #[test]
+    fn test_recursive_descent() {
+        let rom = create_rom(&[
+            (0x0040, &[0xD9]),                   // RETI
+            (0x0100, &[0x00, 0xC3, 0x50, 0x01]), // NOP; JP $0150
+            (0x0150, &[
+                0xCD, 0x60, 0x01, // CALL $0160
+                0x20, 0xFB,       // JR NZ, $0150
+                0x18, 0xFE,       // JR $0155
+            ]),
+            (0x0160, &[0xC9, 0x12, 0x34]), // RET, then data
+        ]);
+        let disassembly = disassemble_rom(&rom);
+
+        assert!(disassembly.instructions.contains_key(&0x0100));
+        assert!(disassembly.instructions.contains_key(&0x0155), "JR NZ falls through to the next instruction");
+        assert!(!disassembly.instructions.contains_key(&0x0157), "JR does not fall through");
+        assert!(disassembly.instructions.contains_key(&0x0040), "Interrupt vectors are followed");
+        assert!(!disassembly.instructions.contains_key(&0x0161), "Bytes after RET are not code");
+        assert!(!disassembly.instructions.contains_key(&0x0104), "The header is not code");
+
+        assert_eq!(disassembly.labels[&0x0100], "Entry");
+        assert_eq!(disassembly.labels[&0x0040], "VBlankInterrupt");
+        assert_eq!(disassembly.labels[&0x0150], "Jump_0150");
+        assert_eq!(disassembly.labels[&0x0160], "Call_0160");
+    }
+
+    #[test]
+    fn test_listing() {
+        let rom = create_rom(&[
+            (0x0100, &[0x00, 0xC3, 0x50, 0x01]),
+            (0x0150, &[0xCD, 0x60, 0x01, 0x18, 0xFE]),
+            (0x0160, &[0xCB, 0x37, 0xC9, 0x12]),
+        ]);
+        let listing = format_listing(&rom, &disassemble_rom(&rom));
+
+        assert!(listing.starts_with("SECTION \"ROM Bank $000\", ROM0[$0000]\n"));
+        assert!(listing.contains("SECTION \"ROM Bank $001\", ROMX[$4000], BANK[$1]"));
+        assert!(listing.contains("\nEntry:\n    NOP\n    JP Jump_0150\n"), "{}", listing);
+        assert!(listing.contains("\nJump_0150:\n    CALL Call_0160\n\nJump_0153:\n    JR Jump_0153\n"), "{}", listing);
+        assert!(listing.contains("\nCall_0160:\n    SWAP A\n    RET\n    DB $12, $FF"), "{}", listing);
+
+        // Every byte of the ROM is accounted for
+        let bytes: usize = listing
+            .lines()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty() && !line.ends_with(':') && !line.starts_with("SECTION"))
+            .map(|line| match line.strip_prefix("DB ") {
+                Some(data) => data.split(", ").count(),
+                None => 1,
+            })
+            .sum::<usize>()
+            + 2 + 2 + 1 + 1; // Operand bytes of JP, CALL, JR and SWAP
+        assert_eq!(bytes, rom.len());
+    }
+}
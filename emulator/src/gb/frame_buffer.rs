@@ -0,0 +1,65 @@
+use crate::gb::image::shade;
+use crate::gb::{SCREEN_HEIGHT, SCREEN_WIDTH};
+
+// RGBA for each shade, from white to black (a simple grayscale palette for now)
+pub const SHADES: [[u8; 4]; 4] = [
+    [0xFF, 0xFF, 0xFF, 0xFF], // White
+    [0xCC, 0xCC, 0xCC, 0xFF], // Light gray
+    [0x77, 0x77, 0x77, 0xFF], // Dark gray
+    [0x00, 0x00, 0x00, 0xFF], // Black
+];
+
+// The 160x144 picture being drawn, kept both as shade numbers (0 to 3) and as the RGBA
+// the frontend shows. Pixels are only written through set_pixel, which keeps the two
+// in step and does the row math in one place
+pub struct FrameBuffer {
+    shades: Vec<u8>, // One byte per pixel
+    rgba: Vec<u8>,   // Four bytes per pixel. White like the shades until a pixel is drawn
+}
+
+impl FrameBuffer {
+    pub fn new() -> Self {
+        FrameBuffer {
+            shades: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT],
+            rgba: SHADES[0].repeat(SCREEN_WIDTH * SCREEN_HEIGHT),
+        }
+    }
+
+    pub fn set_pixel(&mut self, x: usize, y: usize, shade: u8) {
+        assert!(x < SCREEN_WIDTH && y < SCREEN_HEIGHT, "Pixel ({}, {}) is off the screen", x, y);
+        let index = y * SCREEN_WIDTH + x;
+        self.shades[index] = shade;
+        self.rgba[index * 4..index * 4 + 4].copy_from_slice(&SHADES[shade as usize]);
+    }
+
+    pub fn shade(&self, x: usize, y: usize) -> u8 {
+        self.shades[y * SCREEN_WIDTH + x]
+    }
+
+    // Row after row of shade numbers
+    pub fn shades(&self) -> &[u8] {
+        &self.shades
+    }
+
+    // Row after row of RGBA pixels
+    pub fn rgba(&self) -> &[u8] {
+        &self.rgba
+    }
+
+    // Redraws the picture from RGBA pixels, e.g. ones kept in a save state. Colors are
+    // matched to the nearest shade. Transparent pixels, which older states kept for ones
+    // never drawn, come back as white
+    pub fn load_rgba(&mut self, rgba: &[u8]) {
+        assert_eq!(rgba.len(), self.rgba.len());
+        for (index, pixel) in rgba.chunks(4).enumerate() {
+            let shade = if pixel[3] == 0 { 0 } else { shade(pixel) };
+            self.set_pixel(index % SCREEN_WIDTH, index / SCREEN_WIDTH, shade);
+        }
+    }
+}
+
+impl Default for FrameBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,50 @@
+#[cfg(test)]
+mod tests {
+    use crate::gb::frame_buffer::{FrameBuffer, SHADES};
+    use crate::gb::{SCREEN_HEIGHT, SCREEN_WIDTH};
+
+    #[test]
+    fn test_set_pixel() {
+        let mut frame = FrameBuffer::new();
+        assert_eq!(frame.shades().len(), SCREEN_WIDTH * SCREEN_HEIGHT);
+        assert_eq!(frame.rgba().len(), SCREEN_WIDTH * SCREEN_HEIGHT * 4);
+
+        frame.set_pixel(3, 0, 1);
+        frame.set_pixel(0, 1, 2);
+        frame.set_pixel(159, 143, 3);
+
+        assert_eq!(frame.shade(3, 0), 1);
+        assert_eq!(frame.shades()[SCREEN_WIDTH], 2, "The first pixel of the second row");
+        assert_eq!(frame.shades()[SCREEN_WIDTH * SCREEN_HEIGHT - 1], 3);
+        assert_eq!(frame.rgba()[3 * 4..3 * 4 + 4], SHADES[1]);
+        assert_eq!(frame.rgba()[SCREEN_WIDTH * 4..SCREEN_WIDTH * 4 + 4], SHADES[2]);
+        assert_eq!(frame.rgba()[(SCREEN_WIDTH * SCREEN_HEIGHT - 1) * 4..], SHADES[3]);
+        assert_eq!(frame.rgba()[..4], SHADES[0], "Nothing drawn there yet, so white like its shade");
+        assert_eq!(frame.shade(0, 0), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "off the screen")]
+    fn test_set_pixel_off_screen() {
+        FrameBuffer::new().set_pixel(0, 144, 0);
+    }
+
+    #[test]
+    fn test_load_rgba() {
+        let mut frame = FrameBuffer::new();
+        for y in 0..SCREEN_HEIGHT {
+            frame.set_pixel(y % SCREEN_WIDTH, y, (y % 4) as u8);
+        }
+        let mut other = FrameBuffer::new();
+        other.load_rgba(frame.rgba());
+        assert_eq!(other.shades(), frame.shades());
+        assert_eq!(other.rgba(), frame.rgba());
+
+        // Colors from elsewhere end up as the nearest shade
+        let mut rgba = frame.rgba().to_vec();
+        rgba[..4].copy_from_slice(&[0x10, 0x20, 0x10, 0x80]);
+        other.load_rgba(&rgba);
+        assert_eq!(other.shade(0, 0), 3);
+        assert_eq!(other.rgba()[..4], SHADES[3]);
+    }
+}
@@ -0,0 +1,149 @@
+#[cfg(test)]
+mod tests {
+    use crate::gb::{GameBoy, SCREEN_HEIGHT, SCREEN_WIDTH};
+    use crate::gb::joypad::{Button, Buttons, ScriptedInput};
+    use crate::gb::ram::{RAM, INTERRUPT_FLAGS_ADDRESS};
+
+    // Helper function to load a program at the cartridge entry point
+    fn load_program(ram: &mut RAM, program: &[u8]) {
+        for (i, &byte) in program.iter().enumerate() {
+            ram.write(0x0100 + i as u16, byte);
+        }
+    }
+
+    // Jumps to 0x0150, which loops forever with NOP; JP 0x0150
+    const IDLE_LOOP: [u8; 3] = [0xC3, 0x50, 0x01];
+
+    fn create_ram() -> RAM {
+        let mut ram = RAM::new();
+        load_program(&mut ram, &IDLE_LOOP);
+        ram.write(0x0150, 0x00);
+        ram.write(0x0151, 0xC3);
+        ram.write(0x0152, 0x50);
+        ram.write(0x0153, 0x01);
+        ram
+    }
+
+    #[test]
+    fn test_post_boot_state() {
+        let mut ram = create_ram();
+        let gameboy = GameBoy::new(&mut ram);
+        assert_eq!(gameboy.cpu.registers.get_af(), 0x01B0);
+        assert_eq!(gameboy.cpu.registers.get_bc(), 0x0013);
+        assert_eq!(gameboy.cpu.registers.get_de(), 0x00D8);
+        assert_eq!(gameboy.cpu.registers.get_hl(), 0x014D);
+        assert_eq!(gameboy.cpu.registers.get_sp(), 0xFFFE);
+        assert_eq!(gameboy.cpu.registers.get_pc(), 0x0100);
+    }
+
+    #[test]
+    fn test_run_frame() {
+        let mut ram = create_ram();
+        let mut gameboy = GameBoy::new(&mut ram);
+        let mut input = ScriptedInput::new(vec![(1, Buttons::NONE.with(Button::A))]);
+
+        gameboy.run_frame(&mut input);
+        assert_eq!(gameboy.frame, 1);
+        assert_eq!(gameboy.cpu.ram.gpu.get_mode(), 1, "Frame should end at the start of VBlank");
+        assert_ne!(gameboy.cpu.ram.read(INTERRUPT_FLAGS_ADDRESS) & 0x01, 0, "VBlank interrupt should be requested");
+
+        gameboy.run_frame(&mut input);
+        gameboy.cpu.ram.write(0xFF00, 0x10);
+        assert_eq!(gameboy.cpu.ram.read(0xFF00) & 0x01, 0, "Input should be polled every frame");
+        assert_eq!(gameboy.screen_buffer().len(), 160 * 144 * 4);
+    }
+
+    #[test]
+    fn test_screenshot() {
+        let mut ram = create_ram();
+        ram.write(0xFF40, 0x91); // LCD on
+        let mut gameboy = GameBoy::new(&mut ram);
+        gameboy.run_frame(&mut |_| Buttons::NONE);
+
+        let screenshot = gameboy.screenshot();
+        assert_eq!((screenshot.width, screenshot.height), (SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32));
+        assert_eq!(screenshot.rgba, gameboy.screen_buffer());
+    }
+
+    #[test]
+    fn test_frame_length() {
+        let mut ram = create_ram();
+        let mut gameboy = GameBoy::new(&mut ram);
+        let mut input = ScriptedInput::new(Vec::new());
+
+        gameboy.run_frame(&mut input);
+        let start = gameboy.cpu.clock_cycles;
+        gameboy.run_frame(&mut input);
+        let length = gameboy.cpu.clock_cycles - start;
+        // Either end of the frame can land up to one instruction late
+        assert!((70224 - 24..=70224 + 24).contains(&length), "Frame took {} cycles", length);
+    }
+
+    #[test]
+    fn test_timer_interrupt_requested() {
+        let mut ram = create_ram();
+        ram.write(0xFF06, 0xF0);
+        ram.write(0xFF05, 0xFF);
+        ram.write(0xFF07, 0x05); // Enabled, 16 cycles per increment
+        let mut gameboy = GameBoy::new(&mut ram);
+        for _ in 0..8 {
+            gameboy.step();
+        }
+        assert_ne!(gameboy.cpu.ram.read(INTERRUPT_FLAGS_ADDRESS) & 0x04, 0);
+        assert_eq!(gameboy.cpu.ram.read(0xFF05) & 0xF0, 0xF0, "TIMA should reload from TMA");
+    }
+
+    // Helper function to run NOP; NOP; NOP; LDH A, [TIMA] with TIMA counting every 16 cycles
+    fn read_timer_mid_instruction(m_cycle_mode: bool) -> (u8, u8, u64) {
+        let mut ram = RAM::new();
+        load_program(&mut ram, &[0x00, 0x00, 0x00, 0xF0, 0x05]);
+        ram.write(0xFF07, 0x05);
+        let mut gameboy = GameBoy::new(&mut ram);
+        gameboy.cpu.m_cycle_mode = m_cycle_mode;
+        for _ in 0..4 {
+            gameboy.step();
+        }
+        (gameboy.cpu.registers.get_a(), gameboy.cpu.ram.read(0xFF05), gameboy.cpu.clock_cycles)
+    }
+
+    #[test]
+    fn test_m_cycle_mode() {
+        // The read happens in the third M-cycle of LDH, 24 cycles in, so only M-cycle mode
+        // sees the increment at 16. Afterwards both modes agree
+        assert_eq!(read_timer_mid_instruction(false), (0x00, 0x01, 24));
+        assert_eq!(read_timer_mid_instruction(true), (0x01, 0x01, 24));
+    }
+
+    #[test]
+    fn test_m_cycle_mode_frame_length() {
+        let mut ram = create_ram();
+        let mut gameboy = GameBoy::new(&mut ram);
+        gameboy.cpu.m_cycle_mode = true;
+        let mut input = ScriptedInput::new(Vec::new());
+
+        gameboy.run_frame(&mut input);
+        let start = gameboy.cpu.clock_cycles;
+        gameboy.run_frame(&mut input);
+        let length = gameboy.cpu.clock_cycles - start;
+        assert!((70224 - 24..=70224 + 24).contains(&length), "Frame took {} cycles", length);
+    }
+
+    #[test]
+    fn test_oam_dma_takes_160_m_cycles() {
+        let mut ram = RAM::new();
+        for i in 0..0xA0 {
+            ram.write(0xC000 + i, i as u8 + 1);
+        }
+        ram.write(0xFF46, 0xC0);
+        assert!(ram.dma_active());
+        assert_eq!(ram.gpu.oam[0], 0, "Nothing is copied before the first M-cycle");
+
+        ram.tick(4 * 80);
+        assert_eq!(ram.gpu.oam[79], 80);
+        assert_eq!(ram.gpu.oam[80], 0);
+
+        ram.tick(4 * 80);
+        assert!(!ram.dma_active());
+        assert_eq!(ram.gpu.oam[0x9F], 0xA0);
+    }
+}
@@ -1,4 +1,5 @@
-use crate::gb::ram::{RAM, INTERRUPT_FLAGS_ADDRESS};
+use crate::gb::frame_buffer::FrameBuffer;
+use crate::gb::state::{StateError, StateReader, StateWriter};
 
 const VRAM_SIZE: usize = 0x2000;
 const VRAM_ADDRESS: u16 = 0x8000;
@@ -9,11 +10,24 @@ const LCDC_ADDRESS: u16 = 0xFF40; // LCD Control
 const LY_ADDRESS: u16 = 0xFF44; // LCD Y Coordinate (read only)
 const LYC_ADDRESS: u16 = 0xFF45; // LY Compare
 const LCD_STATUS_ADDRESS: u16 = 0xFF41; // LCD Status
+const SCY_ADDRESS: u16 = 0xFF42; // Background viewport Y
+const SCX_ADDRESS: u16 = 0xFF43; // Background viewport X
+const BGP_ADDRESS: u16 = 0xFF47; // Background palette
+const OBP0_ADDRESS: u16 = 0xFF48; // Object palette 0
+const OBP1_ADDRESS: u16 = 0xFF49; // Object palette 1
+const WY_ADDRESS: u16 = 0xFF4A; // Window Y position
+const WX_ADDRESS: u16 = 0xFF4B; // Window X position
+
+const LCD_ENABLE: u8 = 0x80; // LCDC bit 7
+const LCD_STATUS_WRITABLE: u8 = 0x78; // Only the interrupt selects of STAT can be written
+
+pub const VBLANK_INTERRUPT: u8 = 0x01;
+pub const LCD_STAT_INTERRUPT: u8 = 0x02;
 
 const CYCLES_OAM: u32 = 80;      // Mode 2 - OAM Search
 const CYCLES_VRAM: u32 = 172;    // Mode 3 - Pixel Transfer (minimum)
 const CYCLES_HBLANK: u32 = 204;  // Mode 0 - Horizontal Blank
-const CYCLES_VBLANK: u32 = 4560; // Mode 1 - Vertical Blank
+const CYCLES_VBLANK: u32 = 456;  // Mode 1 - Vertical Blank, per scanline
 const SCANLINES_DISPLAY: u8 = 144;  // Visible scanlines
 const MAX_SCANLINES: u8 = 154;      // Total scanlines per frame
 const SCANLINE_SIZE: u8 = 160;      // Number of pixels in a scanline
@@ -132,38 +146,54 @@ impl std::convert::From<u8> for LCD_STATUS_REG {
     }
 }
 
-pub struct GPU<'a> {
-    pub ram: &'a mut RAM,
+pub struct GPU {
     pub vram: [u8; VRAM_SIZE],
     pub oam: [u8; OAM_SIZE],
     pub clock: u32,
     pub mode: Mode,
     current_scanline: u8,
-    pub screen_buffer: Vec<u8>,  // Buffer for the current frame
+    lcdc: u8,
+    lcd_status: u8,
+    scy: u8,
+    scx: u8,
+    lyc: u8,
+    bgp: u8,
+    obp0: u8,
+    obp1: u8,
+    wy: u8,
+    wx: u8,
+    stat_line: bool, // STAT interrupts fire on the rising edge of this line
+    interrupts: u8,  // Interrupts requested during the current step
+    line: [u8; SCANLINE_SIZE as usize], // Background color numbers of the line being drawn, sprites go behind all but 0
+    pub frame_ready: bool, // Set when a complete frame has been moved to screen
+    pub frame: FrameBuffer, // The frame being drawn, line by line
+    pub screen: FrameBuffer, // The last complete frame, what the LCD shows
 }
 
-impl<'a> GPU<'a> {
-    pub fn new(ram: &'a mut RAM) -> Self {
-        // Initialise LCD Status Register in memory
-        let lcd_status = LCD_STATUS_REG {
-            mode: Mode::OAM,
-            ly_compare: false,
-            mode_0_set: false,
-            mode_1_set: false,
-            mode_2_set: false,
-            lyc_int_select: false,
-            empty_1: false,
-        };
-        ram.write(LCD_STATUS_ADDRESS, lcd_status.into());
-
+impl GPU {
+    pub fn new() -> Self {
         Self {
-            ram: ram,
             vram: [0; VRAM_SIZE],
             oam: [0; OAM_SIZE],
             clock: 0,
             mode: Mode::OAM,
             current_scanline: 0,
-            screen_buffer: vec![0; SCANLINE_SIZE as usize * SCANLINES_DISPLAY as usize * 4], // 160x144 pixels, 4 bytes per pixel (RGBA)
+            lcdc: 0x91, // LCD and background on, as left by the boot ROM
+            lcd_status: 0,
+            scy: 0,
+            scx: 0,
+            lyc: 0,
+            bgp: 0xFC,
+            obp0: 0xFF,
+            obp1: 0xFF,
+            wy: 0,
+            wx: 0,
+            stat_line: false,
+            interrupts: 0,
+            line: [0; SCANLINE_SIZE as usize],
+            frame_ready: false,
+            frame: FrameBuffer::new(),
+            screen: FrameBuffer::new(),
         }
     }
 
@@ -177,10 +207,65 @@ impl<'a> GPU<'a> {
         self.current_scanline
     }
 
-    pub fn step(&mut self, cycles: u32) {
+    // Returns the interrupts (IF bits) requested while stepping
+    pub fn step(&mut self, cycles: u32) -> u8 {
+        if (self.lcdc & LCD_ENABLE) == 0 {
+            return 0;
+        }
         self.clock += cycles;
         self.step_set_mode();
         self.step_lcd_status();
+        std::mem::take(&mut self.interrupts)
+    }
+
+    pub fn read_register(&self, address: u16) -> u8 {
+        match address {
+            LCDC_ADDRESS => self.lcdc,
+            LCD_STATUS_ADDRESS => {
+                let ly_compare = (self.current_scanline == self.lyc) as u8;
+                0x80 | (self.lcd_status & LCD_STATUS_WRITABLE) | (ly_compare << 2) | self.mode as u8
+            }
+            SCY_ADDRESS => self.scy,
+            SCX_ADDRESS => self.scx,
+            LY_ADDRESS => self.current_scanline,
+            LYC_ADDRESS => self.lyc,
+            BGP_ADDRESS => self.bgp,
+            OBP0_ADDRESS => self.obp0,
+            OBP1_ADDRESS => self.obp1,
+            WY_ADDRESS => self.wy,
+            WX_ADDRESS => self.wx,
+            _ => panic!("Invalid GPU register address: {}", address),
+        }
+    }
+
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            LCDC_ADDRESS => {
+                let was_enabled = (self.lcdc & LCD_ENABLE) != 0;
+                let enabled = (value & LCD_ENABLE) != 0;
+                self.lcdc = value;
+                if was_enabled && !enabled {
+                    // Turning the LCD off resets LY and frees VRAM and OAM
+                    self.current_scanline = 0;
+                    self.clock = 0;
+                    self.mode = Mode::HBLANK;
+                } else if !was_enabled && enabled {
+                    self.clock = 0;
+                    self.mode = Mode::OAM;
+                }
+            }
+            LCD_STATUS_ADDRESS => self.lcd_status = value & LCD_STATUS_WRITABLE,
+            SCY_ADDRESS => self.scy = value,
+            SCX_ADDRESS => self.scx = value,
+            LY_ADDRESS => {} // Read only
+            LYC_ADDRESS => self.lyc = value,
+            BGP_ADDRESS => self.bgp = value,
+            OBP0_ADDRESS => self.obp0 = value,
+            OBP1_ADDRESS => self.obp1 = value,
+            WY_ADDRESS => self.wy = value,
+            WX_ADDRESS => self.wx = value,
+            _ => panic!("Invalid GPU register address: {}", address),
+        }
     }
 
     fn step_set_mode(&mut self) {
@@ -188,26 +273,27 @@ impl<'a> GPU<'a> {
             Mode::OAM => {
                 if self.clock >= CYCLES_OAM {
                     self.mode = Mode::VRAM;
-                    self.clock = 0;
+                    self.clock -= CYCLES_OAM;
                 }
             }
             Mode::VRAM => {
                 if self.clock >= CYCLES_VRAM {
                     self.mode = Mode::HBLANK;
-                    self.clock = 0;
+                    self.clock -= CYCLES_VRAM;
                 }
             }
             Mode::HBLANK => {
                 if self.clock >= CYCLES_HBLANK {
                     self.render_scanline();
                     self.current_scanline += 1;
-                    self.clock = 0;
+                    self.clock -= CYCLES_HBLANK;
                     if self.current_scanline >= SCANLINES_DISPLAY {
                         self.mode = Mode::VBLANK;
+                        // Every visible line gets drawn again, so the old screen can take the next frame
+                        std::mem::swap(&mut self.frame, &mut self.screen);
+                        self.frame_ready = true;
                         // Trigger V-Blank interrupt
-                        let mut if_flags = self.ram.read(INTERRUPT_FLAGS_ADDRESS);
-                        if_flags |= 0x01; // Set VBlank interrupt flag
-                        self.ram.write(INTERRUPT_FLAGS_ADDRESS, if_flags);
+                        self.interrupts |= VBLANK_INTERRUPT;
                     } else {
                         self.mode = Mode::OAM;
                     }
@@ -216,12 +302,11 @@ impl<'a> GPU<'a> {
             Mode::VBLANK => {
                 if self.clock >= CYCLES_VBLANK {
                     self.current_scanline += 1;
-                    self.clock = 0;
+                    self.clock -= CYCLES_VBLANK;
                     if self.current_scanline >= MAX_SCANLINES {
                         // Frame complete, start new frame
                         self.mode = Mode::OAM;
                         self.current_scanline = 0;
-                        // TODO: Update screen with screen_buffer
                     }
                 }
             }
@@ -229,19 +314,33 @@ impl<'a> GPU<'a> {
     }
 
     fn step_lcd_status(&mut self) {
-        let mut lcd_status = self.get_lcd_status();
-        lcd_status.mode = self.mode;
-        self.ram.write(LY_ADDRESS, self.current_scanline);
-        lcd_status.ly_compare = self.current_scanline == self.ram.read(LYC_ADDRESS);
-        self.ram.write(LCD_STATUS_ADDRESS, lcd_status.into());
+        let lcd_status = self.get_lcd_status();
+        let stat_line = (lcd_status.lyc_int_select && lcd_status.ly_compare)
+            || (lcd_status.mode_0_set && self.mode == Mode::HBLANK)
+            || (lcd_status.mode_1_set && self.mode == Mode::VBLANK)
+            || (lcd_status.mode_2_set && self.mode == Mode::OAM);
+
+        if stat_line && !self.stat_line {
+            self.trigger_lcd_stat_interrupt();
+        }
+        self.stat_line = stat_line;
     }
 
     pub fn render_scanline(&mut self) {
+        // Lines past the bottom of the screen are never shown
+        if self.current_scanline >= SCANLINES_DISPLAY {
+            return;
+        }
         let lcdc = self.get_lcdc();
         
-        // Render background if enabled
+        // Render background if enabled, otherwise the line is blank and sprites show above it
         if lcdc.bg_enable {
             self.render_background();
+        } else {
+            self.line = [0; SCANLINE_SIZE as usize];
+            for x in 0..SCANLINE_SIZE {
+                self.frame.set_pixel(x as usize, self.current_scanline as usize, 0);
+            }
         }
 
         // Render sprites if enabled
@@ -250,44 +349,43 @@ impl<'a> GPU<'a> {
         }
     }
 
+    // Draws the background scrolled by SCX and SCY, with the window over it from WX - 7 and
+    // WY on. The window's lines are counted from WY, rather than from the first one it was shown on
     fn render_background(&mut self) {
         let lcdc = self.get_lcdc();
         let y = self.current_scanline;
-        
-        // Get the base address for the tile map
-        let tile_map_addr = if lcdc.bg_tile_map_display_select {
-            0x9C00
-        } else {
-            0x9800
-        };
-
-        // Get the base address for tile data
-        let tile_data_addr = if lcdc.bg_tile_data_select {
-            0x8000
-        } else {
-            0x8800
-        };
+        let window_x = self.wx as i16 - 7;
+        let window_visible = lcdc.window_enable && y >= self.wy;
 
         // For each pixel in the scanline
         for x in 0..SCANLINE_SIZE {
+            // Find where in the 256x256 tile map the pixel comes from
+            let (tile_map_addr, map_x, map_y) = if window_visible && x as i16 >= window_x {
+                let tile_map_addr = if lcdc.window_tile_map_display_select { 0x9C00 } else { 0x9800 };
+                (tile_map_addr, (x as i16 - window_x) as u8, y - self.wy)
+            } else {
+                let tile_map_addr = if lcdc.bg_tile_map_display_select { 0x9C00 } else { 0x9800 };
+                (tile_map_addr, x.wrapping_add(self.scx), y.wrapping_add(self.scy))
+            };
+
             // Calculate tile coordinates
-            let tile_x = (x / 8) as u8;
-            let tile_y = (y / 8) as u8;
+            let tile_x = map_x / 8;
+            let tile_y = map_y / 8;
             
             // Get tile number from tile map
             let tile_map_index = (tile_y as u16 * 32 + tile_x as u16) + tile_map_addr;
-            let tile_number = self.read_vram(tile_map_index as u16);
+            let tile_number = self.read_vram(tile_map_index);
             
             // Get tile data
             let tile_addr = if lcdc.bg_tile_data_select {
-                tile_data_addr + (tile_number as u16 * 16)
+                0x8000 + (tile_number as u16 * 16)
             } else {
-                tile_data_addr + ((tile_number as i8 as i16 + 128) * 16) as u16
+                0x8800 + ((tile_number as i8 as i16 + 128) * 16) as u16
             };
 
             // Get pixel position within tile
-            let pixel_x = x % 8;
-            let pixel_y = y % 8;
+            let pixel_x = map_x % 8;
+            let pixel_y = map_y % 8;
 
             // Get pixel data from tile
             let tile_line = self.read_vram(tile_addr + (pixel_y * 2) as u16);
@@ -297,18 +395,8 @@ impl<'a> GPU<'a> {
             let color_bit = 7 - pixel_x;
             let color_number = ((tile_line_high >> color_bit) & 1) << 1 | ((tile_line >> color_bit) & 1);
 
-            // Convert color number to RGBA (using a simple grayscale palette for now)
-            let color = match color_number {
-                0 => [0xFF, 0xFF, 0xFF, 0xFF], // White
-                1 => [0xCC, 0xCC, 0xCC, 0xFF], // Light gray
-                2 => [0x77, 0x77, 0x77, 0xFF], // Dark gray
-                3 => [0x00, 0x00, 0x00, 0xFF], // Black
-                _ => [0x00, 0x00, 0x00, 0xFF],
-            };
-
-            // Write to screen buffer
-            let screen_index = y as usize * SCANLINE_SIZE as usize + x as usize * 4 as usize;
-            self.screen_buffer[screen_index..screen_index + 4].copy_from_slice(&color);
+            self.line[x as usize] = color_number;
+            self.frame.set_pixel(x as usize, y as usize, palette_shade(self.bgp, color_number));
         }
     }
 
@@ -345,7 +433,7 @@ impl<'a> GPU<'a> {
             let priority = (attributes & 0x80) == 0; // 0 = above background, 1 = below background
             let y_flip = (attributes & 0x40) != 0;
             let x_flip = (attributes & 0x20) != 0;
-            let palette = (attributes & 0x10) != 0;
+            let palette = if (attributes & 0x10) != 0 { self.obp1 } else { self.obp0 };
 
             // Calculate tile data address
             let tile_addr = 0x8000 + (tile_number as u16 * 16);
@@ -380,20 +468,12 @@ impl<'a> GPU<'a> {
                     continue;
                 }
 
-                // Convert color number to RGBA (using a simple grayscale palette for now)
-                let color = match color_number {
-                    1 => [0xCC, 0xCC, 0xCC, 0xFF], // Light gray
-                    2 => [0x77, 0x77, 0x77, 0xFF], // Dark gray
-                    3 => [0x00, 0x00, 0x00, 0xFF], // Black
-                    _ => continue,
-                };
-
-                // Write to screen buffer if priority allows
+                // Write to the frame if priority allows
                 let screen_x = sprite_x + x as i16;
                 if screen_x >= 0 && screen_x < SCANLINE_SIZE as i16 {
-                    let screen_index = (y as usize * SCANLINE_SIZE as usize + screen_x as usize) * 4;
-                    if priority || self.screen_buffer[screen_index] == 0xFF {
-                        self.screen_buffer[screen_index..screen_index + 4].copy_from_slice(&color);
+                    let screen_x = screen_x as usize;
+                    if priority || self.line[screen_x] == 0 {
+                        self.frame.set_pixel(screen_x, y as usize, palette_shade(palette, color_number));
                     }
                 }
             }
@@ -439,58 +519,79 @@ impl<'a> GPU<'a> {
     }
 
     pub fn get_lcdc(&self) -> LCDC_REG {
-        let lcdc = self.ram.read(LCDC_ADDRESS);
-        LCDC_REG::from(lcdc)    
+        LCDC_REG::from(self.lcdc)
     }
 
     pub fn get_lcd_status(&self) -> LCD_STATUS_REG {
-        let lcd_status = self.ram.read(LCD_STATUS_ADDRESS);
-        LCD_STATUS_REG::from(lcd_status)
+        LCD_STATUS_REG::from(self.read_register(LCD_STATUS_ADDRESS))
     }
 
     pub fn set_lcdc(&mut self, value: u8) {
-        self.ram.write(LCDC_ADDRESS, value);
+        self.write_register(LCDC_ADDRESS, value);
     }
 
     pub fn set_lcd_status(&mut self, value: u8) {
-        self.ram.write(LCD_STATUS_ADDRESS, value);
+        self.write_register(LCD_STATUS_ADDRESS, value);
     }
 
     // Add method to trigger LCD STAT interrupts
     fn trigger_lcd_stat_interrupt(&mut self) {
-        let mut if_flags = self.ram.read(INTERRUPT_FLAGS_ADDRESS);
-        if_flags |= 0x02; // Set LCD STAT interrupt flag
-        self.ram.write(INTERRUPT_FLAGS_ADDRESS, if_flags);
+        self.interrupts |= LCD_STAT_INTERRUPT;
     }
+}
 
-    /*
-    // TODO: Not sure if this is needed 
-
-    // Add method to check and trigger LCD STAT interrupts based on conditions
-    fn check_lcd_stat_interrupts(&mut self, ram: &mut RAM) {
-        let stat = self.get_stat();
-        let ly = self.current_scanline;
-        let lyc = self.get_lyc();
+// Palettes give each color number a shade, two bits each starting from color 0
+fn palette_shade(palette: u8, color_number: u8) -> u8 {
+    (palette >> (color_number * 2)) & 0x03
+}
 
-        let mut should_trigger = false;
+impl Default for GPU {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
-        // Check various LCD STAT interrupt conditions
-        if stat.lyc_ly_int && ly == lyc {
-            should_trigger = true;
+impl GPU {
+    // Only the complete frame is kept, the lines drawn since are drawn again after loading
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.vram);
+        writer.write_bytes(&self.oam);
+        writer.write_u32(self.clock);
+        writer.write_u8(self.mode as u8);
+        for register in [self.current_scanline, self.lcdc, self.lcd_status, self.scy, self.scx, self.lyc, self.bgp, self.obp0, self.obp1, self.wy, self.wx] {
+            writer.write_u8(register);
         }
-        if stat.oam_int && self.mode == Mode::OAM {
-            should_trigger = true;
-        }
-        if stat.vblank_int && self.mode == Mode::VBLANK {
-            should_trigger = true;
+        writer.write_bool(self.stat_line);
+        writer.write_bytes(self.screen.rgba());
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        reader.read_into(&mut self.vram)?;
+        reader.read_into(&mut self.oam)?;
+        // No mode lasts longer than a scanline of VBLANK
+        self.clock = reader.read_u32()?.min(CYCLES_VBLANK);
+        self.mode = match reader.read_u8()? {
+            0 => Mode::HBLANK,
+            1 => Mode::VBLANK,
+            2 => Mode::OAM,
+            3 => Mode::VRAM,
+            _ => return Err(StateError::InvalidValue("GPU mode")),
+        };
+        for register in [&mut self.current_scanline, &mut self.lcdc, &mut self.lcd_status, &mut self.scy, &mut self.scx, &mut self.lyc, &mut self.bgp, &mut self.obp0, &mut self.obp1, &mut self.wy, &mut self.wx] {
+            *register = reader.read_u8()?;
         }
-        if stat.hblank_int && self.mode == Mode::HBLANK {
-            should_trigger = true;
+        // Only VBLANK runs past the visible lines, anything else would draw off the screen
+        if self.current_scanline >= MAX_SCANLINES || (self.mode == Mode::VBLANK) != (self.current_scanline >= SCANLINES_DISPLAY) {
+            return Err(StateError::InvalidValue("scanline"));
         }
-
-        if should_trigger {
-            self.trigger_lcd_stat_interrupt(ram);
+        self.stat_line = reader.read_bool()?;
+        // Older states have no picture, the screen then keeps showing the current one
+        if !reader.is_empty() {
+            self.screen.load_rgba(reader.read_bytes(self.screen.rgba().len())?);
+            self.frame.load_rgba(self.screen.rgba());
         }
+        self.interrupts = 0;
+        self.frame_ready = false;
+        Ok(())
     }
-     */
-}   
\ No newline at end of file
+}
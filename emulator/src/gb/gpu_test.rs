@@ -1,16 +1,15 @@
 #[cfg(test)]
 mod tests {
+    use crate::gb::frame_buffer::SHADES;
     use crate::gb::gpu::{GPU, Mode, LCDC_REG, LCD_STATUS_REG};
-    use crate::gb::ram::RAM;
 
     // Helper function to create a GPU with specific initial state
     fn create_gpu_with_state(
         mode: Mode,
         current_scanline: u8,
-        clock: u32,
-        ram: &mut RAM
+        clock: u32
     ) -> GPU {
-        let mut gpu = GPU::new(ram);
+        let mut gpu = GPU::new();
         gpu.mode = mode;
         gpu.set_current_scanline(current_scanline);
         gpu.clock = clock;
@@ -36,8 +35,7 @@ mod tests {
 
     #[test]
     fn test_mode_transitions() {
-        let mut ram = RAM::new();
-        let mut gpu = create_gpu_with_state(Mode::OAM, 0, 0, &mut ram);
+        let mut gpu = create_gpu_with_state(Mode::OAM, 0, 0);
         
         // Test OAM -> VRAM transition
         gpu.step(80);
@@ -58,8 +56,7 @@ mod tests {
 
     #[test]
     fn test_vblank_transition() {
-        let mut ram = RAM::new();
-        let mut gpu = create_gpu_with_state(Mode::HBLANK, 153, 0, &mut ram);
+        let mut gpu = create_gpu_with_state(Mode::HBLANK, 153, 0);
         
         // Trigger VBLANK
         gpu.step(204);
@@ -74,8 +71,7 @@ mod tests {
 
     #[test]
     fn test_tile_rendering() {
-        let mut ram = RAM::new();
-        let mut gpu = create_gpu_with_state(Mode::HBLANK, 0, 0, &mut ram);
+        let mut gpu = create_gpu_with_state(Mode::HBLANK, 0, 0);
         
         // Create a simple tile pattern (checkerboard)
         let tile_data = [
@@ -121,7 +117,7 @@ mod tests {
                 [0x00, 0x00, 0x00, 0xFF] // Black
             };
             assert_eq!(
-                &gpu.screen_buffer[pixel_index..pixel_index + 4],
+                &gpu.frame.rgba()[pixel_index..pixel_index + 4],
                 &expected_color,
                 "Pixel index {:?} at x={} has wrong color",
                 pixel_index, x
@@ -131,8 +127,7 @@ mod tests {
 
     #[test]
     fn test_sprite_rendering() {
-        let mut ram = RAM::new();
-        let mut gpu = create_gpu_with_state(Mode::HBLANK, 0, 0, &mut ram);
+        let mut gpu = create_gpu_with_state(Mode::HBLANK, 0, 0);
         
         // Create a simple sprite pattern
         let sprite_data = [
@@ -171,14 +166,14 @@ mod tests {
         for x in 0..8 {
             let pixel_index = ((x) * 4) as usize;
             let expected_color = if x < 2 || x > 5 {
-                [0x00, 0x00, 0x00, 0x00] // Transparent
+                [0xFF, 0xFF, 0xFF, 0xFF] // White, the background is off
             } else {
                 [0x00, 0x00, 0x00, 0xFF] // Black
             };
             println!("check x {:?}", x);
-            println!("check screen buffer {:?} {:?} {:?} {:?}", gpu.screen_buffer[pixel_index], gpu.screen_buffer[pixel_index + 1], gpu.screen_buffer[pixel_index + 2], gpu.screen_buffer[pixel_index + 3]);
+            println!("check screen buffer {:?} {:?} {:?} {:?}", gpu.frame.rgba()[pixel_index], gpu.frame.rgba()[pixel_index + 1], gpu.frame.rgba()[pixel_index + 2], gpu.frame.rgba()[pixel_index + 3]);
             assert_eq!(
-                &gpu.screen_buffer[pixel_index..pixel_index + 4],
+                &gpu.frame.rgba()[pixel_index..pixel_index + 4],
                 &expected_color,
                 "Sprite pixel at x={} has wrong color, pixel index {}",
                 x, pixel_index
@@ -188,8 +183,7 @@ mod tests {
 
     #[test]
     fn test_sprite_priority() {
-        let mut ram: RAM = RAM::new();
-        let mut gpu = create_gpu_with_state(Mode::HBLANK, 0, 0, &mut ram);
+        let mut gpu = create_gpu_with_state(Mode::HBLANK, 0, 0);
         
         // Create two tiles: one for background, one for sprite
         let bg_tile = [0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF]; // Dark grey
@@ -223,7 +217,7 @@ mod tests {
         // Check that sprite with higher priority (0x00) is visible
         let pixel_index = (7 * 4) as usize;
         assert_eq!(
-            &gpu.screen_buffer[pixel_index..pixel_index + 4],
+            &gpu.frame.rgba()[pixel_index..pixel_index + 4],
             &[0x00, 0x00, 0x00, 0xFF], // Black (sprite color)
             "Higher priority sprite should be visible"
         );
@@ -231,8 +225,7 @@ mod tests {
 
     #[test]
     fn test_sprite_flipping() {
-        let mut ram = RAM::new();
-        let mut gpu = create_gpu_with_state(Mode::HBLANK, 0, 0, &mut ram);
+        let mut gpu = create_gpu_with_state(Mode::HBLANK, 0, 0);
         
         // Create a simple sprite pattern
         let sprite_data = [
@@ -251,6 +244,7 @@ mod tests {
 
         // Set up sprite with X flip
         write_sprite(&mut gpu, 0, 16, 8, 0, 0x20); // X flip enabled
+        gpu.write_register(0xFF48, 0xE4); // Each color number shows as the same shade
 
         // Enable sprite rendering
         let lcdc = LCDC_REG {
@@ -276,7 +270,7 @@ mod tests {
                 [0xCC, 0xCC, 0xCC, 0xFF] // Light gray
             };
             assert_eq!(
-                &gpu.screen_buffer[pixel_index..pixel_index + 4],
+                &gpu.frame.rgba()[pixel_index..pixel_index + 4],
                 &expected_color,
                 "Flipped sprite pixel at x={} has wrong color",
                 x
@@ -284,10 +278,81 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_background_scanlines() {
+        let mut gpu = create_gpu_with_state(Mode::HBLANK, 0, 0);
+
+        // Each row of the tile is a solid line of the next shade
+        let mut tile_data = [0; 16];
+        for row in 0..8 {
+            tile_data[row * 2] = if row & 1 != 0 { 0xFF } else { 0x00 };
+            tile_data[row * 2 + 1] = if row & 2 != 0 { 0xFF } else { 0x00 };
+        }
+        write_tile(&mut gpu, 0, &tile_data);
+        gpu.write_register(0xFF47, 0xE4);
+
+        let lcdc = LCDC_REG {
+            bg_enable: true,
+            obj_enable: false,
+            obj_size: false,
+            bg_tile_map_display_select: false,
+            bg_tile_data_select: true,
+            window_enable: false,
+            window_tile_map_display_select: false,
+        };
+        gpu.set_lcdc(lcdc.into());
+
+        for y in 0..144 {
+            gpu.set_current_scanline(y);
+            gpu.render_scanline();
+        }
+
+        for y in 0..144 {
+            let expected = (y % 4) as u8;
+            for x in [0, 37, 159] {
+                assert_eq!(gpu.frame.shade(x, y), expected, "Background pixel at ({}, {})", x, y);
+                let pixel_index = (y * 160 + x) * 4;
+                assert_eq!(&gpu.frame.rgba()[pixel_index..pixel_index + 4], &SHADES[expected as usize]);
+            }
+        }
+
+        // Lines below the screen are never drawn
+        let before = gpu.frame.rgba().to_vec();
+        gpu.set_current_scanline(150);
+        gpu.render_scanline();
+        assert_eq!(gpu.frame.rgba(), before.as_slice());
+    }
+
+    #[test]
+    fn test_sprite_on_later_scanline() {
+        let mut gpu = create_gpu_with_state(Mode::HBLANK, 0, 0);
+        write_tile(&mut gpu, 0, &[0xFF; 16]); // Solid black
+        write_sprite(&mut gpu, 0, 16 + 96, 8 + 20, 0, 0); // Covers lines 96 to 103 from x=20
+
+        let lcdc = LCDC_REG {
+            bg_enable: false,
+            obj_enable: true,
+            obj_size: false,
+            bg_tile_map_display_select: false,
+            bg_tile_data_select: true,
+            window_enable: false,
+            window_tile_map_display_select: false,
+        };
+        gpu.set_lcdc(lcdc.into()); // Turning the LCD off puts LY back to 0
+        gpu.set_current_scanline(100);
+        gpu.render_scanline();
+
+        for x in 20..28 {
+            assert_eq!(gpu.frame.shade(x, 100), 3, "Sprite pixel at x={}", x);
+        }
+        assert_eq!(gpu.frame.shade(19, 100), 0);
+        assert_eq!(gpu.frame.shade(28, 100), 0);
+        assert!(gpu.frame.shades()[..100 * 160].iter().all(|&shade| shade == 0), "Earlier lines are untouched");
+    }
+
     #[test]
     fn test_vram_access_restrictions() {
-        let mut ram = RAM::new();
-        let mut gpu = create_gpu_with_state(Mode::OAM, 0, 0, &mut ram);
+        let mut gpu = create_gpu_with_state(Mode::OAM, 0, 0);
         
         // Try to write to VRAM during OAM mode
         gpu.write_vram(0x8000, 0x42);
@@ -303,8 +368,7 @@ mod tests {
 
     #[test]
     fn test_oam_access_restrictions() {
-        let mut ram = RAM::new();
-        let mut gpu = create_gpu_with_state(Mode::VRAM, 0, 0, &mut ram);
+        let mut gpu = create_gpu_with_state(Mode::VRAM, 0, 0);
         
         // Try to write to OAM during VRAM mode
         gpu.write_oam(0xFE00, 0x42);
@@ -320,15 +384,14 @@ mod tests {
 
     #[test]
     fn test_lcd_status_register() {
-        let mut ram = RAM::new();
-        let mut gpu = create_gpu_with_state(Mode::OAM, 0, 0, &mut ram);
+        let mut gpu = create_gpu_with_state(Mode::OAM, 0, 0);
         
         // Test mode bits
         let status = gpu.get_lcd_status();
         assert_eq!(status.mode as u8, Mode::OAM as u8, "LCD status mode should match current mode");
 
         // Test LY compare
-        gpu.ram.write(0xFF45, 0x42); // Set LYC to 0x42
+        gpu.write_register(0xFF45, 0x42); // Set LYC to 0x42
         gpu.set_current_scanline(0x42);
         gpu.step(1);
         let status = gpu.get_lcd_status();
@@ -348,4 +411,91 @@ mod tests {
         let new_status = gpu.get_lcd_status();
         assert!(new_status.mode_0_set, "Mode 0 interrupt should be enabled");
     }
-} 
\ No newline at end of file
+
+    #[test]
+    fn test_background_scroll_and_window() {
+        let mut gpu = create_gpu_with_state(Mode::HBLANK, 0, 0);
+        write_tile(&mut gpu, 1, &[0xFF; 16]); // Solid color 3
+        gpu.write_vram(0x9800 + 32 + 2, 1); // Background tile (2, 1)
+        for address in 0x9C00..0xA000 {
+            gpu.write_vram(address, 1); // The whole window
+        }
+        gpu.write_register(0xFF47, 0xE4);
+        gpu.write_register(0xFF42, 4); // SCY
+        gpu.write_register(0xFF43, 8); // SCX
+        gpu.write_register(0xFF4A, 50); // WY
+        gpu.write_register(0xFF4B, 107); // WX, the window starts at x = 100
+
+        let lcdc = LCDC_REG {
+            bg_enable: true,
+            obj_enable: false,
+            obj_size: false,
+            bg_tile_map_display_select: false,
+            bg_tile_data_select: true,
+            window_enable: true,
+            window_tile_map_display_select: true,
+        };
+        gpu.set_lcdc(lcdc.into());
+        for y in 0..144 {
+            gpu.set_current_scanline(y);
+            gpu.render_scanline();
+        }
+
+        // The background tile at (16, 8) is scrolled to (8, 4)
+        assert_eq!(gpu.frame.shade(8, 4), 3);
+        assert_eq!(gpu.frame.shade(15, 11), 3);
+        assert_eq!(gpu.frame.shade(7, 4), 0);
+        assert_eq!(gpu.frame.shade(8, 3), 0);
+        assert_eq!(gpu.frame.shade(16, 4), 0);
+
+        // The window covers the bottom right
+        assert_eq!(gpu.frame.shade(100, 50), 3);
+        assert_eq!(gpu.frame.shade(159, 143), 3);
+        assert_eq!(gpu.frame.shade(99, 50), 0);
+        assert_eq!(gpu.frame.shade(100, 49), 0);
+    }
+
+    #[test]
+    fn test_palettes() {
+        let mut gpu = create_gpu_with_state(Mode::HBLANK, 0, 0);
+        write_tile(&mut gpu, 1, &[0xFF; 16]); // Solid color 3
+        write_sprite(&mut gpu, 0, 16, 8, 1, 0x90); // Behind the background, using OBP1
+        gpu.write_register(0xFF47, 0x1B); // Color 0 is black
+        gpu.write_register(0xFF48, 0xFF);
+        gpu.write_register(0xFF49, 0x40); // Color 3 is light gray
+
+        let lcdc = LCDC_REG {
+            bg_enable: true,
+            obj_enable: true,
+            obj_size: false,
+            bg_tile_map_display_select: false,
+            bg_tile_data_select: true,
+            window_enable: false,
+            window_tile_map_display_select: false,
+        };
+        gpu.set_lcdc(lcdc.into());
+        gpu.render_scanline();
+
+        assert_eq!(gpu.frame.shade(8, 0), 3, "Background color 0 goes through BGP");
+        // Sprites behind the background still show over its color 0, whatever shade that is
+        assert_eq!(gpu.frame.shade(0, 0), 1, "Sprite color 3 goes through OBP1");
+        assert_eq!(gpu.frame.shade(7, 0), 1);
+    }
+
+    #[test]
+    fn test_screen_swapped_at_vblank() {
+        let mut gpu = create_gpu_with_state(Mode::HBLANK, 142, 0);
+        gpu.write_register(0xFF47, 0xFF); // Everything black
+
+        gpu.step(204);
+        assert_eq!(gpu.frame.shade(0, 142), 3);
+        assert_eq!(gpu.screen.shade(0, 142), 0, "Half drawn frames aren't shown");
+
+        for cycles in [80, 172, 204] {
+            gpu.step(cycles);
+        }
+        assert!(gpu.frame_ready);
+        assert_eq!(gpu.screen.shade(0, 142), 3);
+        assert_eq!(gpu.screen.shade(159, 143), 3);
+    }
+}
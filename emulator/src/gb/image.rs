@@ -0,0 +1,103 @@
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::Path;
+
+// Writes an 8-bit RGBA buffer out as a PNG file
+pub fn save_png(path: &Path, width: u32, height: u32, rgba: &[u8]) -> io::Result<()> {
+    assert_eq!(rgba.len(), width as usize * height as usize * 4);
+
+    let file = File::create(path)?;
+    let mut encoder = png::Encoder::new(BufWriter::new(file), width, height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let mut writer = encoder.write_header().map_err(io::Error::other)?;
+    writer.write_image_data(rgba).map_err(io::Error::other)?;
+    writer.finish().map_err(io::Error::other)
+}
+
+// An 8-bit RGBA picture, such as a screenshot of the Game Boy's screen
+#[derive(Debug, PartialEq, Clone)]
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    pub rgba: Vec<u8>,
+}
+
+impl Image {
+    pub fn new(width: u32, height: u32, rgba: Vec<u8>) -> Self {
+        assert_eq!(rgba.len(), width as usize * height as usize * 4);
+        Image { width, height, rgba }
+    }
+
+    // Scaled up by whole pixels, so the picture stays as sharp as on the screen
+    pub fn scaled(&self, factor: u32) -> Image {
+        let factor = factor.max(1);
+        let width = self.width * factor;
+        let mut rgba = Vec::with_capacity(self.rgba.len() * (factor * factor) as usize);
+        for y in 0..self.height * factor {
+            let row = (y / factor * self.width) as usize;
+            for x in 0..width {
+                rgba.extend_from_slice(&self.rgba[(row + (x / factor) as usize) * 4..][..4]);
+            }
+        }
+        Image::new(width, self.height * factor, rgba)
+    }
+
+    pub fn save_png(&self, path: &Path) -> io::Result<()> {
+        save_png(path, self.width, self.height, &self.rgba)
+    }
+}
+
+// Reads a PNG of any color type back as 8-bit RGBA. Returns width, height and pixels
+pub fn load_png(path: &Path) -> io::Result<(u32, u32, Vec<u8>)> {
+    let mut decoder = png::Decoder::new(File::open(path)?);
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let mut reader = decoder.read_info().map_err(io::Error::other)?;
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buffer).map_err(io::Error::other)?;
+    buffer.truncate(info.buffer_size());
+
+    let rgba = match info.color_type {
+        png::ColorType::Rgba => buffer,
+        png::ColorType::Rgb => buffer.chunks(3).flat_map(|pixel| [pixel[0], pixel[1], pixel[2], 0xFF]).collect(),
+        png::ColorType::GrayscaleAlpha => buffer.chunks(2).flat_map(|pixel| [pixel[0], pixel[0], pixel[0], pixel[1]]).collect(),
+        png::ColorType::Grayscale => buffer.iter().flat_map(|&gray| [gray, gray, gray, 0xFF]).collect(),
+        png::ColorType::Indexed => return Err(io::Error::other("Indexed PNG was not expanded")),
+    };
+    Ok((info.width, info.height, rgba))
+}
+
+// Which of the four shades, 0 (lightest) to 3, an RGBA pixel is closest to. Reference
+// screenshots use different palettes, so images are compared by shade rather than color
+pub fn shade(pixel: &[u8]) -> u8 {
+    let luminance = (pixel[0] as u32 * 299 + pixel[1] as u32 * 587 + pixel[2] as u32 * 114) / 1000;
+    match luminance {
+        0xE0.. => 0,
+        0x99.. => 1,
+        0x33.. => 2,
+        _ => 3,
+    }
+}
+
+pub struct ImageDiff {
+    pub different: usize, // Number of pixels whose shade differs
+    pub rgba: Vec<u8>,    // The expected image faded out, with differing pixels in red
+}
+
+// Compares two RGBA images of the same size pixel by pixel
+pub fn diff_images(actual: &[u8], expected: &[u8]) -> ImageDiff {
+    assert_eq!(actual.len(), expected.len());
+    let mut different = 0;
+    let mut rgba = Vec::with_capacity(expected.len());
+    for (actual, expected) in actual.chunks(4).zip(expected.chunks(4)) {
+        if shade(actual) == shade(expected) {
+            let faded = 0xC0 + (3 - shade(expected)) * 0x15;
+            rgba.extend_from_slice(&[faded, faded, faded, 0xFF]);
+        } else {
+            different += 1;
+            rgba.extend_from_slice(&[0xFF, 0x00, 0x00, 0xFF]);
+        }
+    }
+    ImageDiff { different, rgba }
+}
@@ -0,0 +1,50 @@
+#[cfg(test)]
+mod tests {
+    use crate::gb::image::{diff_images, load_png, save_png, shade, Image};
+
+    #[test]
+    fn test_png_round_trip() {
+        let path = std::env::temp_dir().join(format!("gb_image_test_{}.png", std::process::id()));
+        let rgba: Vec<u8> = (0..3 * 2).flat_map(|i| [i * 40, 0x80, 0xFF - i * 40, 0xFF]).collect();
+        save_png(&path, 3, 2, &rgba).unwrap();
+        assert_eq!(load_png(&path).unwrap(), (3, 2, rgba));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_scaled() {
+        let image = Image::new(2, 1, vec![0x00, 0x00, 0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+        assert_eq!(image.scaled(1), image);
+
+        let scaled = image.scaled(3);
+        assert_eq!((scaled.width, scaled.height, scaled.rgba.len()), (6, 3, 6 * 3 * 4));
+        for y in 0..3 {
+            let row = &scaled.rgba[y * 6 * 4..][..6 * 4];
+            assert_eq!(row[..12], [0x00, 0x00, 0x00, 0xFF].repeat(3), "Row {}", y);
+            assert_eq!(row[12..], [0xFF; 12], "Row {}", y);
+        }
+    }
+
+    #[test]
+    fn test_shades_of_different_palettes() {
+        // This emulator's grays and the evenly spaced ones reference screenshots use
+        for (pixels, expected) in [([0xFF, 0xCC, 0x77, 0x00], [0, 1, 2, 3]), ([0xFF, 0xAA, 0x55, 0x00], [0, 1, 2, 3])] {
+            let shades: Vec<u8> = pixels.iter().map(|&gray| shade(&[gray, gray, gray, 0xFF])).collect();
+            assert_eq!(shades, expected);
+        }
+        // The classic green one
+        assert_eq!(shade(&[0x9B, 0xBC, 0x0F, 0xFF]), 1);
+        assert_eq!(shade(&[0x0F, 0x38, 0x0F, 0xFF]), 3);
+    }
+
+    #[test]
+    fn test_diff_images() {
+        let expected = [0xFF, 0xFF, 0xFF, 0xFF, 0xAA, 0xAA, 0xAA, 0xFF, 0x00, 0x00, 0x00, 0xFF];
+        let actual = [0xFF, 0xFF, 0xFF, 0xFF, 0xCC, 0xCC, 0xCC, 0xFF, 0x77, 0x77, 0x77, 0xFF];
+        let diff = diff_images(&actual, &expected);
+        assert_eq!(diff.different, 1, "Only the last pixel has another shade");
+        assert_eq!(diff.rgba.len(), expected.len());
+        assert_eq!(diff.rgba[8..], [0xFF, 0x00, 0x00, 0xFF]);
+        assert_ne!(diff.rgba[0..4], diff.rgba[8..12]);
+    }
+}
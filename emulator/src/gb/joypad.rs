@@ -1,3 +1,5 @@
+use crate::gb::state::{StateError, StateReader, StateWriter};
+
 pub struct Joypad {
     p1: u8,           // Joypad register at 0xFF00
     right: bool,
@@ -30,28 +32,25 @@ impl Joypad {
         // Bit 4 = 0: Read D-pad buttons
         // Bit 5 = 0: Read action buttons
         // Bits 0-3 contain the button states (active low)
-        let mut result = self.p1 & 0xF0; // Keep the selection bits
+        // Bits 6-7 are unused and always read as 1
+        let mut buttons = 0x0F;
 
         if (self.p1 & 0x10) == 0 {
             // D-pad buttons selected
-            let mut dpad = 0x0F;
-            if self.right { dpad &= !0x01; }
-            if self.left { dpad &= !0x02; }
-            if self.up { dpad &= !0x04; }
-            if self.down { dpad &= !0x08; }
-            result |= dpad;
+            if self.right { buttons &= !0x01; }
+            if self.left { buttons &= !0x02; }
+            if self.up { buttons &= !0x04; }
+            if self.down { buttons &= !0x08; }
         }
         if (self.p1 & 0x20) == 0 {
             // Action buttons selected
-            let mut action = 0x0F;
-            if self.a { action &= !0x01; }
-            if self.b { action &= !0x02; }
-            if self.select { action &= !0x04; }
-            if self.start { action &= !0x08; }
-            result |= action;
+            if self.a { buttons &= !0x01; }
+            if self.b { buttons &= !0x02; }
+            if self.select { buttons &= !0x04; }
+            if self.start { buttons &= !0x08; }
         }
 
-        result
+        0xC0 | (self.p1 & 0x30) | buttons
     }
 
     pub fn write_register(&mut self, value: u8) {
@@ -103,9 +102,24 @@ impl Joypad {
         
         false
     }
+
+    // Applies a full set of button states, returns whether the joypad interrupt should fire
+    pub fn set_buttons(&mut self, buttons: Buttons) -> bool {
+        let mut interrupt = false;
+        for button in Button::ALL {
+            interrupt |= self.set_button_state(button, buttons.is_pressed(button));
+        }
+        interrupt
+    }
+}
+
+impl Default for Joypad {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
-#[derive(Copy, Clone)]
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
 pub enum Button {
     Right,
     Left,
@@ -116,3 +130,122 @@ pub enum Button {
     Select,
     Start,
 }
+
+impl Button {
+    pub const ALL: [Button; 8] = [
+        Button::Right,
+        Button::Left,
+        Button::Up,
+        Button::Down,
+        Button::A,
+        Button::B,
+        Button::Select,
+        Button::Start,
+    ];
+
+    fn mask(self) -> u8 {
+        match self {
+            Button::Right => 0x01,
+            Button::Left => 0x02,
+            Button::Up => 0x04,
+            Button::Down => 0x08,
+            Button::A => 0x10,
+            Button::B => 0x20,
+            Button::Select => 0x40,
+            Button::Start => 0x80,
+        }
+    }
+}
+
+// The state of all eight buttons packed into one byte, one bit per button (1 = pressed)
+#[derive(Debug, Default, PartialEq, Eq, Copy, Clone)]
+pub struct Buttons(u8);
+
+impl Buttons {
+    pub const NONE: Buttons = Buttons(0);
+
+    pub fn from_bits(bits: u8) -> Self {
+        Buttons(bits)
+    }
+
+    pub fn bits(self) -> u8 {
+        self.0
+    }
+
+    pub fn is_pressed(self, button: Button) -> bool {
+        (self.0 & button.mask()) != 0
+    }
+
+    pub fn set(&mut self, button: Button, pressed: bool) {
+        if pressed {
+            self.0 |= button.mask();
+        } else {
+            self.0 &= !button.mask();
+        }
+    }
+
+    pub fn with(mut self, button: Button) -> Self {
+        self.set(button, true);
+        self
+    }
+}
+
+// Anything that can drive the joypad: keyboards, gamepads, scripted inputs and replays
+pub trait InputSource {
+    // Returns the buttons held down during the given frame
+    fn poll(&mut self, frame: u64) -> Buttons;
+}
+
+impl<F: FnMut(u64) -> Buttons> InputSource for F {
+    fn poll(&mut self, frame: u64) -> Buttons {
+        self(frame)
+    }
+}
+
+// Plays back a fixed list of (frame, buttons) changes, holding each state until the next one
+pub struct ScriptedInput {
+    events: Vec<(u64, Buttons)>,
+    next_event: usize,
+    current: Buttons,
+}
+
+impl ScriptedInput {
+    pub fn new(mut events: Vec<(u64, Buttons)>) -> Self {
+        events.sort_by_key(|&(frame, _)| frame);
+        ScriptedInput {
+            events,
+            next_event: 0,
+            current: Buttons::NONE,
+        }
+    }
+}
+
+impl InputSource for ScriptedInput {
+    fn poll(&mut self, frame: u64) -> Buttons {
+        while let Some(&(event_frame, buttons)) = self.events.get(self.next_event) {
+            if event_frame > frame {
+                break;
+            }
+            self.current = buttons;
+            self.next_event += 1;
+        }
+        self.current
+    }
+}
+
+impl Joypad {
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.p1);
+        for pressed in [self.right, self.left, self.up, self.down, self.a, self.b, self.select, self.start] {
+            writer.write_bool(pressed);
+        }
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.p1 = reader.read_u8()?;
+        for pressed in [&mut self.right, &mut self.left, &mut self.up, &mut self.down, &mut self.a, &mut self.b, &mut self.select, &mut self.start] {
+            *pressed = reader.read_bool()?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,98 @@
+#[cfg(test)]
+mod tests {
+    use crate::gb::joypad::{Button, Buttons, InputSource, Joypad, ScriptedInput};
+    use crate::gb::ram::{RAM, INTERRUPT_FLAGS_ADDRESS};
+
+    const SELECT_DPAD: u8 = 0x20;
+    const SELECT_ACTION: u8 = 0x10;
+    const SELECT_NONE: u8 = 0x30;
+
+    fn joypad_interrupt_requested(ram: &RAM) -> bool {
+        (ram.read(INTERRUPT_FLAGS_ADDRESS) & 0x10) != 0
+    }
+
+    #[test]
+    fn test_button_matrix() {
+        let mut joypad = Joypad::new();
+        joypad.set_button_state(Button::Left, true);
+        joypad.set_button_state(Button::Start, true);
+
+        joypad.write_register(SELECT_NONE);
+        assert_eq!(joypad.read_register(), 0xFF, "No group selected should read as nothing pressed");
+
+        joypad.write_register(SELECT_DPAD);
+        assert_eq!(joypad.read_register(), 0xED, "Left should pull bit 1 low");
+
+        joypad.write_register(SELECT_ACTION);
+        assert_eq!(joypad.read_register(), 0xD7, "Start should pull bit 3 low");
+
+        joypad.write_register(0x00);
+        assert_eq!(joypad.read_register(), 0xC5, "Both groups selected should combine the pressed lines");
+    }
+
+    #[test]
+    fn test_joypad_mapped_on_bus() {
+        let mut ram = RAM::new();
+        ram.write(0xFF00, SELECT_ACTION);
+        ram.set_button_state(Button::A, true);
+        assert_eq!(ram.read(0xFF00) & 0x0F, 0x0E);
+    }
+
+    #[test]
+    fn test_press_requests_interrupt() {
+        let mut ram = RAM::new();
+        ram.write(0xFF00, SELECT_DPAD);
+
+        ram.set_button_state(Button::A, true);
+        assert!(!joypad_interrupt_requested(&ram), "Unselected group should not interrupt");
+
+        ram.set_button_state(Button::Up, true);
+        assert!(joypad_interrupt_requested(&ram), "Selected group press should interrupt");
+    }
+
+    #[test]
+    fn test_selecting_held_button_requests_interrupt() {
+        let mut ram = RAM::new();
+        ram.write(0xFF00, SELECT_NONE);
+        ram.set_button_state(Button::B, true);
+        assert!(!joypad_interrupt_requested(&ram));
+
+        ram.write(0xFF00, SELECT_ACTION);
+        assert!(joypad_interrupt_requested(&ram), "Selecting a group with a held button should interrupt");
+    }
+
+    #[test]
+    fn test_set_buttons() {
+        let mut ram = RAM::new();
+        ram.write(0xFF00, SELECT_ACTION);
+
+        ram.set_buttons(Buttons::NONE.with(Button::Select).with(Button::Down));
+        assert_eq!(ram.read(0xFF00) & 0x0F, 0x0B);
+        assert!(joypad_interrupt_requested(&ram));
+
+        ram.set_buttons(Buttons::NONE);
+        assert_eq!(ram.read(0xFF00) & 0x0F, 0x0F, "Released buttons should read high again");
+    }
+
+    #[test]
+    fn test_scripted_input() {
+        let mut input = ScriptedInput::new(vec![
+            (10, Buttons::NONE),
+            (2, Buttons::NONE.with(Button::Start)),
+            (5, Buttons::NONE.with(Button::A).with(Button::Right)),
+        ]);
+
+        assert_eq!(input.poll(0), Buttons::NONE);
+        assert_eq!(input.poll(2), Buttons::NONE.with(Button::Start));
+        assert_eq!(input.poll(4), Buttons::NONE.with(Button::Start));
+        assert!(input.poll(6).is_pressed(Button::Right));
+        assert_eq!(input.poll(10), Buttons::NONE);
+    }
+
+    #[test]
+    fn test_closure_input_source() {
+        let mut input = |frame: u64| if frame % 2 == 0 { Buttons::NONE.with(Button::B) } else { Buttons::NONE };
+        assert!(input.poll(0).is_pressed(Button::B));
+        assert!(!input.poll(1).is_pressed(Button::B));
+    }
+}
@@ -0,0 +1,319 @@
+use std::fmt;
+
+use crate::gb::joypad::Buttons;
+use crate::gb::state::{self, StateError, StateReader, StateWriter};
+use crate::gb::GameBoy;
+
+/*
+Input movies: the buttons held on every frame, plus resets and power cycles, from a known
+starting point. The emulator is deterministic, so playing the inputs back from the same
+start reproduces the run exactly. Every hash_interval frames the movie also keeps a CRC32 of
+the whole machine state, which playback compares against to catch a desync as soon as it
+happens instead of when the game visibly goes wrong.
+
+File format, little endian like save states:
+  magic "GBMV", version u16, ROM CRC32 u32,
+  start kind u8 (0 power on, 1 battery RAM, 2 save state) and its data as u32 length + bytes,
+  frame count u32, then per frame: buttons u8, event u8 (0 none, 1 reset, 2 power cycle),
+  hash interval u32, hash count u32, then per hash: frame u64, CRC32 u32
+*/
+
+pub const MOVIE_MAGIC: &[u8; 4] = b"GBMV";
+pub const MOVIE_VERSION: u16 = 1;
+pub const DEFAULT_HASH_INTERVAL: u32 = 60;
+
+#[derive(Debug, PartialEq)]
+pub enum MovieError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    RomMismatch { expected: u32, found: u32 },
+    InvalidValue(&'static str),
+    State(StateError),                                  // The file is truncated or its start state doesn't load
+    Desync { frame: u64, expected: u32, found: u32 },   // The machine no longer matches the recording
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MovieError::BadMagic => write!(f, "Not a movie"),
+            MovieError::UnsupportedVersion(version) => write!(f, "Movie needs version {} of the format", version),
+            MovieError::RomMismatch { expected, found } => {
+                write!(f, "Movie was recorded with another ROM (CRC32 {:08X}, loaded {:08X})", found, expected)
+            }
+            MovieError::InvalidValue(field) => write!(f, "Movie has an invalid {}", field),
+            MovieError::State(error) => write!(f, "{}", error),
+            MovieError::Desync { frame, expected, found } => {
+                write!(f, "Desync at frame {}: state hash {:08X}, recorded {:08X}", frame, found, expected)
+            }
+        }
+    }
+}
+
+impl std::error::Error for MovieError {}
+
+impl From<StateError> for MovieError {
+    fn from(error: StateError) -> Self {
+        MovieError::State(error)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum MovieStart {
+    PowerOn,        // A freshly switched on machine with empty cartridge RAM
+    Sram(Vec<u8>),  // Switched on with this battery RAM
+    State(Vec<u8>), // A save state
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum MovieEvent {
+    Reset,
+    PowerCycle,
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct MovieFrame {
+    pub buttons: Buttons,
+    pub event: Option<MovieEvent>, // Happens before the frame runs
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Movie {
+    pub rom_checksum: u32,
+    pub start: MovieStart,
+    pub frames: Vec<MovieFrame>,
+    pub hash_interval: u32,
+    pub hashes: Vec<(u64, u32)>, // State hash after that many frames of the movie
+}
+
+impl Movie {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = StateWriter::new();
+        writer.write_bytes(MOVIE_MAGIC);
+        writer.write_u16(MOVIE_VERSION);
+        writer.write_u32(self.rom_checksum);
+        match &self.start {
+            MovieStart::PowerOn => {
+                writer.write_u8(0);
+                writer.write_vec(&[]);
+            }
+            MovieStart::Sram(ram) => {
+                writer.write_u8(1);
+                writer.write_vec(ram);
+            }
+            MovieStart::State(state) => {
+                writer.write_u8(2);
+                writer.write_vec(state);
+            }
+        }
+
+        writer.write_u32(self.frames.len() as u32);
+        for frame in &self.frames {
+            writer.write_u8(frame.buttons.bits());
+            writer.write_u8(match frame.event {
+                None => 0,
+                Some(MovieEvent::Reset) => 1,
+                Some(MovieEvent::PowerCycle) => 2,
+            });
+        }
+
+        writer.write_u32(self.hash_interval);
+        writer.write_u32(self.hashes.len() as u32);
+        for &(frame, hash) in &self.hashes {
+            writer.write_u64(frame);
+            writer.write_u32(hash);
+        }
+        writer.into_bytes()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, MovieError> {
+        let mut reader = StateReader::new(bytes);
+        if reader.read_bytes(4)? != MOVIE_MAGIC {
+            return Err(MovieError::BadMagic);
+        }
+        let version = reader.read_u16()?;
+        if version > MOVIE_VERSION {
+            return Err(MovieError::UnsupportedVersion(version));
+        }
+        let rom_checksum = reader.read_u32()?;
+        let kind = reader.read_u8()?;
+        let data = reader.read_vec()?.to_vec();
+        let start = match kind {
+            0 => MovieStart::PowerOn,
+            1 => MovieStart::Sram(data),
+            2 => MovieStart::State(data),
+            _ => return Err(MovieError::InvalidValue("start")),
+        };
+
+        let frame_count = reader.read_u32()?;
+        let mut frames = Vec::new();
+        for _ in 0..frame_count {
+            let buttons = Buttons::from_bits(reader.read_u8()?);
+            let event = match reader.read_u8()? {
+                0 => None,
+                1 => Some(MovieEvent::Reset),
+                2 => Some(MovieEvent::PowerCycle),
+                _ => return Err(MovieError::InvalidValue("event")),
+            };
+            frames.push(MovieFrame { buttons, event });
+        }
+
+        let hash_interval = reader.read_u32()?;
+        let hash_count = reader.read_u32()?;
+        let mut hashes = Vec::new();
+        for _ in 0..hash_count {
+            hashes.push((reader.read_u64()?, reader.read_u32()?));
+        }
+        Ok(Movie { rom_checksum, start, frames, hash_interval, hashes })
+    }
+}
+
+// What playback compares: a CRC32 of the complete save state
+pub fn state_hash(gameboy: &GameBoy) -> u32 {
+    state::crc32(&gameboy.save_state())
+}
+
+// Puts the machine at the movie's starting point. Frames and cycles are counted from there
+fn apply_start(gameboy: &mut GameBoy, start: &MovieStart) -> Result<(), MovieError> {
+    match start {
+        MovieStart::State(state) => gameboy.load_state(state)?,
+        MovieStart::PowerOn | MovieStart::Sram(_) => {
+            gameboy.power_cycle();
+            if let Some(cartridge) = &mut gameboy.cpu.ram.cartridge {
+                let ram = match start {
+                    MovieStart::Sram(ram) => ram.clone(),
+                    _ => vec![0; cartridge.ram().len()],
+                };
+                cartridge.load_ram(&ram);
+            }
+            gameboy.frame = 0;
+            gameboy.cpu.clock_cycles = 0;
+        }
+    }
+    Ok(())
+}
+
+fn apply_event(gameboy: &mut GameBoy, event: Option<MovieEvent>) {
+    match event {
+        Some(MovieEvent::Reset) => gameboy.reset(),
+        Some(MovieEvent::PowerCycle) => gameboy.power_cycle(),
+        None => {}
+    }
+}
+
+pub struct MovieRecorder {
+    movie: Movie,
+    pending_event: Option<MovieEvent>,
+}
+
+impl MovieRecorder {
+    // Starts recording from the given point, putting the machine there first. For
+    // MovieStart::State the state is loaded, so it has to be one of this ROM's
+    pub fn new(gameboy: &mut GameBoy, start: MovieStart, hash_interval: u32) -> Result<Self, MovieError> {
+        apply_start(gameboy, &start)?;
+        let movie = Movie {
+            rom_checksum: gameboy.rom_checksum(),
+            start,
+            frames: Vec::new(),
+            hash_interval: hash_interval.max(1),
+            hashes: Vec::new(),
+        };
+        Ok(MovieRecorder { movie, pending_event: None })
+    }
+
+    // Starts from the machine exactly as it is now
+    pub fn from_current_state(gameboy: &mut GameBoy, hash_interval: u32) -> Self {
+        let state = gameboy.save_state();
+        MovieRecorder::new(gameboy, MovieStart::State(state), hash_interval).expect("A fresh state always loads")
+    }
+
+    // Resets and power cycles take effect at the start of the next frame
+    pub fn reset(&mut self) {
+        self.pending_event = Some(MovieEvent::Reset);
+    }
+
+    pub fn power_cycle(&mut self) {
+        self.pending_event = Some(MovieEvent::PowerCycle);
+    }
+
+    pub fn run_frame(&mut self, gameboy: &mut GameBoy, buttons: Buttons) {
+        let event = self.pending_event.take();
+        apply_event(gameboy, event);
+        gameboy.run_frame(&mut |_| buttons);
+        self.movie.frames.push(MovieFrame { buttons, event });
+
+        let frame = self.movie.frames.len() as u64;
+        if frame.is_multiple_of(self.movie.hash_interval as u64) {
+            self.movie.hashes.push((frame, state_hash(gameboy)));
+        }
+    }
+
+    pub fn movie(&self) -> &Movie {
+        &self.movie
+    }
+
+    pub fn finish(self) -> Movie {
+        self.movie
+    }
+}
+
+pub struct MoviePlayer {
+    movie: Movie,
+    frame: usize,      // Frames of the movie played so far
+    next_hash: usize,
+}
+
+impl MoviePlayer {
+    // Checks the movie is for this ROM and puts the machine at its starting point
+    pub fn new(gameboy: &mut GameBoy, movie: Movie) -> Result<Self, MovieError> {
+        if movie.rom_checksum != gameboy.rom_checksum() {
+            return Err(MovieError::RomMismatch { expected: gameboy.rom_checksum(), found: movie.rom_checksum });
+        }
+        apply_start(gameboy, &movie.start)?;
+        Ok(MoviePlayer { movie, frame: 0, next_hash: 0 })
+    }
+
+    pub fn frame(&self) -> u64 {
+        self.frame as u64
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.frame >= self.movie.frames.len()
+    }
+
+    // Applies the next frame's reset or power cycle and returns the buttons to run it with,
+    // or None once the movie has ended. Follow with check_hash after running the frame
+    pub fn next_frame(&mut self, gameboy: &mut GameBoy) -> Option<Buttons> {
+        let MovieFrame { buttons, event } = *self.movie.frames.get(self.frame)?;
+        apply_event(gameboy, event);
+        self.frame += 1;
+        Some(buttons)
+    }
+
+    // Compares the machine against the hash recorded for the frame just run, if there is one
+    pub fn check_hash(&mut self, gameboy: &GameBoy) -> Result<(), MovieError> {
+        while let Some(&(frame, expected)) = self.movie.hashes.get(self.next_hash) {
+            if frame > self.frame as u64 {
+                break;
+            }
+            self.next_hash += 1;
+            if frame == self.frame as u64 {
+                let found = state_hash(gameboy);
+                if found != expected {
+                    return Err(MovieError::Desync { frame, expected, found });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Plays the next frame. Returns false once the movie has ended, and an error as soon as
+    // the machine stops matching a recorded hash
+    pub fn run_frame(&mut self, gameboy: &mut GameBoy) -> Result<bool, MovieError> {
+        let Some(buttons) = self.next_frame(gameboy) else {
+            return Ok(false);
+        };
+        gameboy.run_frame(&mut |_| buttons);
+        self.check_hash(gameboy)?;
+        Ok(true)
+    }
+}
@@ -0,0 +1,168 @@
+#[cfg(test)]
+mod tests {
+    use crate::gb::cartridge::Cartridge;
+    use crate::gb::joypad::{Button, Buttons};
+    use crate::gb::movie::{Movie, MovieError, MovieEvent, MoviePlayer, MovieRecorder, MovieStart};
+    use crate::gb::ram::RAM;
+    use crate::gb::GameBoy;
+
+    // Copies the action buttons into WRAM and the first byte of cartridge RAM, over and over
+    const PROGRAM: [u8; 29] = [
+        0x3E, 0x0A,       // LD A, 0x0A
+        0xEA, 0x00, 0x00, // LD (0x0000), A    ; Enable cartridge RAM
+        0x21, 0x00, 0xC0, // LD HL, 0xC000
+        0x3E, 0x10,       // loop: LD A, 0x10
+        0xE0, 0x00,       // LDH (P1), A       ; Select the action buttons
+        0xF0, 0x00,       // LDH A, (P1)
+        0x22,             // LD (HL+), A
+        0xEA, 0x00, 0xA0, // LD (0xA000), A
+        0xCB, 0x6C,       // BIT 5, H
+        0x28, 0xF2,       // JR Z, loop
+        0x21, 0x00, 0xC0, // LD HL, 0xC000
+        0x18, 0xED,       // JR loop
+        0x00, 0x00,
+    ];
+
+    // Helper function to build a ROM with the program above and cartridge RAM, with or without a battery
+    fn create_ram(battery: bool) -> RAM {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x100 + PROGRAM.len()].copy_from_slice(&PROGRAM);
+        rom[0x134..0x139].copy_from_slice(b"MOVIE");
+        rom[0x147] = if battery { 0x03 } else { 0x02 }; // MBC1 + RAM (+ BATTERY)
+        rom[0x149] = 0x02;
+        RAM::with_cartridge(Cartridge::new(rom).unwrap())
+    }
+
+    // Helper function to capture what a test compares after a run
+    fn snapshot(gameboy: &GameBoy) -> (Vec<u8>, u16, u64, u64, Vec<u8>) {
+        let cpu = &gameboy.cpu;
+        (cpu.ram.dump(), cpu.registers.get_pc(), cpu.clock_cycles, gameboy.frame, cpu.ram.cartridge.as_ref().unwrap().ram().to_vec())
+    }
+
+    // Helper function to record 30 frames of mashing A and B, with a reset and a power cycle
+    fn record(gameboy: &mut GameBoy, start: MovieStart) -> Movie {
+        let mut recorder = MovieRecorder::new(gameboy, start, 5).unwrap();
+        for frame in 0..30 {
+            match frame {
+                10 => recorder.reset(),
+                20 => recorder.power_cycle(),
+                _ => {}
+            }
+            let buttons = if frame % 3 == 0 { Buttons::NONE.with(Button::A) } else { Buttons::NONE.with(Button::B) };
+            recorder.run_frame(gameboy, buttons);
+        }
+        recorder.finish()
+    }
+
+    #[test]
+    fn test_playback_matches_recording() {
+        let mut ram = create_ram(true);
+        let mut gameboy = GameBoy::new(&mut ram);
+        // Whatever ran before recording started doesn't matter
+        for _ in 0..3 {
+            gameboy.run_frame(&mut |_| Buttons::NONE.with(Button::Start));
+        }
+        let movie = record(&mut gameboy, MovieStart::PowerOn);
+        let expected = snapshot(&gameboy);
+        assert_eq!(movie.frames.len(), 30);
+        assert_eq!(movie.frames[10].event, Some(MovieEvent::Reset));
+        assert_eq!(movie.frames[20].event, Some(MovieEvent::PowerCycle));
+        assert_eq!(movie.hashes.iter().map(|&(frame, _)| frame).collect::<Vec<_>>(), [5, 10, 15, 20, 25, 30]);
+
+        let loaded = Movie::from_bytes(&movie.to_bytes()).unwrap();
+        assert_eq!(loaded, movie);
+
+        let mut other_ram = create_ram(true);
+        let mut other = GameBoy::new(&mut other_ram);
+        let mut player = MoviePlayer::new(&mut other, loaded).unwrap();
+        while player.run_frame(&mut other).unwrap() {}
+        assert!(player.is_finished());
+        assert_eq!(player.frame(), 30);
+        assert_eq!(snapshot(&other), expected);
+    }
+
+    #[test]
+    fn test_starts_from_sram_or_state() {
+        let mut ram = create_ram(true);
+        ram.cartridge.as_mut().unwrap().load_ram(&[0x42; 0x2000]);
+        let mut gameboy = GameBoy::new(&mut ram);
+        let movie = record(&mut gameboy, MovieStart::Sram(vec![0x42; 0x2000]));
+        let expected = snapshot(&gameboy);
+
+        // The recorded battery RAM replaces whatever the player had
+        let mut other_ram = create_ram(true);
+        let mut other = GameBoy::new(&mut other_ram);
+        let mut player = MoviePlayer::new(&mut other, movie).unwrap();
+        assert_eq!(other.cpu.ram.cartridge.as_ref().unwrap().ram()[1], 0x42);
+        while player.run_frame(&mut other).unwrap() {}
+        assert_eq!(snapshot(&other), expected);
+
+        // Starting from a save state keeps the frame count
+        let mut recorder = MovieRecorder::from_current_state(&mut gameboy, 1);
+        recorder.run_frame(&mut gameboy, Buttons::NONE);
+        let movie = recorder.finish();
+        assert!(matches!(movie.start, MovieStart::State(_)));
+        let mut player = MoviePlayer::new(&mut other, movie).unwrap();
+        assert!(player.run_frame(&mut other).unwrap());
+        assert_eq!(snapshot(&other), snapshot(&gameboy));
+        assert_eq!(other.frame, 31);
+    }
+
+    #[test]
+    fn test_detects_desync() {
+        let mut ram = create_ram(true);
+        let mut gameboy = GameBoy::new(&mut ram);
+        let mut movie = record(&mut gameboy, MovieStart::PowerOn);
+        movie.frames[12].buttons = Buttons::NONE.with(Button::Select);
+
+        let mut other_ram = create_ram(true);
+        let mut other = GameBoy::new(&mut other_ram);
+        let mut player = MoviePlayer::new(&mut other, movie).unwrap();
+        let error = loop {
+            match player.run_frame(&mut other) {
+                Ok(true) => {}
+                Ok(false) => panic!("Played to the end without noticing"),
+                Err(error) => break error,
+            }
+        };
+        assert!(matches!(error, MovieError::Desync { frame: 15, .. }), "{}", error);
+    }
+
+    #[test]
+    fn test_invalid_movies() {
+        let mut ram = create_ram(true);
+        let mut gameboy = GameBoy::new(&mut ram);
+        let movie = record(&mut gameboy, MovieStart::PowerOn);
+        let bytes = movie.to_bytes();
+
+        assert_eq!(Movie::from_bytes(b"GBST"), Err(MovieError::BadMagic));
+        assert!(matches!(Movie::from_bytes(&bytes[..bytes.len() - 1]), Err(MovieError::State(_))));
+        let mut newer = bytes.clone();
+        newer[4] = 9;
+        assert_eq!(Movie::from_bytes(&newer), Err(MovieError::UnsupportedVersion(9)));
+
+        let mut other_ram = RAM::new();
+        let mut other = GameBoy::new(&mut other_ram);
+        assert!(matches!(MoviePlayer::new(&mut other, movie), Err(MovieError::RomMismatch { expected: 0, .. })));
+    }
+
+    #[test]
+    fn test_reset_and_power_cycle() {
+        for battery in [false, true] {
+            let mut ram = create_ram(battery);
+            let mut gameboy = GameBoy::new(&mut ram);
+            gameboy.run_frame(&mut |_| Buttons::NONE);
+            assert_eq!(gameboy.cpu.ram.read(0xC000), 0xDF, "Nothing is held");
+
+            gameboy.reset();
+            assert_eq!(gameboy.cpu.registers.get_pc(), 0x0100);
+            assert_eq!(gameboy.cpu.ram.read(0xC000), 0x00, "WRAM is cleared");
+            assert_eq!(gameboy.cpu.ram.cartridge.as_ref().unwrap().ram()[0], 0xDF, "A reset keeps cartridge RAM");
+            assert_eq!(gameboy.frame, 1);
+
+            gameboy.power_cycle();
+            let kept = if battery { 0xDF } else { 0x00 };
+            assert_eq!(gameboy.cpu.ram.cartridge.as_ref().unwrap().ram()[0], kept);
+        }
+    }
+}
@@ -0,0 +1,97 @@
+#[cfg(test)]
+mod tests {
+    use crate::gb::opcodes::{is_illegal, lookup, lookup_cb, CB_OPCODES, OPCODES};
+
+    /*
+    The unprefixed opcode matrix as published at https://gbdev.io/gb-opcodes/optables/,
+    typed in separately from the table so a mistake in one shows up against the other.
+    */
+    const SIZES: [[u8; 16]; 16] = [
+        [1, 3, 1, 1, 1, 1, 2, 1, 3, 1, 1, 1, 1, 1, 2, 1], // 0x00
+        [2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1], // 0x10
+        [2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1], // 0x20
+        [2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1], // 0x30
+        [1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1], // 0x40
+        [1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1], // 0x50
+        [1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1], // 0x60
+        [1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1], // 0x70
+        [1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1], // 0x80
+        [1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1], // 0x90
+        [1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1], // 0xA0
+        [1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1], // 0xB0
+        [1, 1, 3, 3, 3, 1, 2, 1, 1, 1, 3, 1, 3, 3, 2, 1], // 0xC0
+        [1, 1, 3, 1, 3, 1, 2, 1, 1, 1, 3, 1, 3, 1, 2, 1], // 0xD0
+        [2, 1, 1, 1, 1, 1, 2, 1, 2, 1, 3, 1, 1, 1, 2, 1], // 0xE0
+        [2, 1, 1, 1, 1, 1, 2, 1, 2, 1, 3, 1, 1, 1, 2, 1], // 0xF0
+    ];
+
+    // Cycles with conditional branches not taken
+    const CYCLES: [[u8; 16]; 16] = [
+        [4, 12, 8, 8, 4, 4, 8, 4, 20, 8, 8, 8, 4, 4, 8, 4], // 0x00
+        [4, 12, 8, 8, 4, 4, 8, 4, 12, 8, 8, 8, 4, 4, 8, 4], // 0x10
+        [8, 12, 8, 8, 4, 4, 8, 4, 8, 8, 8, 8, 4, 4, 8, 4], // 0x20
+        [8, 12, 8, 8, 12, 12, 12, 4, 8, 8, 8, 8, 4, 4, 8, 4], // 0x30
+        [4, 4, 4, 4, 4, 4, 8, 4, 4, 4, 4, 4, 4, 4, 8, 4], // 0x40
+        [4, 4, 4, 4, 4, 4, 8, 4, 4, 4, 4, 4, 4, 4, 8, 4], // 0x50
+        [4, 4, 4, 4, 4, 4, 8, 4, 4, 4, 4, 4, 4, 4, 8, 4], // 0x60
+        [8, 8, 8, 8, 8, 8, 4, 8, 4, 4, 4, 4, 4, 4, 8, 4], // 0x70
+        [4, 4, 4, 4, 4, 4, 8, 4, 4, 4, 4, 4, 4, 4, 8, 4], // 0x80
+        [4, 4, 4, 4, 4, 4, 8, 4, 4, 4, 4, 4, 4, 4, 8, 4], // 0x90
+        [4, 4, 4, 4, 4, 4, 8, 4, 4, 4, 4, 4, 4, 4, 8, 4], // 0xA0
+        [4, 4, 4, 4, 4, 4, 8, 4, 4, 4, 4, 4, 4, 4, 8, 4], // 0xB0
+        [8, 12, 12, 16, 12, 16, 8, 16, 8, 16, 12, 4, 12, 24, 8, 16], // 0xC0
+        [8, 12, 12, 4, 12, 16, 8, 16, 8, 16, 12, 4, 12, 4, 8, 16], // 0xD0
+        [12, 12, 8, 4, 4, 16, 8, 16, 16, 4, 16, 4, 4, 4, 8, 16], // 0xE0
+        [12, 12, 8, 4, 4, 16, 8, 16, 12, 8, 16, 4, 4, 4, 8, 16], // 0xF0
+    ];
+
+    // Conditional branches and their cycles when taken
+    const TAKEN: [(u8, u8); 16] = [
+        (0x20, 12), (0x28, 12), (0x30, 12), (0x38, 12), // JR cc
+        (0xC0, 20), (0xC8, 20), (0xD0, 20), (0xD8, 20), // RET cc
+        (0xC2, 16), (0xCA, 16), (0xD2, 16), (0xDA, 16), // JP cc
+        (0xC4, 24), (0xCC, 24), (0xD4, 24), (0xDC, 24), // CALL cc
+    ];
+
+    #[test]
+    fn test_sizes_match_published_matrix() {
+        for opcode in 0..=0xFFu8 {
+            let expected = SIZES[(opcode >> 4) as usize][(opcode & 0x0F) as usize];
+            assert_eq!(lookup(opcode).size, expected, "size of {:#04X}", opcode);
+        }
+    }
+
+    #[test]
+    fn test_cycles_match_published_matrix() {
+        for opcode in 0..=0xFFu8 {
+            let expected = CYCLES[(opcode >> 4) as usize][(opcode & 0x0F) as usize];
+            let taken = TAKEN.iter().find(|(op, _)| *op == opcode).map_or(expected, |(_, cycles)| *cycles);
+            assert_eq!(lookup(opcode).cycles, expected, "cycles of {:#04X}", opcode);
+            assert_eq!(lookup(opcode).cycles_taken, taken, "taken cycles of {:#04X}", opcode);
+        }
+    }
+
+    #[test]
+    fn test_cb_matrix() {
+        for opcode in 0..=0xFFu8 {
+            let uses_memory = opcode & 0x07 == 6;
+            let expected = match (opcode >> 6, uses_memory) {
+                (_, false) => 8,
+                (1, true) => 12, // BIT only reads (HL)
+                _ => 16,
+            };
+            let info = lookup_cb(opcode);
+            assert_eq!(info.size, 2, "size of CB {:#04X}", opcode);
+            assert_eq!((info.cycles, info.cycles_taken), (expected, expected), "cycles of CB {:#04X}", opcode);
+        }
+        assert_eq!((lookup_cb(0x37).mnemonic, lookup_cb(0x37).operands), ("SWAP", &["A"][..]));
+        assert_eq!((lookup_cb(0x7E).mnemonic, lookup_cb(0x7E).operands), ("BIT", &["7", "[HL]"][..]));
+        assert_eq!(CB_OPCODES.len() + OPCODES.len(), 512);
+    }
+
+    #[test]
+    fn test_illegal_opcodes() {
+        let illegal: Vec<u8> = (0..=0xFFu8).filter(|&opcode| is_illegal(opcode)).collect();
+        assert_eq!(illegal, vec![0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC, 0xFD]);
+    }
+}
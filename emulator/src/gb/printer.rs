@@ -0,0 +1,331 @@
+use std::io;
+use std::path::PathBuf;
+
+use crate::gb::image::save_png;
+use crate::gb::serial::SerialDevice;
+
+/*
+Game Boy Printer, attached through the link cable.
+
+Every packet is: 0x88 0x33, command, compression flag, data length (little endian),
+data, checksum (little endian), then two bytes where the printer answers with its
+device id (0x81) and its status.
+
+https://gbdev.io/pandocs/Gameboy_Printer.html
+*/
+
+const MAGIC_1: u8 = 0x88;
+const MAGIC_2: u8 = 0x33;
+const DEVICE_ID: u8 = 0x81;
+
+const COMMAND_INIT: u8 = 0x01;
+const COMMAND_PRINT: u8 = 0x02;
+const COMMAND_DATA: u8 = 0x04;
+const COMMAND_STATUS: u8 = 0x0F;
+
+const STATUS_CHECKSUM_ERROR: u8 = 0x01;
+const STATUS_PRINTING: u8 = 0x02;
+const STATUS_IMAGE_DATA_FULL: u8 = 0x04;
+const STATUS_UNPROCESSED_DATA: u8 = 0x08;
+const STATUS_PACKET_ERROR: u8 = 0x10;
+
+pub const PRINTER_WIDTH: usize = 160; // Pixels per printed line
+const TILES_PER_ROW: usize = 20;
+const BYTES_PER_TILE: usize = 16;
+const BYTES_PER_TILE_ROW: usize = TILES_PER_ROW * BYTES_PER_TILE;
+const MAX_IMAGE_DATA: usize = 0x1680; // 160x144 pixels worth of tiles
+const MARGIN_LINE_HEIGHT: usize = 8; // Pixels fed per margin unit (approximation)
+
+// Same grayscale palette as the GPU uses for the screen
+const SHADES: [[u8; 4]; 4] = [
+    [0xFF, 0xFF, 0xFF, 0xFF], // White
+    [0xCC, 0xCC, 0xCC, 0xFF], // Light gray
+    [0x77, 0x77, 0x77, 0xFF], // Dark gray
+    [0x00, 0x00, 0x00, 0xFF], // Black
+];
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+enum State {
+    Magic1,
+    Magic2,
+    Command,
+    Compression,
+    LengthLow,
+    LengthHigh,
+    Data,
+    ChecksumLow,
+    ChecksumHigh,
+    DeviceId,
+    Status,
+}
+
+// A finished printout, stored as one shade (0-3) per pixel
+pub struct PrintedPage {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>,
+}
+
+impl PrintedPage {
+    pub fn to_rgba(&self) -> Vec<u8> {
+        self.pixels.iter().flat_map(|&shade| SHADES[shade as usize & 0x03]).collect()
+    }
+}
+
+pub struct Printer {
+    state: State,
+    command: u8,
+    compressed: bool,
+    length: u16,
+    packet: Vec<u8>,
+    checksum: u16,
+    received_checksum: u16,
+    status: u8,
+    buffer: Vec<u8>, // Decompressed tile data waiting for a print command
+    page: Vec<u8>,   // Shades of the printout currently coming out of the printer
+    pages: Vec<PrintedPage>,
+    output_dir: Option<PathBuf>,
+    printed: usize,
+    save_error: Option<io::Error>, // The first printout that couldn't be written, until taken
+}
+
+impl Printer {
+    pub fn new() -> Self {
+        Printer {
+            state: State::Magic1,
+            command: 0,
+            compressed: false,
+            length: 0,
+            packet: Vec::new(),
+            checksum: 0,
+            received_checksum: 0,
+            status: 0,
+            buffer: Vec::new(),
+            page: Vec::new(),
+            pages: Vec::new(),
+            output_dir: None,
+            printed: 0,
+            save_error: None,
+        }
+    }
+
+    // Finished pages are also written to this directory as printout_NNN.png
+    pub fn set_output_dir(&mut self, dir: PathBuf) {
+        self.output_dir = Some(dir);
+    }
+
+    pub fn pages(&self) -> &[PrintedPage] {
+        &self.pages
+    }
+
+    pub fn take_pages(&mut self) -> Vec<PrintedPage> {
+        std::mem::take(&mut self.pages)
+    }
+
+    // Printing carries on when a page can't be written, the host reports the error
+    pub fn take_save_error(&mut self) -> Option<io::Error> {
+        self.save_error.take()
+    }
+
+    pub fn get_status(&self) -> u8 {
+        self.status
+    }
+
+    fn add_to_checksum(&mut self, value: u8) {
+        self.checksum = self.checksum.wrapping_add(value as u16);
+    }
+
+    fn process_packet(&mut self) {
+        if self.checksum != self.received_checksum {
+            self.status |= STATUS_CHECKSUM_ERROR;
+            return;
+        }
+        self.status &= !(STATUS_CHECKSUM_ERROR | STATUS_PACKET_ERROR);
+
+        match self.command {
+            COMMAND_INIT => {
+                self.buffer.clear();
+                self.status = 0;
+            }
+            COMMAND_DATA => {
+                let data = if self.compressed {
+                    decompress(&self.packet)
+                } else {
+                    std::mem::take(&mut self.packet)
+                };
+                let space = MAX_IMAGE_DATA - self.buffer.len();
+                self.buffer.extend_from_slice(&data[..data.len().min(space)]);
+
+                if !self.buffer.is_empty() {
+                    self.status |= STATUS_UNPROCESSED_DATA;
+                }
+                if self.buffer.len() >= MAX_IMAGE_DATA {
+                    self.status |= STATUS_IMAGE_DATA_FULL;
+                }
+            }
+            COMMAND_PRINT => {
+                if self.packet.len() < 4 {
+                    self.status |= STATUS_PACKET_ERROR;
+                    return;
+                }
+                // Byte 0 is the number of sheets and byte 3 the exposure, neither affects the image
+                let margins = self.packet[1];
+                let palette = self.packet[2];
+                self.print(margins, palette);
+                self.status &= !(STATUS_UNPROCESSED_DATA | STATUS_IMAGE_DATA_FULL);
+                self.status |= STATUS_PRINTING;
+            }
+            COMMAND_STATUS => {}
+            _ => {
+                self.status |= STATUS_PACKET_ERROR;
+            }
+        }
+    }
+
+    fn print(&mut self, margins: u8, palette: u8) {
+        let margin_before = (margins >> 4) as usize * MARGIN_LINE_HEIGHT;
+        let margin_after = (margins & 0x0F) as usize * MARGIN_LINE_HEIGHT;
+
+        self.page.resize(self.page.len() + margin_before * PRINTER_WIDTH, 0);
+
+        let tile_rows = self.buffer.len() / BYTES_PER_TILE_ROW;
+        for tile_row in 0..tile_rows {
+            for y in 0..8 {
+                for x in 0..PRINTER_WIDTH {
+                    let tile = tile_row * TILES_PER_ROW + x / 8;
+                    let address = tile * BYTES_PER_TILE + y * 2;
+                    let low = self.buffer[address];
+                    let high = self.buffer[address + 1];
+
+                    let color_bit = 7 - (x % 8);
+                    let color_number = ((high >> color_bit) & 1) << 1 | ((low >> color_bit) & 1);
+                    let shade = (palette >> (color_number * 2)) & 0x03;
+                    self.page.push(shade);
+                }
+            }
+        }
+        self.buffer.clear();
+
+        self.page.resize(self.page.len() + margin_after * PRINTER_WIDTH, 0);
+
+        // A margin after the image feeds the paper out, which ends the printout
+        if margin_after > 0 {
+            self.finish_page();
+        }
+    }
+
+    fn finish_page(&mut self) {
+        let pixels = std::mem::take(&mut self.page);
+        let page = PrintedPage {
+            width: PRINTER_WIDTH,
+            height: pixels.len() / PRINTER_WIDTH,
+            pixels,
+        };
+
+        if let Some(dir) = &self.output_dir {
+            let path = dir.join(format!("printout_{:03}.png", self.printed));
+            if let Err(error) = save_png(&path, page.width as u32, page.height as u32, &page.to_rgba())
+                && self.save_error.is_none()
+            {
+                self.save_error = Some(io::Error::new(error.kind(), format!("Failed to save printout to {}: {}", path.display(), error)));
+            }
+        }
+        self.printed += 1;
+        self.pages.push(page);
+    }
+}
+
+impl Default for Printer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SerialDevice for Printer {
+    fn exchange(&mut self, value: u8) -> u8 {
+        // The reply is shifted out while the Game Boy's byte is shifted in
+        let response = match self.state {
+            State::DeviceId => DEVICE_ID,
+            State::Status => self.status,
+            _ => 0x00,
+        };
+
+        self.state = match self.state {
+            State::Magic1 => {
+                if value == MAGIC_1 { State::Magic2 } else { State::Magic1 }
+            }
+            State::Magic2 => {
+                if value == MAGIC_2 { State::Command } else { State::Magic1 }
+            }
+            State::Command => {
+                self.command = value;
+                self.checksum = value as u16;
+                State::Compression
+            }
+            State::Compression => {
+                self.compressed = (value & 0x01) != 0;
+                self.add_to_checksum(value);
+                State::LengthLow
+            }
+            State::LengthLow => {
+                self.length = value as u16;
+                self.add_to_checksum(value);
+                State::LengthHigh
+            }
+            State::LengthHigh => {
+                self.length |= (value as u16) << 8;
+                self.add_to_checksum(value);
+                self.packet.clear();
+                if self.length == 0 { State::ChecksumLow } else { State::Data }
+            }
+            State::Data => {
+                self.packet.push(value);
+                self.add_to_checksum(value);
+                if self.packet.len() >= self.length as usize { State::ChecksumLow } else { State::Data }
+            }
+            State::ChecksumLow => {
+                self.received_checksum = value as u16;
+                State::ChecksumHigh
+            }
+            State::ChecksumHigh => {
+                self.received_checksum |= (value as u16) << 8;
+                self.process_packet();
+                State::DeviceId
+            }
+            State::DeviceId => State::Status,
+            State::Status => {
+                // Printing finishes once it has been reported
+                self.status &= !STATUS_PRINTING;
+                State::Magic1
+            }
+        };
+
+        response
+    }
+}
+
+/*
+Run length encoding used by data packets:
+  control byte with bit 7 set:   repeat the next byte (control & 0x7F) + 2 times
+  control byte with bit 7 clear: copy the next control + 1 bytes as they are
+*/
+pub fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut result = Vec::new();
+    let mut index = 0;
+    while index < data.len() {
+        let control = data[index];
+        index += 1;
+        if (control & 0x80) != 0 {
+            let count = (control & 0x7F) as usize + 2;
+            if let Some(&value) = data.get(index) {
+                result.resize(result.len() + count, value);
+                index += 1;
+            }
+        } else {
+            let end = (index + control as usize + 1).min(data.len());
+            result.extend_from_slice(&data[index..end]);
+            index = end;
+        }
+    }
+    result
+}
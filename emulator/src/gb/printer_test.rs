@@ -0,0 +1,178 @@
+#[cfg(test)]
+mod tests {
+    use crate::gb::printer::{decompress, Printer, PRINTER_WIDTH};
+    use crate::gb::ram::RAM;
+    use crate::gb::serial::SerialDevice;
+
+    // Helper function to build a complete packet, including the two trailing reply bytes
+    fn packet(command: u8, compressed: bool, data: &[u8]) -> Vec<u8> {
+        let header = [command, compressed as u8, (data.len() & 0xFF) as u8, (data.len() >> 8) as u8];
+        let checksum = header.iter().chain(data.iter()).fold(0u16, |sum, &b| sum.wrapping_add(b as u16));
+
+        let mut bytes = vec![0x88, 0x33];
+        bytes.extend_from_slice(&header);
+        bytes.extend_from_slice(data);
+        bytes.push((checksum & 0xFF) as u8);
+        bytes.push((checksum >> 8) as u8);
+        bytes.push(0x00);
+        bytes.push(0x00);
+        bytes
+    }
+
+    // Helper function to send bytes and collect the printer's replies
+    fn send(printer: &mut Printer, bytes: &[u8]) -> Vec<u8> {
+        bytes.iter().map(|&b| printer.exchange(b)).collect()
+    }
+
+    // Helper function to get the (device id, status) reply of a packet
+    fn reply(printer: &mut Printer, bytes: &[u8]) -> (u8, u8) {
+        let replies = send(printer, bytes);
+        (replies[replies.len() - 2], replies[replies.len() - 1])
+    }
+
+    // One 160x16 strip where the first tile row is color 3 and the second color 1
+    fn strip() -> Vec<u8> {
+        let mut data = vec![0xFF; 20 * 16];
+        for _ in 0..20 {
+            data.extend_from_slice(&[0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00]);
+        }
+        data
+    }
+
+    #[test]
+    fn test_status_reply() {
+        let mut printer = Printer::new();
+        assert_eq!(reply(&mut printer, &packet(0x0F, false, &[])), (0x81, 0x00));
+    }
+
+    #[test]
+    fn test_checksum_error() {
+        let mut printer = Printer::new();
+        let mut bytes = packet(0x0F, false, &[]);
+        let checksum_index = bytes.len() - 4;
+        bytes[checksum_index] ^= 0xFF;
+
+        let (_, status) = reply(&mut printer, &bytes);
+        assert_eq!(status & 0x01, 0x01, "Checksum error bit should be set");
+
+        let (_, status) = reply(&mut printer, &packet(0x01, false, &[]));
+        assert_eq!(status, 0x00, "Init should clear the error");
+    }
+
+    #[test]
+    fn test_data_is_unprocessed_until_printed() {
+        let mut printer = Printer::new();
+        reply(&mut printer, &packet(0x01, false, &[]));
+
+        let (_, status) = reply(&mut printer, &packet(0x04, false, &strip()));
+        assert_eq!(status & 0x08, 0x08, "Unprocessed data bit should be set");
+
+        let (_, status) = reply(&mut printer, &packet(0x02, false, &[0x01, 0x00, 0xE4, 0x40]));
+        assert_eq!(status & 0x08, 0x00, "Unprocessed data bit should be cleared");
+        assert_eq!(status & 0x02, 0x02, "Printing bit should be set");
+
+        let (_, status) = reply(&mut printer, &packet(0x0F, false, &[]));
+        assert_eq!(status & 0x02, 0x00, "Printing should have finished");
+    }
+
+    #[test]
+    fn test_print_applies_palette_and_margins() {
+        let mut printer = Printer::new();
+        reply(&mut printer, &packet(0x01, false, &[]));
+        reply(&mut printer, &packet(0x04, false, &strip()));
+        reply(&mut printer, &packet(0x04, false, &[]));
+
+        // No margin after the image: the page keeps going
+        reply(&mut printer, &packet(0x02, false, &[0x01, 0x10, 0xE4, 0x40]));
+        assert!(printer.pages().is_empty(), "Page should not be finished without a margin after");
+
+        // Inverted palette, one margin unit after the image
+        reply(&mut printer, &packet(0x04, false, &strip()));
+        reply(&mut printer, &packet(0x02, false, &[0x01, 0x01, 0x1B, 0x40]));
+
+        let pages = printer.pages();
+        assert_eq!(pages.len(), 1);
+        let page = &pages[0];
+        assert_eq!(page.width, PRINTER_WIDTH);
+        assert_eq!(page.height, 8 + 16 + 16 + 8);
+
+        let shade_at = |x: usize, y: usize| page.pixels[y * PRINTER_WIDTH + x];
+        assert_eq!(shade_at(0, 0), 0, "Top margin should be blank");
+        assert_eq!(shade_at(0, 8), 3, "Color 3 with palette 0xE4 is black");
+        assert_eq!(shade_at(159, 16), 1, "Color 1 with palette 0xE4 is light gray");
+        assert_eq!(shade_at(0, 24), 0, "Color 3 with palette 0x1B is white");
+        assert_eq!(shade_at(80, 32), 2, "Color 1 with palette 0x1B is dark gray");
+        assert_eq!(shade_at(0, 47), 0, "Bottom margin should be blank");
+    }
+
+    #[test]
+    fn test_compressed_data() {
+        assert_eq!(decompress(&[0x81, 0xAA, 0x01, 0x12, 0x34]), vec![0xAA, 0xAA, 0xAA, 0x12, 0x34]);
+
+        // A full strip as runs of 0xFF and repeated 0xFF 0x00 pairs
+        let mut compressed = Vec::new();
+        for _ in 0..(20 * 16 / 64) {
+            compressed.extend_from_slice(&[0x80 | 62, 0xFF]);
+        }
+        for _ in 0..(20 * 16 / 64) {
+            compressed.push(63);
+            for _ in 0..32 {
+                compressed.extend_from_slice(&[0xFF, 0x00]);
+            }
+        }
+        assert_eq!(decompress(&compressed), strip());
+
+        let mut printer = Printer::new();
+        reply(&mut printer, &packet(0x04, true, &compressed));
+        reply(&mut printer, &packet(0x02, false, &[0x01, 0x00, 0xE4, 0x40]));
+        reply(&mut printer, &packet(0x02, false, &[0x01, 0x01, 0xE4, 0x40]));
+        assert_eq!(printer.pages()[0].pixels[0], 3);
+    }
+
+    #[test]
+    fn test_printout_written_as_png() {
+        let dir = std::env::temp_dir().join(format!("gb_printer_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut printer = Printer::new();
+        printer.set_output_dir(dir.clone());
+        reply(&mut printer, &packet(0x04, false, &strip()));
+        reply(&mut printer, &packet(0x02, false, &[0x01, 0x01, 0xE4, 0x40]));
+
+        let path = dir.join("printout_000.png");
+        let bytes = std::fs::read(&path).expect("Printout should have been written");
+        assert_eq!(&bytes[1..4], b"PNG");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_printout_save_error_kept() {
+        let dir = std::env::temp_dir().join(format!("gb_printer_test_missing_{}", std::process::id()));
+
+        let mut printer = Printer::new();
+        printer.set_output_dir(dir.join("missing"));
+        reply(&mut printer, &packet(0x04, false, &strip()));
+        reply(&mut printer, &packet(0x02, false, &[0x01, 0x01, 0xE4, 0x40]));
+
+        assert_eq!(printer.pages().len(), 1, "The page still comes out");
+        let error = printer.take_save_error().expect("The failed write should be kept");
+        assert!(error.to_string().contains("printout_000.png"));
+        assert!(printer.take_save_error().is_none());
+    }
+
+    #[test]
+    fn test_printer_over_link_cable() {
+        let mut ram = RAM::new();
+        ram.serial.connect(Box::new(Printer::new()));
+
+        let mut replies = Vec::new();
+        for byte in packet(0x0F, false, &[]) {
+            ram.write(0xFF01, byte);
+            ram.write(0xFF02, 0x81);
+            ram.tick(4096);
+            replies.push(ram.read(0xFF01));
+        }
+
+        assert_eq!(&replies[replies.len() - 2..], &[0x81, 0x00]);
+    }
+}
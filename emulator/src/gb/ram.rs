@@ -1,6 +1,24 @@
+use std::cell::Cell;
+
+use crate::gb::apu::APU;
+use crate::gb::cartridge::Cartridge;
+use crate::gb::gpu::GPU;
+use crate::gb::joypad::{Button, Buttons, Joypad};
+use crate::gb::serial::Serial;
+use crate::gb::timer::Timer;
+use crate::gb::state::{StateError, StateReader, StateWriter};
+
 pub const INTERRUPT_ENABLE_ADDRESS: u16 = 0xFFFF;
 pub const INTERRUPT_FLAGS_ADDRESS: u16 = 0xFF0F;
 
+const DMA_ADDRESS: u16 = 0xFF46;
+const DMA_LENGTH: u16 = 0xA0;
+const DMA_CYCLES_PER_BYTE: u32 = 4;
+
+const TIMER_INTERRUPT: u8 = 0x04;
+const SERIAL_INTERRUPT: u8 = 0x08;
+const JOYPAD_INTERRUPT: u8 = 0x10;
+
 /*
 const EXT_RAM_SIZE: usize = 8192;
 const W_RAM_SIZE: usize = 8192;
@@ -23,22 +41,281 @@ need to figure out what the above values/addresses are
 */
 
 
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+// Debugger watchpoint on a single address
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct Watchpoint {
+    pub address: u16,
+    pub on_read: bool,
+    pub on_write: bool,
+}
+
+// The access that triggered a watchpoint
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct WatchHit {
+    pub address: u16,
+    pub access: Access,
+    pub value: u8,
+}
+
 pub struct RAM {
-    memory: [u8; 0xFFFF], // 65535 bytes (64KB) of memory
+    memory: [u8; 0x10000], // 64KB of memory, used for whatever no peripheral owns
+    pub serial: Serial,
+    pub joypad: Joypad,
+    pub gpu: GPU,
+    pub timer: Timer,
+    pub apu: APU,
+    pub cartridge: Option<Cartridge>, // Without a cartridge ROM and external RAM are plain memory
+    pub watchpoints: Vec<Watchpoint>,
+    watch_hit: Cell<Option<WatchHit>>, // Set from read(), which only borrows self
+    dma_source: u16,    // Start of the block being copied into OAM
+    dma_progress: u16,  // Bytes copied so far, DMA_LENGTH when no transfer is running
+    dma_clock: u32,
+    pub ly_override: Option<u8>, // What LY reads as instead of the current scanline, for tracing
+    flat: bool, // All 64KB is plain memory and nothing is ticked, see flat()
 }
 
 impl RAM {
     pub fn new() -> Self {
         RAM {
-            memory: [0; 0xFFFF],
+            memory: [0; 0x10000],
+            serial: Serial::new(),
+            joypad: Joypad::new(),
+            gpu: GPU::new(),
+            timer: Timer::new(),
+            apu: APU::new(),
+            cartridge: None,
+            watchpoints: Vec::new(),
+            watch_hit: Cell::new(None),
+            dma_source: 0,
+            dma_progress: DMA_LENGTH,
+            dma_clock: 0,
+            ly_override: None,
+            flat: false,
+        }
+    }
+
+    // 64KB of plain memory with no peripherals or cartridge mapped in, for CPU tests that
+    // expect to read back exactly what they put anywhere in the address space
+    pub fn flat() -> Self {
+        let mut ram = RAM::new();
+        ram.flat = true;
+        ram
+    }
+
+    pub fn with_cartridge(cartridge: Cartridge) -> Self {
+        let mut ram = RAM::new();
+        ram.cartridge = Some(cartridge);
+        ram
+    }
+
+    // Puts every component back in its power on state. Without a cartridge the ROM and
+    // external RAM areas are kept, as they stand in for one. Watchpoints, the serial sink and
+    // device and the sample rate belong to the host and stay as they are
+    pub fn reset(&mut self) {
+        self.memory[0x8000..0xA000].fill(0);
+        self.memory[0xC000..].fill(0);
+        self.serial.reset();
+        self.joypad = Joypad::new();
+        self.gpu = GPU::new();
+        self.timer = Timer::new();
+        let sample_rate = self.apu.sample_rate();
+        self.apu = APU::new();
+        self.apu.set_sample_rate(sample_rate);
+        if let Some(cartridge) = &mut self.cartridge {
+            cartridge.reset();
         }
+        self.dma_source = 0;
+        self.dma_progress = DMA_LENGTH;
+        self.dma_clock = 0;
     }
 
     pub fn read(&self, address: u16) -> u8 {
-        self.memory[address as usize]
+        let value = self.peek(address);
+        if !self.watchpoints.is_empty() {
+            self.check_watchpoints(address, Access::Read, value);
+        }
+        value
+    }
+
+    // Reads without triggering watchpoints, for debuggers and dumps
+    pub fn peek(&self, address: u16) -> u8 {
+        if self.flat {
+            return self.memory[address as usize];
+        }
+        match address {
+            0x0000..=0x7FFF | 0xA000..=0xBFFF if self.cartridge.is_some() => {
+                self.cartridge.as_ref().unwrap().read(address)
+            }
+            0x8000..=0x9FFF => self.gpu.read_vram(address),
+            0xE000..=0xFDFF => self.memory[(address - 0x2000) as usize], // Echo of 0xC000-0xDDFF
+            0xFE00..=0xFE9F => self.gpu.read_oam(address),
+            0xFEA0..=0xFEFF => 0xFF, // Not usable
+            0xFF00 => self.joypad.read_register(),
+            0xFF01..=0xFF02 => self.serial.read_register(address),
+            0xFF04..=0xFF07 => self.timer.read_register(address),
+            0xFF0F => 0xE0 | self.memory[address as usize],
+            0xFF44 if self.ly_override.is_some() => self.ly_override.unwrap(),
+            0xFF10..=0xFF3F => self.apu.read_register(address),
+            0xFF40..=0xFF45 | 0xFF47..=0xFF4B => self.gpu.read_register(address),
+            _ => self.memory[address as usize],
+        }
     }
 
     pub fn write(&mut self, address: u16, value: u8) {
-        self.memory[address as usize] = value;
+        if !self.watchpoints.is_empty() {
+            self.check_watchpoints(address, Access::Write, value);
+        }
+        if self.flat {
+            self.memory[address as usize] = value;
+            return;
+        }
+        match address {
+            0xFF00 => {
+                // Selecting a group with a button held pulls a line low, which also requests the interrupt
+                let before = self.joypad.read_register();
+                self.joypad.write_register(value);
+                let after = self.joypad.read_register();
+                if (before & !after & 0x0F) != 0 {
+                    self.request_interrupt(JOYPAD_INTERRUPT);
+                }
+            }
+            0xFF01..=0xFF02 => self.serial.write_register(address, value),
+            0x0000..=0x7FFF | 0xA000..=0xBFFF if self.cartridge.is_some() => {
+                self.cartridge.as_mut().unwrap().write(address, value)
+            }
+            0x8000..=0x9FFF => self.gpu.write_vram(address, value),
+            0xE000..=0xFDFF => self.memory[(address - 0x2000) as usize] = value,
+            0xFE00..=0xFE9F => self.gpu.write_oam(address, value),
+            0xFEA0..=0xFEFF => {}
+            0xFF04..=0xFF07 => self.timer.write_register(address, value),
+            0xFF10..=0xFF3F => self.apu.write_register(address, value),
+            0xFF40..=0xFF45 | 0xFF47..=0xFF4B => self.gpu.write_register(address, value),
+            DMA_ADDRESS => {
+                self.memory[address as usize] = value;
+                self.start_dma(value);
+            }
+            _ => self.memory[address as usize] = value,
+        }
+    }
+
+    fn check_watchpoints(&self, address: u16, access: Access, value: u8) {
+        let hit = self.watchpoints.iter().any(|watchpoint| {
+            watchpoint.address == address
+                && match access {
+                    Access::Read => watchpoint.on_read,
+                    Access::Write => watchpoint.on_write,
+                }
+        });
+        if hit && self.watch_hit.get().is_none() {
+            self.watch_hit.set(Some(WatchHit { address, access, value }));
+        }
+    }
+
+    // Returns and clears the first watchpoint hit since the last call
+    pub fn take_watch_hit(&self) -> Option<WatchHit> {
+        self.watch_hit.take()
+    }
+
+    // Snapshot of the whole address space. VRAM and OAM are read even while the GPU locks them
+    pub fn dump(&self) -> Vec<u8> {
+        (0..=0xFFFF)
+            .map(|address: u16| match address {
+                0x8000..=0x9FFF => self.gpu.vram[(address - 0x8000) as usize],
+                0xFE00..=0xFE9F => self.gpu.oam[(address - 0xFE00) as usize],
+                _ => self.peek(address),
+            })
+            .collect()
     }
-}
\ No newline at end of file
+
+    // OAM DMA copies 0xXX00-0xXX9F into OAM, one byte per M-cycle over the following 160
+    fn start_dma(&mut self, value: u8) {
+        self.dma_source = (value as u16) << 8;
+        self.dma_progress = 0;
+        self.dma_clock = 0;
+    }
+
+    pub fn dma_active(&self) -> bool {
+        self.dma_progress < DMA_LENGTH
+    }
+
+    fn step_dma(&mut self, cycles: u32) {
+        if !self.dma_active() {
+            return;
+        }
+        self.dma_clock += cycles;
+        while self.dma_clock >= DMA_CYCLES_PER_BYTE && self.dma_active() {
+            self.dma_clock -= DMA_CYCLES_PER_BYTE;
+            let byte = self.peek(self.dma_source.wrapping_add(self.dma_progress));
+            self.gpu.oam[self.dma_progress as usize] = byte;
+            self.dma_progress += 1;
+        }
+    }
+
+    fn request_interrupt(&mut self, interrupt: u8) {
+        self.memory[INTERRUPT_FLAGS_ADDRESS as usize] |= interrupt;
+    }
+
+    pub fn set_button_state(&mut self, button: Button, pressed: bool) {
+        if self.joypad.set_button_state(button, pressed) {
+            self.request_interrupt(JOYPAD_INTERRUPT);
+        }
+    }
+
+    // Called once per frame with the state reported by an InputSource
+    pub fn set_buttons(&mut self, buttons: Buttons) {
+        if self.joypad.set_buttons(buttons) {
+            self.request_interrupt(JOYPAD_INTERRUPT);
+        }
+    }
+
+    // Advance the memory mapped peripherals by the given number of clock cycles
+    pub fn tick(&mut self, cycles: u32) {
+        if self.flat {
+            return;
+        }
+        if self.timer.do_cycle(cycles) {
+            self.request_interrupt(TIMER_INTERRUPT);
+        }
+        if self.serial.do_cycle(cycles) {
+            self.request_interrupt(SERIAL_INTERRUPT);
+        }
+        self.step_dma(cycles);
+        let gpu_interrupts = self.gpu.step(cycles);
+        self.request_interrupt(gpu_interrupts);
+        self.apu.tick(cycles);
+        if let Some(cartridge) = &mut self.cartridge {
+            cartridge.tick(cycles);
+        }
+    }
+}
+
+impl Default for RAM {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RAM {
+    // Plain memory and OAM DMA progress. The peripherals save their own sections
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.memory);
+        writer.write_u16(self.dma_source);
+        writer.write_u16(self.dma_progress);
+        writer.write_u32(self.dma_clock);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        reader.read_into(&mut self.memory)?;
+        // Transfers always start on a page boundary, as written to the DMA register
+        self.dma_source = reader.read_u16()? & 0xFF00;
+        self.dma_progress = reader.read_u16()?.min(DMA_LENGTH);
+        self.dma_clock = reader.read_u32()?.min(DMA_CYCLES_PER_BYTE);
+        Ok(())
+    }
+}
@@ -46,6 +46,7 @@ pub enum FlagMasks {
   
   
   
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub struct Flags {
     pub zero: bool,
     pub subtract: bool,
@@ -111,7 +112,6 @@ impl Registers {
     /*
     af, bc, de, hl
     */
-    get_set_u16!(a, f, get_af, set_af);
     get_set_u16!(b, c, get_bc, set_bc);
     get_set_u16!(d, e, get_de, set_de);
     get_set_u16!(h, l, get_hl, set_hl); 
@@ -120,6 +120,16 @@ impl Registers {
         self.f
     }
 
+    pub fn get_af(&self) -> u16 {
+        ((self.a as u16) << 8) | (self.f as u16)
+    }
+
+    // Goes through set_f, as the low nibble of F doesn't exist and always reads back as 0
+    pub fn set_af(&mut self, val: u16) {
+        self.a = (val >> 8) as u8;
+        self.set_f((val & 0xFF) as u8);
+    }
+
     pub fn set_f(&mut self, value: u8) {
         let flags = Flags::from_u8(value);
         self.f = flags.to_u8();
@@ -128,12 +138,12 @@ impl Registers {
 
     pub fn get_and_increment_pc(&mut self) -> u16 {
         let pc = self.pc;
-        self.pc += 1;
+        self.pc = self.pc.wrapping_add(1);
         pc
     }
     
     pub fn increment_pc(&mut self) -> u16 {
-        self.pc += 1;
+        self.pc = self.pc.wrapping_add(1);
         self.pc
     }
     
@@ -0,0 +1,173 @@
+use std::collections::VecDeque;
+
+use crate::gb::GameBoy;
+
+/*
+Rewind buffer built on save states.
+
+Only the newest snapshot is kept whole. Every older one is stored as the XOR of itself
+with the snapshot that followed it, run-length encoded. Consecutive states differ in a few
+kilobytes at most, so the XOR is mostly zeros and compresses well. Stepping back XORs the
+newest delta into the current snapshot to get the one before it.
+
+Delta encoding, repeated until the end:
+  zero run length, literal length, literal bytes   (lengths are LEB128 varints)
+Deltas start with the length of the older snapshot, in case the two differ in size.
+*/
+
+pub const DEFAULT_INTERVAL: u32 = 1;
+pub const DEFAULT_BUDGET: usize = 32 * 1024 * 1024;
+
+pub struct Rewind {
+    interval: u32,               // Frames between snapshots
+    budget: usize,               // Bytes the snapshot and deltas may use together
+    snapshot: Vec<u8>,           // Newest state, empty until the first one is taken
+    deltas: VecDeque<Vec<u8>>,   // Older states, oldest first
+    delta_bytes: usize,
+    frames_since_snapshot: u32,
+}
+
+impl Rewind {
+    pub fn new(interval: u32, budget: usize) -> Self {
+        Rewind {
+            interval: interval.max(1),
+            budget,
+            snapshot: Vec::new(),
+            deltas: VecDeque::new(),
+            delta_bytes: 0,
+            frames_since_snapshot: 0,
+        }
+    }
+
+    // Call once after every frame. Takes a snapshot every interval frames
+    pub fn record(&mut self, gameboy: &GameBoy) {
+        self.frames_since_snapshot += 1;
+        if self.snapshot.is_empty() || self.frames_since_snapshot >= self.interval {
+            self.push(gameboy.save_state());
+        }
+    }
+
+    // Adds a snapshot, dropping the oldest ones until everything fits in the budget
+    pub fn push(&mut self, state: Vec<u8>) {
+        if !self.snapshot.is_empty() {
+            let delta = encode_delta(&self.snapshot, &state);
+            self.delta_bytes += delta.len();
+            self.deltas.push_back(delta);
+        }
+        self.snapshot = state;
+        self.frames_since_snapshot = 0;
+
+        while self.memory_used() > self.budget {
+            match self.deltas.pop_front() {
+                Some(delta) => self.delta_bytes -= delta.len(),
+                None => break,
+            }
+        }
+    }
+
+    // Goes back to the previous snapshot. If frames have run since the newest one, that is
+    // where it goes first. Returns false when there is nothing left to go back to
+    pub fn step_back(&mut self, gameboy: &mut GameBoy) -> bool {
+        if self.snapshot.is_empty() {
+            return false;
+        }
+        if self.frames_since_snapshot == 0 {
+            let Some(delta) = self.deltas.pop_back() else {
+                return false;
+            };
+            self.delta_bytes -= delta.len();
+            self.snapshot = decode_delta(&self.snapshot, &delta);
+        }
+        self.frames_since_snapshot = 0;
+        // Every snapshot came from this machine, so it always loads
+        gameboy.load_state(&self.snapshot).is_ok()
+    }
+
+    // Number of states that can still be stepped back to
+    pub fn len(&self) -> usize {
+        if self.snapshot.is_empty() { 0 } else { self.deltas.len() + 1 }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.snapshot.is_empty()
+    }
+
+    pub fn memory_used(&self) -> usize {
+        self.snapshot.len() + self.delta_bytes
+    }
+
+    pub fn clear(&mut self) {
+        self.snapshot.clear();
+        self.deltas.clear();
+        self.delta_bytes = 0;
+        self.frames_since_snapshot = 0;
+    }
+}
+
+fn write_varint(output: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        output.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    output.push(value as u8);
+}
+
+fn read_varint(input: &[u8], position: &mut usize) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+    while let Some(&byte) = input.get(*position) {
+        *position += 1;
+        value |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+    }
+    value
+}
+
+// XORs older against newer and run-length encodes the zeros
+pub fn encode_delta(newer: &[u8], older: &[u8]) -> Vec<u8> {
+    let length = newer.len().max(older.len());
+    let xor = |i: usize| newer.get(i).copied().unwrap_or(0) ^ older.get(i).copied().unwrap_or(0);
+
+    let mut output = Vec::new();
+    write_varint(&mut output, older.len());
+    let mut i = 0;
+    while i < length {
+        let zeros_start = i;
+        while i < length && xor(i) == 0 {
+            i += 1;
+        }
+        // A short run of zeros is cheaper to keep in the literal than to end it for
+        let literal_start = i;
+        while i < length && (xor(i) != 0 || (i + 2 < length && xor(i + 1) != 0 && xor(i + 2) != 0)) {
+            i += 1;
+        }
+        write_varint(&mut output, literal_start - zeros_start);
+        write_varint(&mut output, i - literal_start);
+        output.extend((literal_start..i).map(xor));
+    }
+    output
+}
+
+// Rebuilds the older state from the newer one and the delta between them
+pub fn decode_delta(newer: &[u8], delta: &[u8]) -> Vec<u8> {
+    let mut position = 0;
+    let length = read_varint(delta, &mut position);
+    let mut older = newer.to_vec();
+    older.resize(length.max(newer.len()), 0);
+
+    let mut i = 0;
+    while position < delta.len() {
+        i += read_varint(delta, &mut position);
+        let literal = read_varint(delta, &mut position);
+        for &byte in delta.get(position..position + literal).unwrap_or(&[]) {
+            older[i] ^= byte;
+            i += 1;
+        }
+        position += literal;
+    }
+    older.truncate(length);
+    older
+}
@@ -0,0 +1,137 @@
+#[cfg(test)]
+mod tests {
+    use crate::gb::joypad::Buttons;
+    use crate::gb::ram::RAM;
+    use crate::gb::rewind::{decode_delta, encode_delta, Rewind};
+    use crate::gb::GameBoy;
+
+    // Fills WRAM with the low byte of each address, over and over
+    const PROGRAM: [u8; 14] = [
+        0x21, 0x00, 0xC0, // LD HL, 0xC000
+        0x7D,             // loop: LD A, L
+        0x22,             // LD (HL+), A
+        0xCB, 0x6C,       // BIT 5, H
+        0x28, 0xFA,       // JR Z, loop
+        0x21, 0x00, 0xC0, // LD HL, 0xC000
+        0x18, 0xF5,       // JR loop
+    ];
+
+    fn create_ram() -> RAM {
+        let mut ram = RAM::new();
+        for (i, &byte) in PROGRAM.iter().enumerate() {
+            ram.write(0x0100 + i as u16, byte);
+        }
+        ram.write(0xFF40, 0x91); // LCD on
+        ram
+    }
+
+    // Helper function to capture what a test compares after stepping back
+    fn snapshot(gameboy: &GameBoy) -> (u64, u16, u16, Vec<u8>) {
+        let cpu = &gameboy.cpu;
+        (gameboy.frame, cpu.registers.get_pc(), cpu.registers.get_hl(), cpu.ram.dump())
+    }
+
+    #[test]
+    fn test_delta_round_trip() {
+        let newer: Vec<u8> = (0..1000).map(|i| (i / 7) as u8).collect();
+        let mut older = newer.clone();
+        older[3] ^= 0xFF;
+        older[500..520].fill(0xAA);
+        older[998] = 0;
+
+        let delta = encode_delta(&newer, &older);
+        assert!(delta.len() < 40, "Delta took {} bytes", delta.len());
+        assert_eq!(decode_delta(&newer, &delta), older);
+
+        // Identical states cost next to nothing
+        assert!(encode_delta(&newer, &newer).len() <= 5);
+        assert_eq!(decode_delta(&newer, &encode_delta(&newer, &newer)), newer);
+
+        // States of different lengths
+        assert_eq!(decode_delta(&newer, &encode_delta(&newer, &older[..600])), older[..600]);
+        let longer = [older.as_slice(), &[1, 2, 3]].concat();
+        assert_eq!(decode_delta(&newer, &encode_delta(&newer, &longer)), longer);
+    }
+
+    #[test]
+    fn test_step_back_frame_by_frame() {
+        let mut ram = create_ram();
+        let mut gameboy = GameBoy::new(&mut ram);
+        let mut rewind = Rewind::new(1, usize::MAX);
+        let mut expected = Vec::new();
+        for _ in 0..6 {
+            gameboy.run_frame(&mut |_| Buttons::NONE);
+            rewind.record(&gameboy);
+            expected.push((snapshot(&gameboy), gameboy.screen_buffer().to_vec()));
+        }
+        assert_eq!(rewind.len(), 6);
+
+        // The newest snapshot is where the machine already is, so the first step goes to the one before it
+        for (state, screen) in expected[..5].iter().rev() {
+            assert!(rewind.step_back(&mut gameboy));
+            assert_eq!(&snapshot(&gameboy), state);
+            assert_eq!(gameboy.screen_buffer(), screen.as_slice());
+        }
+        assert!(!rewind.step_back(&mut gameboy), "Nothing older than the first frame");
+        assert_eq!(gameboy.frame, 1);
+
+        // Playing on from a rewound state records from there
+        gameboy.run_frame(&mut |_| Buttons::NONE);
+        rewind.record(&gameboy);
+        assert_eq!(rewind.len(), 2);
+        assert_eq!(snapshot(&gameboy), expected[1].0);
+    }
+
+    #[test]
+    fn test_interval() {
+        let mut ram = create_ram();
+        let mut gameboy = GameBoy::new(&mut ram);
+        let mut rewind = Rewind::new(4, usize::MAX);
+        for _ in 0..10 {
+            gameboy.run_frame(&mut |_| Buttons::NONE);
+            rewind.record(&gameboy);
+        }
+        assert_eq!(rewind.len(), 3);
+
+        // Frames run since the last snapshot are undone first
+        for frame in [9, 5, 1] {
+            assert!(rewind.step_back(&mut gameboy));
+            assert_eq!(gameboy.frame, frame);
+        }
+        assert!(!rewind.step_back(&mut gameboy));
+    }
+
+    #[test]
+    fn test_memory_budget() {
+        let mut ram = create_ram();
+        let mut gameboy = GameBoy::new(&mut ram);
+        gameboy.run_frame(&mut |_| Buttons::NONE);
+        let state_size = gameboy.save_state().len();
+
+        // Deltas are a fraction of a full state
+        let mut rewind = Rewind::new(1, usize::MAX);
+        for _ in 0..10 {
+            gameboy.run_frame(&mut |_| Buttons::NONE);
+            rewind.record(&gameboy);
+        }
+        assert!(rewind.memory_used() < state_size * 3, "10 states took {} bytes", rewind.memory_used());
+
+        // A budget with little room for deltas drops the oldest ones
+        let budget = state_size + 512;
+        let mut rewind = Rewind::new(1, budget);
+        for _ in 0..40 {
+            gameboy.run_frame(&mut |_| Buttons::NONE);
+            rewind.record(&gameboy);
+            assert!(rewind.memory_used() <= budget);
+        }
+        let kept = rewind.len();
+        assert!(kept > 1 && kept < 40, "Kept {} states", kept);
+        let newest = gameboy.frame;
+        while rewind.step_back(&mut gameboy) {}
+        assert_eq!(gameboy.frame, newest + 1 - kept as u64);
+
+        rewind.clear();
+        assert!(rewind.is_empty());
+        assert_eq!(rewind.memory_used(), 0);
+    }
+}
@@ -1,8 +1,22 @@
+use crate::gb::state::{StateError, StateReader, StateWriter};
+
+// Only the most recent output is kept, so a ROM printing forever doesn't eat up memory
+const MAX_OUTPUT: usize = 0x10000;
+
+// A device plugged into the other end of the link cable
+pub trait SerialDevice {
+    // Receives the byte shifted out of SB and returns the byte shifted back in
+    fn exchange(&mut self, value: u8) -> u8;
+}
+
 pub struct Serial {
     sb: u8,  // Serial transfer data (0xFF01)
     sc: u8,  // Serial transfer control (0xFF02)
     clock_cycles: u64,
     transfer_cycles: u32,
+    output: Vec<u8>, // Bytes shifted out, at most MAX_OUTPUT recent ones, used by headless test harnesses
+    sink: Option<Box<dyn FnMut(u8)>>,
+    device: Option<Box<dyn SerialDevice>>,
 }
 
 impl Serial {
@@ -12,6 +26,9 @@ impl Serial {
             sc: 0,
             clock_cycles: 0,
             transfer_cycles: 0,
+            output: Vec::new(),
+            sink: None,
+            device: None,
         }
     }
 
@@ -37,21 +54,93 @@ impl Serial {
         }
     }
 
+    // Clears the registers and any transfer in progress. What is plugged in stays connected
+    pub fn reset(&mut self) {
+        self.sb = 0;
+        self.sc = 0;
+        self.clock_cycles = 0;
+        self.transfer_cycles = 0;
+    }
+
+    // Registers a callback that receives every byte shifted out of SB
+    pub fn set_sink(&mut self, sink: Box<dyn FnMut(u8)>) {
+        self.sink = Some(sink);
+    }
+
+    pub fn connect(&mut self, device: Box<dyn SerialDevice>) {
+        self.device = Some(device);
+    }
+
+    pub fn disconnect(&mut self) -> Option<Box<dyn SerialDevice>> {
+        self.device.take()
+    }
+
+    // The bytes transmitted so far, or the last MAX_OUTPUT of them after a long run
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+
+    // Transmitted bytes as text, e.g. the "Passed"/"Failed" report of Blargg's test ROMs
+    pub fn output_string(&self) -> String {
+        String::from_utf8_lossy(&self.output).into_owned()
+    }
+
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+
     pub fn do_cycle(&mut self, ticks: u32) -> bool {
         let mut interrupt_triggered = false;
 
-        // Check if transfer is in progress (bit 7 of SC is set)
-        if (self.sc & 0x80) != 0 {
+        // Check if transfer is in progress (bit 7 of SC is set). Only the internal clock
+        // (bit 0) is emulated, on the external one we wait for a partner that never clocks
+        if (self.sc & 0x81) == 0x81 {
             self.transfer_cycles += ticks;
 
             // Serial transfer takes 8 bits * 512 cycles per bit = 4096 cycles
             if self.transfer_cycles >= 4096 {
                 // Transfer complete
                 self.sc &= !0x80; // Clear transfer start bit
+                if self.output.len() == MAX_OUTPUT {
+                    self.output.drain(..MAX_OUTPUT / 2);
+                }
+                self.output.push(self.sb);
+                if let Some(sink) = self.sink.as_mut() {
+                    sink(self.sb);
+                }
+                // With nothing connected the line reads back as all ones
+                self.sb = match self.device.as_mut() {
+                    Some(device) => device.exchange(self.sb),
+                    None => 0xFF,
+                };
                 interrupt_triggered = true;
             }
         }
 
         interrupt_triggered
     }
-} 
\ No newline at end of file
+}
+
+impl Default for Serial {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Serial {
+    // The output log, sink and attached device belong to the host, not the machine
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.sb);
+        writer.write_u8(self.sc);
+        writer.write_u64(self.clock_cycles);
+        writer.write_u32(self.transfer_cycles);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.sb = reader.read_u8()?;
+        self.sc = reader.read_u8()?;
+        self.clock_cycles = reader.read_u64()?;
+        self.transfer_cycles = reader.read_u32()?.min(4096);
+        Ok(())
+    }
+}
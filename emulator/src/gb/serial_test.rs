@@ -0,0 +1,106 @@
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use crate::gb::cpu::CPU;
+    use crate::gb::ram::{RAM, INTERRUPT_FLAGS_ADDRESS};
+    use crate::gb::serial::Serial;
+
+    // Helper function to send a byte the way test ROMs do: load SB, then start an internal clock transfer
+    fn send_byte(ram: &mut RAM, value: u8) {
+        ram.write(0xFF01, value);
+        ram.write(0xFF02, 0x81);
+        ram.tick(4096);
+    }
+
+    #[test]
+    fn test_transfer_captures_byte() {
+        let mut ram = RAM::new();
+        send_byte(&mut ram, b'P');
+
+        assert_eq!(ram.serial.output(), b"P");
+        assert_eq!(ram.read(0xFF02) & 0x80, 0, "Transfer start bit should be cleared");
+        assert_eq!(ram.read(0xFF01), 0xFF, "SB should read back 0xFF with nothing connected");
+        assert_ne!(ram.read(INTERRUPT_FLAGS_ADDRESS) & 0x08, 0, "Serial interrupt should be requested");
+    }
+
+    #[test]
+    fn test_transfer_waits_for_full_byte() {
+        let mut ram = RAM::new();
+        ram.write(0xFF01, b'X');
+        ram.write(0xFF02, 0x81);
+        ram.tick(4095);
+
+        assert!(ram.serial.output().is_empty(), "Byte should not be sent before 4096 cycles");
+        assert_ne!(ram.read(0xFF02) & 0x80, 0, "Transfer should still be in progress");
+    }
+
+    #[test]
+    fn test_external_clock_waits_for_partner() {
+        let mut ram = RAM::new();
+        ram.write(0xFF01, b'X');
+        ram.write(0xFF02, 0x80);
+        ram.tick(4096 * 4);
+
+        assert!(ram.serial.output().is_empty(), "Nothing clocks the byte out");
+        assert_ne!(ram.read(0xFF02) & 0x80, 0, "Transfer should still be waiting");
+        assert_eq!(ram.read(0xFF01), b'X');
+        assert_eq!(ram.read(INTERRUPT_FLAGS_ADDRESS) & 0x08, 0, "No serial interrupt without a clock");
+    }
+
+    #[test]
+    fn test_sink_receives_bytes() {
+        let mut ram = RAM::new();
+        let received = Rc::new(RefCell::new(Vec::new()));
+        let sink_received = Rc::clone(&received);
+        ram.serial.set_sink(Box::new(move |byte| sink_received.borrow_mut().push(byte)));
+
+        for &byte in b"Passed" {
+            send_byte(&mut ram, byte);
+        }
+
+        assert_eq!(received.borrow().as_slice(), b"Passed");
+        assert_eq!(ram.serial.output_string(), "Passed");
+        assert_eq!(ram.serial.take_output(), b"Passed");
+        assert!(ram.serial.output().is_empty(), "Output should be empty after take_output");
+    }
+
+    #[test]
+    fn test_cpu_writes_reach_serial() {
+        let mut ram = RAM::new();
+        let program = [
+            0x21, 0x01, 0xFF, // LD HL, 0xFF01
+            0x3E, b'O',       // LD A, 'O'
+            0x77,             // LD (HL), A
+            0x2C,             // INC L
+            0x3E, 0x81,       // LD A, 0x81
+            0x77,             // LD (HL), A
+        ];
+        for (i, &byte) in program.iter().enumerate() {
+            ram.write(i as u16, byte);
+        }
+
+        let mut cpu = CPU::new(&mut ram);
+        for _ in 0..6 {
+            let cycles = cpu.step();
+            cpu.ram.tick(cycles as u32);
+        }
+        cpu.ram.tick(4096);
+
+        assert_eq!(cpu.ram.serial.output_string(), "O");
+    }
+
+    #[test]
+    fn test_output_is_capped() {
+        let mut serial = Serial::new();
+        for i in 0..0x10000 + 10 {
+            serial.write_register(0xFF01, i as u8);
+            serial.write_register(0xFF02, 0x81);
+            serial.do_cycle(4096);
+        }
+
+        assert!(serial.output().len() <= 0x10000, "Old output should be dropped");
+        assert_eq!(serial.output().last(), Some(&((0x10000 + 9) as u8)), "The newest byte is kept");
+    }
+}
@@ -0,0 +1,247 @@
+use std::collections::HashMap;
+use std::fmt;
+
+/*
+Binary save state format. Everything is little endian.
+
+  magic "GBST", version u16, compatible version u16, ROM CRC32 u32,
+  thumbnail width u16, height u16 and RGBA pixels,
+  then sections until the end: tag [u8; 4], length u32, payload
+
+Each component writes its own section and reads its fields back in the same order.
+To stay forward compatible, new fields are only ever appended to a section and new
+components get a new tag: readers skip unknown sections and ignore bytes left over at
+the end of one they know. The compatible version is the oldest reader able to load the
+file, and is only raised when a change can't be made that way.
+*/
+
+pub const STATE_MAGIC: &[u8; 4] = b"GBST";
+pub const STATE_VERSION: u16 = 1;
+const COMPATIBLE_VERSION: u16 = 1;
+
+pub const THUMBNAIL_WIDTH: usize = 80;
+pub const THUMBNAIL_HEIGHT: usize = 72;
+
+#[derive(Debug, PartialEq)]
+pub enum StateError {
+    BadMagic,
+    UnsupportedVersion(u16),         // Needs a newer emulator
+    RomMismatch { expected: u32, found: u32 },
+    Truncated,
+    MissingSection([u8; 4]),
+    InvalidValue(&'static str),      // A field that doesn't fit the machine, like a bad cartridge RAM size
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::BadMagic => write!(f, "Not a save state"),
+            StateError::UnsupportedVersion(version) => write!(f, "Save state needs version {} of the format", version),
+            StateError::RomMismatch { expected, found } => {
+                write!(f, "Save state belongs to another ROM (CRC32 {:08X}, loaded {:08X})", found, expected)
+            }
+            StateError::Truncated => write!(f, "Save state is truncated"),
+            StateError::MissingSection(tag) => write!(f, "Save state has no {} section", String::from_utf8_lossy(tag)),
+            StateError::InvalidValue(field) => write!(f, "Save state has an invalid {}", field),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+pub struct StateWriter {
+    bytes: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        StateWriter { bytes: Vec::new() }
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.bytes.push(value as u8);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    // Fixed size data, the reader has to know the length
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    // Variable size data, prefixed with its length
+    pub fn write_vec(&mut self, bytes: &[u8]) {
+        self.write_u32(bytes.len() as u32);
+        self.write_bytes(bytes);
+    }
+
+    // Writes a tagged section with whatever the closure writes as its payload
+    pub fn section(&mut self, tag: &[u8; 4], write: impl FnOnce(&mut StateWriter)) {
+        let mut payload = StateWriter::new();
+        write(&mut payload);
+        self.write_bytes(tag);
+        self.write_vec(&payload.bytes);
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+impl Default for StateWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct StateReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        StateReader { bytes, position: 0 }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.position >= self.bytes.len()
+    }
+
+    pub fn read_bytes(&mut self, length: usize) -> Result<&'a [u8], StateError> {
+        let end = self.position.checked_add(length).ok_or(StateError::Truncated)?;
+        let bytes = self.bytes.get(self.position..end).ok_or(StateError::Truncated)?;
+        self.position = end;
+        Ok(bytes)
+    }
+
+    // Fills a fixed size buffer, the counterpart of write_bytes
+    pub fn read_into(&mut self, buffer: &mut [u8]) -> Result<(), StateError> {
+        buffer.copy_from_slice(self.read_bytes(buffer.len())?);
+        Ok(())
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, StateError> {
+        Ok(self.read_u8()? != 0)
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, StateError> {
+        Ok(u16::from_le_bytes(self.read_bytes(2)?.try_into().unwrap()))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, StateError> {
+        Ok(u32::from_le_bytes(self.read_bytes(4)?.try_into().unwrap()))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, StateError> {
+        Ok(u64::from_le_bytes(self.read_bytes(8)?.try_into().unwrap()))
+    }
+
+    pub fn read_vec(&mut self) -> Result<&'a [u8], StateError> {
+        let length = self.read_u32()? as usize;
+        self.read_bytes(length)
+    }
+}
+
+pub struct StateHeader {
+    pub version: u16,
+    pub rom_checksum: u32,
+    pub thumbnail: Vec<u8>, // THUMBNAIL_WIDTH x THUMBNAIL_HEIGHT RGBA pixels
+}
+
+// Writes the header in front of the sections
+pub fn write_header(writer: &mut StateWriter, rom_checksum: u32, screen: &[u8], width: usize) {
+    writer.write_bytes(STATE_MAGIC);
+    writer.write_u16(STATE_VERSION);
+    writer.write_u16(COMPATIBLE_VERSION);
+    writer.write_u32(rom_checksum);
+    writer.write_u16(THUMBNAIL_WIDTH as u16);
+    writer.write_u16(THUMBNAIL_HEIGHT as u16);
+    writer.write_bytes(&thumbnail(screen, width));
+}
+
+// Reads just the header, e.g. to show the thumbnail in a list of save slots
+pub fn read_header(reader: &mut StateReader) -> Result<StateHeader, StateError> {
+    if reader.read_bytes(4)? != STATE_MAGIC {
+        return Err(StateError::BadMagic);
+    }
+    let version = reader.read_u16()?;
+    let compatible_version = reader.read_u16()?;
+    if compatible_version > STATE_VERSION {
+        return Err(StateError::UnsupportedVersion(compatible_version));
+    }
+    let rom_checksum = reader.read_u32()?;
+    let width = reader.read_u16()? as usize;
+    let height = reader.read_u16()? as usize;
+    let thumbnail = reader.read_bytes(width * height * 4)?.to_vec();
+    Ok(StateHeader { version, rom_checksum, thumbnail })
+}
+
+// Splits the rest of the state into its sections. Later duplicates win
+pub fn read_sections<'a>(reader: &mut StateReader<'a>) -> Result<HashMap<[u8; 4], StateReader<'a>>, StateError> {
+    let mut sections = HashMap::new();
+    while !reader.is_empty() {
+        let tag: [u8; 4] = reader.read_bytes(4)?.try_into().unwrap();
+        let payload = reader.read_vec()?;
+        sections.insert(tag, StateReader::new(payload));
+    }
+    Ok(sections)
+}
+
+// Half size copy of the screen, averaging each 2x2 block
+fn thumbnail(screen: &[u8], width: usize) -> Vec<u8> {
+    let mut pixels = vec![0; THUMBNAIL_WIDTH * THUMBNAIL_HEIGHT * 4];
+    for y in 0..THUMBNAIL_HEIGHT {
+        for x in 0..THUMBNAIL_WIDTH {
+            for channel in 0..4 {
+                let sum: u32 = [(0, 0), (1, 0), (0, 1), (1, 1)]
+                    .iter()
+                    .map(|(dx, dy)| screen.get(((y * 2 + dy) * width + x * 2 + dx) * 4 + channel).copied().unwrap_or(0) as u32)
+                    .sum();
+                pixels[(y * THUMBNAIL_WIDTH + x) * 4 + channel] = (sum / 4) as u8;
+            }
+        }
+    }
+    pixels
+}
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut value = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            value = if value & 1 != 0 { 0xEDB88320 ^ (value >> 1) } else { value >> 1 };
+            bit += 1;
+        }
+        table[i] = value;
+        i += 1;
+    }
+    table
+}
+
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+// The same CRC32 as zip and PNG use
+pub fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0u32, |crc, &byte| CRC32_TABLE[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8))
+}
@@ -0,0 +1,240 @@
+#[cfg(test)]
+mod tests {
+    use crate::gb::cartridge::Cartridge;
+    use crate::gb::joypad::{Button, Buttons, ScriptedInput};
+    use crate::gb::ram::RAM;
+    use crate::gb::state::{crc32, read_header, StateError, StateReader, StateWriter, THUMBNAIL_HEIGHT, THUMBNAIL_WIDTH};
+    use crate::gb::GameBoy;
+
+    // Fills WRAM with the low byte of each address and pokes the sound and timer registers
+    const PROGRAM: [u8; 17] = [
+        0x21, 0x00, 0xC0, // LD HL, 0xC000
+        0x7D,             // loop: LD A, L
+        0x22,             // LD (HL+), A
+        0xE0, 0x12,       // LDH (NR12), A
+        0xE0, 0x07,       // LDH (TAC), A
+        0xCB, 0x6C,       // BIT 5, H
+        0x28, 0xF6,       // JR Z, loop
+        0x21, 0x00, 0xC0, // LD HL, 0xC000
+        0x18,             // JR loop
+    ];
+
+    // Helper function to build an MBC3 ROM with a clock, battery RAM and the program above
+    fn create_rom(title: &[u8]) -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x100 + PROGRAM.len()].copy_from_slice(&PROGRAM);
+        rom[0x100 + PROGRAM.len()] = 0xF1; // Back to loop
+        rom[0x134..0x134 + title.len()].copy_from_slice(title);
+        rom[0x147] = 0x10; // MBC3 + TIMER + RAM + BATTERY
+        rom[0x148] = 0x00;
+        rom[0x149] = 0x02;
+        rom
+    }
+
+    fn create_ram(title: &[u8]) -> RAM {
+        let mut ram = RAM::with_cartridge(Cartridge::new(create_rom(title)).unwrap());
+        ram.write(0xFF40, 0x91); // LCD on
+        ram.write(0x0000, 0x0A); // Enable cartridge RAM
+        ram.write(0xA000, 0x5A);
+        ram
+    }
+
+    // Helper function to capture everything a test can compare after running
+    fn snapshot(gameboy: &GameBoy) -> (Vec<u8>, u16, u16, u64, u64, Vec<u8>) {
+        let cpu = &gameboy.cpu;
+        (cpu.ram.dump(), cpu.registers.get_af(), cpu.registers.get_pc(), cpu.clock_cycles, gameboy.frame, cpu.ram.cartridge.as_ref().unwrap().ram().to_vec())
+    }
+
+    fn scripted_input() -> ScriptedInput {
+        ScriptedInput::new(vec![(3, Buttons::NONE.with(Button::Start)), (6, Buttons::NONE)])
+    }
+
+    #[test]
+    fn test_round_trip_is_deterministic() {
+        let mut ram = create_ram(b"STATE");
+        let mut gameboy = GameBoy::new(&mut ram);
+        let mut input = scripted_input();
+        for _ in 0..5 {
+            gameboy.run_frame(&mut input);
+        }
+        let state = gameboy.save_state();
+        for _ in 0..5 {
+            gameboy.run_frame(&mut input);
+        }
+        let expected = snapshot(&gameboy);
+
+        // A fresh machine picks up exactly where the first one saved
+        let mut other_ram = create_ram(b"STATE");
+        let mut other = GameBoy::new(&mut other_ram);
+        other.load_state(&state).unwrap();
+        assert_eq!(other.frame, 5);
+        let mut input = scripted_input();
+        for _ in 0..5 {
+            other.run_frame(&mut input);
+        }
+        assert_eq!(snapshot(&other), expected);
+    }
+
+    #[test]
+    fn test_header() {
+        let mut ram = create_ram(b"STATE");
+        let mut gameboy = GameBoy::new(&mut ram);
+        gameboy.run_frame(&mut |_| Buttons::NONE);
+        let state = gameboy.save_state();
+
+        let header = read_header(&mut StateReader::new(&state)).unwrap();
+        assert_eq!(header.rom_checksum, crc32(&create_rom(b"STATE")));
+        assert_eq!(header.thumbnail.len(), THUMBNAIL_WIDTH * THUMBNAIL_HEIGHT * 4);
+        assert_eq!(header.thumbnail[..4], gameboy.screen_buffer()[..4]);
+    }
+
+    #[test]
+    fn test_refuses_other_rom() {
+        let mut ram = create_ram(b"STATE");
+        let state = GameBoy::new(&mut ram).save_state();
+
+        let mut other_ram = create_ram(b"OTHER");
+        let mut other = GameBoy::new(&mut other_ram);
+        let pc = other.cpu.registers.get_pc();
+        assert!(matches!(other.load_state(&state), Err(StateError::RomMismatch { .. })));
+        assert_eq!(other.cpu.registers.get_pc(), pc, "A refused state changes nothing");
+    }
+
+    #[test]
+    fn test_forward_compatible() {
+        let mut ram = create_ram(b"STATE");
+        let mut gameboy = GameBoy::new(&mut ram);
+        gameboy.run_frame(&mut |_| Buttons::NONE);
+        let mut state = gameboy.save_state();
+
+        // A newer writer with an extra section, still loadable by this version
+        state[4] = 7;
+        let mut writer = StateWriter::new();
+        writer.section(b"NEW!", |writer| writer.write_u32(0xDEADBEEF));
+        state.extend(writer.into_bytes());
+        assert_eq!(gameboy.load_state(&state), Ok(()));
+
+        // One that says it needs a newer reader
+        state[6] = 7;
+        assert_eq!(gameboy.load_state(&state), Err(StateError::UnsupportedVersion(7)));
+    }
+
+    #[test]
+    fn test_invalid_states() {
+        let mut ram = create_ram(b"STATE");
+        let mut gameboy = GameBoy::new(&mut ram);
+        let state = gameboy.save_state();
+
+        assert_eq!(gameboy.load_state(b"nope"), Err(StateError::BadMagic));
+        assert_eq!(gameboy.load_state(&state[..state.len() - 1]), Err(StateError::Truncated));
+
+        // Drop the trailing cartridge section
+        let cartridge = state.windows(4).rposition(|tag| tag == b"CART").unwrap();
+        assert_eq!(gameboy.load_state(&state[..cartridge]), Err(StateError::MissingSection(*b"CART")));
+    }
+
+    // Helper function for a repeatable stream of pseudo random numbers
+    fn next_random(seed: &mut u32) -> u32 {
+        *seed ^= *seed << 13;
+        *seed ^= *seed >> 17;
+        *seed ^= *seed << 5;
+        *seed
+    }
+
+    // Helper function to find the state's register fields, leaving out memory, VRAM and the screen
+    fn register_fields(state: &[u8]) -> Vec<usize> {
+        let width = u16::from_le_bytes([state[12], state[13]]) as usize;
+        let height = u16::from_le_bytes([state[14], state[15]]) as usize;
+        let mut offset = 16 + width * height * 4;
+        let mut fields = Vec::new();
+        while offset < state.len() {
+            let tag = &state[offset..offset + 4];
+            let length = u32::from_le_bytes(state[offset + 4..offset + 8].try_into().unwrap()) as usize;
+            let payload = offset + 8..offset + 8 + length;
+            match tag {
+                b"MEM " | b"CART" => fields.extend(payload.end - 24..payload.end),
+                b"GPU " => fields.extend(payload.start + 0x20A0..payload.start + 0x20B1),
+                _ => fields.extend(payload.clone()),
+            }
+            offset = payload.end;
+        }
+        fields
+    }
+
+    // Helper function to find where a section's payload is in a state
+    fn section_payload(state: &[u8], section: &[u8; 4]) -> std::ops::Range<usize> {
+        let width = u16::from_le_bytes([state[12], state[13]]) as usize;
+        let height = u16::from_le_bytes([state[14], state[15]]) as usize;
+        let mut offset = 16 + width * height * 4;
+        loop {
+            let length = u32::from_le_bytes(state[offset + 4..offset + 8].try_into().unwrap()) as usize;
+            if &state[offset..offset + 4] == section {
+                return offset + 8..offset + 8 + length;
+            }
+            offset += 8 + length;
+        }
+    }
+
+    #[test]
+    fn test_dma_source_near_end_of_memory() {
+        let mut ram = create_ram(b"STATE");
+        let mut gameboy = GameBoy::new(&mut ram);
+        let mut state = gameboy.save_state();
+
+        // A transfer from 0xFFF0 that has just started
+        let memory = section_payload(&state, b"MEM ");
+        state[memory.end - 8..memory.end].copy_from_slice(&[0xF0, 0xFF, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        gameboy.load_state(&state).unwrap();
+        gameboy.run_frame(&mut |_| Buttons::NONE);
+    }
+
+    #[test]
+    fn test_failed_load_changes_nothing() {
+        let mut ram = create_ram(b"STATE");
+        let mut gameboy = GameBoy::new(&mut ram);
+        gameboy.run_frame(&mut |_| Buttons::NONE);
+        let mut state = gameboy.save_state();
+        for _ in 0..3 {
+            gameboy.run_frame(&mut scripted_input());
+        }
+        let before = snapshot(&gameboy);
+        let screen = gameboy.screen_buffer().to_vec();
+
+        // The GPU mode is invalid, after the CPU and memory sections have loaded
+        let gpu = section_payload(&state, b"GPU ");
+        state[gpu.start + 0x20A4] = 9;
+        assert_eq!(gameboy.load_state(&state), Err(StateError::InvalidValue("GPU mode")));
+        assert_eq!(snapshot(&gameboy), before);
+        assert_eq!(gameboy.screen_buffer(), screen.as_slice());
+    }
+
+    // Whatever a corrupted state holds, loading it either fails or leaves something that runs
+    #[test]
+    fn test_corrupted_states_never_panic() {
+        let mut ram = create_ram(b"STATE");
+        let mut gameboy = GameBoy::new(&mut ram);
+        for _ in 0..3 {
+            gameboy.run_frame(&mut |_| Buttons::NONE);
+        }
+        let state = gameboy.save_state();
+        let fields = register_fields(&state);
+
+        let mut seed = 0xC0FF_EE00;
+        for _ in 0..500 {
+            let mut corrupted = state.clone();
+            for _ in 0..=next_random(&mut seed) % 8 {
+                let position = fields[next_random(&mut seed) as usize % fields.len()];
+                corrupted[position] = next_random(&mut seed) as u8;
+            }
+            if gameboy.load_state(&corrupted).is_ok() {
+                gameboy.run_frame(&mut |_| Buttons::NONE);
+            }
+        }
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+    }
+}
@@ -1,3 +1,5 @@
+use crate::gb::state::{StateError, StateReader, StateWriter};
+
 pub struct Timer {
     div: u8, // Divider register at 0xFF04
     tima: u8, // Timer counter at 0xFF05
@@ -33,7 +35,11 @@ impl Timer {
 
     pub fn write_register(&mut self, address: u16, value: u8) {
         match address {
-            0xFF04 => self.div = value,
+            0xFF04 => {
+                // Any write resets the divider
+                self.div = 0;
+                self.internal_div = 0;
+            }
             0xFF05 => self.tima = value,
             0xFF06 => self.tma = value,
             0xFF07 => self.tac = value,
@@ -77,3 +83,31 @@ impl Timer {
     }
 }
 
+impl Default for Timer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Timer {
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.div);
+        writer.write_u8(self.tima);
+        writer.write_u8(self.tma);
+        writer.write_u8(self.tac);
+        writer.write_u64(self.clock_cycles);
+        writer.write_u16(self.internal_div);
+        writer.write_u16(self.internal_tima);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.div = reader.read_u8()?;
+        self.tima = reader.read_u8()?;
+        self.tma = reader.read_u8()?;
+        self.tac = reader.read_u8()?;
+        self.clock_cycles = reader.read_u64()?;
+        self.internal_div = reader.read_u16()?;
+        self.internal_tima = reader.read_u16()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,106 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use crate::gb::cpu::CPU;
+
+/*
+Instruction traces, one line per instruction with the state before it runs:
+
+  A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02
+
+This is the format most emulators can log, so a trace can be diffed line by line against a
+known good one. Gameboy Doctor uses the same lines, but its reference logs were made with
+the LCD stubbed out so LY always reads 0x90. The Doctor format does the same while the
+tracer is attached, which changes what the game sees.
+
+Lines go through a buffered writer, so tracing millions of instructions stays cheap. The
+first write error stops the trace and is returned by finish.
+*/
+
+// Gameboy Doctor's logs have LY stuck at the start of VBlank
+pub const DOCTOR_LY: u8 = 0x90;
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum TraceFormat {
+    Registers,
+    Doctor,
+}
+
+pub struct Tracer {
+    output: Box<dyn Write>,
+    format: TraceFormat,
+    lines: u64,
+    error: Option<io::Error>,
+}
+
+impl Tracer {
+    pub fn new(output: Box<dyn Write>, format: TraceFormat) -> Self {
+        Tracer { output: Box::new(BufWriter::new(output)), format, lines: 0, error: None }
+    }
+
+    pub fn to_file(path: &Path, format: TraceFormat) -> io::Result<Self> {
+        Ok(Tracer::new(Box::new(File::create(path)?), format))
+    }
+
+    pub fn format(&self) -> TraceFormat {
+        self.format
+    }
+
+    // Number of instructions traced so far
+    pub fn lines(&self) -> u64 {
+        self.lines
+    }
+
+    pub fn trace(&mut self, cpu: &CPU) {
+        if self.error.is_some() {
+            return;
+        }
+        if let Err(error) = write_trace_line(&mut self.output, cpu) {
+            self.error = Some(error);
+        }
+        self.lines += 1;
+    }
+
+    // Flushes what is buffered and reports the first error, if writing ever failed
+    pub fn finish(mut self) -> io::Result<()> {
+        if let Some(error) = self.error.take() {
+            return Err(error);
+        }
+        self.output.flush()
+    }
+}
+
+// The state of the CPU as one trace line
+pub fn trace_line(cpu: &CPU) -> String {
+    let mut line = Vec::new();
+    write_trace_line(&mut line, cpu).expect("Writing to a Vec doesn't fail");
+    line.pop(); // The newline
+    String::from_utf8(line).unwrap()
+}
+
+// Writes the line straight to the output, as this runs for every instruction traced.
+// Memory is peeked so watchpoints don't fire
+fn write_trace_line(output: &mut impl Write, cpu: &CPU) -> io::Result<()> {
+    let registers = &cpu.registers;
+    let pc = registers.get_pc();
+    let memory = |offset: u16| cpu.ram.peek(pc.wrapping_add(offset));
+    writeln!(
+        output,
+        "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+        registers.get_a(),
+        registers.get_f(),
+        registers.get_b(),
+        registers.get_c(),
+        registers.get_d(),
+        registers.get_e(),
+        registers.get_h(),
+        registers.get_l(),
+        registers.get_sp(),
+        pc,
+        memory(0),
+        memory(1),
+        memory(2),
+        memory(3),
+    )
+}
@@ -0,0 +1,106 @@
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::io::{self, Write};
+    use std::rc::Rc;
+
+    use crate::gb::ram::RAM;
+    use crate::gb::trace::{trace_line, TraceFormat, Tracer};
+    use crate::gb::GameBoy;
+
+    // A writer the test can still read after handing it to a tracer
+    #[derive(Clone)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(bytes);
+            Ok(bytes.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    struct BrokenWriter;
+
+    impl Write for BrokenWriter {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::Error::other("disk full"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // NOP; JP 0x0213; ... 0x0213: LDH A, (LY); JR -4
+    fn create_ram() -> RAM {
+        let mut ram = RAM::new();
+        for (i, &byte) in [0x00, 0xC3, 0x13, 0x02].iter().enumerate() {
+            ram.write(0x0100 + i as u16, byte);
+        }
+        for (i, &byte) in [0xF0, 0x44, 0x18, 0xFC].iter().enumerate() {
+            ram.write(0x0213 + i as u16, byte);
+        }
+        ram
+    }
+
+    #[test]
+    fn test_trace_line() {
+        let mut ram = create_ram();
+        let gameboy = GameBoy::new(&mut ram);
+        assert_eq!(trace_line(&gameboy.cpu), "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02");
+    }
+
+    #[test]
+    fn test_traces_every_instruction() {
+        let mut ram = create_ram();
+        let mut gameboy = GameBoy::new(&mut ram);
+        let buffer = SharedBuffer(Rc::new(RefCell::new(Vec::new())));
+        gameboy.cpu.set_tracer(Tracer::new(Box::new(buffer.clone()), TraceFormat::Registers));
+        for _ in 0..4 {
+            gameboy.step();
+        }
+        let tracer = gameboy.cpu.take_tracer().unwrap();
+        assert_eq!(tracer.lines(), 4);
+        tracer.finish().unwrap();
+
+        let log = String::from_utf8(buffer.0.borrow().clone()).unwrap();
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0], "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02");
+        assert_eq!(lines[1], "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0101 PCMEM:C3,13,02,00");
+        assert_eq!(lines[2], "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0213 PCMEM:F0,44,18,FC");
+        assert!(lines[3].ends_with("PC:0215 PCMEM:18,FC,00,00"));
+
+        // Nothing is logged once the tracer is gone
+        gameboy.step();
+        assert_eq!(buffer.0.borrow().len(), log.len());
+    }
+
+    #[test]
+    fn test_doctor_format_pins_ly() {
+        let mut ram = create_ram();
+        let mut gameboy = GameBoy::new(&mut ram);
+        gameboy.cpu.set_tracer(Tracer::new(Box::new(io::sink()), TraceFormat::Doctor));
+        for _ in 0..3 {
+            gameboy.step();
+        }
+        assert_eq!(gameboy.cpu.registers.get_a(), 0x90);
+
+        gameboy.cpu.take_tracer();
+        assert_eq!(gameboy.cpu.ram.read(0xFF44), gameboy.cpu.ram.gpu.get_current_scanline());
+    }
+
+    #[test]
+    fn test_write_errors_are_reported() {
+        let mut ram = create_ram();
+        let mut gameboy = GameBoy::new(&mut ram);
+        gameboy.cpu.set_tracer(Tracer::new(Box::new(BrokenWriter), TraceFormat::Registers));
+        gameboy.step();
+        let error = gameboy.cpu.take_tracer().unwrap().finish().unwrap_err();
+        assert_eq!(error.to_string(), "disk full");
+    }
+}
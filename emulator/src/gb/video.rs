@@ -0,0 +1,219 @@
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use gif::{DisposalMethod, Encoder, Frame, Repeat};
+
+use crate::gb::frame_buffer::{FrameBuffer, SHADES};
+use crate::gb::{FRAMES_PER_SECOND, SCREEN_HEIGHT, SCREEN_WIDTH};
+
+/*
+Records gameplay clips from the frames the GPU finishes and the samples the APU mixes.
+
+GIF uses the four shades as its palette, which keeps files tiny. Only every other frame
+is kept, since viewers slow down frames shown for less than 2 centiseconds, and each one
+only stores the part of the screen that changed. A still screen becomes one long frame.
+
+Raw writes every frame back to back as 160x144 RGBA, with the sound next to it as a WAV
+file of 16-bit stereo PCM, for handing to an external encoder:
+
+  ffmpeg -f rawvideo -pixel_format rgba -video_size 160x144 -framerate 59.7275 -i clip.rgba -i clip.wav clip.mp4
+ */
+
+const GIF_FRAME_STEP: u64 = 2;
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum VideoFormat {
+    Gif,
+    Raw,
+}
+
+impl VideoFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            VideoFormat::Gif => "gif",
+            VideoFormat::Raw => "rgba",
+        }
+    }
+}
+
+pub struct VideoRecorder {
+    output: Output,
+    frames: u64, // Frames added so far
+}
+
+enum Output {
+    Gif(GifWriter),
+    Raw { video: BufWriter<File>, audio: WavWriter },
+}
+
+impl VideoRecorder {
+    // A raw recording's sound goes next to it, in a file with the .wav extension
+    pub fn create(path: &Path, format: VideoFormat, sample_rate: u32) -> io::Result<Self> {
+        let output = match format {
+            VideoFormat::Gif => Output::Gif(GifWriter::create(path)?),
+            VideoFormat::Raw => Output::Raw {
+                video: BufWriter::new(File::create(path)?),
+                audio: WavWriter::create(&path.with_extension("wav"), sample_rate)?,
+            },
+        };
+        Ok(VideoRecorder { output, frames: 0 })
+    }
+
+    // Adds a finished frame, along with the interleaved stereo samples made while it ran
+    pub fn add_frame(&mut self, frame: &FrameBuffer, samples: &[f32]) -> io::Result<()> {
+        match &mut self.output {
+            Output::Gif(gif) => {
+                if self.frames.is_multiple_of(GIF_FRAME_STEP) {
+                    gif.add_frame(frame.shades(), self.frames)?;
+                }
+            }
+            Output::Raw { video, audio } => {
+                video.write_all(frame.rgba())?;
+                audio.write_samples(samples)?;
+            }
+        }
+        self.frames += 1;
+        Ok(())
+    }
+
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    // Writes out whatever is still buffered. Without this the files are incomplete
+    pub fn finish(self) -> io::Result<()> {
+        match self.output {
+            Output::Gif(gif) => gif.finish(self.frames),
+            Output::Raw { mut video, audio } => {
+                video.flush()?;
+                audio.finish()
+            }
+        }
+    }
+}
+
+// How long into the recording a frame starts, in the centiseconds GIF delays are given in
+fn centiseconds(frame: u64) -> u64 {
+    (frame as f64 * 100.0 / FRAMES_PER_SECOND).round() as u64
+}
+
+struct GifWriter {
+    encoder: Encoder<BufWriter<File>>,
+    shown: Vec<u8>, // Shade of every pixel, as of the pending frame
+    // The last change to the screen and the frame it appeared on. It's written once the
+    // next change comes along, when it's known how long it stayed up
+    pending: Option<(Frame<'static>, u64)>,
+}
+
+impl GifWriter {
+    fn create(path: &Path) -> io::Result<Self> {
+        let palette: Vec<u8> = SHADES.iter().flat_map(|color| color[..3].to_vec()).collect();
+        let file = BufWriter::new(File::create(path)?);
+        let mut encoder = Encoder::new(file, SCREEN_WIDTH as u16, SCREEN_HEIGHT as u16, &palette).map_err(io::Error::other)?;
+        encoder.set_repeat(Repeat::Infinite).map_err(io::Error::other)?;
+        Ok(GifWriter { encoder, shown: Vec::new(), pending: None })
+    }
+
+    fn add_frame(&mut self, shades: &[u8], frame: u64) -> io::Result<()> {
+        let Some((left, top, right, bottom)) = changed_area(&self.shown, shades) else {
+            return Ok(());
+        };
+        self.write_pending(frame)?;
+
+        let mut buffer = Vec::with_capacity((right - left + 1) * (bottom - top + 1));
+        for y in top..=bottom {
+            buffer.extend_from_slice(&shades[y * SCREEN_WIDTH + left..=y * SCREEN_WIDTH + right]);
+        }
+        let area = Frame {
+            left: left as u16,
+            top: top as u16,
+            width: (right - left + 1) as u16,
+            height: (bottom - top + 1) as u16,
+            buffer: Cow::Owned(buffer),
+            dispose: DisposalMethod::Keep,
+            ..Frame::default()
+        };
+        self.pending = Some((area, frame));
+        self.shown = shades.to_vec();
+        Ok(())
+    }
+
+    // Writes the pending frame, shown until the given one replaces it
+    fn write_pending(&mut self, until: u64) -> io::Result<()> {
+        if let Some((mut area, since)) = self.pending.take() {
+            area.delay = (centiseconds(until) - centiseconds(since)).min(u16::MAX as u64) as u16;
+            self.encoder.write_frame(&area).map_err(io::Error::other)?;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self, frames: u64) -> io::Result<()> {
+        self.write_pending(frames)?;
+        self.encoder.into_inner()?.flush()
+    }
+}
+
+// The smallest rectangle holding every pixel that differs, as left, top, right and bottom
+fn changed_area(before: &[u8], after: &[u8]) -> Option<(usize, usize, usize, usize)> {
+    if before.len() != after.len() {
+        return Some((0, 0, SCREEN_WIDTH - 1, SCREEN_HEIGHT - 1));
+    }
+    let mut area: Option<(usize, usize, usize, usize)> = None;
+    for (i, _) in before.iter().zip(after).enumerate().filter(|(_, (before, after))| before != after) {
+        let (x, y) = (i % SCREEN_WIDTH, i / SCREEN_WIDTH);
+        area = Some(match area {
+            None => (x, y, x, y),
+            Some((left, top, right, bottom)) => (left.min(x), top.min(y), right.max(x), bottom.max(y)),
+        });
+    }
+    area
+}
+
+// 16-bit stereo PCM. The header's sizes are filled in once the length is known
+struct WavWriter {
+    file: BufWriter<File>,
+    data_size: u32,
+}
+
+impl WavWriter {
+    fn create(path: &Path, sample_rate: u32) -> io::Result<Self> {
+        let channels: u16 = 2;
+        let bytes_per_frame = channels * 2;
+        let mut header = Vec::new();
+        header.extend_from_slice(b"RIFF");
+        header.extend_from_slice(&0u32.to_le_bytes()); // Size of everything after this
+        header.extend_from_slice(b"WAVEfmt ");
+        header.extend_from_slice(&16u32.to_le_bytes());
+        header.extend_from_slice(&1u16.to_le_bytes()); // PCM
+        header.extend_from_slice(&channels.to_le_bytes());
+        header.extend_from_slice(&sample_rate.to_le_bytes());
+        header.extend_from_slice(&(sample_rate * bytes_per_frame as u32).to_le_bytes());
+        header.extend_from_slice(&bytes_per_frame.to_le_bytes());
+        header.extend_from_slice(&16u16.to_le_bytes()); // Bits per sample
+        header.extend_from_slice(b"data");
+        header.extend_from_slice(&0u32.to_le_bytes()); // Size of the samples
+
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(&header)?;
+        Ok(WavWriter { file, data_size: 0 })
+    }
+
+    fn write_samples(&mut self, samples: &[f32]) -> io::Result<()> {
+        for &sample in samples {
+            let value = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            self.file.write_all(&value.to_le_bytes())?;
+        }
+        self.data_size = self.data_size.saturating_add(samples.len() as u32 * 2);
+        Ok(())
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(4))?;
+        self.file.write_all(&self.data_size.saturating_add(36).to_le_bytes())?;
+        self.file.seek(SeekFrom::Start(40))?;
+        self.file.write_all(&self.data_size.to_le_bytes())?;
+        self.file.flush()
+    }
+}
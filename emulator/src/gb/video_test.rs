@@ -0,0 +1,84 @@
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+
+    use crate::gb::frame_buffer::{FrameBuffer, SHADES};
+    use crate::gb::video::{VideoFormat, VideoRecorder};
+    use crate::gb::{SCREEN_HEIGHT, SCREEN_WIDTH};
+
+    // Helper function for a white screen with black pixels at the given spots
+    fn screen(black: &[(usize, usize)]) -> FrameBuffer {
+        let mut frame = FrameBuffer::new();
+        for y in 0..SCREEN_HEIGHT {
+            for x in 0..SCREEN_WIDTH {
+                frame.set_pixel(x, y, if black.contains(&(x, y)) { 3 } else { 0 });
+            }
+        }
+        frame
+    }
+
+    #[test]
+    fn test_gif() {
+        let path = std::env::temp_dir().join(format!("gb_video_test_{}.gif", std::process::id()));
+        let mut recorder = VideoRecorder::create(&path, VideoFormat::Gif, 48_000).unwrap();
+        for _ in 0..4 {
+            recorder.add_frame(&screen(&[]), &[]).unwrap();
+        }
+        for _ in 0..4 {
+            recorder.add_frame(&screen(&[(10, 20), (30, 100)]), &[]).unwrap();
+        }
+        assert_eq!(recorder.frames(), 8);
+        recorder.finish().unwrap();
+
+        let mut options = gif::DecodeOptions::new();
+        options.set_color_output(gif::ColorOutput::Indexed);
+        let mut decoder = options.read_info(File::open(&path).unwrap()).unwrap();
+        let palette: Vec<u8> = SHADES.iter().flat_map(|color| color[..3].to_vec()).collect();
+        assert_eq!(decoder.global_palette(), Some(&palette[..]));
+
+        // The unchanged frames are merged into one
+        let first = decoder.read_next_frame().unwrap().unwrap().clone();
+        assert_eq!((first.left, first.top, first.width, first.height), (0, 0, 160, 144));
+        assert!(first.buffer.iter().all(|&shade| shade == 0));
+        assert_eq!(first.delay, 7, "4 frames are 6.7 centiseconds");
+
+        // Then only the area around the two pixels that changed
+        let second = decoder.read_next_frame().unwrap().unwrap().clone();
+        assert_eq!((second.left, second.top, second.width, second.height), (10, 20, 21, 81));
+        assert_eq!(second.buffer[0], 3);
+        assert_eq!(second.buffer[second.buffer.len() - 1], 3);
+        assert_eq!(second.buffer.iter().filter(|&&shade| shade == 3).count(), 2);
+        assert_eq!(second.delay, 6);
+
+        assert!(decoder.read_next_frame().unwrap().is_none());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_raw() {
+        let path = std::env::temp_dir().join(format!("gb_video_test_{}.rgba", std::process::id()));
+        let mut recorder = VideoRecorder::create(&path, VideoFormat::Raw, 44_100).unwrap();
+        for frame in 0..3 {
+            recorder.add_frame(&screen(&[(frame, 0)]), &[0.5, -1.0, 0.0, 2.0]).unwrap();
+        }
+        recorder.finish().unwrap();
+
+        let video = std::fs::read(&path).unwrap();
+        assert_eq!(video.len(), 3 * SCREEN_WIDTH * SCREEN_HEIGHT * 4);
+        assert_eq!(video[SCREEN_WIDTH * SCREEN_HEIGHT * 4 + 4..][..4], SHADES[3], "The second frame's black pixel");
+
+        let wav_path = path.with_extension("wav");
+        let wav = std::fs::read(&wav_path).unwrap();
+        let word = |offset: usize| u32::from_le_bytes(wav[offset..offset + 4].try_into().unwrap());
+        assert_eq!(wav.len(), 44 + 3 * 4 * 2);
+        assert_eq!((&wav[0..4], &wav[8..16], &wav[36..40]), (&b"RIFF"[..], &b"WAVEfmt "[..], &b"data"[..]));
+        assert_eq!(word(4), 36 + 24);
+        assert_eq!(word(24), 44_100);
+        assert_eq!(word(40), 24);
+        let samples: Vec<i16> = wav[44..52].chunks(2).map(|pair| i16::from_le_bytes([pair[0], pair[1]])).collect();
+        assert_eq!(samples, [16383, -32767, 0, 32767], "Clamped to the 16-bit range");
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&wav_path).unwrap();
+    }
+}
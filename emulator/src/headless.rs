@@ -0,0 +1,295 @@
+use std::fs;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+
+use serde_json::{json, Value};
+
+use crate::gb::cpu::CPU;
+use crate::gb::joypad::Buttons;
+use crate::gb::movie::{Movie, MovieError, MoviePlayer};
+use crate::gb::ram::RAM;
+use crate::gb::trace::{TraceFormat, Tracer};
+use crate::gb::video::{VideoFormat, VideoRecorder};
+use crate::gb::GameBoy;
+
+/*
+Runs a ROM without a window or audio device, for CI and test scripts.
+
+The run ends after a number of frames or as soon as a stop condition holds, then
+optionally writes a screenshot, a dump of the address space and a JSON summary. Every
+instruction can also be traced to a file, see trace.rs, and every frame recorded as a
+clip, see video.rs.
+*/
+
+pub const DEFAULT_FRAME_LIMIT: u64 = 60 * 60; // One minute of emulated time
+
+// LD B, B does nothing, so test ROMs like Mooneye's and dmg-acid2 use it as a breakpoint
+pub const BREAKPOINT_OPCODE: u8 = 0x40;
+
+// What Mooneye tests leave in B, C, D, E, H and L when they pass. A failure fills them with 0x42
+pub const MOONEYE_PASS: [u8; 6] = [3, 5, 8, 13, 21, 34];
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum StopCondition {
+    Pc(u16),             // The next instruction is at this address
+    Serial(String),      // The serial output contains this text
+    Memory(u16, u8),     // The byte at this address has this value
+    Breakpoint,          // The next instruction is LD B, B
+}
+
+impl StopCondition {
+    pub fn is_met(&self, cpu: &CPU) -> bool {
+        match self {
+            StopCondition::Pc(address) => cpu.registers.get_pc() == *address,
+            StopCondition::Serial(text) => cpu.ram.serial.output_string().contains(text.as_str()),
+            StopCondition::Memory(address, value) => cpu.ram.peek(*address) == *value,
+            StopCondition::Breakpoint => cpu.ram.peek(cpu.registers.get_pc()) == BREAKPOINT_OPCODE,
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            StopCondition::Pc(address) => format!("pc == {:#06X}", address),
+            StopCondition::Serial(text) => format!("serial contains {:?}", text),
+            StopCondition::Memory(address, value) => format!("[{:#06X}] == {:#04X}", address, value),
+            StopCondition::Breakpoint => "ld b, b".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum ExitStatus {
+    ConditionMet, // One of the stop conditions held
+    FrameLimit,   // Ran out of frames before any condition held
+    Crashed,      // The emulator panicked
+    LockedUp,     // The CPU ran an illegal opcode and hung, as the hardware does
+    Desync,       // The movie being played no longer matched the machine
+    VideoFailed,  // The run went fine, but the clip couldn't be written
+}
+
+impl ExitStatus {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ExitStatus::ConditionMet => "condition_met",
+            ExitStatus::FrameLimit => "frame_limit",
+            ExitStatus::Crashed => "crashed",
+            ExitStatus::LockedUp => "locked_up",
+            ExitStatus::Desync => "desync",
+            ExitStatus::VideoFailed => "video_failed",
+        }
+    }
+
+    // Process exit code. Hitting the frame limit is only a failure if something was waited for
+    pub fn code(&self, has_conditions: bool) -> i32 {
+        match self {
+            ExitStatus::ConditionMet => 0,
+            ExitStatus::FrameLimit if has_conditions => 1,
+            ExitStatus::FrameLimit => 0,
+            ExitStatus::Crashed => 2,
+            ExitStatus::LockedUp => 2,
+            ExitStatus::Desync => 1,
+            ExitStatus::VideoFailed => 3,
+        }
+    }
+}
+
+pub struct HeadlessOptions {
+    pub frames: u64,
+    pub conditions: Vec<StopCondition>,
+    pub screenshot: Option<PathBuf>,
+    pub screenshot_scale: u32,
+    pub memory_dump: Option<PathBuf>,
+    pub summary: Option<PathBuf>,
+    pub movie: Option<Movie>, // Inputs to play, without one no buttons are pressed
+    pub trace: Option<PathBuf>,
+    pub trace_format: TraceFormat,
+    pub video: Option<PathBuf>,
+    pub video_format: VideoFormat,
+}
+
+impl HeadlessOptions {
+    pub fn new() -> Self {
+        HeadlessOptions {
+            frames: DEFAULT_FRAME_LIMIT,
+            conditions: Vec::new(),
+            screenshot: None,
+            screenshot_scale: 1,
+            memory_dump: None,
+            summary: None,
+            movie: None,
+            trace: None,
+            trace_format: TraceFormat::Registers,
+            video: None,
+            video_format: VideoFormat::Gif,
+        }
+    }
+}
+
+impl Default for HeadlessOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct RunResult {
+    pub status: ExitStatus,
+    pub exit_code: i32,
+    pub frames: u64,
+    pub cycles: u64,
+    pub condition: Option<StopCondition>, // The condition that ended the run
+    pub error: Option<String>,           // Panic, lockup, desync or video message when the run failed
+    pub summary: Value,
+}
+
+// Runs the ROM already loaded into ram and writes whichever outputs were asked for
+pub fn run(ram: &mut RAM, options: &HeadlessOptions) -> io::Result<RunResult> {
+    let mut gameboy = GameBoy::new(ram);
+    let mut met = None;
+    let mut player = None;
+    if let Some(movie) = &options.movie {
+        player = Some(MoviePlayer::new(&mut gameboy, movie.clone()).map_err(io::Error::other)?);
+    }
+    if let Some(path) = &options.trace {
+        gameboy.cpu.set_tracer(Tracer::to_file(path, options.trace_format)?);
+    }
+    let mut recorder = match &options.video {
+        Some(path) => Some(VideoRecorder::create(path, options.video_format, gameboy.cpu.ram.apu.sample_rate())?),
+        None => None,
+    };
+    let mut video_error = None;
+    // Serial conditions only need checking again once more output has come in
+    let mut serial_checked = None;
+
+    // Once the movie ends the run carries on with nothing pressed
+    let outcome = panic::catch_unwind(AssertUnwindSafe(|| -> Result<(), MovieError> {
+        while gameboy.frame < options.frames {
+            let buttons = player.as_mut().and_then(|player| player.next_frame(&mut gameboy)).unwrap_or(Buttons::NONE);
+            // A locked up CPU never does anything again, so there's no point running on
+            let mut condition = |cpu: &CPU| {
+                let serial_length = Some(cpu.ram.serial.output().len());
+                let serial_changed = serial_length != serial_checked;
+                serial_checked = serial_length;
+                met = options
+                    .conditions
+                    .iter()
+                    .filter(|condition| serial_changed || !matches!(condition, StopCondition::Serial(_)))
+                    .find(|condition| condition.is_met(cpu))
+                    .cloned();
+                met.is_some() || cpu.lockup.is_some()
+            };
+            if gameboy.run_frame_until(&mut |_| buttons, &mut condition) {
+                return Ok(());
+            }
+            // Samples pile up in the APU unless taken, so they're drained even with no recording.
+            // A failed write only stops the recording, the run goes on and reports it at the end
+            let samples = gameboy.cpu.ram.apu.take_samples();
+            if let Some(video) = &mut recorder
+                && let Err(error) = video.add_frame(gameboy.frame_buffer(), &samples)
+            {
+                video_error = Some(error);
+                recorder = None;
+            }
+            if let Some(player) = &mut player {
+                player.check_hash(&gameboy)?;
+            }
+        }
+        Ok(())
+    }));
+
+    let (status, error) = match outcome {
+        Ok(Err(desync)) => (ExitStatus::Desync, Some(desync.to_string())),
+        Ok(Ok(())) if met.is_some() => (ExitStatus::ConditionMet, None),
+        Ok(Ok(())) if let Some(lockup) = gameboy.cpu.lockup => (ExitStatus::LockedUp, Some(lockup.to_string())),
+        Ok(Ok(())) => (ExitStatus::FrameLimit, None),
+        Err(payload) => {
+            let message = payload
+                .downcast_ref::<String>()
+                .cloned()
+                .or_else(|| payload.downcast_ref::<&str>().map(|s| s.to_string()))
+                .unwrap_or_else(|| "unknown panic".to_string());
+            (ExitStatus::Crashed, Some(message))
+        }
+    };
+
+    if let Some(tracer) = gameboy.cpu.take_tracer() {
+        tracer.finish()?;
+    }
+    let video_error = video_error.or_else(|| recorder.and_then(|video| video.finish().err())).map(|error| error.to_string());
+    // Whatever else went wrong matters more than the clip
+    let (status, error) = match (status, &video_error) {
+        (ExitStatus::ConditionMet | ExitStatus::FrameLimit, Some(video_error)) => (ExitStatus::VideoFailed, Some(video_error.clone())),
+        _ => (status, error),
+    };
+    if let Some(path) = &options.screenshot {
+        gameboy.screenshot().scaled(options.screenshot_scale).save_png(path)?;
+    }
+    if let Some(path) = &options.memory_dump {
+        fs::write(path, gameboy.cpu.ram.dump())?;
+    }
+
+    let exit_code = status.code(!options.conditions.is_empty());
+    let registers = &gameboy.cpu.registers;
+    let summary = json!({
+        "status": status.name(),
+        "exit_code": exit_code,
+        "condition": met.as_ref().map(|condition| condition.describe()),
+        "error": error,
+        "frames": gameboy.frame,
+        "cycles": gameboy.cpu.clock_cycles,
+        "registers": {
+            "a": registers.get_a(),
+            "f": registers.get_f(),
+            "b": registers.get_b(),
+            "c": registers.get_c(),
+            "d": registers.get_d(),
+            "e": registers.get_e(),
+            "h": registers.get_h(),
+            "l": registers.get_l(),
+            "sp": registers.get_sp(),
+            "pc": registers.get_pc(),
+        },
+        "serial": gameboy.cpu.ram.serial.output_string(),
+        "mooneye_passed": (met == Some(StopCondition::Breakpoint)).then(|| mooneye_passed(&gameboy.cpu)),
+        "screenshot": options.screenshot.as_ref().map(|path| path.display().to_string()),
+        "memory_dump": options.memory_dump.as_ref().map(|path| path.display().to_string()),
+        "video": options.video.as_ref().map(|path| path.display().to_string()),
+        "video_error": video_error,
+    });
+    if let Some(path) = &options.summary {
+        fs::write(path, serde_json::to_string_pretty(&summary).map_err(io::Error::other)?)?;
+    }
+
+    Ok(RunResult {
+        status,
+        exit_code,
+        frames: gameboy.frame,
+        cycles: gameboy.cpu.clock_cycles,
+        condition: met,
+        error,
+        summary,
+    })
+}
+
+// Whether the registers hold the Fibonacci numbers a passing Mooneye test ends with
+pub fn mooneye_passed(cpu: &CPU) -> bool {
+    let registers = &cpu.registers;
+    [registers.get_b(), registers.get_c(), registers.get_d(), registers.get_e(), registers.get_h(), registers.get_l()] == MOONEYE_PASS
+}
+
+// Parses "0x1234", "$1234" or decimal
+pub fn parse_number(text: &str) -> Option<u32> {
+    if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")).or_else(|| text.strip_prefix('$')) {
+        u32::from_str_radix(hex, 16).ok()
+    } else {
+        text.parse().ok()
+    }
+}
+
+// Parses the "ADDRESS=VALUE" argument of --until-mem
+pub fn parse_memory_condition(text: &str) -> Option<StopCondition> {
+    let (address, value) = text.split_once('=')?;
+    let address = u16::try_from(parse_number(address)?).ok()?;
+    let value = u8::try_from(parse_number(value)?).ok()?;
+    Some(StopCondition::Memory(address, value))
+}
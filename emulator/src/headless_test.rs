@@ -0,0 +1,227 @@
+#[cfg(test)]
+mod tests {
+    use crate::headless::{self, ExitStatus, HeadlessOptions, StopCondition};
+    use crate::gb::apu::DEFAULT_SAMPLE_RATE;
+    use crate::gb::image::load_png;
+    use crate::gb::joypad::{Button, Buttons};
+    use crate::gb::movie::{MovieRecorder, MovieStart};
+    use crate::gb::ram::{Watchpoint, RAM};
+    use crate::gb::video::VideoFormat;
+    use crate::gb::GameBoy;
+
+    // Helper function to load a program at 0x0100, followed by an idle loop at 0x0150
+    fn create_ram(program: &[u8]) -> RAM {
+        let mut ram = RAM::new();
+        let mut code = program.to_vec();
+        code.extend_from_slice(&[0xC3, 0x50, 0x01]); // JP 0x0150
+        for (i, &byte) in code.iter().enumerate() {
+            ram.write(0x0100 + i as u16, byte);
+        }
+        for (i, &byte) in [0x00, 0xC3, 0x50, 0x01].iter().enumerate() {
+            ram.write(0x0150 + i as u16, byte); // NOP; JP 0x0150
+        }
+        ram
+    }
+
+    fn options(frames: u64, conditions: Vec<StopCondition>) -> HeadlessOptions {
+        let mut options = HeadlessOptions::new();
+        options.frames = frames;
+        options.conditions = conditions;
+        options
+    }
+
+    #[test]
+    fn test_frame_limit() {
+        let mut ram = create_ram(&[]);
+        let result = headless::run(&mut ram, &options(3, Vec::new())).unwrap();
+        assert_eq!(result.status, ExitStatus::FrameLimit);
+        assert_eq!(result.exit_code, 0, "Running out of frames is fine when nothing was waited for");
+        assert_eq!(result.frames, 3);
+    }
+
+    #[test]
+    fn test_samples_do_not_pile_up() {
+        let mut ram = create_ram(&[]);
+        headless::run(&mut ram, &options(30, Vec::new())).unwrap();
+        let samples_per_frame = DEFAULT_SAMPLE_RATE as usize * 2 / 59;
+        assert!(ram.apu.take_samples().len() <= samples_per_frame, "Only the last frame's samples are left");
+    }
+
+    #[test]
+    fn test_unmet_condition_fails() {
+        let mut ram = create_ram(&[]);
+        let result = headless::run(&mut ram, &options(2, vec![StopCondition::Pc(0x4000)])).unwrap();
+        assert_eq!(result.status, ExitStatus::FrameLimit);
+        assert_eq!(result.exit_code, 1);
+    }
+
+    #[test]
+    fn test_until_pc() {
+        let mut ram = create_ram(&[]);
+        let result = headless::run(&mut ram, &options(10, vec![StopCondition::Pc(0x0151)])).unwrap();
+        assert_eq!(result.status, ExitStatus::ConditionMet);
+        assert_eq!(result.condition, Some(StopCondition::Pc(0x0151)));
+        assert_eq!(result.frames, 0, "Should stop in the middle of the first frame");
+        assert_eq!(result.summary["registers"]["pc"], 0x0151);
+    }
+
+    #[test]
+    fn test_until_serial() {
+        let mut ram = create_ram(&[
+            0x3E, b'O',       // LD A, 'O'
+            0x21, 0x01, 0xFF, // LD HL, 0xFF01
+            0x77,             // LD (HL), A
+            0x3E, 0x81,       // LD A, 0x81
+            0x2C,             // INC L
+            0x77,             // LD (HL), A
+        ]);
+        let conditions = vec![StopCondition::Serial("OK".to_string()), StopCondition::Serial("O".to_string())];
+        let result = headless::run(&mut ram, &options(10, conditions)).unwrap();
+        assert_eq!(result.condition, Some(StopCondition::Serial("O".to_string())));
+        assert_eq!(result.summary["serial"], "O");
+    }
+
+    #[test]
+    fn test_until_memory() {
+        let mut ram = create_ram(&[
+            0x21, 0x00, 0xC0, // LD HL, 0xC000
+            0x36, 0x42,       // LD (HL), 0x42
+        ]);
+        let condition = headless::parse_memory_condition("0xC000=0x42").unwrap();
+        assert_eq!(condition, StopCondition::Memory(0xC000, 0x42));
+        let result = headless::run(&mut ram, &options(10, vec![condition])).unwrap();
+        assert_eq!(result.status, ExitStatus::ConditionMet);
+    }
+
+    #[test]
+    fn test_memory_condition_skips_watchpoints() {
+        let mut ram = create_ram(&[]);
+        ram.write(0xC000, 0x42);
+        ram.watchpoints.push(Watchpoint { address: 0xC000, on_read: true, on_write: false });
+        let result = headless::run(&mut ram, &options(1, vec![StopCondition::Memory(0xC000, 0x42)])).unwrap();
+        assert_eq!(result.status, ExitStatus::ConditionMet);
+        assert_eq!(ram.take_watch_hit(), None, "Checking the condition isn't a read by the game");
+    }
+
+    #[test]
+    fn test_until_breakpoint() {
+        let mut ram = create_ram(&[
+            0x06, 3, 0x0E, 5, 0x16, 8, 0x1E, 13, 0x26, 21, 0x2E, 34, // LD B, 3 ... LD L, 34
+            0x40,                                                     // LD B, B
+        ]);
+        let result = headless::run(&mut ram, &options(10, vec![StopCondition::Breakpoint])).unwrap();
+        assert_eq!(result.status, ExitStatus::ConditionMet);
+        assert_eq!(result.summary["registers"]["pc"], 0x010C);
+        assert_eq!(result.summary["mooneye_passed"], true);
+
+        let mut ram = create_ram(&[0x06, 0x42, 0x40]); // LD B, 0x42; LD B, B
+        let result = headless::run(&mut ram, &options(10, vec![StopCondition::Breakpoint])).unwrap();
+        assert_eq!(result.summary["mooneye_passed"], false);
+    }
+
+    #[test]
+    fn test_lockup_is_reported() {
+        let mut ram = create_ram(&[0x00, 0xD3]);
+        let result = headless::run(&mut ram, &options(10, Vec::new())).unwrap();
+        assert_eq!(result.status, ExitStatus::LockedUp);
+        assert_eq!(result.exit_code, 2);
+        assert_eq!(result.error.as_deref(), Some("illegal opcode 0xD3 at 0x0101"));
+        assert_eq!(result.frames, 0, "The run stops as soon as the CPU hangs");
+        assert_eq!(result.summary["status"], "locked_up");
+        assert_eq!(result.summary["registers"]["pc"], 0x0101);
+    }
+
+    #[test]
+    fn test_outputs_written() {
+        let dir = std::env::temp_dir().join(format!("gb_headless_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut options = options(1, Vec::new());
+        options.screenshot = Some(dir.join("screen.png"));
+        options.screenshot_scale = 2;
+        options.memory_dump = Some(dir.join("memory.bin"));
+        options.summary = Some(dir.join("summary.json"));
+        options.trace = Some(dir.join("trace.log"));
+        options.video = Some(dir.join("clip.rgba"));
+        options.video_format = VideoFormat::Raw;
+
+        let mut ram = create_ram(&[]);
+        headless::run(&mut ram, &options).unwrap();
+
+        let (width, height, _) = load_png(&dir.join("screen.png")).unwrap();
+        assert_eq!((width, height), (320, 288), "Scaled up twice");
+        let dump = std::fs::read(dir.join("memory.bin")).unwrap();
+        assert_eq!(dump.len(), 0x10000);
+        assert_eq!(&dump[0x0150..0x0154], &[0x00, 0xC3, 0x50, 0x01]);
+        let summary: serde_json::Value = serde_json::from_slice(&std::fs::read(dir.join("summary.json")).unwrap()).unwrap();
+        assert_eq!(summary["status"], "frame_limit");
+        assert_eq!(summary["frames"], 1);
+        let trace = std::fs::read_to_string(dir.join("trace.log")).unwrap();
+        assert!(trace.starts_with("A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:C3,50,01,00\n"));
+        assert!(trace.lines().count() > 5000, "A frame runs thousands of instructions");
+        assert_eq!(std::fs::read(dir.join("clip.rgba")).unwrap().len(), 160 * 144 * 4);
+        assert!(dir.join("clip.wav").exists());
+        assert_eq!(summary["video"], dir.join("clip.rgba").display().to_string());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_video_error_keeps_other_outputs() {
+        let dir = std::env::temp_dir().join(format!("gb_headless_video_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut options = options(2, vec![StopCondition::Pc(0x0151)]);
+        options.video = Some("/dev/full".into()); // Every write fails
+        options.screenshot = Some(dir.join("screen.png"));
+        options.summary = Some(dir.join("summary.json"));
+
+        let mut ram = create_ram(&[]);
+        let result = headless::run(&mut ram, &options).unwrap();
+        assert_eq!(result.status, ExitStatus::VideoFailed);
+        assert_eq!(result.exit_code, 3);
+        assert_eq!(result.condition, Some(StopCondition::Pc(0x0151)), "The run itself still finished");
+
+        assert!(dir.join("screen.png").exists());
+        let summary: serde_json::Value = serde_json::from_slice(&std::fs::read(dir.join("summary.json")).unwrap()).unwrap();
+        assert_eq!(summary["status"], "video_failed");
+        assert!(summary["video_error"].is_string());
+        assert_eq!(summary["error"], summary["video_error"]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_movie_playback() {
+        let mut ram = create_ram(&[]);
+        let mut gameboy = GameBoy::new(&mut ram);
+        let mut recorder = MovieRecorder::new(&mut gameboy, MovieStart::PowerOn, 2).unwrap();
+        for _ in 0..4 {
+            recorder.run_frame(&mut gameboy, Buttons::NONE.with(Button::A));
+        }
+        let mut movie = recorder.finish();
+
+        let mut options = options(6, Vec::new());
+        options.movie = Some(movie.clone());
+        let result = headless::run(&mut create_ram(&[]), &options).unwrap();
+        assert_eq!(result.status, ExitStatus::FrameLimit);
+        assert_eq!(result.frames, 6, "Runs on after the movie ends");
+
+        movie.frames[1].buttons = Buttons::NONE;
+        options.movie = Some(movie);
+        let result = headless::run(&mut create_ram(&[]), &options).unwrap();
+        assert_eq!(result.status, ExitStatus::Desync);
+        assert_eq!(result.exit_code, 1);
+        assert!(result.error.unwrap().starts_with("Desync at frame 2"));
+    }
+
+    #[test]
+    fn test_parse_number() {
+        assert_eq!(headless::parse_number("0x100"), Some(0x100));
+        assert_eq!(headless::parse_number("$FF"), Some(0xFF));
+        assert_eq!(headless::parse_number("42"), Some(42));
+        assert_eq!(headless::parse_number("nope"), None);
+        assert_eq!(headless::parse_memory_condition("0x10000=1"), None);
+    }
+}
@@ -1,12 +1,7 @@
-pub mod gb {
-    pub mod cpu;
-    pub mod ram;
-    pub mod register;
-    pub mod cpu_test;
-    pub mod gpu;
-    pub mod gpu_test;
-}
-// pub mod cpu;
-// pub mod register;
-// pub mod gpu;
-// pub mod gpu_test; 
\ No newline at end of file
+pub mod debugger;
+pub mod debugger_test;
+pub mod gb;
+pub mod headless;
+pub mod headless_test;
+#[cfg(feature = "frontend")]
+pub mod frontend;
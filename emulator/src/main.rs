@@ -1,4 +1,242 @@
-mod gb;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use emulator::debugger::Debugger;
+use emulator::gb::cartridge::Cartridge;
+use emulator::gb::disassembler;
+use emulator::gb::movie::Movie;
+use emulator::gb::trace::TraceFormat;
+use emulator::gb::video::VideoFormat;
+use emulator::gb::ram::RAM;
+use emulator::gb::GameBoy;
+use emulator::headless::{self, HeadlessOptions, StopCondition};
+
+const USAGE: &str = "Usage:
+  emulator <rom.gb> [--scale N] [--movie FILE | --record FILE] [--screenshot-dir DIR] [--screenshot-scale N]
+      [--video FILE] [--video-format gif|raw] [--mute]
+  emulator run <rom.gb> (same as above)
+  emulator run --headless <rom.gb> [--frames N] [--until-pc ADDRESS] [--until-serial TEXT]
+      [--until-mem ADDRESS=VALUE] [--until-breakpoint] [--screenshot FILE.png] [--screenshot-scale N]
+      [--dump FILE] [--summary FILE.json] [--movie FILE] [--trace FILE] [--trace-format registers|doctor]
+      [--video FILE] [--video-format gif|raw]
+  emulator debug <rom.gb>
+  emulator disasm <rom.gb> [-o FILE.asm]
+
+In the window F12 saves a screenshot and F10 starts or stops recording a clip, named after
+the game and frame, in the screenshot directory. Raw clips are RGBA frames with a WAV file
+of the sound next to them.";
+
 fn main() {
-    println!("Hello, world!");
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("run") => {
+            args.remove(0);
+            run(args);
+        }
+        Some("debug") => debug(&args[1..]),
+        Some("disasm") => disasm(&args[1..]),
+        _ => run(args),
+    }
+}
+
+fn debug(args: &[String]) {
+    let rom_path = match args {
+        [rom_path] => rom_path,
+        _ => exit_with_usage("debug takes a ROM path"),
+    };
+    let (mut ram, _) = load_rom(rom_path);
+    let mut debugger = Debugger::new(GameBoy::new(&mut ram));
+    if let Err(error) = debugger.repl() {
+        eprintln!("Debugger error: {}", error);
+        process::exit(1);
+    }
+}
+
+fn run(args: Vec<String>) {
+    let mut rom_path = None;
+    let mut scale = 3;
+    let mut audio = true;
+    let mut headless = false;
+    let mut record = None;
+    let mut screenshot_dir = None;
+    let mut options = HeadlessOptions::new();
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--scale" => {
+                scale = match args.next().and_then(|value| value.parse().ok()) {
+                    Some(value) if value > 0 => value,
+                    _ => exit_with_usage("--scale takes a positive integer"),
+                };
+            }
+            "--headless" => headless = true,
+            "--mute" => audio = false,
+            "--frames" => {
+                options.frames = match args.next().and_then(|value| headless::parse_number(&value)) {
+                    Some(value) => value as u64,
+                    None => exit_with_usage("--frames takes a number"),
+                };
+            }
+            "--until-pc" => {
+                let address = args.next().and_then(|value| headless::parse_number(&value)).and_then(|value| u16::try_from(value).ok());
+                match address {
+                    Some(address) => options.conditions.push(StopCondition::Pc(address)),
+                    None => exit_with_usage("--until-pc takes a 16-bit address"),
+                }
+            }
+            "--until-serial" => match args.next() {
+                Some(text) => options.conditions.push(StopCondition::Serial(text)),
+                None => exit_with_usage("--until-serial takes some text"),
+            },
+            "--until-mem" => match args.next().and_then(|value| headless::parse_memory_condition(&value)) {
+                Some(condition) => options.conditions.push(condition),
+                None => exit_with_usage("--until-mem takes ADDRESS=VALUE"),
+            },
+            "--until-breakpoint" => options.conditions.push(StopCondition::Breakpoint),
+            "--screenshot" => options.screenshot = Some(path_argument(&mut args, "--screenshot")),
+            "--screenshot-dir" => screenshot_dir = Some(path_argument(&mut args, "--screenshot-dir")),
+            "--screenshot-scale" => {
+                options.screenshot_scale = match args.next().and_then(|value| value.parse().ok()) {
+                    Some(value) if value > 0 => value,
+                    _ => exit_with_usage("--screenshot-scale takes a positive integer"),
+                };
+            }
+            "--dump" => options.memory_dump = Some(path_argument(&mut args, "--dump")),
+            "--summary" => options.summary = Some(path_argument(&mut args, "--summary")),
+            "--movie" => options.movie = Some(load_movie(&path_argument(&mut args, "--movie"))),
+            "--trace" => options.trace = Some(path_argument(&mut args, "--trace")),
+            "--trace-format" => {
+                options.trace_format = match args.next().as_deref() {
+                    Some("registers") => TraceFormat::Registers,
+                    Some("doctor") => TraceFormat::Doctor,
+                    _ => exit_with_usage("--trace-format takes registers or doctor"),
+                };
+            }
+            "--record" => record = Some(path_argument(&mut args, "--record")),
+            "--video" => options.video = Some(path_argument(&mut args, "--video")),
+            "--video-format" => {
+                options.video_format = match args.next().as_deref() {
+                    Some("gif") => VideoFormat::Gif,
+                    Some("raw") => VideoFormat::Raw,
+                    _ => exit_with_usage("--video-format takes gif or raw"),
+                };
+            }
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            }
+            _ if rom_path.is_none() && !arg.starts_with("--") => rom_path = Some(arg),
+            _ => exit_with_usage(&format!("Unexpected argument: {}", arg)),
+        }
+    }
+    let rom_path = rom_path.unwrap_or_else(|| exit_with_usage("Missing ROM path"));
+    let (mut ram, title) = load_rom(&rom_path);
+
+    if headless {
+        match headless::run(&mut ram, &options) {
+            Ok(result) => {
+                if options.summary.is_none() {
+                    println!("{}", result.summary);
+                }
+                process::exit(result.exit_code);
+            }
+            Err(error) => {
+                eprintln!("Headless run failed: {}", error);
+                process::exit(3);
+            }
+        }
+    }
+    if options.movie.is_some() && record.is_some() {
+        exit_with_usage("--movie and --record can't be used together");
+    }
+    run_frontend(&mut ram, &title, scale, audio, options, record, screenshot_dir);
+}
+
+fn disasm(args: &[String]) {
+    let (rom_path, output) = match args {
+        [rom_path] => (rom_path, None),
+        [rom_path, flag, output] | [flag, output, rom_path] if flag == "-o" => (rom_path, Some(output)),
+        _ => exit_with_usage("disasm takes a ROM path and optionally -o FILE"),
+    };
+    let rom = fs::read(rom_path).unwrap_or_else(|error| {
+        eprintln!("Failed to read {}: {}", rom_path, error);
+        process::exit(1);
+    });
+
+    let listing = disassembler::format_listing(&rom, &disassembler::disassemble_rom(&rom));
+    match output {
+        Some(path) => {
+            if let Err(error) = fs::write(path, listing) {
+                eprintln!("Failed to write {}: {}", path, error);
+                process::exit(1);
+            }
+        }
+        None => print!("{}", listing),
+    }
+}
+
+fn path_argument(args: &mut impl Iterator<Item = String>, flag: &str) -> PathBuf {
+    match args.next() {
+        Some(path) => PathBuf::from(path),
+        None => exit_with_usage(&format!("{} takes a file path", flag)),
+    }
+}
+
+fn load_movie(path: &Path) -> Movie {
+    let bytes = fs::read(path).unwrap_or_else(|error| {
+        eprintln!("Failed to read {}: {}", path.display(), error);
+        process::exit(1);
+    });
+    Movie::from_bytes(&bytes).unwrap_or_else(|error| {
+        eprintln!("Failed to load {}: {}", path.display(), error);
+        process::exit(1);
+    })
+}
+
+fn load_rom(rom_path: &str) -> (RAM, String) {
+    let rom = fs::read(rom_path).unwrap_or_else(|error| {
+        eprintln!("Failed to read {}: {}", rom_path, error);
+        process::exit(1);
+    });
+    let cartridge = Cartridge::new(rom).unwrap_or_else(|error| {
+        eprintln!("Failed to load {}: {}", rom_path, error);
+        process::exit(1);
+    });
+
+    let title = if cartridge.title.is_empty() { rom_path.to_string() } else { cartridge.title.clone() };
+    (RAM::with_cartridge(cartridge), title)
+}
+
+// Takes the options the window shares with headless runs from those
+#[cfg(feature = "frontend")]
+fn run_frontend(ram: &mut RAM, title: &str, scale: usize, audio: bool, shared: HeadlessOptions, record: Option<PathBuf>, screenshot_dir: Option<PathBuf>) {
+    let mut options = emulator::frontend::FrontendOptions::new();
+    options.scale = scale;
+    options.audio = audio;
+    options.movie = shared.movie;
+    options.record = record;
+    if let Some(dir) = screenshot_dir {
+        options.screenshot_dir = dir;
+    }
+    options.screenshot_scale = shared.screenshot_scale;
+    options.video = shared.video;
+    options.video_format = shared.video_format;
+    if let Err(error) = emulator::frontend::run(ram, title, options) {
+        eprintln!("Frontend error: {}", error);
+        process::exit(1);
+    }
+}
+
+#[cfg(not(feature = "frontend"))]
+fn run_frontend(_ram: &mut RAM, _title: &str, _scale: usize, _audio: bool, _shared: HeadlessOptions, _record: Option<PathBuf>, _screenshot_dir: Option<PathBuf>) {
+    eprintln!("This build has no window support, rebuild with the frontend feature or use --headless");
+    process::exit(1);
+}
+
+fn exit_with_usage(message: &str) -> ! {
+    eprintln!("{}\n{}", message, USAGE);
+    process::exit(2);
 }
@@ -0,0 +1,159 @@
+use std::cell::RefCell;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Lines, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use emulator::gb::cartridge::Cartridge;
+use emulator::gb::disassembler;
+use emulator::gb::joypad::Buttons;
+use emulator::gb::ram::RAM;
+use emulator::gb::trace::{TraceFormat, Tracer};
+use emulator::gb::GameBoy;
+
+/*
+Runs the 11 individual cpu_instrs ROMs from Blargg's test suite. The ROMs aren't part of the
+repository, point CPU_INSTRS_DIR at a directory holding them under their usual names
+("01-special.gb" ... "11-op a,(hl).gb"). Without it the test only says it was skipped.
+
+Each ROM reports "Passed" or "Failed" over the serial port. If CPU_INSTRS_LOGS names a
+directory of Gameboy Doctor's golden logs ("1.log" ... "11.log"), every instruction is also
+traced in the Doctor format and compared as it runs, and the first line that differs is
+reported along with the instruction that was about to run.
+*/
+
+const ROMS: [&str; 11] = [
+    "01-special",
+    "02-interrupts",
+    "03-op sp,hl",
+    "04-op r,imm",
+    "05-op rp",
+    "06-ld r,r",
+    "07-jr,jp,call,ret,rst",
+    "08-misc instrs",
+    "09-op r,r",
+    "10-bit ops",
+    "11-op a,(hl)",
+];
+
+// The slowest ROM needs well under a minute of emulated time
+const FRAME_LIMIT: u64 = 60 * 90;
+
+struct Divergence {
+    line: u64,
+    expected: String,
+    found: String,
+}
+
+// Checks trace lines against a golden log as the tracer writes them, keeping the first mismatch
+struct GoldenLog {
+    lines: Lines<BufReader<File>>,
+    partial: Vec<u8>,
+    line: u64,
+    ended: bool,
+    divergence: Rc<RefCell<Option<Divergence>>>,
+}
+
+impl Write for GoldenLog {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.partial.extend_from_slice(bytes);
+        while let Some(end) = self.partial.iter().position(|&byte| byte == b'\n') {
+            let found = String::from_utf8_lossy(&self.partial[..end]).into_owned();
+            self.partial.drain(..=end);
+            if self.ended || self.divergence.borrow().is_some() {
+                continue;
+            }
+            self.line += 1;
+            match self.lines.next() {
+                Some(expected) => {
+                    let expected = expected?;
+                    if expected.trim_end() != found {
+                        *self.divergence.borrow_mut() = Some(Divergence { line: self.line, expected, found });
+                    }
+                }
+                // The log stops once the ROM has passed, the serial output says the rest
+                None => self.ended = true,
+            }
+        }
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Disassembles the instruction a trace line was about to run, from its PC and PCMEM fields
+fn disassemble_line(line: &str) -> String {
+    let field = |name: &str| line.split(' ').find_map(|part| part.strip_prefix(name));
+    let (Some(pc), Some(memory)) = (field("PC:"), field("PCMEM:")) else {
+        return "?".to_string();
+    };
+    let pc = u16::from_str_radix(pc, 16).unwrap_or(0);
+    let bytes: Vec<u8> = memory.split(',').filter_map(|byte| u8::from_str_radix(byte, 16).ok()).collect();
+    let code = disassembler::disassemble(&|address| bytes.get(address.wrapping_sub(pc) as usize).copied().unwrap_or(0), pc);
+    format!("{:04X}: {}", pc, code.text())
+}
+
+// Runs one ROM, returning None if it passed or a description of what went wrong
+fn run_rom(path: &Path, golden_log: Option<PathBuf>) -> Option<String> {
+    let rom = fs::read(path).unwrap_or_else(|error| panic!("Failed to read {}: {}", path.display(), error));
+    let cartridge = Cartridge::new(rom).unwrap_or_else(|error| panic!("Failed to load {}: {}", path.display(), error));
+    let mut ram = RAM::with_cartridge(cartridge);
+    let mut gameboy = GameBoy::new(&mut ram);
+
+    let divergence = Rc::new(RefCell::new(None));
+    if let Some(log) = &golden_log {
+        let file = File::open(log).unwrap_or_else(|error| panic!("Failed to open {}: {}", log.display(), error));
+        let golden = GoldenLog { lines: BufReader::new(file).lines(), partial: Vec::new(), line: 0, ended: false, divergence: divergence.clone() };
+        gameboy.cpu.set_tracer(Tracer::new(Box::new(golden), TraceFormat::Doctor));
+    }
+
+    let mut serial = String::new();
+    while gameboy.frame < FRAME_LIMIT && divergence.borrow().is_none() {
+        gameboy.run_frame(&mut |_| Buttons::NONE);
+        serial = gameboy.cpu.ram.serial.output_string();
+        if serial.contains("Passed") || serial.contains("Failed") {
+            break;
+        }
+    }
+    if let Some(tracer) = gameboy.cpu.take_tracer() {
+        tracer.finish().unwrap();
+    }
+
+    if let Some(Divergence { line, expected, found }) = divergence.take() {
+        return Some(format!(
+            "trace differs at line {} before {}\n    expected: {}\n    found:    {}",
+            line,
+            disassemble_line(&found),
+            expected,
+            found
+        ));
+    }
+    if serial.contains("Passed") {
+        return None;
+    }
+    if serial.contains("Failed") {
+        return Some(format!("failed:\n{}", serial.trim_end()));
+    }
+    Some(format!("no result after {} frames, serial output: {:?}", FRAME_LIMIT, serial))
+}
+
+#[test]
+fn cpu_instrs() {
+    let Some(rom_dir) = env::var_os("CPU_INSTRS_DIR").map(PathBuf::from) else {
+        eprintln!("Skipping cpu_instrs, set CPU_INSTRS_DIR to the directory with the ROMs");
+        return;
+    };
+    let log_dir = env::var_os("CPU_INSTRS_LOGS").map(PathBuf::from);
+
+    let mut failures = Vec::new();
+    for (i, name) in ROMS.iter().enumerate() {
+        let golden_log = log_dir.as_ref().map(|dir| dir.join(format!("{}.log", i + 1)));
+        if let Some(failure) = run_rom(&rom_dir.join(format!("{}.gb", name)), golden_log) {
+            failures.push(format!("{}: {}", name, failure));
+        }
+    }
+    assert!(failures.is_empty(), "{} of {} ROMs failed\n{}", failures.len(), ROMS.len(), failures.join("\n"));
+}
@@ -0,0 +1,111 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use emulator::gb::cartridge::Cartridge;
+use emulator::gb::image::{diff_images, load_png, save_png};
+use emulator::gb::joypad::Buttons;
+use emulator::gb::ram::RAM;
+use emulator::gb::{GameBoy, SCREEN_HEIGHT, SCREEN_WIDTH};
+use emulator::headless::{self, StopCondition};
+
+/*
+Test ROMs that report through the CPU and the screen rather than the serial port. Neither
+suite is part of the repository, so each test is skipped unless its variable is set.
+
+MOONEYE_DIR: every .gb file under it is run until the LD B, B breakpoint, which passes if B,
+C, D, E, H and L hold the Fibonacci numbers 3, 5, 8, 13, 21, 34.
+
+DMG_ACID2_DIR: holds dmg-acid2.gb and its reference-dmg.png. Once the ROM hits its
+breakpoint the screen has to match the reference shade for shade. If it doesn't, the
+differing pixels are written to dmg-acid2-diff.png next to the screenshot in the test's
+temporary directory.
+*/
+
+const FRAME_LIMIT: u64 = 60 * 20;
+
+// Runs a ROM until it executes LD B, B, returning the machine or None if it never did
+fn run_to_breakpoint(ram: &mut RAM) -> Option<GameBoy<'_>> {
+    let mut gameboy = GameBoy::new(ram);
+    while gameboy.frame < FRAME_LIMIT {
+        if gameboy.run_frame_until(&mut |_| Buttons::NONE, &mut |cpu| StopCondition::Breakpoint.is_met(cpu)) {
+            return Some(gameboy);
+        }
+    }
+    None
+}
+
+fn load_rom(path: &Path) -> RAM {
+    let rom = fs::read(path).unwrap_or_else(|error| panic!("Failed to read {}: {}", path.display(), error));
+    let cartridge = Cartridge::new(rom).unwrap_or_else(|error| panic!("Failed to load {}: {}", path.display(), error));
+    RAM::with_cartridge(cartridge)
+}
+
+// Helper function to collect every ROM in a directory tree, in a stable order
+fn find_roms(dir: &Path, roms: &mut Vec<PathBuf>) {
+    let entries = fs::read_dir(dir).unwrap_or_else(|error| panic!("Failed to read {}: {}", dir.display(), error));
+    for entry in entries {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            find_roms(&path, roms);
+        } else if path.extension().is_some_and(|extension| extension == "gb") {
+            roms.push(path);
+        }
+    }
+    roms.sort();
+}
+
+#[test]
+fn mooneye() {
+    let Some(dir) = env::var_os("MOONEYE_DIR").map(PathBuf::from) else {
+        eprintln!("Skipping Mooneye tests, set MOONEYE_DIR to a directory of test ROMs");
+        return;
+    };
+    let mut roms = Vec::new();
+    find_roms(&dir, &mut roms);
+    assert!(!roms.is_empty(), "No ROMs in {}", dir.display());
+
+    let mut failures = Vec::new();
+    for path in &roms {
+        let name = path.strip_prefix(&dir).unwrap().display().to_string();
+        let mut ram = load_rom(path);
+        match run_to_breakpoint(&mut ram) {
+            Some(gameboy) if headless::mooneye_passed(&gameboy.cpu) => {}
+            Some(gameboy) => {
+                let registers = &gameboy.cpu.registers;
+                failures.push(format!(
+                    "{}: B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X}",
+                    name,
+                    registers.get_b(),
+                    registers.get_c(),
+                    registers.get_d(),
+                    registers.get_e(),
+                    registers.get_h(),
+                    registers.get_l()
+                ));
+            }
+            None => failures.push(format!("{}: no LD B, B after {} frames", name, FRAME_LIMIT)),
+        }
+    }
+    assert!(failures.is_empty(), "{} of {} ROMs failed\n{}", failures.len(), roms.len(), failures.join("\n"));
+}
+
+#[test]
+fn dmg_acid2() {
+    let Some(dir) = env::var_os("DMG_ACID2_DIR").map(PathBuf::from) else {
+        eprintln!("Skipping dmg-acid2, set DMG_ACID2_DIR to the directory with the ROM and reference image");
+        return;
+    };
+    let (width, height, expected) = load_png(&dir.join("reference-dmg.png")).expect("Failed to load the reference image");
+    assert_eq!((width as usize, height as usize), (SCREEN_WIDTH, SCREEN_HEIGHT));
+
+    let mut ram = load_rom(&dir.join("dmg-acid2.gb"));
+    let gameboy = run_to_breakpoint(&mut ram).expect("dmg-acid2 never reached its breakpoint");
+    let diff = diff_images(gameboy.screen_buffer(), &expected);
+    if diff.different > 0 {
+        let output = PathBuf::from(env!("CARGO_TARGET_TMPDIR"));
+        save_png(&output.join("dmg-acid2.png"), width, height, gameboy.screen_buffer()).unwrap();
+        save_png(&output.join("dmg-acid2-diff.png"), width, height, &diff.rgba).unwrap();
+        panic!("{} pixels differ from the reference, see {}", diff.different, output.join("dmg-acid2-diff.png").display());
+    }
+}